@@ -0,0 +1,225 @@
+//! `super_orchestrator::Command` does not support writing to the stdin of the
+//! child or per-command timeouts, [PipedCommand] is for tools that prompt for
+//! input (e.x. keyring passphrases) or may hang
+
+use std::{fmt, process::ExitStatus, time::Duration};
+
+use super_orchestrator::stacked_errors::{Error, MapAddError, Result};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt},
+    process,
+};
+
+use crate::MapAddContext;
+
+enum StdinSource {
+    Bytes(Vec<u8>),
+    Reader(Box<dyn AsyncRead + Send + Unpin>),
+}
+
+/// A command with piped stdin, stdout, and stderr
+pub struct PipedCommand {
+    program: String,
+    args: Vec<String>,
+    stdin: Option<StdinSource>,
+    timeout: Option<Duration>,
+}
+
+impl fmt::Debug for PipedCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PipedCommand")
+            .field("program", &self.program)
+            .field("args", &self.args)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PipedCommandResult {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl PipedCommandResult {
+    pub fn assert_success(&self) -> Result<()> {
+        if self.status.success() {
+            Ok(())
+        } else {
+            Err(Error::from(format!("command was not successful: {self:?}")))
+        }
+    }
+}
+
+/// Returned by [PipedCommand::run_to_completion] if the timeout was reached,
+/// this includes whatever output was captured before the child was killed
+#[derive(Debug, Clone)]
+pub struct CommandTimeout {
+    pub command: String,
+    pub timeout: Duration,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl fmt::Display for CommandTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} timed out after {:?}, partial stdout: {:?}, partial stderr: {:?}",
+            self.command, self.timeout, self.stdout, self.stderr
+        )
+    }
+}
+
+impl std::error::Error for CommandTimeout {}
+
+impl PipedCommand {
+    /// The same argument handling as `super_orchestrator::Command::new`,
+    /// `cmd_with_args` is split by whitespace and `args` are passed as-is
+    pub fn new(cmd_with_args: &str, args: &[&str]) -> Self {
+        let mut split = cmd_with_args.split_whitespace();
+        let program = split.next().unwrap_or_default().to_owned();
+        let mut res_args: Vec<String> = split.map(|s| s.to_owned()).collect();
+        res_args.extend(args.iter().map(|s| (*s).to_owned()));
+        Self {
+            program,
+            args: res_args,
+            stdin: None,
+            timeout: None,
+        }
+    }
+
+    /// Writes `bytes` to the stdin of the child, after which stdin is closed
+    pub fn stdin_bytes(mut self, bytes: Vec<u8>) -> Self {
+        self.stdin = Some(StdinSource::Bytes(bytes));
+        self
+    }
+
+    /// Streams `reader` into the stdin of the child, after which stdin is
+    /// closed
+    pub fn stdin_from<R: AsyncRead + Send + Unpin + 'static>(mut self, reader: R) -> Self {
+        self.stdin = Some(StdinSource::Reader(Box::new(reader)));
+        self
+    }
+
+    /// If the command does not complete within `timeout`, the child is killed
+    /// and a [CommandTimeout] error is returned
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub async fn run_to_completion(self) -> Result<PipedCommandResult> {
+        let debug = format!("{self:?}");
+        let mut child = process::Command::new(&self.program)
+            .args(&self.args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_add_err(|| format!("failed to spawn {debug}"))?;
+
+        let mut child_stdin = child.stdin.take().locate()?;
+        let stdin = self.stdin;
+        // write concurrently with reading, otherwise a child that fills its output
+        // pipes before reading all of its input would deadlock
+        let write_stdin = async move {
+            match stdin {
+                Some(StdinSource::Bytes(bytes)) => child_stdin.write_all(&bytes).await?,
+                Some(StdinSource::Reader(mut reader)) => {
+                    io::copy(&mut reader, &mut child_stdin).await?;
+                }
+                None => (),
+            }
+            // dropping closes stdin
+            drop(child_stdin);
+            io::Result::Ok(())
+        };
+        let mut child_stdout = child.stdout.take().locate()?;
+        let mut child_stderr = child.stderr.take().locate()?;
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        let completion = async {
+            let (write_res, stdout_res, stderr_res) = tokio::join!(
+                write_stdin,
+                child_stdout.read_to_end(&mut stdout),
+                child_stderr.read_to_end(&mut stderr)
+            );
+            (write_res, stdout_res, stderr_res, child.wait().await)
+        };
+        let res = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, completion).await.ok(),
+            None => Some(completion.await),
+        };
+        let Some((write_res, stdout_res, stderr_res, status)) = res else {
+            // the reads are cancellation safe, so the partial output is kept
+            child
+                .kill()
+                .await
+                .map_add_err(|| format!("failed to kill {debug}"))?;
+            return Err(Error::boxed(Box::new(CommandTimeout {
+                command: debug,
+                timeout: self.timeout.unwrap_or_default(),
+                stdout: String::from_utf8_lossy(&stdout).into_owned(),
+                stderr: String::from_utf8_lossy(&stderr).into_owned(),
+            })))
+        };
+        // a child exiting without reading all of stdin is not an error on our side
+        if let Err(e) = write_res {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                return Err(e).map_add_err(|| format!("failed to write stdin of {debug}"))
+            }
+        }
+        stdout_res.map_add_err(|| format!("failed to read stdout of {debug}"))?;
+        stderr_res.map_add_err(|| format!("failed to read stderr of {debug}"))?;
+        let status = status.map_add_err(|| format!("failed to wait for {debug}"))?;
+        Ok(PipedCommandResult {
+            status,
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_piped_command() {
+    let comres = PipedCommand::new("cat", &[])
+        .stdin_bytes(b"hello\nworld\n".to_vec())
+        .run_to_completion()
+        .await
+        .unwrap();
+    comres.assert_success().unwrap();
+    assert_eq!(comres.stdout, "hello\nworld\n");
+    let comres = PipedCommand::new("cat", &[])
+        .stdin_from(&b"streamed"[..])
+        .run_to_completion()
+        .await
+        .unwrap();
+    assert_eq!(comres.stdout, "streamed");
+}
+
+#[tokio::test]
+async fn test_piped_command_timeout() {
+    let start = std::time::Instant::now();
+    let res = PipedCommand::new("sh -c", &["echo before_timeout && sleep 100"])
+        .timeout(Duration::from_secs(1))
+        .run_to_completion()
+        .await;
+    // the child should be killed right at the timeout
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_secs(1), "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(10), "{elapsed:?}");
+    let e = format!("{:?}", res.unwrap_err());
+    assert!(e.contains("CommandTimeout") && e.contains("timeout: 1s"), "{e}");
+    // the output from before the timeout is kept
+    assert!(e.contains("before_timeout"));
+    // a command that completes in time is unaffected
+    let comres = PipedCommand::new("echo in_time", &[])
+        .timeout(Duration::from_secs(10))
+        .run_to_completion()
+        .await
+        .unwrap();
+    assert_eq!(comres.stdout, "in_time\n");
+}
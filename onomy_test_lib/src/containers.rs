@@ -0,0 +1,1574 @@
+//! Helpers for the runner to interact with the live containers of a
+//! `ContainerNetwork`, e.x. while it is paused with `--keep-running`
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::IpAddr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use regex::Regex;
+use serde_json::{json, Value};
+use super_orchestrator::{
+    docker::{Container, ContainerNetwork, Dockerfile},
+    sh_no_dbg,
+    stacked_errors::{Error, MapAddError, Result},
+    FileOptions,
+};
+use tokio::time::sleep;
+
+use crate::{
+    command::{PipedCommand, PipedCommandResult},
+    logs::parse_log_timestamp,
+    poll_until, yaml_str_to_json_value, Args, MapAddContext,
+};
+
+/// Returns the full name of the running container for `name` (the name given
+/// to `Container::new`). Names may have a suffix added by the network.
+pub async fn find_container(name: &str) -> Result<String> {
+    let ps = sh_no_dbg("docker ps --format {{.Names}} --filter", &[&format!(
+        "name={name}"
+    )])
+    .await
+    .map_add_err(|| format!("find_container(name: {name})"))?;
+    let mut found: Vec<&str> = ps
+        .lines()
+        .map(|s| s.trim())
+        .filter(|s| (*s == name) || s.starts_with(&format!("{name}_")))
+        .collect();
+    if found.contains(&name) {
+        return Ok(name.to_owned())
+    }
+    match found.len() {
+        0 => Err(Error::from(format!(
+            "find_container(name: {name}) no running container found"
+        ))),
+        1 => Ok(found.pop().unwrap().to_owned()),
+        _ => Err(Error::from(format!(
+            "find_container(name: {name}) found multiple containers: {found:?}"
+        ))),
+    }
+}
+
+/// Runs `docker exec` in the running container for `name` (see
+/// [find_container]) and returns the output without asserting success. This
+/// can be used for e.x. ad-hoc `onomyd query ...`s in a specific container.
+pub async fn container_exec(
+    name: &str,
+    cmd_with_args: &str,
+    args: &[&str],
+) -> Result<PipedCommandResult> {
+    let container = find_container(name).await?;
+    let mut exec_args = vec![container.as_str()];
+    exec_args.extend(cmd_with_args.split_whitespace());
+    exec_args.extend(args);
+    PipedCommand::new("docker exec", &exec_args)
+        .run_to_completion()
+        .await
+        .map_add_err(|| {
+            format!("container_exec(name: {name}, cmd_with_args: {cmd_with_args}, args: {args:?})")
+        })
+}
+
+/// Returns the IP address of the running container for `name` (see
+/// [find_container]) on its first network. Returns an error if the container is
+/// not running yet.
+pub async fn container_ip(name: &str) -> Result<IpAddr> {
+    let container = find_container(name).await?;
+    let comres = PipedCommand::new("docker inspect --format", &[
+        "{{range .NetworkSettings.Networks}}{{.IPAddress}} {{end}}",
+        &container,
+    ])
+    .run_to_completion()
+    .await?;
+    comres
+        .assert_success()
+        .map_add_err(|| format!("container_ip(name: {name})"))?;
+    parse_container_ips(&comres.stdout)
+        .into_iter()
+        .next()
+        .map_add_err(|| format!("container_ip(name: {name}) container has no IP address"))
+}
+
+fn parse_container_ips(s: &str) -> Vec<IpAddr> {
+    // containers that are not attached to a network have empty addresses
+    s.split_whitespace()
+        .filter_map(|s| s.parse().ok())
+        .collect()
+}
+
+/// A log line matched by [wait_for_log] or [wait_for_log_file]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchedLine {
+    pub line: String,
+    /// The capture groups of the pattern, index 0 is the whole match
+    pub captures: Vec<Option<String>>,
+}
+
+impl MatchedLine {
+    /// Returns capture group `i`, if it participated in the match
+    pub fn get(&self, i: usize) -> Option<&str> {
+        self.captures.get(i)?.as_deref()
+    }
+}
+
+/// Returns the first line of `log` matching `pattern`
+pub fn find_log_match(log: &str, pattern: &Regex) -> Option<MatchedLine> {
+    log.lines().find_map(|line| {
+        let captures = pattern.captures(line)?;
+        Some(MatchedLine {
+            line: line.to_owned(),
+            captures: captures
+                .iter()
+                .map(|c| c.map(|c| c.as_str().to_owned()))
+                .collect(),
+        })
+    })
+}
+
+/// Polls the `docker logs` (both stdout and stderr) of the running container
+/// for `entry_name` (see [find_container]) until a line matches `pattern`,
+/// e.x. "committed state" for the first block of a daemon. This is for
+/// readiness conditions of things that have no health endpoint.
+pub async fn wait_for_log(
+    entry_name: &str,
+    pattern: &Regex,
+    timeout: Duration,
+) -> Result<MatchedLine> {
+    let query = move || async move {
+        let container = find_container(entry_name).await?;
+        let comres = PipedCommand::new("docker logs", &[&container])
+            .run_to_completion()
+            .await?;
+        comres.assert_success()?;
+        Ok(find_log_match(&comres.stdout, pattern)
+            .or_else(|| find_log_match(&comres.stderr, pattern)))
+    };
+    poll_until(query, Option::is_some, timeout, Duration::from_millis(200))
+        .await
+        .map_add_err(|| format!("wait_for_log(entry_name: {entry_name}, pattern: {pattern})"))?
+        .locate()
+}
+
+/// The same as [wait_for_log] but for a log file, e.x. one in the log
+/// directory of the `ContainerNetwork`. The file does not need to exist yet.
+pub async fn wait_for_log_file(
+    path: &str,
+    pattern: &Regex,
+    timeout: Duration,
+) -> Result<MatchedLine> {
+    let query = move || async move {
+        Ok(find_log_match(
+            &FileOptions::read_to_string(path).await?,
+            pattern,
+        ))
+    };
+    poll_until(query, Option::is_some, timeout, Duration::from_millis(100))
+        .await
+        .map_add_err(|| format!("wait_for_log_file(path: {path}, pattern: {pattern})"))?
+        .locate()
+}
+
+/// Options for a user defined docker network, for environments where the
+/// default `172.x` ranges collide with e.x. a VPN
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DockerNetworkOptions {
+    /// CIDR subnet, e.x. "10.123.0.0/16"
+    pub subnet: Option<String>,
+    /// Must be within `subnet`
+    pub gateway: Option<String>,
+    /// Extra DNS servers for the containers, note that the hostnames of
+    /// containers on the network are always resolved by the docker DNS first
+    pub dns: Vec<String>,
+}
+
+/// Parses a CIDR subnet into its address and prefix length
+pub fn parse_cidr(cidr: &str) -> Result<(IpAddr, u8)> {
+    let (addr, prefix_len) = cidr
+        .split_once('/')
+        .map_add_err(|| format!("parse_cidr({cidr}) is missing the prefix length"))?;
+    let addr = addr
+        .parse::<IpAddr>()
+        .map_err(|e| Error::boxed(Box::new(e)))
+        .map_add_err(|| format!("parse_cidr({cidr}) invalid address"))?;
+    let prefix_len = prefix_len
+        .parse::<u8>()
+        .map_add_err(|| format!("parse_cidr({cidr}) invalid prefix length"))?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix_len > max {
+        return Err(Error::from(format!(
+            "parse_cidr({cidr}) prefix length is larger than {max}"
+        )))
+    }
+    Ok((addr, prefix_len))
+}
+
+fn cidr_contains(subnet: (IpAddr, u8), ip: IpAddr) -> bool {
+    let (addr, prefix_len) = subnet;
+    match (addr, ip) {
+        (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len))
+                .unwrap_or(0);
+            (u32::from(addr) & mask) == (u32::from(ip) & mask)
+        }
+        (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len))
+                .unwrap_or(0);
+            (u128::from(addr) & mask) == (u128::from(ip) & mask)
+        }
+        _ => false,
+    }
+}
+
+impl DockerNetworkOptions {
+    /// The validated options from `--subnet`, `--gateway`, and `--dns`
+    pub fn from_args(args: &Args) -> Result<Self> {
+        let options = Self {
+            subnet: args.subnet.clone(),
+            gateway: args.gateway.clone(),
+            dns: args.dns.clone(),
+        };
+        options
+            .validate()
+            .map_add_err(|| "DockerNetworkOptions::from_args")?;
+        Ok(options)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let subnet = match self.subnet {
+            Some(ref subnet) => Some(parse_cidr(subnet)?),
+            None => None,
+        };
+        if let Some(ref gateway) = self.gateway {
+            let subnet = subnet.map_add_err(|| "a gateway was given without a subnet")?;
+            let gateway = gateway
+                .parse::<IpAddr>()
+                .map_err(|e| Error::boxed(Box::new(e)))
+                .map_add_err(|| format!("invalid gateway {gateway}"))?;
+            if !cidr_contains(subnet, gateway) {
+                return Err(Error::from(format!(
+                    "gateway {gateway} is not within subnet {:?}",
+                    self.subnet
+                )))
+            }
+        }
+        for dns in &self.dns {
+            dns.parse::<IpAddr>()
+                .map_err(|e| Error::boxed(Box::new(e)))
+                .map_add_err(|| format!("invalid DNS server {dns}"))?;
+        }
+        Ok(())
+    }
+
+    /// Arguments for `docker network create`
+    pub fn create_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(ref subnet) = self.subnet {
+            args.push(format!("--subnet={subnet}"));
+        }
+        if let Some(ref gateway) = self.gateway {
+            args.push(format!("--gateway={gateway}"));
+        }
+        args
+    }
+
+    /// The `ipam` of the network in a docker-compose file, if a subnet is set
+    fn compose_ipam(&self) -> Option<Value> {
+        let subnet = self.subnet.as_ref()?;
+        let mut config = json!({ "subnet": subnet });
+        if let Some(ref gateway) = self.gateway {
+            config["gateway"] = json!(gateway);
+        }
+        Some(json!({ "config": [config] }))
+    }
+}
+
+/// Returns the `docker create` arguments that make a container use the extra
+/// `dns` servers, see [DockerNetworkOptions::dns]
+pub fn dns_args(dns: &[String]) -> Vec<String> {
+    dns.iter().map(|dns| format!("--dns={dns}")).collect()
+}
+
+/// Validates `options` and creates the docker network `name`. Running
+/// containers can be attached with [connect_to_network] so that they are
+/// reachable by their name on the network.
+pub async fn create_docker_network(name: &str, options: &DockerNetworkOptions) -> Result<()> {
+    options
+        .validate()
+        .map_add_err(|| format!("create_docker_network(name: {name})"))?;
+    let mut args = options.create_args();
+    args.push(name.to_owned());
+    let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+    PipedCommand::new("docker network create", &args)
+        .run_to_completion()
+        .await?
+        .assert_success()
+        .map_add_err(|| format!("create_docker_network(name: {name}, options: {options:?})"))
+}
+
+/// Makes the first run of `cn` use a network created with `options`. A
+/// `ContainerNetwork` recreates its network `network_name` without any
+/// options on its first run, so this does that run with no containers and
+/// then replaces the network with one from [create_docker_network]. Does
+/// nothing if neither a subnet nor a gateway is set.
+pub async fn apply_network_create_options(
+    cn: &mut ContainerNetwork,
+    network_name: &str,
+    options: &DockerNetworkOptions,
+) -> Result<()> {
+    if options.subnet.is_none() && options.gateway.is_none() {
+        return Ok(())
+    }
+    cn.run(&[], true)
+        .await
+        .map_add_err(|| format!("apply_network_create_options(network_name: {network_name})"))?;
+    PipedCommand::new("docker network rm", &[network_name])
+        .run_to_completion()
+        .await?
+        .assert_success()
+        .map_add_err(|| format!("apply_network_create_options(network_name: {network_name})"))?;
+    create_docker_network(network_name, options).await
+}
+
+/// Connects the running container for `name` (see [find_container]) to
+/// `network`, with `name` as its hostname on that network
+pub async fn connect_to_network(network: &str, name: &str) -> Result<()> {
+    let container = find_container(name).await?;
+    PipedCommand::new("docker network connect --alias", &[
+        name, network, &container,
+    ])
+    .run_to_completion()
+    .await?
+    .assert_success()
+    .map_add_err(|| format!("connect_to_network(network: {network}, name: {name})"))
+}
+
+/// Returns the subnets of the docker network `name`
+pub async fn docker_network_subnets(name: &str) -> Result<Vec<String>> {
+    let comres = PipedCommand::new("docker network inspect --format", &[
+        "{{range .IPAM.Config}}{{.Subnet}} {{end}}",
+        name,
+    ])
+    .run_to_completion()
+    .await?;
+    comres
+        .assert_success()
+        .map_add_err(|| format!("docker_network_subnets(name: {name})"))?;
+    Ok(comres
+        .stdout
+        .split_whitespace()
+        .map(|s| s.to_owned())
+        .collect())
+}
+
+/// The image used by [NetworkPartition] to run `iptables` in the network
+/// namespace of a container, so that the container images themselves do not
+/// need `iptables` or extra capabilities
+pub const PARTITION_IMAGE: &str = "nicolaka/netshoot:v0.11";
+
+/// Returns a shell script that runs `iptables {action}` (e.x. "-A" to add or
+/// "-D" to delete) for rules dropping all traffic to and from `peers`
+pub fn partition_script(action: &str, peers: &[IpAddr]) -> String {
+    let mut rules = vec![];
+    for peer in peers {
+        rules.push(format!("iptables {action} INPUT -s {peer} -j DROP"));
+        rules.push(format!("iptables {action} OUTPUT -d {peer} -j DROP"));
+    }
+    rules.join(" && ")
+}
+
+async fn run_partition_script(container: &str, script: &str) -> Result<()> {
+    PipedCommand::new("docker run --rm --cap-add NET_ADMIN --network", &[
+        &format!("container:{container}"),
+        PARTITION_IMAGE,
+        "sh",
+        "-c",
+        script,
+    ])
+    .run_to_completion()
+    .await?
+    .assert_success()
+    .map_add_err(|| format!("run_partition_script(container: {container}, script: {script})"))
+}
+
+/// A network partition between two groups of running containers, for testing
+/// recovery paths (e.x. the relayer losing its connection to a chain). The
+/// containers stay attached to the network and keep their addresses, only the
+/// traffic between the groups is dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkPartition {
+    /// The full container names of the first group and the addresses of the
+    /// second group that they cannot reach
+    isolated: Vec<String>,
+    peers: Vec<IpAddr>,
+}
+
+impl NetworkPartition {
+    /// Partitions the containers for the names in `a` from those in `b` (see
+    /// [find_container]). Traffic within each group and to other containers is
+    /// unaffected. Use [NetworkPartition::heal] to restore connectivity.
+    pub async fn partition(a: &[&str], b: &[&str]) -> Result<Self> {
+        let mut peers = vec![];
+        for name in b {
+            peers.push(container_ip(name).await?);
+        }
+        let mut res = Self {
+            isolated: vec![],
+            peers,
+        };
+        let script = partition_script("-A", &res.peers);
+        for name in a {
+            let applied = match find_container(name).await {
+                Ok(container) => run_partition_script(&container, &script)
+                    .await
+                    .map(|_| container),
+                Err(e) => Err(e),
+            };
+            match applied {
+                Ok(container) => res.isolated.push(container),
+                Err(e) => {
+                    // do not leave a partial partition behind
+                    if let Err(heal_e) = res.heal().await {
+                        warn!("{heal_e:?}");
+                    }
+                    return Err(e)
+                        .map_add_err(|| format!("NetworkPartition::partition(a: {a:?}, b: {b:?})"))
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Removes the partition. The rules of every container are removed even if
+    /// some fail, and the first error is returned. Only the containers that
+    /// failed are left partitioned, so this can be called again to retry and
+    /// does nothing once the partition is healed.
+    pub async fn heal(&mut self) -> Result<()> {
+        let script = partition_script("-D", &self.peers);
+        let mut res = Ok(());
+        for container in std::mem::take(&mut self.isolated) {
+            if let Err(e) = run_partition_script(&container, &script).await {
+                if res.is_ok() {
+                    res = Err(e);
+                } else {
+                    warn!("{e:?}");
+                }
+                self.isolated.push(container);
+            }
+        }
+        res.map_add_err(|| format!("NetworkPartition::heal {self:?}"))
+    }
+}
+
+/// The label with the id of the run that created a container
+pub const RUN_ID_LABEL: &str = "onomy_test.run_id";
+/// The label with the name of the test that created a container
+pub const TEST_NAME_LABEL: &str = "onomy_test.test_name";
+/// The label with the git commit of the tests that created a container
+pub const GIT_SHA_LABEL: &str = "onomy_test.git_sha";
+
+/// Returns the labels that every container of a run of `test_name` should
+/// have, with a new random [RUN_ID_LABEL]. [GIT_SHA_LABEL] is left out if `git
+/// rev-parse HEAD` fails.
+pub async fn run_labels(test_name: &str) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    labels.insert(
+        RUN_ID_LABEL.to_owned(),
+        format!("{:016x}", rand::random::<u64>()),
+    );
+    labels.insert(TEST_NAME_LABEL.to_owned(), test_name.to_owned());
+    if let Ok(comres) = PipedCommand::new("git rev-parse HEAD", &[])
+        .run_to_completion()
+        .await
+    {
+        if comres.status.success() {
+            labels.insert(GIT_SHA_LABEL.to_owned(), comres.stdout.trim().to_owned());
+        }
+    }
+    labels
+}
+
+/// Returns the `--label key=value` arguments for `docker create` or `docker
+/// run`
+pub fn label_args(labels: &BTreeMap<String, String>) -> Vec<String> {
+    let mut args = vec![];
+    for (key, value) in labels {
+        args.push("--label".to_owned());
+        args.push(format!("{key}={value}"));
+    }
+    args
+}
+
+/// Returns the `docker create` arguments that add the network `aliases` to a
+/// container, so that other containers on the network can reach it by these
+/// hostnames in addition to its name
+pub fn network_alias_args(aliases: &[String]) -> Vec<String> {
+    let mut args = vec![];
+    for alias in aliases {
+        args.push("--network-alias".to_owned());
+        args.push(alias.clone());
+    }
+    args
+}
+
+/// Returns the `docker create` arguments that publish the `(host port,
+/// container port)` pairs
+pub fn publish_args(ports: &[(u16, u16)]) -> Vec<String> {
+    let mut args = vec![];
+    for (host, container) in ports {
+        args.push("-p".to_owned());
+        args.push(format!("{host}:{container}"));
+    }
+    args
+}
+
+/// Returns the labels of the container `name` from `docker inspect`
+pub async fn container_labels(name: &str) -> Result<BTreeMap<String, String>> {
+    let comres = PipedCommand::new("docker inspect --format", &[
+        "{{json .Config.Labels}}",
+        name,
+    ])
+    .run_to_completion()
+    .await?;
+    comres
+        .assert_success()
+        .map_add_err(|| format!("container_labels(name: {name})"))?;
+    let labels: Option<BTreeMap<String, String>> = serde_json::from_str(comres.stdout.trim())
+        .map_add_err(|| format!("container_labels(name: {name}) unexpected output"))?;
+    Ok(labels.unwrap_or_default())
+}
+
+/// Force removes all containers (running or not) that have the label `key`,
+/// or `key=value` if `value` is set, e.x. all containers of any run with
+/// `(RUN_ID_LABEL, None)`. Returns the ids of the removed containers.
+pub async fn remove_labeled_containers(key: &str, value: Option<&str>) -> Result<Vec<String>> {
+    let filter = match value {
+        Some(value) => format!("label={key}={value}"),
+        None => format!("label={key}"),
+    };
+    let ps = sh_no_dbg("docker ps -a -q --filter", &[&filter])
+        .await
+        .map_add_err(|| format!("remove_labeled_containers(filter: {filter})"))?;
+    let ids: Vec<String> = ps.split_whitespace().map(|s| s.to_owned()).collect();
+    if !ids.is_empty() {
+        let mut args = vec!["-f"];
+        args.extend(ids.iter().map(|s| s.as_str()));
+        sh_no_dbg("docker rm", &args)
+            .await
+            .map_add_err(|| format!("remove_labeled_containers(filter: {filter})"))?;
+    }
+    Ok(ids)
+}
+
+/// What [preflight_check] verifies
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PreflightOptions {
+    /// Checks that the docker daemon is reachable with `docker info`
+    pub docker: bool,
+    /// Rust targets (e.x. "x86_64-unknown-linux-gnu") that need to be
+    /// installed with `rustup`
+    pub rust_targets: Vec<String>,
+    /// Ports that need to be free on the host
+    pub free_ports: Vec<u16>,
+}
+
+/// Returns the `ports` that cannot be bound on the host
+pub fn ports_in_use(ports: &[u16]) -> Vec<u16> {
+    ports
+        .iter()
+        .copied()
+        .filter(|port| std::net::TcpListener::bind(("0.0.0.0", *port)).is_err())
+        .collect()
+}
+
+/// Checks the environment before anything is built or run, so that a missing
+/// docker daemon or toolchain results in one actionable error listing
+/// everything that is missing instead of a confusing failure midway through
+/// the run
+pub async fn preflight_check(options: &PreflightOptions) -> Result<()> {
+    let mut problems = vec![];
+    if options.docker {
+        let docker_info = PipedCommand::new("docker info --format", &["{{.ServerVersion}}"])
+            .run_to_completion()
+            .await;
+        match docker_info {
+            Ok(comres) if comres.status.success() => (),
+            Ok(comres) => problems.push(format!(
+                "docker daemon not reachable, is it running? `docker info` failed with: {}",
+                comres.stderr.trim()
+            )),
+            Err(_) => problems.push("docker is not installed (`docker` was not found)".to_owned()),
+        }
+    }
+    if !options.rust_targets.is_empty() {
+        match PipedCommand::new("rustup target list --installed", &[])
+            .run_to_completion()
+            .await
+        {
+            Ok(comres) if comres.status.success() => {
+                let installed: Vec<&str> = comres.stdout.lines().map(|s| s.trim()).collect();
+                for target in &options.rust_targets {
+                    if !installed.contains(&target.as_str()) {
+                        problems.push(format!(
+                            "rust target {target} is not installed, run `rustup target add \
+                             {target}`"
+                        ));
+                    }
+                }
+            }
+            _ => problems.push(format!(
+                "could not list the installed rust targets with `rustup`, the targets {:?} are \
+                 required",
+                options.rust_targets
+            )),
+        }
+    }
+    for port in ports_in_use(&options.free_ports) {
+        problems.push(format!("port {port} is already in use"));
+    }
+    if !problems.is_empty() {
+        return Err(Error::from(format!(
+            "preflight_check failed (use `--skip-preflight` if the environment is managed \
+             externally):\n{}",
+            problems.join("\n")
+        )))
+    }
+    Ok(())
+}
+
+/// The ELF `e_machine` of x86_64, the architecture of our containers
+pub const ELF_MACHINE_X86_64: u16 = 62;
+
+/// Returns the `e_machine` field of an ELF header, or `None` if `header` is not
+/// the start of a little endian ELF file
+pub fn elf_machine(header: &[u8]) -> Option<u16> {
+    // magic, and EI_DATA of 1 for little endian
+    if (header.get(..4)? != b"\x7fELF") || (*header.get(5)? != 1) {
+        return None
+    }
+    Some(u16::from_le_bytes([*header.get(18)?, *header.get(19)?]))
+}
+
+/// Checks that `path` is an executable x86_64 ELF binary that can be mounted
+/// into a container (see `Args::daemon_binary`), and returns the file name
+pub async fn validate_daemon_binary(path: &str) -> Result<String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let err = || format!("validate_daemon_binary(path: {path})");
+    let metadata = tokio::fs::metadata(path).await.map_add_err(err)?;
+    if !metadata.is_file() || (metadata.permissions().mode() & 0o111 == 0) {
+        return Err(Error::from(format!(
+            "validate_daemon_binary(path: {path}) is not an executable file, try `chmod +x {path}`"
+        )))
+    }
+    let mut header = [0u8; 20];
+    let mut file = tokio::fs::File::open(path).await.map_add_err(err)?;
+    tokio::io::AsyncReadExt::read_exact(&mut file, &mut header)
+        .await
+        .map_add_err(err)?;
+    match elf_machine(&header) {
+        Some(ELF_MACHINE_X86_64) => (),
+        Some(machine) => {
+            return Err(Error::from(format!(
+                "validate_daemon_binary(path: {path}) is built for ELF machine {machine}, but the \
+                 containers are x86_64"
+            )))
+        }
+        None => {
+            return Err(Error::from(format!(
+                "validate_daemon_binary(path: {path}) is not a Linux (ELF) binary"
+            )))
+        }
+    }
+    path.rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_owned())
+        .map_add_err(err)
+}
+
+/// The startup timing of one container, the times are nanoseconds since the
+/// Unix epoch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerStartup {
+    pub name: String,
+    /// The `State.StartedAt` time reported by docker
+    pub started_at: Option<i128>,
+    /// When the readiness port of the container first accepted a connection
+    pub ready_at: Option<i128>,
+}
+
+impl ContainerStartup {
+    /// The time from `started_at` to `ready_at`, if the container became ready
+    pub fn time_to_ready(&self) -> Option<Duration> {
+        let nanos = self.ready_at? - self.started_at?;
+        Some(Duration::from_nanos(u64::try_from(nanos.max(0)).ok()?))
+    }
+}
+
+/// The startup timing of the containers of a network, see [measure_startup]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StartupReport {
+    pub containers: Vec<ContainerStartup>,
+}
+
+impl StartupReport {
+    /// Returns a table with a row for each container, ordered by start time
+    pub fn summary(&self) -> String {
+        let mut containers: Vec<&ContainerStartup> = self.containers.iter().collect();
+        containers.sort_by_key(|c| c.started_at);
+        let start = containers.iter().filter_map(|c| c.started_at).min();
+        let mut s = format!(
+            "{:<20} {:>12} {:>14}\n",
+            "container", "started", "time to ready"
+        );
+        for c in containers {
+            let started = match (c.started_at, start) {
+                (Some(t), Some(start)) => format!("+{:.3}s", (t - start) as f64 / 1e9),
+                _ => "-".to_owned(),
+            };
+            let ready = c
+                .time_to_ready()
+                .map(|d| format!("{:.3}s", d.as_secs_f64()))
+                .unwrap_or_else(|| "not ready".to_owned());
+            writeln!(s, "{:<20} {:>12} {:>14}", c.name, started, ready).unwrap();
+        }
+        s
+    }
+
+    /// If no container became ready at all, which is what happens when the
+    /// host cannot reach the container IPs (e.x. with Docker Desktop)
+    pub fn none_ready(&self) -> bool {
+        self.containers.iter().all(|c| c.ready_at.is_none())
+    }
+
+    /// Returns an error listing the containers that did not become ready
+    /// within `budget` of being started
+    pub fn assert_ready_within(&self, budget: Duration) -> Result<()> {
+        let slow: Vec<String> = self
+            .containers
+            .iter()
+            .filter_map(|c| match c.time_to_ready() {
+                Some(d) if d <= budget => None,
+                Some(d) => Some(format!("{} took {}s", c.name, d.as_secs_f64())),
+                None => Some(format!("{} never became ready", c.name)),
+            })
+            .collect();
+        if slow.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::from(format!(
+                "StartupReport::assert_ready_within(budget: {budget:?}) slow containers:\n{}",
+                slow.join("\n")
+            )))
+        }
+    }
+}
+
+/// Returns the `State.StartedAt` time of the container for `name` (see
+/// [find_container]) in nanoseconds since the Unix epoch
+pub async fn container_started_at(name: &str) -> Result<i128> {
+    let container = find_container(name).await?;
+    let started_at = sh_no_dbg("docker inspect --format {{.State.StartedAt}}", &[
+        &container,
+    ])
+    .await
+    .map_add_err(|| format!("container_started_at(name: {name})"))?;
+    parse_log_timestamp(started_at.trim()).map_add_err(|| {
+        format!(
+            "container_started_at(name: {name}) could not parse {}",
+            started_at.trim()
+        )
+    })
+}
+
+fn unix_nanos_now() -> i128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i128)
+        .unwrap_or(0)
+}
+
+/// Polls the `(name, port)` pairs until the port on the container IP accepts a
+/// TCP connection, which is when the container is considered ready (e.x. the
+/// `RPC_PORT` of a daemon). This is meant to run concurrently with the
+/// `ContainerNetwork` being waited on. Containers that are not ready within
+/// `timeout` are left with a `ready_at` of `None` instead of this returning an
+/// error. Note that this requires the host to be able to reach the container
+/// IPs, which is not the case with Docker Desktop.
+pub async fn measure_startup(ready_ports: &[(&str, u16)], timeout: Duration) -> StartupReport {
+    let start = Instant::now();
+    let mut containers: Vec<ContainerStartup> = ready_ports
+        .iter()
+        .map(|(name, _)| ContainerStartup {
+            name: (*name).to_owned(),
+            started_at: None,
+            ready_at: None,
+        })
+        .collect();
+    while start.elapsed() < timeout {
+        let mut all_ready = true;
+        for (c, (name, port)) in containers.iter_mut().zip(ready_ports) {
+            if c.ready_at.is_some() {
+                continue
+            }
+            if c.started_at.is_none() {
+                c.started_at = container_started_at(name).await.ok();
+            }
+            let ready = match container_ip(name).await {
+                Ok(ip) => matches!(
+                    tokio::time::timeout(
+                        Duration::from_secs(1),
+                        tokio::net::TcpStream::connect((ip, *port))
+                    )
+                    .await,
+                    Ok(Ok(_))
+                ),
+                Err(_) => false,
+            };
+            if ready {
+                c.ready_at = Some(unix_nanos_now());
+            } else {
+                all_ready = false;
+            }
+        }
+        if all_ready {
+            break
+        }
+        sleep(Duration::from_millis(500)).await;
+    }
+    StartupReport { containers }
+}
+
+/// The description of a container that can be turned into either a
+/// `Container` for a `ContainerNetwork` or a service of a docker-compose file
+/// (see [write_compose_file]), so that a topology can be run without the
+/// runner
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComposeService {
+    pub name: String,
+    /// The contents of the Dockerfile
+    pub dockerfile: String,
+    /// The runner binary that is mounted into the container and run
+    pub entrypoint: Option<String>,
+    pub args: Vec<String>,
+    /// `(host path, container path)` pairs
+    pub volumes: Vec<(String, String)>,
+    /// Docker labels, see [run_labels]
+    pub labels: BTreeMap<String, String>,
+    /// Extra hostnames on the network, see [ComposeService::network_alias]
+    pub aliases: Vec<String>,
+    /// Extra DNS servers, see [ComposeService::network_options]
+    pub dns: Vec<String>,
+    /// `(host port, container port)` pairs, see [ComposeService::publish]
+    pub ports: Vec<(u16, u16)>,
+}
+
+impl ComposeService {
+    /// The same arguments as `Container::new`
+    pub fn new(name: &str, dockerfile: &str, entrypoint: Option<&str>, args: &[&str]) -> Self {
+        Self {
+            name: name.to_owned(),
+            dockerfile: dockerfile.to_owned(),
+            entrypoint: entrypoint.map(|s| s.to_owned()),
+            args: args.iter().map(|s| (*s).to_owned()).collect(),
+            volumes: vec![],
+            labels: BTreeMap::new(),
+            aliases: vec![],
+            dns: vec![],
+            ports: vec![],
+        }
+    }
+
+    /// Applies the per container parts of `options` (the DNS servers), the
+    /// subnet is applied when the network is created
+    pub fn network_options(mut self, options: &DockerNetworkOptions) -> Self {
+        self.dns.extend(options.dns.iter().cloned());
+        self
+    }
+
+    /// Adds a logical hostname that peers can use instead of the container
+    /// name, e.x. "provider" so that runners connect to "provider:26001"
+    /// regardless of which daemon container plays that role
+    pub fn network_alias(mut self, alias: &str) -> Self {
+        self.aliases.push(alias.to_owned());
+        self
+    }
+
+    /// Publishes `container_port` on `host_port` of the host. The host port
+    /// should be passed to [preflight_check] so that a port already in use is
+    /// reported before anything is built.
+    pub fn publish(mut self, host_port: u16, container_port: u16) -> Self {
+        self.ports.push((host_port, container_port));
+        self
+    }
+
+    pub fn labels(mut self, labels: &BTreeMap<String, String>) -> Self {
+        self.labels
+            .extend(labels.iter().map(|(k, v)| (k.clone(), v.clone())));
+        self
+    }
+
+    pub fn volumes(mut self, volumes: &[(&str, &str)]) -> Self {
+        self.volumes.extend(
+            volumes
+                .iter()
+                .map(|(host, container)| ((*host).to_owned(), (*container).to_owned())),
+        );
+        self
+    }
+
+    pub fn to_container(&self) -> Container {
+        let args: Vec<&str> = self.args.iter().map(|s| s.as_str()).collect();
+        let volumes: Vec<(&str, &str)> = self
+            .volumes
+            .iter()
+            .map(|(host, container)| (host.as_str(), container.as_str()))
+            .collect();
+        let mut create_args = label_args(&self.labels);
+        create_args.extend(network_alias_args(&self.aliases));
+        create_args.extend(dns_args(&self.dns));
+        create_args.extend(publish_args(&self.ports));
+        let create_args: Vec<&str> = create_args.iter().map(|s| s.as_str()).collect();
+        Container::new(
+            &self.name,
+            Dockerfile::Contents(self.dockerfile.clone()),
+            self.entrypoint.as_deref(),
+            &args,
+        )
+        .volumes(&volumes)
+        .create_args(&create_args)
+    }
+
+    /// The file name that [write_compose_file] writes the Dockerfile to
+    pub fn dockerfile_name(&self) -> String {
+        format!("{}__compose.dockerfile", self.name)
+    }
+
+    fn to_compose_json(&self, dockerfiles_dir: &str, common_volumes: &[(&str, &str)]) -> Value {
+        let mut volumes: Vec<String> = self
+            .volumes
+            .iter()
+            .map(|(host, container)| format!("{host}:{container}"))
+            .collect();
+        volumes.extend(
+            common_volumes
+                .iter()
+                .map(|(host, container)| format!("{host}:{container}")),
+        );
+        let mut service = json!({
+            "build": {"context": dockerfiles_dir, "dockerfile": self.dockerfile_name()},
+            "container_name": self.name,
+            "hostname": self.name,
+            "command": self.args,
+        });
+        if let Some(ref entrypoint) = self.entrypoint {
+            let file_name = entrypoint.rsplit('/').next().unwrap_or(entrypoint);
+            volumes.push(format!("{entrypoint}:/{file_name}"));
+            service["entrypoint"] = json!([format!("/{file_name}")]);
+        }
+        service["volumes"] = json!(volumes);
+        if !self.labels.is_empty() {
+            service["labels"] = json!(self.labels);
+        }
+        if !self.aliases.is_empty() {
+            service["networks"] = json!({"default": {"aliases": self.aliases}});
+        }
+        if !self.dns.is_empty() {
+            service["dns"] = json!(self.dns);
+        }
+        if !self.ports.is_empty() {
+            let ports: Vec<String> = self
+                .ports
+                .iter()
+                .map(|(host, container)| format!("{host}:{container}"))
+                .collect();
+            service["ports"] = json!(ports);
+        }
+        service
+    }
+}
+
+/// Returns a docker-compose file for `services` on the network `network_name`,
+/// which is created with the subnet of `options`. Relative paths are kept
+/// as-is, so the file should be used from the same working directory as the
+/// runner.
+pub fn compose_yaml(
+    network_name: &str,
+    options: &DockerNetworkOptions,
+    services: &[ComposeService],
+    dockerfiles_dir: &str,
+    common_volumes: &[(&str, &str)],
+) -> Result<String> {
+    let mut compose_services = serde_json::Map::new();
+    for service in services {
+        compose_services.insert(
+            service.name.clone(),
+            service.to_compose_json(dockerfiles_dir, common_volumes),
+        );
+    }
+    options.validate().map_add_err(|| "compose_yaml")?;
+    let mut network = json!({ "name": network_name });
+    if let Some(ipam) = options.compose_ipam() {
+        network["ipam"] = ipam;
+    }
+    let compose = json!({
+        "services": compose_services,
+        "networks": {"default": network},
+    });
+    let yaml = serde_yaml::to_string(&compose).map_add_err(|| "compose_yaml")?;
+    // make sure what we emit parses back to the same thing
+    let parsed = yaml_str_to_json_value(&yaml).map_add_err(|| "compose_yaml")?;
+    if parsed != compose {
+        return Err(Error::from(format!(
+            "compose_yaml emitted yaml that does not round trip: {yaml}"
+        )))
+    }
+    Ok(yaml)
+}
+
+/// Writes the Dockerfiles of `services` to `dockerfiles_dir` and the
+/// [compose_yaml] to `path`
+pub async fn write_compose_file(
+    path: &str,
+    network_name: &str,
+    options: &DockerNetworkOptions,
+    services: &[ComposeService],
+    dockerfiles_dir: &str,
+    common_volumes: &[(&str, &str)],
+) -> Result<()> {
+    for service in services {
+        FileOptions::write_str(
+            &format!("{dockerfiles_dir}/{}", service.dockerfile_name()),
+            &service.dockerfile,
+        )
+        .await?;
+    }
+    let yaml = compose_yaml(
+        network_name,
+        options,
+        services,
+        dockerfiles_dir,
+        common_volumes,
+    )?;
+    FileOptions::write_str(path, &yaml)
+        .await
+        .map_add_err(|| format!("write_compose_file(path: {path})"))
+}
+
+/// The marker in the names of the temporary files generated for building
+/// containers, e.x. the `__tmp.dockerfile`s of `Dockerfile::Contents` and the
+/// `__tmp_hermes_config.toml` resource
+pub const TMP_ARTIFACT_MARKER: &str = "__tmp";
+
+/// Returns the paths of the files in `dirs` that have [TMP_ARTIFACT_MARKER] in
+/// their names. Directories that do not exist are skipped.
+pub async fn tmp_artifacts(dirs: &[&str]) -> Result<Vec<String>> {
+    let mut res: Vec<String> = vec![];
+    for dir in dirs {
+        let start = res.len();
+        let mut entries = match tokio::fs::read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(Error::boxed(Box::new(e)))
+                    .map_add_err(|| format!("tmp_artifacts could not read {dir}"))
+            }
+        };
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_add_err(|| format!("tmp_artifacts could not read an entry of {dir}"))?
+        {
+            let is_artifact = entry
+                .file_name()
+                .to_string_lossy()
+                .contains(TMP_ARTIFACT_MARKER);
+            if is_artifact && entry.path().is_file() {
+                res.push(entry.path().to_str().locate()?.to_owned());
+            }
+        }
+        res[start..].sort();
+    }
+    Ok(res)
+}
+
+/// Removes the [tmp_artifacts] of `dirs` and returns their paths. This should
+/// run after the images are built, stale artifacts from an earlier run can
+/// otherwise be picked up (e.x. an old hermes config).
+pub async fn clean_tmp_artifacts(dirs: &[&str]) -> Result<Vec<String>> {
+    let artifacts = tmp_artifacts(dirs).await?;
+    for path in &artifacts {
+        tokio::fs::remove_file(path)
+            .await
+            .map_add_err(|| format!("clean_tmp_artifacts could not remove {path}"))?;
+    }
+    Ok(artifacts)
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn test_container_exec() {
+    let name = "onomy_test_lib_exec_test";
+    PipedCommand::new("docker run -d --rm --name", &[
+        name,
+        "fedora:38",
+        "sleep",
+        "60",
+    ])
+    .run_to_completion()
+    .await
+    .unwrap()
+    .assert_success()
+    .unwrap();
+    let comres = container_exec(name, "echo", &["hello"]).await;
+    PipedCommand::new("docker rm -f", &[name])
+        .run_to_completion()
+        .await
+        .unwrap();
+    let comres = comres.unwrap();
+    comres.assert_success().unwrap();
+    assert_eq!(comres.stdout.trim(), "hello");
+}
+
+#[test]
+fn test_parse_container_ips() {
+    assert_eq!(parse_container_ips("172.18.0.3 \n"), vec![IpAddr::from([
+        172, 18, 0, 3
+    ])]);
+    assert!(parse_container_ips(" \n").is_empty());
+}
+
+#[test]
+fn test_docker_network_options() {
+    let options = DockerNetworkOptions {
+        subnet: Some("10.123.0.0/16".to_owned()),
+        gateway: Some("10.123.0.1".to_owned()),
+        dns: vec!["1.1.1.1".to_owned()],
+    };
+    options.validate().unwrap();
+    assert_eq!(options.create_args(), vec![
+        "--subnet=10.123.0.0/16",
+        "--gateway=10.123.0.1"
+    ]);
+    let mut bad = options.clone();
+    bad.gateway = Some("10.124.0.1".to_owned());
+    assert!(bad.validate().is_err());
+    bad.gateway = None;
+    bad.subnet = Some("10.123.0.0/33".to_owned());
+    assert!(bad.validate().is_err());
+    bad.subnet = Some("10.123.0.0".to_owned());
+    assert!(bad.validate().is_err());
+    assert!(parse_cidr("fd00::/64").is_ok());
+
+    // the options are threaded into the compose network and services
+    let service =
+        ComposeService::new("onomyd", "FROM fedora:38\n", None, &[]).network_options(&options);
+    assert_eq!(dns_args(&service.dns), vec!["--dns=1.1.1.1"]);
+    let yaml = compose_yaml("test", &options, &[service], "./tests/dockerfiles", &[]).unwrap();
+    let compose = yaml_str_to_json_value(&yaml).unwrap();
+    assert_eq!(
+        compose["networks"]["default"],
+        json!({
+            "name": "test",
+            "ipam": {"config": [{"subnet": "10.123.0.0/16", "gateway": "10.123.0.1"}]}
+        })
+    );
+    assert_eq!(compose["services"]["onomyd"]["dns"], json!(["1.1.1.1"]));
+    assert!(compose_yaml("test", &bad, &[], "./tests/dockerfiles", &[]).is_err());
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn test_create_docker_network() {
+    let name = "onomy_test_lib_subnet_test";
+    let options = DockerNetworkOptions {
+        subnet: Some("10.123.0.0/16".to_owned()),
+        gateway: Some("10.123.0.1".to_owned()),
+        dns: vec![],
+    };
+    create_docker_network(name, &options).await.unwrap();
+    let subnets = docker_network_subnets(name).await;
+    PipedCommand::new("docker network rm", &[name])
+        .run_to_completion()
+        .await
+        .unwrap();
+    assert_eq!(subnets.unwrap(), vec!["10.123.0.0/16".to_owned()]);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn test_apply_network_create_options() {
+    let name = "onomy_test_lib_cn_subnet_test";
+    let options = DockerNetworkOptions {
+        subnet: Some("10.124.0.0/16".to_owned()),
+        gateway: None,
+        dns: vec![],
+    };
+    let mut cn = ContainerNetwork::new(name, vec![], None, true, "/tmp").unwrap();
+    apply_network_create_options(&mut cn, name, &options)
+        .await
+        .unwrap();
+    // the first `run` must not recreate the network without the subnet
+    cn.run_all(true).await.unwrap();
+    let subnets = docker_network_subnets(name).await;
+    PipedCommand::new("docker network rm", &[name])
+        .run_to_completion()
+        .await
+        .unwrap();
+    assert_eq!(subnets.unwrap(), vec!["10.124.0.0/16".to_owned()]);
+}
+
+#[test]
+fn test_compose_yaml() {
+    let mut labels = BTreeMap::new();
+    labels.insert(RUN_ID_LABEL.to_owned(), "abcd".to_owned());
+    assert_eq!(label_args(&labels), vec![
+        "--label",
+        "onomy_test.run_id=abcd"
+    ]);
+    let services = vec![
+        ComposeService::new(
+            "onomyd",
+            "FROM fedora:38\n",
+            Some("./target/release/ics_basic"),
+            &["--entry-name", "onomyd"],
+        )
+        .volumes(&[(
+            "./tests/resources/keyring-test/onomyd",
+            "/root/.onomy/keyring-test",
+        )]),
+        ComposeService::new("hermes", "FROM fedora:38\n", None, &[])
+            .labels(&labels)
+            .network_alias("relayer")
+            .publish(3000, 3001),
+    ];
+    let yaml = compose_yaml(
+        "test",
+        &Default::default(),
+        &services,
+        "./tests/dockerfiles",
+        &[("./tests/logs", "/logs")],
+    )
+    .unwrap();
+    let compose = yaml_str_to_json_value(&yaml).unwrap();
+    let onomyd = &compose["services"]["onomyd"];
+    assert_eq!(onomyd["build"]["dockerfile"], "onomyd__compose.dockerfile");
+    assert_eq!(onomyd["entrypoint"], json!(["/ics_basic"]));
+    assert_eq!(onomyd["command"], json!(["--entry-name", "onomyd"]));
+    assert_eq!(
+        onomyd["volumes"],
+        json!([
+            "./tests/resources/keyring-test/onomyd:/root/.onomy/keyring-test",
+            "./tests/logs:/logs",
+            "./target/release/ics_basic:/ics_basic"
+        ])
+    );
+    assert!(onomyd["labels"].is_null());
+    assert!(onomyd["networks"].is_null());
+    assert_eq!(
+        compose["services"]["hermes"]["networks"],
+        json!({"default": {"aliases": ["relayer"]}})
+    );
+    assert_eq!(network_alias_args(&services[1].aliases), vec![
+        "--network-alias",
+        "relayer"
+    ]);
+    assert!(onomyd["ports"].is_null());
+    assert_eq!(compose["services"]["hermes"]["ports"], json!(["3000:3001"]));
+    assert_eq!(publish_args(&services[1].ports), vec!["-p", "3000:3001"]);
+    assert!(compose["services"]["hermes"]["entrypoint"].is_null());
+    assert_eq!(
+        compose["services"]["hermes"]["labels"],
+        json!({"onomy_test.run_id": "abcd"})
+    );
+    assert_eq!(compose["networks"]["default"]["name"], "test");
+}
+
+#[tokio::test]
+async fn test_preflight_check() {
+    let listener = std::net::TcpListener::bind(("0.0.0.0", 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    assert_eq!(ports_in_use(&[port]), vec![port]);
+    let e = preflight_check(&PreflightOptions {
+        free_ports: vec![port],
+        ..Default::default()
+    })
+    .await
+    .unwrap_err();
+    assert!(format!("{e:?}").contains(&format!("port {port} is already in use")));
+    drop(listener);
+    preflight_check(&PreflightOptions::default()).await.unwrap();
+}
+
+#[test]
+fn test_elf_machine() {
+    let mut header = [0u8; 20];
+    header[..4].copy_from_slice(b"\x7fELF");
+    header[5] = 1;
+    header[18] = 62;
+    assert_eq!(elf_machine(&header), Some(ELF_MACHINE_X86_64));
+    // aarch64
+    header[18] = 183;
+    assert_eq!(elf_machine(&header), Some(183));
+    header[5] = 2;
+    assert_eq!(elf_machine(&header), None);
+    assert_eq!(elf_machine(b"#!/bin/sh\n"), None);
+    assert_eq!(elf_machine(b"\x7fELF"), None);
+}
+
+#[test]
+fn test_startup_report() {
+    let report = StartupReport {
+        containers: vec![
+            ContainerStartup {
+                name: "onomyd".to_owned(),
+                started_at: Some(1_000_000_000),
+                ready_at: Some(6_500_000_000),
+            },
+            ContainerStartup {
+                name: "hermes".to_owned(),
+                started_at: Some(0),
+                ready_at: Some(1_000_000_000),
+            },
+            ContainerStartup {
+                name: "marketd".to_owned(),
+                started_at: Some(2_000_000_000),
+                ready_at: None,
+            },
+        ],
+    };
+    assert_eq!(
+        report.containers[0].time_to_ready(),
+        Some(Duration::from_millis(5500))
+    );
+    let summary = report.summary();
+    let names: Vec<&str> = summary
+        .lines()
+        .skip(1)
+        .map(|l| l.split_whitespace().next().unwrap())
+        .collect();
+    assert_eq!(names, ["hermes", "onomyd", "marketd"]);
+    assert!(summary.contains("+1.000s"));
+    assert!(summary.contains("not ready"));
+    let e = format!(
+        "{:?}",
+        report
+            .assert_ready_within(Duration::from_secs(6))
+            .unwrap_err()
+    );
+    assert!(e.contains("marketd never became ready"));
+    assert!(!e.contains("onomyd"));
+    assert!(!report.none_ready());
+    assert!(StartupReport {
+        containers: vec![report.containers[2].clone()]
+    }
+    .none_ready());
+    let e = format!(
+        "{:?}",
+        report
+            .assert_ready_within(Duration::from_secs(5))
+            .unwrap_err()
+    );
+    assert!(e.contains("onomyd took 5.5s"));
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn test_container_labels() {
+    let name = "onomy_test_lib_test_container_labels";
+    let mut labels = run_labels("test_container_labels").await;
+    labels.insert("custom".to_owned(), "a=b".to_owned());
+    let mut args = vec!["-d".to_owned(), "--name".to_owned(), name.to_owned()];
+    args.extend(label_args(&labels));
+    args.extend(["fedora:38".to_owned(), "sleep".to_owned(), "60".to_owned()]);
+    let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+    PipedCommand::new("docker run", &args)
+        .run_to_completion()
+        .await
+        .unwrap()
+        .assert_success()
+        .unwrap();
+    let res = container_labels(name).await;
+    let removed = remove_labeled_containers(RUN_ID_LABEL, Some(&labels[RUN_ID_LABEL]))
+        .await
+        .unwrap();
+    let res = res.unwrap();
+    assert_eq!(removed.len(), 1);
+    for (key, value) in &labels {
+        assert_eq!(res.get(key), Some(value));
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn test_network_alias() {
+    let network = "onomy_test_lib_alias_test";
+    let name = "onomy_test_lib_alias_target";
+    create_docker_network(network, &DockerNetworkOptions::default())
+        .await
+        .unwrap();
+    let mut args = vec!["-d", "--rm", "--network", network, "--name", name];
+    let alias_args = network_alias_args(&["provider".to_owned()]);
+    args.extend(alias_args.iter().map(|s| s.as_str()));
+    args.extend(["fedora:38", "sleep", "60"]);
+    let started = PipedCommand::new("docker run", &args)
+        .run_to_completion()
+        .await
+        .unwrap();
+    // the alias should resolve for other containers on the network
+    let resolved = PipedCommand::new("docker run --rm --network", &[
+        network,
+        "fedora:38",
+        "getent",
+        "hosts",
+        "provider",
+    ])
+    .run_to_completion()
+    .await
+    .unwrap();
+    let _ = PipedCommand::new("docker rm -f", &[name])
+        .run_to_completion()
+        .await;
+    let _ = PipedCommand::new("docker network rm", &[network])
+        .run_to_completion()
+        .await;
+    started.assert_success().unwrap();
+    resolved.assert_success().unwrap();
+    assert!(resolved.stdout.contains("provider"));
+}
+
+#[tokio::test]
+async fn test_wait_for_log_file() {
+    let pattern = Regex::new(r"committed state height=(\d+)").unwrap();
+    let log = "starting\nI[..] committed state height=7 module=state\n";
+    let matched = find_log_match(log, &pattern).unwrap();
+    assert_eq!(matched.line, "I[..] committed state height=7 module=state");
+    assert_eq!(matched.get(1), Some("7"));
+    assert_eq!(matched.get(2), None);
+    assert!(find_log_match("starting", &pattern).is_none());
+
+    let path = std::env::temp_dir().join("onomy_test_lib_wait_for_log_file.log");
+    let path = path.to_str().unwrap().to_owned();
+    let _ = std::fs::remove_file(&path);
+    // a helper process that emits the line after a delay
+    let mut helper = tokio::process::Command::new("sh")
+        .args([
+            "-c",
+            &format!(
+                "echo starting > {path}; sleep 0.3; echo 'committed state height=1' >> {path}"
+            ),
+        ])
+        .spawn()
+        .unwrap();
+    let matched = wait_for_log_file(&path, &pattern, Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(matched.get(1), Some("1"));
+    helper.wait().await.unwrap();
+    let e = format!(
+        "{:?}",
+        wait_for_log_file(&path, &Regex::new("never").unwrap(), Duration::ZERO)
+            .await
+            .unwrap_err()
+    );
+    assert!(e.contains("wait_for_log_file"));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_clean_tmp_artifacts() {
+    let dir = std::env::temp_dir().join("onomy_test_lib_tmp_artifacts");
+    let resources = dir.join("dockerfile_resources");
+    std::fs::create_dir_all(&resources).unwrap();
+    let dir = dir.to_str().unwrap().to_owned();
+    let resources = resources.to_str().unwrap().to_owned();
+    for path in [
+        format!("{dir}/hermes__tmp.dockerfile"),
+        format!("{dir}/onomyd__compose.dockerfile"),
+        format!("{resources}/__tmp_hermes_config.toml"),
+        format!("{resources}/onomyd"),
+    ] {
+        std::fs::write(path, "").unwrap();
+    }
+    let dirs = [dir.as_str(), resources.as_str(), "./nonexistent_dir"];
+    assert_eq!(tmp_artifacts(&dirs).await.unwrap(), vec![
+        format!("{dir}/hermes__tmp.dockerfile"),
+        format!("{resources}/__tmp_hermes_config.toml"),
+    ]);
+    assert_eq!(clean_tmp_artifacts(&dirs).await.unwrap().len(), 2);
+    assert!(tmp_artifacts(&dirs).await.unwrap().is_empty());
+    // other files are kept
+    assert!(std::path::Path::new(&format!("{resources}/onomyd")).exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_partition_script() {
+    let peers: Vec<IpAddr> = vec!["172.18.0.2".parse().unwrap(), "172.18.0.3".parse().unwrap()];
+    assert_eq!(
+        partition_script("-A", &peers[..1]),
+        "iptables -A INPUT -s 172.18.0.2 -j DROP && iptables -A OUTPUT -d 172.18.0.2 -j DROP"
+    );
+    assert_eq!(
+        partition_script("-D", &peers)
+            .matches("iptables -D")
+            .count(),
+        4
+    );
+    assert_eq!(partition_script("-A", &[]), "");
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn test_network_partition() {
+    let network = "onomy_test_lib_partition_test";
+    let names = ["onomy_test_lib_partition_a", "onomy_test_lib_partition_b"];
+    create_docker_network(network, &DockerNetworkOptions::default())
+        .await
+        .unwrap();
+    for name in names {
+        PipedCommand::new("docker run -d --rm --network", &[
+            network,
+            "--name",
+            name,
+            PARTITION_IMAGE,
+            "sleep",
+            "60",
+        ])
+        .run_to_completion()
+        .await
+        .unwrap()
+        .assert_success()
+        .unwrap();
+    }
+    let reachable = || async {
+        container_exec(names[0], "ping -c 1 -W 1", &[names[1]])
+            .await
+            .unwrap()
+            .assert_success()
+            .is_ok()
+    };
+    let before = reachable().await;
+    let partition = NetworkPartition::partition(&names[..1], &names[1..]).await;
+    let during = reachable().await;
+    let mut partition = partition.unwrap();
+    let healed = partition.heal().await;
+    let after = reachable().await;
+    let healed_again = partition.heal().await;
+    PipedCommand::new("docker rm -f", &names)
+        .run_to_completion()
+        .await
+        .unwrap();
+    PipedCommand::new("docker network rm", &[network])
+        .run_to_completion()
+        .await
+        .unwrap();
+    healed.unwrap();
+    healed_again.unwrap();
+    assert_eq!((before, during, after), (true, false, true));
+}
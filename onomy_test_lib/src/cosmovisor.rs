@@ -1,15 +1,29 @@
-use std::{collections::BTreeMap, time::Duration};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, Instant},
+};
 
+use futures::{stream, StreamExt};
 use log::info;
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use super_orchestrator::{
     get_separated_val, sh, sh_no_dbg,
     stacked_errors::{Error, MapAddError, Result},
     wait_for_ok, Command, CommandRunner, FileOptions, STD_DELAY, STD_TRIES,
 };
-use tokio::time::sleep;
+use tokio::{io::AsyncReadExt, time::sleep};
+use toml_edit::{value, Document};
 
-use crate::{anom_to_nom, json_inner, yaml_str_to_json_value};
+use crate::{
+    anom_to_nom,
+    command::{PipedCommand, PipedCommandResult},
+    genesis::DenomMetadata,
+    hex_encode, json_inner, poll_until,
+    ports::{GRPC_PORT, P2P_PORT, PPROF_PORT, REST_PORT, RPC_PORT},
+    yaml_str_to_json_value, MapAddContext, TIMEOUT,
+};
 
 /// A wrapper around `super_orchestrator::sh` that prefixes "cosmovisor run"
 /// onto `cmd_with_args` and removes the first line of output (in order to
@@ -32,6 +46,183 @@ pub async fn sh_cosmovisor_no_dbg(cmd_with_args: &str, args: &[&str]) -> Result<
         .to_owned())
 }
 
+/// The directory that a daemon binary passed with `--daemon-binary` is mounted
+/// in, see [apply_daemon_override]
+pub const DAEMON_OVERRIDE_DIR: &str = "/daemon_override";
+
+/// If a binary for `$DAEMON_NAME` was mounted in [DAEMON_OVERRIDE_DIR], copies
+/// it over the binary that Cosmovisor currently runs and returns true. This is
+/// called by `dispatch_entry` before any daemon command runs, a daemon that is
+/// already running needs to be restarted to pick up the override.
+pub async fn apply_daemon_override() -> Result<bool> {
+    let (Ok(daemon_name), Ok(daemon_home)) =
+        (std::env::var("DAEMON_NAME"), std::env::var("DAEMON_HOME"))
+    else {
+        return Ok(false)
+    };
+    let src = format!("{DAEMON_OVERRIDE_DIR}/{daemon_name}");
+    if tokio::fs::metadata(&src).await.is_err() {
+        return Ok(false)
+    }
+    let dst = format!("{daemon_home}/cosmovisor/current/bin/{daemon_name}");
+    sh("cp", &[&src, &dst])
+        .await
+        .map_add_err(|| format!("apply_daemon_override(src: {src}, dst: {dst})"))?;
+    info!("using the overridden daemon binary {src}");
+    Ok(true)
+}
+
+/// The backend used for `--keyring-backend`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum KeyringBackend {
+    /// Unencrypted, never prompts
+    #[default]
+    Test,
+    /// Encrypted with `passphrase`, which is fed to the prompts
+    File {
+        passphrase: String,
+    },
+    Os,
+}
+
+impl KeyringBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyringBackend::Test => "test",
+            KeyringBackend::File { .. } => "file",
+            KeyringBackend::Os => "os",
+        }
+    }
+}
+
+/// Runs `cosmovisor run config keyring-backend ...`
+pub async fn cosmovisor_set_keyring_backend(keyring: &KeyringBackend) -> Result<()> {
+    sh_cosmovisor("config keyring-backend", &[keyring.as_str()]).await?;
+    Ok(())
+}
+
+/// Runs `cosmovisor run {cmd_with_args} {args}` to completion and returns the
+/// result without asserting success. If `keyring` is `File`, the passphrase is
+/// piped in for any prompts.
+pub async fn cosmovisor_run_with_keyring(
+    cmd_with_args: &str,
+    args: &[&str],
+    keyring: &KeyringBackend,
+) -> Result<PipedCommandResult> {
+    let mut command = PipedCommand::new(&format!("cosmovisor run {cmd_with_args}"), args);
+    if let KeyringBackend::File { passphrase } = keyring {
+        // a new keyring asks for the passphrase twice, after that once per command
+        command = command.stdin_bytes(format!("{passphrase}\n{passphrase}\n").into_bytes());
+    }
+    command.run_to_completion().await.map_add_err(|| {
+        format!("cosmovisor_run_with_keyring(cmd_with_args: {cmd_with_args}, args: {args:?})")
+    })
+}
+
+/// Adds a new key and returns its mnemonic
+pub async fn cosmovisor_keys_add(key_name: &str, keyring: &KeyringBackend) -> Result<String> {
+    let comres = cosmovisor_run_with_keyring("keys add", &[key_name], keyring).await?;
+    comres.assert_success()?;
+    // the mnemonic is the last line of stderr
+    Ok(comres
+        .stderr
+        .trim()
+        .lines()
+        .last()
+        .map_add_err(|| format!("cosmovisor_keys_add(key_name: {key_name}) no mnemonic"))?
+        .trim()
+        .to_owned())
+}
+
+/// A key created in the keyring
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyInfo {
+    pub name: String,
+    pub address: String,
+    pub mnemonic: String,
+}
+
+/// Adds a key recovered from `mnemonic`, so that the address is deterministic
+pub async fn cosmovisor_keys_recover(
+    key_name: &str,
+    mnemonic: &str,
+    keyring: &KeyringBackend,
+) -> Result<()> {
+    let mut stdin = String::new();
+    if let KeyringBackend::File { passphrase } = keyring {
+        // the keyring is opened before the mnemonic is asked for
+        stdin.push_str(&format!("{passphrase}\n{passphrase}\n"));
+    }
+    stdin.push_str(mnemonic.trim());
+    stdin.push('\n');
+    PipedCommand::new("cosmovisor run keys add", &[
+        key_name,
+        "--recover",
+        "--keyring-backend",
+        keyring.as_str(),
+    ])
+    .stdin_bytes(stdin.into_bytes())
+    .run_to_completion()
+    .await?
+    .assert_success()
+    .map_add_err(|| format!("cosmovisor_keys_recover(key_name: {key_name})"))
+}
+
+/// A transaction that was accepted by the node but rejected by the chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxFailure {
+    pub code: u64,
+    pub codespace: String,
+    pub raw_log: String,
+}
+
+impl fmt::Display for TxFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tx failed with code {} (codespace: \"{}\"), raw_log: {}",
+            self.code, self.codespace, self.raw_log
+        )
+    }
+}
+
+impl std::error::Error for TxFailure {}
+
+impl TxFailure {
+    /// Returns `None` if the tx result has a zero code
+    pub fn from_tx_result(res: &Value) -> Result<Option<Self>> {
+        let code = res["code"]
+            .as_u64()
+            .map_add_err(|| format!("tx result did not have a \"code\": {res}"))?;
+        if code == 0 {
+            return Ok(None)
+        }
+        Ok(Some(Self {
+            code,
+            codespace: json_inner(&res["codespace"]),
+            raw_log: json_inner(&res["raw_log"]),
+        }))
+    }
+}
+
+/// The same as [sh_cosmovisor_tx], except that a chain level failure is
+/// returned as the inner `Err`, so that expected on-chain rejections can be
+/// distinguished from orchestration errors
+pub async fn sh_cosmovisor_tx_result(
+    cmd_with_args: &str,
+    args: &[&str],
+) -> Result<std::result::Result<Value, TxFailure>> {
+    let res = sh_cosmovisor_no_dbg(&format!("tx {cmd_with_args}"), args)
+        .await
+        .map_add_err(|| "sh_cosmovisor_tx() initial command failed")?;
+
+    let res = yaml_str_to_json_value(&res).locate()?;
+    match TxFailure::from_tx_result(&res)? {
+        None => Ok(Ok(res)),
+        Some(failure) => Ok(Err(failure)),
+    }
+}
+
 /// This adds on a "tx" command arg and adds extra handling to propogate if the
 /// chain level transaction failed (cosmovisor will not return a successful
 /// status if the transaction was at least successfully transmitted, ignoring if
@@ -40,33 +231,97 @@ pub async fn sh_cosmovisor_no_dbg(cmd_with_args: &str, args: &[&str]) -> Result<
 /// NOTE: You need to pass the argument `-y` to confirm without needing piped
 /// input, and the arguments `-b block` for the error handling to work properly
 pub async fn sh_cosmovisor_tx(cmd_with_args: &str, args: &[&str]) -> Result<serde_json::Value> {
-    let res = sh_cosmovisor_no_dbg(&format!("tx {cmd_with_args}"), args)
-        .await
-        .map_add_err(|| "sh_cosmovisor_tx() initial command failed")?;
+    match sh_cosmovisor_tx_result(cmd_with_args, args).await? {
+        Ok(res) => Ok(res),
+        Err(failure) => {
+            Err(Error::from(format!("raw_log: {}", failure.raw_log))).map_add_err(|| {
+                format!("sh_cosmovisor_tx(cmd_with_args: {cmd_with_args}, args: {args:?})")
+            })
+        }
+    }
+}
 
-    let res = yaml_str_to_json_value(&res).map_add_err(|| ())?;
-    if res["code"].as_u64().map_add_err(|| ())? == 0 {
-        Ok(res)
-    } else {
-        Err(Error::from(format!("raw_log: {}", res["raw_log"]))).map_add_err(|| {
-            format!("sh_cosmovisor_tx(cmd_with_args: {cmd_with_args}, args: {args:?})")
-        })
+/// `ErrWrongSequence` of the "sdk" codespace
+const WRONG_SEQUENCE_CODE: u64 = 32;
+
+/// The same as [sh_cosmovisor_tx], but if the transaction is rejected with the
+/// sdk code of an account sequence mismatch, or the gas simulation fails with
+/// one, (e.x. because another transaction from the same account was submitted
+/// concurrently) it is retried up to `tries` times, waiting `STD_DELAY` in
+/// between so that the pending transaction commits
+pub async fn sh_cosmovisor_tx_sequence_retry(
+    cmd_with_args: &str,
+    args: &[&str],
+    tries: u64,
+) -> Result<Value> {
+    let err = || format!("sh_cosmovisor_tx_sequence_retry(cmd_with_args: {cmd_with_args})");
+    let mut i = 0;
+    loop {
+        i += 1;
+        let mismatch = match sh_cosmovisor_tx_result(cmd_with_args, args).await {
+            Ok(Ok(res)) => return Ok(res),
+            Ok(Err(failure)) if failure.code == WRONG_SEQUENCE_CODE => {
+                Error::boxed(Box::new(failure))
+            }
+            Ok(Err(failure)) => return Err(Error::boxed(Box::new(failure))).map_add_err(err),
+            // with `--gas auto` the mismatch is found by the simulation, which fails at the
+            // command level before there is any tx code
+            Err(e) if format!("{e:?}").contains("account sequence mismatch") => e,
+            Err(e) => return Err(e).map_add_err(err),
+        };
+        if i >= tries {
+            return Err(mismatch).map_add_err(|| format!("{} ran out of tries", err()))
+        }
+        info!("retrying {cmd_with_args} after an account sequence mismatch");
+        sleep(STD_DELAY).await;
     }
 }
 
+/// Parses `toml_s` with `toml_edit` (which preserves comments and formatting),
+/// applies `f`, and returns the new string
+pub fn edit_toml_str<F: FnOnce(&mut Document)>(toml_s: &str, f: F) -> Result<String> {
+    let mut doc = toml_s
+        .parse::<Document>()
+        .map_err(|e| Error::boxed(Box::new(e)))
+        .map_add_err(|| "edit_toml_str")?;
+    f(&mut doc);
+    Ok(doc.to_string())
+}
+
+/// Applies `f` to the TOML file at `path`, preserving comments and formatting.
+/// The new file is written next to the old one and then renamed over it so
+/// that a partially written file is never observed.
+pub async fn edit_toml_file<F: FnOnce(&mut Document)>(path: &str, f: F) -> Result<()> {
+    let toml_s = FileOptions::read_to_string(path).await?;
+    let toml_s = edit_toml_str(&toml_s, f).map_add_err(|| format!("edit_toml_file({path})"))?;
+    let tmp_path = format!("{path}.tmp");
+    FileOptions::write_str(&tmp_path, &toml_s).await?;
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .map_add_err(|| format!("edit_toml_file({path}) rename failed"))?;
+    Ok(())
+}
+
+/// Edits `{daemon_home}/config/app.toml`, see [edit_toml_file]
+pub async fn edit_app_toml<F: FnOnce(&mut Document)>(daemon_home: &str, f: F) -> Result<()> {
+    edit_toml_file(&format!("{daemon_home}/config/app.toml"), f).await
+}
+
+/// Edits `{daemon_home}/config/config.toml`, see [edit_toml_file]
+pub async fn edit_config_toml<F: FnOnce(&mut Document)>(daemon_home: &str, f: F) -> Result<()> {
+    edit_toml_file(&format!("{daemon_home}/config/config.toml"), f).await
+}
+
 /// Cosmos-SDK configuration gets messed up by different Git commit and tag
 /// states, this overwrites the in the given genesis and client.toml
 pub async fn force_chain_id(daemon_home: &str, genesis: &mut Value, chain_id: &str) -> Result<()> {
     // genesis
     genesis["chain_id"] = chain_id.into();
     // client.toml
-    let client_file_path = format!("{daemon_home}/config/client.toml");
-    let client_s = FileOptions::read_to_string(&client_file_path).await?;
-    let mut client: toml::Value = toml::from_str(&client_s).map_add_err(|| ())?;
-    client["chain-id"] = chain_id.into();
-    let client_s = toml::to_string_pretty(&client).map_add_err(|| ())?;
-    FileOptions::write_str(&client_file_path, &client_s).await?;
-    Ok(())
+    edit_toml_file(&format!("{daemon_home}/config/client.toml"), |client| {
+        client["chain-id"] = value(chain_id);
+    })
+    .await
 }
 
 pub async fn fast_block_times(daemon_home: &str) -> Result<()> {
@@ -80,43 +335,445 @@ pub async fn fast_block_times(daemon_home: &str) -> Result<()> {
     //
     //genesis["app_state"]["gravity"]["params"]["average_block_time"] =
     // "1000".into();
-    let config_file_path = format!("{daemon_home}/config/config.toml");
-    let config_s = FileOptions::read_to_string(&config_file_path).await?;
-    let mut config: toml::Value = toml::from_str(&config_s).map_add_err(|| ())?;
-    // reduce all of these by a factor of 5
-    /*
-    timeout_propose = "3s"
-    timeout_propose_delta = "500ms"
-    timeout_prevote = "1s"
-    timeout_prevote_delta = "500ms"
-    timeout_precommit = "1s"
-    timeout_precommit_delta = "500ms"
-    timeout_commit = "5s"
-     */
-    config["consensus"]["timeout_propose"] = "600ms".into();
-    config["consensus"]["timeout_propose_delta"] = "100ms".into();
-    config["consensus"]["timeout_prevote"] = "200ms".into();
-    config["consensus"]["timeout_prevote_delta"] = "100ms".into();
-    config["consensus"]["timeout_precommit"] = "200ms".into();
-    config["consensus"]["timeout_precommit_delta"] = "100ms".into();
-    config["consensus"]["timeout_commit"] = "1000ms".into();
-    let config_s = toml::to_string_pretty(&config)?;
-    FileOptions::write_str(&config_file_path, &config_s).await?;
-    Ok(())
+    edit_config_toml(daemon_home, |config| {
+        // reduce all of these by a factor of 5
+        /*
+        timeout_propose = "3s"
+        timeout_propose_delta = "500ms"
+        timeout_prevote = "1s"
+        timeout_prevote_delta = "500ms"
+        timeout_precommit = "1s"
+        timeout_precommit_delta = "500ms"
+        timeout_commit = "5s"
+         */
+        config["consensus"]["timeout_propose"] = value("600ms");
+        config["consensus"]["timeout_propose_delta"] = value("100ms");
+        config["consensus"]["timeout_prevote"] = value("200ms");
+        config["consensus"]["timeout_prevote_delta"] = value("100ms");
+        config["consensus"]["timeout_precommit"] = value("200ms");
+        config["consensus"]["timeout_precommit_delta"] = value("100ms");
+        config["consensus"]["timeout_commit"] = value("1000ms");
+    })
+    .await
 }
 
 pub async fn set_minimum_gas_price(daemon_home: &str, min_gas_price: &str) -> Result<()> {
+    edit_app_toml(daemon_home, |app_toml| {
+        app_toml["minimum-gas-prices"] = value(min_gas_price);
+    })
+    .await
+}
+
+/// Sets `minimum-gas-prices` to multiple prices (e.x. `&["1anom",
+/// "1ibc/..."]`), so that fees are accepted in any of the denoms
+pub async fn set_minimum_gas_prices(daemon_home: &str, min_gas_prices: &[&str]) -> Result<()> {
+    set_minimum_gas_price(daemon_home, &min_gas_prices.join(",")).await
+}
+
+/// Returns the `minimum-gas-prices` string from `{daemon_home}/config/app.toml`
+pub async fn get_minimum_gas_price(daemon_home: &str) -> Result<String> {
     let app_toml_path = format!("{daemon_home}/config/app.toml");
     let app_toml_s = FileOptions::read_to_string(&app_toml_path).await?;
-    let mut app_toml: toml::Value = toml::from_str(&app_toml_s).map_add_err(|| ())?;
-    app_toml["minimum-gas-prices"] = min_gas_price.into();
-    let app_toml_s = toml::to_string_pretty(&app_toml)?;
-    FileOptions::write_str(&app_toml_path, &app_toml_s).await?;
+    let app_toml: toml::Value =
+        toml::from_str(&app_toml_s).map_add_err(|| format!("could not parse {app_toml_path}"))?;
+    let min_gas_price = app_toml["minimum-gas-prices"]
+        .as_str()
+        .map_add_err(|| format!("{app_toml_path} has no string `minimum-gas-prices`"))?;
+    Ok(min_gas_price.to_owned())
+}
+
+/// Splits a decimal coin such as "0.5anom" into its amount and denom
+pub fn parse_dec_coin(coin: &str) -> Result<(f64, String)> {
+    let coin = coin.trim();
+    let i = coin
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .map_add_err(|| format!("parse_dec_coin(coin: {coin}) has no denom"))?;
+    let (amount, denom) = coin.split_at(i);
+    let amount = amount
+        .parse::<f64>()
+        .map_add_err(|| format!("parse_dec_coin(coin: {coin}) has a bad amount"))?;
+    Ok((amount, denom.to_owned()))
+}
+
+/// Parses comma separated integer coins such as "100anom,5ibc/ABCD" into a map
+/// of denoms to amounts, adding up repeated denoms. An empty string is no
+/// coins.
+pub fn parse_coins(coins: &str) -> Result<BTreeMap<String, u128>> {
+    let mut res = BTreeMap::new();
+    for coin in coins.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        let i = coin
+            .find(|c: char| !c.is_ascii_digit())
+            .map_add_err(|| format!("parse_coins(coins: {coins}) {coin} has no denom"))?;
+        let (amount, denom) = coin.split_at(i);
+        let amount = amount
+            .parse::<u128>()
+            .map_add_err(|| format!("parse_coins(coins: {coins}) {coin} has a bad amount"))?;
+        *res.entry(denom.to_owned()).or_insert(0) += amount;
+    }
+    Ok(res)
+}
+
+/// Parses the `supply` of `query bank total` into a map of denoms to amounts
+pub fn parse_total_supply(total: &Value) -> Result<BTreeMap<String, u128>> {
+    let supply = total["supply"]
+        .as_array()
+        .map_add_err(|| format!("parse_total_supply({total}) has no supply"))?;
+    let mut res = BTreeMap::new();
+    for coin in supply {
+        let denom = json_inner(&coin["denom"]);
+        let amount = json_inner(&coin["amount"])
+            .parse::<u128>()
+            .map_add_err(|| format!("parse_total_supply bad amount in {coin}"))?;
+        res.insert(denom, amount);
+    }
+    Ok(res)
+}
+
+/// Returns the total supply of every denom
+pub async fn cosmovisor_total_supply() -> Result<BTreeMap<String, u128>> {
+    let total = sh_cosmovisor_no_dbg("query bank total --limit 1000", &[]).await?;
+    parse_total_supply(&yaml_str_to_json_value(&total)?).map_add_err(|| "cosmovisor_total_supply")
+}
+
+/// The same as [cosmovisor_total_supply] but queries the node at `node` (e.x.
+/// "tcp://marketd:26657")
+pub async fn cosmovisor_total_supply_at(node: &str) -> Result<BTreeMap<String, u128>> {
+    let total = sh_cosmovisor_no_dbg("query bank total --limit 1000 --node", &[node]).await?;
+    parse_total_supply(&yaml_str_to_json_value(&total)?)
+        .map_add_err(|| format!("cosmovisor_total_supply_at(node: {node})"))
+}
+
+/// Checks that the supply went from `before` to `after` by exactly `expected`
+/// (pairs of denoms and signed deltas, unlisted denoms should not change),
+/// except that each denom in `tolerance_per_block` may additionally change by
+/// up to that amount times `blocks` (e.x. for inflation). The error lists the
+/// discrepancy of every mismatched denom.
+pub fn check_supply_delta(
+    before: &BTreeMap<String, u128>,
+    after: &BTreeMap<String, u128>,
+    blocks: u64,
+    expected: &[(&str, i128)],
+    tolerance_per_block: &[(&str, u128)],
+) -> Result<()> {
+    let mut denoms: Vec<&str> = before
+        .keys()
+        .chain(after.keys())
+        .map(|s| s.as_str())
+        .chain(expected.iter().map(|(denom, _)| *denom))
+        .collect();
+    denoms.sort_unstable();
+    denoms.dedup();
+    let mut mismatches = vec![];
+    for denom in denoms {
+        let before = i128::try_from(*before.get(denom).unwrap_or(&0)).unwrap_or(i128::MAX);
+        let after = i128::try_from(*after.get(denom).unwrap_or(&0)).unwrap_or(i128::MAX);
+        let delta = after - before;
+        let expected = expected
+            .iter()
+            .filter(|(d, _)| *d == denom)
+            .map(|(_, delta)| *delta)
+            .sum::<i128>();
+        let tolerance = tolerance_per_block
+            .iter()
+            .filter(|(d, _)| *d == denom)
+            .map(|(_, tolerance)| tolerance.saturating_mul(u128::from(blocks)))
+            .sum::<u128>();
+        if (delta - expected).unsigned_abs() > tolerance {
+            mismatches.push(format!(
+                "{denom}: supply changed by {delta} but expected {expected} (tolerance \
+                 {tolerance} over {blocks} blocks)"
+            ));
+        }
+    }
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::from(format!(
+            "check_supply_delta mismatches:\n{}",
+            mismatches.join("\n")
+        )))
+    }
+}
+
+/// Tracks the total supply over a scenario, to check that no tokens were
+/// minted or burned unexpectedly
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SupplyTracker {
+    pub supply: BTreeMap<String, u128>,
+    pub height: u64,
+}
+
+impl SupplyTracker {
+    /// Records the current total supply and height
+    pub async fn snapshot() -> Result<Self> {
+        let height = get_block_height().await?;
+        let supply = cosmovisor_total_supply().await?;
+        Ok(Self { supply, height })
+    }
+
+    /// Checks the current supply against the snapshot with
+    /// [check_supply_delta], the number of blocks for the tolerances is the
+    /// number of blocks since the snapshot
+    pub async fn assert_delta(
+        &self,
+        expected: &[(&str, i128)],
+        tolerance_per_block: &[(&str, u128)],
+    ) -> Result<()> {
+        let supply = cosmovisor_total_supply().await?;
+        let height = get_block_height().await?;
+        // the height is queried after the supply so that minting in between is
+        // still covered by the tolerance
+        check_supply_delta(
+            &self.supply,
+            &supply,
+            height.saturating_sub(self.height) + 1,
+            expected,
+            tolerance_per_block,
+        )
+        .map_add_err(|| format!("SupplyTracker::assert_delta(since height: {})", self.height))
+    }
+}
+
+/// Checks that the balances of a sender went from `sender_before` to
+/// `sender_after` (as returned by [cosmovisor_get_balances]) by exactly
+/// `sent` plus `fee` (in the format of [parse_coins], so that multi-denom fees
+/// are supported), and that no other denoms changed. The error lists the
+/// discrepancy of every mismatched denom.
+pub fn assert_exact_spend(
+    sender_before: &BTreeMap<String, String>,
+    sender_after: &BTreeMap<String, String>,
+    sent: &str,
+    fee: &str,
+) -> Result<()> {
+    let parse_balances = |balances: &BTreeMap<String, String>| -> Result<BTreeMap<String, u128>> {
+        let mut res = BTreeMap::new();
+        for (denom, amount) in balances {
+            let amount = amount
+                .parse::<u128>()
+                .map_add_err(|| format!("assert_exact_spend bad balance {amount}{denom}"))?;
+            res.insert(denom.clone(), amount);
+        }
+        Ok(res)
+    };
+    let before = parse_balances(sender_before)?;
+    let after = parse_balances(sender_after)?;
+    let mut spent = parse_coins(sent)?;
+    for (denom, amount) in parse_coins(fee)? {
+        *spent.entry(denom).or_insert(0) += amount;
+    }
+    let mut denoms: Vec<&String> = before
+        .keys()
+        .chain(after.keys())
+        .chain(spent.keys())
+        .collect();
+    denoms.sort();
+    denoms.dedup();
+    let mut discrepancies = vec![];
+    for denom in denoms {
+        let before = before.get(denom).copied().unwrap_or(0);
+        let after = after.get(denom).copied().unwrap_or(0);
+        let spent = spent.get(denom).copied().unwrap_or(0);
+        let Some(expected) = before.checked_sub(spent) else {
+            discrepancies.push(format!("{denom}: spent {spent} but only had {before}"));
+            continue
+        };
+        if after != expected {
+            let (sign, diff) = if after > expected {
+                ("+", after - expected)
+            } else {
+                ("-", expected - after)
+            };
+            discrepancies.push(format!(
+                "{denom}: expected {expected} but got {after} ({sign}{diff})"
+            ));
+        }
+    }
+    if !discrepancies.is_empty() {
+        return Err(Error::from(format!(
+            "assert_exact_spend(sent: {sent}, fee: {fee}) discrepancies: {discrepancies:?}"
+        )))
+    }
     Ok(())
 }
 
+/// The `--gas-adjustment` of [GasConfig::auto]
+pub const DEFAULT_GAS_ADJUSTMENT: f64 = 1.3;
+
+/// How the gas and fees of a transaction are determined
+#[derive(Debug, Clone)]
+pub enum GasConfig {
+    /// Uses "--gas auto --gas-adjustment {adjustment} --gas-prices
+    /// {price}{denom}", `price` should be at least the `minimum-gas-prices`
+    /// of the chain for `denom`
+    Auto {
+        adjustment: f64,
+        price: f64,
+        denom: String,
+    },
+    /// Uses "--gas {gas} --fees {fees}"
+    Fixed { gas: u64, fees: String },
+    /// Uses "--gas {gas} --fees" with the fees being in the denom of the first
+    /// entry of the `minimum-gas-prices` in `{daemon_home}/config/app.toml`,
+    /// multiplied by `gas`. This means the caller does not need to know
+    /// what gas denom the chain is currently configured with.
+    MinGasPrice { daemon_home: String, gas: u64 },
+    /// Uses the flags of `gas` and "--fee-granter {granter}", so that the fees
+    /// are paid by `granter` which needs to have granted a fee allowance to
+    /// the sender (see [cosmovisor_feegrant])
+    FeeGranter {
+        gas: Box<GasConfig>,
+        granter: String,
+    },
+    /// Uses the flags of `gas`, but [GasConfig::to_flags] returns an error if
+    /// more than `max_gas` would be used. The simulated gas of a
+    /// [GasConfig::Auto] is not known ahead of time, so instead it uses
+    /// "--gas {max_gas} --gas-prices {price}{denom}" and the transaction
+    /// runs out of gas if it needs more.
+    MaxGas { gas: Box<GasConfig>, max_gas: u64 },
+}
+
+impl GasConfig {
+    /// [GasConfig::Auto] with the [DEFAULT_GAS_ADJUSTMENT] and gas prices of
+    /// `1{denom}`
+    pub fn auto(denom: &str) -> Self {
+        GasConfig::Auto {
+            adjustment: DEFAULT_GAS_ADJUSTMENT,
+            price: 1.0,
+            denom: denom.to_owned(),
+        }
+    }
+
+    /// Wraps `self` in a [GasConfig::MaxGas]
+    pub fn max_gas(self, max_gas: u64) -> Self {
+        GasConfig::MaxGas {
+            gas: Box::new(self),
+            max_gas,
+        }
+    }
+
+    /// Returns the config inside of any [GasConfig::FeeGranter] and
+    /// [GasConfig::MaxGas] wrappers
+    pub fn inner(&self) -> &GasConfig {
+        match self {
+            GasConfig::FeeGranter { gas, .. } | GasConfig::MaxGas { gas, .. } => gas.inner(),
+            gas => gas,
+        }
+    }
+
+    /// If `--gas auto` is used, which means every transaction is simulated
+    /// first
+    pub fn is_auto(&self) -> bool {
+        match self {
+            GasConfig::Auto { .. } => true,
+            GasConfig::FeeGranter { gas, .. } => gas.is_auto(),
+            // the max gas is used as a fixed gas limit instead
+            GasConfig::MaxGas { .. } | GasConfig::Fixed { .. } | GasConfig::MinGasPrice { .. } => {
+                false
+            }
+        }
+    }
+
+    /// Wraps `self` in a [GasConfig::FeeGranter]
+    pub fn fee_granter(self, granter: &str) -> Self {
+        GasConfig::FeeGranter {
+            gas: Box::new(self),
+            granter: granter.to_owned(),
+        }
+    }
+
+    /// Returns the gas and fee related flags
+    pub async fn to_flags(&self) -> Result<Vec<String>> {
+        let mut gas = self;
+        let mut fee_granter = None;
+        let mut max_gas: Option<u64> = None;
+        loop {
+            match gas {
+                GasConfig::FeeGranter {
+                    gas: inner,
+                    granter,
+                } => {
+                    gas = inner.as_ref();
+                    fee_granter = Some(granter);
+                }
+                GasConfig::MaxGas {
+                    gas: inner,
+                    max_gas: max,
+                } => {
+                    gas = inner.as_ref();
+                    max_gas = Some(max_gas.map_or(*max, |m| m.min(*max)));
+                }
+                _ => break,
+            }
+        }
+        if let (Some(max_gas), GasConfig::Fixed { gas, .. } | GasConfig::MinGasPrice { gas, .. }) =
+            (max_gas, gas)
+        {
+            if *gas > max_gas {
+                return Err(Error::from(format!(
+                    "GasConfig::to_flags gas of {gas} is more than the max_gas of {max_gas}"
+                )))
+            }
+        }
+        let mut flags = match (gas, max_gas) {
+            // the simulated gas is not known ahead of time, so the max is used as the limit
+            (GasConfig::Auto { price, denom, .. }, Some(max_gas)) => vec![
+                "--gas".to_owned(),
+                format!("{max_gas}"),
+                "--gas-prices".to_owned(),
+                format!("{price}{denom}"),
+            ],
+            (
+                GasConfig::Auto {
+                    adjustment,
+                    price,
+                    denom,
+                },
+                None,
+            ) => vec![
+                "--gas".to_owned(),
+                "auto".to_owned(),
+                "--gas-adjustment".to_owned(),
+                format!("{adjustment}"),
+                "--gas-prices".to_owned(),
+                format!("{price}{denom}"),
+            ],
+            (GasConfig::Fixed { gas, fees }, _) => vec![
+                "--gas".to_owned(),
+                format!("{gas}"),
+                "--fees".to_owned(),
+                fees.to_owned(),
+            ],
+            (GasConfig::MinGasPrice { daemon_home, gas }, _) => {
+                let min_gas_prices = get_minimum_gas_price(daemon_home).await?;
+                let first = min_gas_prices
+                    .split(',')
+                    .next()
+                    .map_add_err(|| "empty `minimum-gas-prices`")?;
+                let (price, denom) = parse_dec_coin(first)?;
+                let fee = (price * (*gas as f64)).ceil() as u128;
+                vec![
+                    "--gas".to_owned(),
+                    format!("{gas}"),
+                    "--fees".to_owned(),
+                    format!("{fee}{denom}"),
+                ]
+            }
+            (GasConfig::FeeGranter { .. } | GasConfig::MaxGas { .. }, _) => unreachable!(),
+        };
+        if let Some(granter) = fee_granter {
+            flags.push("--fee-granter".to_owned());
+            flags.push(granter.to_owned());
+        }
+        Ok(flags)
+    }
+}
+
 /// Note that this interprets "null" height as 0
 pub async fn get_block_height() -> Result<u64> {
+    #[cfg(feature = "grpc_support")]
+    if let Ok(height) = crate::grpc::grpc_get_block_height().await {
+        return Ok(height)
+    }
     let block_s = sh_cosmovisor_no_dbg("query block", &[]).await?;
     let block: Value = serde_json::from_str(&block_s)?;
     let height = &block["block"]["header"]["height"].to_string();
@@ -127,16 +784,170 @@ pub async fn get_block_height() -> Result<u64> {
         .unwrap_or(0))
 }
 
-pub async fn wait_for_height(num_tries: u64, delay: Duration, height: u64) -> Result<()> {
-    async fn height_is_ge(height: u64) -> Result<()> {
-        if get_block_height().await? >= height {
-            Ok(())
+/// Returns the json of `cosmovisor run status`. Some versions of the SDK
+/// output the status to stderr instead of stdout, so this handles both.
+pub async fn cosmovisor_status() -> Result<Value> {
+    let comres = Command::new("cosmovisor run status", &[])
+        .run_to_completion()
+        .await?;
+    comres.assert_success()?;
+    let line = comres
+        .stdout
+        .lines()
+        .chain(comres.stderr.lines())
+        .find(|line| line.trim_start().starts_with('{'))
+        .map_add_err(|| "cosmovisor run status did not output any json")?;
+    serde_json::from_str(line).map_add_err(|| format!("cosmovisor_status() line: {line}"))
+}
+
+/// Returns the hash of the block at `height`
+pub async fn get_block_hash(height: u64) -> Result<String> {
+    let block_s = sh_cosmovisor_no_dbg("query block", &[&format!("{height}")]).await?;
+    let block: Value = serde_json::from_str(&block_s)?;
+    let hash = json_inner(&block["block_id"]["hash"]);
+    if hash.is_empty() || (hash == "null") {
+        return Err(Error::from(format!(
+            "get_block_hash(height: {height}) could not find hash in {block_s}"
+        )))
+    }
+    Ok(hash)
+}
+
+fn parse_app_hash(response: &Value) -> Result<String> {
+    response["result"]["block"]["header"]["app_hash"]
+        .as_str()
+        .map(|s| s.to_owned())
+        .map_add_err(|| format!("unexpected block RPC response: {response}"))
+}
+
+/// Returns the app hash in the header of the block at `height` from the RPC of
+/// `node` (e.x. "http://onomyd:26657"). Note that this is the app hash that
+/// resulted from executing the block at `height - 1`.
+pub async fn cosmovisor_app_hash_at(node: &str, height: u64) -> Result<String> {
+    let url = format!("{node}/block?height={height}");
+    let response = sh_no_dbg("curl -s --fail", &[&url])
+        .await
+        .map_add_err(|| format!("cosmovisor_app_hash_at could not reach {url}"))?;
+    let response: Value = serde_json::from_str(&response)
+        .map_add_err(|| format!("cosmovisor_app_hash_at response was not json: {response}"))?;
+    parse_app_hash(&response)
+}
+
+/// Fetches the app hash at `height` from each of `nodes` and returns an error
+/// if they do not all agree. Nodes that have not reached `height` yet are
+/// waited on for up to [TIMEOUT].
+pub async fn assert_app_hashes_agree(nodes: &[&str], height: u64) -> Result<String> {
+    let mut hashes = vec![];
+    for node in nodes {
+        let hash = poll_until(
+            || cosmovisor_app_hash_at(node, height),
+            |_| true,
+            TIMEOUT,
+            Duration::from_millis(300),
+        )
+        .await
+        .map_add_err(|| format!("assert_app_hashes_agree(height: {height})"))?;
+        hashes.push((*node, hash));
+    }
+    let first = hashes
+        .first()
+        .map_add_err(|| "assert_app_hashes_agree with no nodes")?;
+    if hashes.iter().any(|(_, hash)| *hash != first.1) {
+        return Err(Error::from(format!(
+            "assert_app_hashes_agree app hashes disagree at height {height}: {hashes:?}"
+        )))
+    }
+    Ok(first.1.clone())
+}
+
+/// Enables state sync snapshots in the app.toml of a node that other nodes
+/// can state sync from
+pub async fn enable_state_sync_snapshots(
+    daemon_home: &str,
+    snapshot_interval: u64,
+    snapshot_keep_recent: u64,
+) -> Result<()> {
+    edit_app_toml(daemon_home, |app_toml| {
+        app_toml["state-sync"]["snapshot-interval"] = value(snapshot_interval as i64);
+        app_toml["state-sync"]["snapshot-keep-recent"] = value(snapshot_keep_recent as i64);
+    })
+    .await
+}
+
+/// Configures the `[statesync]` section of config.toml so that the node state
+/// syncs on startup. Tendermint requires at least two `rpc_servers`, but they
+/// can be the same server repeated.
+pub async fn enable_state_sync(
+    daemon_home: &str,
+    rpc_servers: &[&str],
+    trust_height: u64,
+    trust_hash: &str,
+) -> Result<()> {
+    let rpc_servers = rpc_servers.join(",");
+    edit_config_toml(daemon_home, |config| {
+        config["statesync"]["enable"] = value(true);
+        config["statesync"]["rpc_servers"] = value(rpc_servers);
+        config["statesync"]["trust_height"] = value(trust_height as i64);
+        config["statesync"]["trust_hash"] = value(trust_hash);
+        config["statesync"]["trust_period"] = value("168h0m0s");
+    })
+    .await
+}
+
+/// Sets `p2p.persistent_peers` in config.toml, `peers` should be in the format
+/// returned by [get_persistent_peer_info]
+pub async fn set_persistent_peers(daemon_home: &str, peers: &[&str]) -> Result<()> {
+    let peers = peers.join(",");
+    edit_config_toml(daemon_home, |config| {
+        config["p2p"]["persistent_peers"] = value(peers);
+    })
+    .await
+}
+
+/// Waits for the node to no longer be catching up, and returns the earliest
+/// block height the node has. If the node state synced, this will be greater
+/// than 1, because it did not replay from genesis.
+pub async fn wait_for_state_sync_complete(timeout: Duration) -> Result<u64> {
+    async fn synced() -> Result<u64> {
+        let status = cosmovisor_status().await?;
+        // the key is "SyncInfo" or "sync_info" depending on the version
+        let sync_info = if status["SyncInfo"].is_null() {
+            &status["sync_info"]
         } else {
-            ().map_add_err(|| ())
+            &status["SyncInfo"]
+        };
+        let catching_up = sync_info["catching_up"]
+            .as_bool()
+            .map_add_err(|| format!("unexpected status: {status}"))?;
+        let latest_height = json_inner(&sync_info["latest_block_height"])
+            .parse::<u64>()
+            .unwrap_or(0);
+        if catching_up || (latest_height == 0) {
+            return Err(Error::from(format!(
+                "still syncing, catching_up: {catching_up}, latest_block_height: {latest_height}"
+            )))
         }
+        json_inner(&sync_info["earliest_block_height"])
+            .parse::<u64>()
+            .map_add_err(|| format!("unexpected status: {status}"))
     }
+    info!("waiting for state sync to complete");
+    let delay = Duration::from_millis(500);
+    let num_tries = u64::try_from(timeout.as_millis() / delay.as_millis())
+        .unwrap_or(u64::MAX)
+        .max(1);
+    wait_for_ok(num_tries, delay, synced)
+        .await
+        .map_add_err(|| format!("wait_for_state_sync_complete(timeout: {timeout:?})"))
+}
+
+pub async fn wait_for_height(num_tries: u64, delay: Duration, height: u64) -> Result<()> {
     info!("waiting for height {height}");
-    wait_for_ok(num_tries, delay, || height_is_ge(height)).await
+    let timeout = delay.saturating_mul(u32::try_from(num_tries).unwrap_or(u32::MAX));
+    poll_until(get_block_height, |h| *h >= height, timeout, delay)
+        .await
+        .map_add_err(|| format!("wait_for_height(height: {height})"))?;
+    Ok(())
 }
 
 /// Waits for `num_blocks`. Note: if you are calling this in some timed sequence
@@ -148,6 +959,85 @@ pub async fn wait_for_num_blocks(num_blocks: u64) -> Result<()> {
     wait_for_height(STD_TRIES, STD_DELAY, height + num_blocks).await
 }
 
+/// Parses a Go duration string as found in `config.toml` (e.x. "5s", "1m30s",
+/// "500ms")
+pub fn parse_go_duration(s: &str) -> Result<Duration> {
+    let err = || format!("parse_go_duration({s}) unexpected format");
+    let mut rest = s.trim();
+    if rest == "0" {
+        return Ok(Duration::ZERO)
+    }
+    let mut res = Duration::ZERO;
+    while !rest.is_empty() {
+        let num_len = rest
+            .find(|c: char| !(c.is_ascii_digit() || (c == '.')))
+            .map_add_err(err)?;
+        let num = rest[..num_len]
+            .parse::<f64>()
+            .map_err(|e| Error::boxed(Box::new(e)))
+            .map_add_err(err)?;
+        rest = &rest[num_len..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || (c == '.'))
+            .unwrap_or(rest.len());
+        let unit_secs = match &rest[..unit_len] {
+            "ns" => 1.0e-9,
+            "us" | "µs" => 1.0e-6,
+            "ms" => 1.0e-3,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return Err(Error::from(err())),
+        };
+        rest = &rest[unit_len..];
+        res += Duration::from_secs_f64(num * unit_secs);
+    }
+    Ok(res)
+}
+
+/// Returns the number of blocks that need to be produced for at least
+/// `duration` of block time to pass, given that consecutive blocks are at least
+/// `min_block_time` apart
+pub fn blocks_to_pass_duration(duration: Duration, min_block_time: Duration) -> u64 {
+    if min_block_time.is_zero() {
+        return 1
+    }
+    let min_block_time = min_block_time.as_nanos();
+    let blocks = duration.as_nanos().div_ceil(min_block_time);
+    // one more because the current block may have started at any time
+    u64::try_from(blocks).unwrap_or(u64::MAX).saturating_add(1)
+}
+
+/// Waits for at least `duration` of block time to pass, e.x. for an IBC client
+/// to pass its trusting period. Chain time follows the wall-clock time of the
+/// validators, so it cannot be advanced directly. Instead, the `timeout_commit`
+/// in `config.toml` is used as a lower bound on the time between blocks, so a
+/// short `timeout_commit` will not make this shorter than `duration`, but
+/// waiting on block heights avoids timing flakiness.
+pub async fn wait_for_chain_duration(daemon_home: &str, duration: Duration) -> Result<()> {
+    let config_path = format!("{daemon_home}/config/config.toml");
+    let config_s = FileOptions::read_to_string(&config_path).await?;
+    let config: toml::Value =
+        toml::from_str(&config_s).map_add_err(|| format!("could not parse {config_path}"))?;
+    let timeout_commit = config["consensus"]["timeout_commit"]
+        .as_str()
+        .map_add_err(|| format!("{config_path} has no string `consensus.timeout_commit`"))?;
+    let min_block_time = parse_go_duration(timeout_commit)?;
+    let num_blocks = blocks_to_pass_duration(duration, min_block_time);
+    info!("waiting {num_blocks} blocks for {duration:?} of block time to pass");
+    let height = get_block_height().await?;
+    // allow for the blocks to be several times slower than `timeout_commit`
+    let delay = Duration::from_millis(300);
+    let num_tries = u64::try_from(
+        (min_block_time.as_millis() * 4 * u128::from(num_blocks)) / delay.as_millis(),
+    )
+    .unwrap_or(u64::MAX)
+    .max(STD_TRIES);
+    wait_for_height(num_tries, delay, height + num_blocks)
+        .await
+        .map_add_err(|| format!("wait_for_chain_duration(duration: {duration:?})"))
+}
+
 /// Returns the number of proposals
 pub async fn cosmovisor_get_num_proposals() -> Result<u64> {
     let comres = Command::new(
@@ -175,7 +1065,9 @@ pub async fn cosmovisor_get_num_proposals() -> Result<u64> {
         .1;
 
     let v = yaml_str_to_json_value(stdout)?;
-    let total = v["pagination"]["total"].as_str().map_add_err(|| ())?;
+    let total = v["pagination"]["total"]
+        .as_str()
+        .map_add_err(|| format!("unexpected proposals query result: {v}"))?;
     total.parse::<u64>().map_add_err(|| ())
 }
 
@@ -204,34 +1096,31 @@ pub async fn cosmovisor_submit_gov_file_proposal(
     daemon_home: &str,
     proposal_type: &str,
     proposal_s: &str,
-    base_fee: &str,
+    gas: &GasConfig,
 ) -> Result<()> {
     let proposal_file_path = format!("{daemon_home}/config/proposal.json");
     FileOptions::write_str(&proposal_file_path, proposal_s)
         .await
         .map_add_err(|| ())?;
-    sh_cosmovisor_tx("gov submit-proposal", &[
+    let gas_flags = gas.to_flags().await?;
+    let mut args = vec![
         proposal_type,
         &proposal_file_path,
-        "--gas",
-        "auto",
-        "--gas-adjustment",
-        "1.3",
-        "--gas-prices",
-        base_fee,
         "-y",
         "-b",
         "block",
         "--from",
         "validator",
-    ])
-    .await
-    .map_add_err(|| {
-        format!(
-            "cosmovisor_submit_gov_file_proposal(proposal_type: {proposal_type}, proposal_s: \
-             {proposal_s})"
-        )
-    })?;
+    ];
+    args.extend(gas_flags.iter().map(|s| s.as_str()));
+    sh_cosmovisor_tx("gov submit-proposal", &args)
+        .await
+        .map_add_err(|| {
+            format!(
+                "cosmovisor_submit_gov_file_proposal(proposal_type: {proposal_type}, proposal_s: \
+                 {proposal_s})"
+            )
+        })?;
     Ok(())
 }
 
@@ -239,54 +1128,65 @@ pub async fn cosmovisor_gov_file_proposal(
     daemon_home: &str,
     proposal_type: &str,
     proposal_s: &str,
-    base_fee: &str,
+    gas: &GasConfig,
 ) -> Result<()> {
-    cosmovisor_submit_gov_file_proposal(daemon_home, proposal_type, proposal_s, base_fee)
+    cosmovisor_submit_gov_file_proposal(daemon_home, proposal_type, proposal_s, gas)
         .await
         .map_add_err(|| ())?;
-    let proposal_id = format!("{}", cosmovisor_get_num_proposals().await?);
+    let proposal_id = cosmovisor_get_num_proposals().await?;
     // the deposit is done as part of the chain addition proposal
-    sh_cosmovisor_tx("gov vote", &[
-        &proposal_id,
-        "yes",
-        "--gas",
-        "auto",
-        "--gas-adjustment",
-        // market ICS needs this for some reason
-        "2.3",
-        "--gas-prices",
-        base_fee,
-        "-y",
-        "-b",
-        "block",
-        "--from",
-        "validator",
-    ])
-    .await?;
+    cosmovisor_gov_vote(proposal_id, "yes", "validator", gas).await?;
+    Ok(())
+}
+
+/// Returns the status of a proposal, e.x. "PROPOSAL_STATUS_PASSED"
+pub async fn cosmovisor_get_proposal_status(proposal_id: u64) -> Result<String> {
+    #[cfg(feature = "grpc_support")]
+    if let Ok(status) = crate::grpc::grpc_get_proposal_status(proposal_id).await {
+        return Ok(status)
+    }
+    let proposal = sh_cosmovisor_no_dbg("query gov proposal", &[&format!("{proposal_id}")])
+        .await
+        .map_add_err(|| format!("cosmovisor_get_proposal_status(proposal_id: {proposal_id})"))?;
+    let proposal = yaml_str_to_json_value(&proposal)?;
+    let status = &proposal["status"];
+    if status.is_null() {
+        return Err(Error::from(format!(
+            "unexpected proposal query result: {proposal}"
+        )))
+    }
+    Ok(json_inner(status))
+}
+
+/// Waits for the proposal to have the given `status`
+pub async fn wait_for_proposal_status(
+    proposal_id: u64,
+    status: &str,
+    timeout: Duration,
+) -> Result<()> {
+    info!("waiting for proposal {proposal_id} to have status {status}");
+    poll_until(
+        || cosmovisor_get_proposal_status(proposal_id),
+        |s| s == status,
+        timeout,
+        STD_DELAY,
+    )
+    .await
+    .map_add_err(|| format!("wait_for_proposal_status(proposal_id: {proposal_id})"))?;
     Ok(())
 }
 
 pub async fn cosmovisor_submit_gov_proposal(
     proposal_type: &str,
     proposal_args: &[&str],
-    base_fee: &str,
+    gas: &GasConfig,
 ) -> Result<()> {
+    let gas_flags = gas.to_flags().await?;
     let mut args = vec![];
     args.push(proposal_type);
     args.extend(proposal_args);
-    args.extend([
-        "--gas",
-        "auto",
-        "--gas-adjustment",
-        "1.3",
-        "--gas-prices",
-        base_fee,
-        "-y",
-        "-b",
-        "block",
-        "--from",
-        "validator",
-    ]);
+    args.extend(["-y", "-b", "block", "--from", "validator"]);
+    args.extend(gas_flags.iter().map(|s| s.as_str()));
     sh_cosmovisor_tx("gov submit-proposal", &args)
         .await
         .map_add_err(|| ())?;
@@ -297,52 +1197,256 @@ pub async fn cosmovisor_gov_proposal(
     proposal_type: &str,
     proposal_args: &[&str],
     deposit: &str,
-    base_fee: &str,
+    gas: &GasConfig,
 ) -> Result<()> {
-    cosmovisor_submit_gov_proposal(proposal_type, proposal_args, base_fee)
+    cosmovisor_submit_gov_proposal(proposal_type, proposal_args, gas)
         .await
         .map_add_err(|| ())?;
-    let proposal_id = format!("{}", cosmovisor_get_num_proposals().await?);
-    sh_cosmovisor_tx("gov deposit", &[
-        &proposal_id,
+    let proposal_id = cosmovisor_get_num_proposals().await?;
+    let proposal_id_s = proposal_id.to_string();
+    let gas_flags = gas.to_flags().await?;
+    let mut args: Vec<&str> = vec![
+        &proposal_id_s,
         deposit,
-        "--gas",
-        "auto",
-        "--gas-adjustment",
-        "1.3",
-        "--gas-prices",
-        base_fee,
         "-y",
         "-b",
         "block",
         "--from",
         "validator",
-    ])
-    .await?;
+    ];
+    args.extend(gas_flags.iter().map(|s| s.as_str()));
+    sh_cosmovisor_tx("gov deposit", &args).await?;
     // the deposit is done as part of the chain addition proposal
-    sh_cosmovisor_tx("gov vote", &[
-        &proposal_id,
-        "yes",
-        "--gas",
-        "auto",
-        "--gas-adjustment",
-        "1.3",
-        "--gas-prices",
-        base_fee,
+    cosmovisor_gov_vote(proposal_id, "yes", "validator", gas).await?;
+    Ok(())
+}
+
+/// Votes `option` (e.x. "yes" or "no_with_veto") on `proposal_id` from
+/// `from_key`, retrying on sequence mismatches. Returns the tx result.
+pub async fn cosmovisor_gov_vote(
+    proposal_id: u64,
+    option: &str,
+    from_key: &str,
+    gas: &GasConfig,
+) -> Result<Value> {
+    let proposal_id_s = proposal_id.to_string();
+    let gas_flags = gas.to_flags().await?;
+    let mut args: Vec<&str> = vec![
+        &proposal_id_s,
+        option,
         "-y",
         "-b",
         "block",
         "--from",
-        "validator",
-    ])
-    .await?;
-    Ok(())
+        from_key,
+    ];
+    args.extend(gas_flags.iter().map(|s| s.as_str()));
+    sh_cosmovisor_tx_sequence_retry("gov vote", &args, STD_TRIES)
+        .await
+        .map_add_err(|| {
+            format!(
+                "cosmovisor_gov_vote(proposal_id: {proposal_id}, option: {option}, from_key: \
+                 {from_key})"
+            )
+        })
 }
 
-pub async fn get_persistent_peer_info(hostname: &str) -> Result<String> {
+/// The votes on a proposal in voting power
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GovTally {
+    pub yes: u128,
+    pub no: u128,
+    pub abstain: u128,
+    pub no_with_veto: u128,
+}
+
+impl GovTally {
+    /// Parses the result of `query gov tally`, both the v1beta1 keys and the
+    /// v1 keys with a "_count" suffix are accepted
+    pub fn from_json(tally: &Value) -> Result<Self> {
+        let field = |key: &str| -> Result<u128> {
+            let v = if tally[key].is_null() {
+                &tally[format!("{key}_count")]
+            } else {
+                &tally[key]
+            };
+            json_inner(v)
+                .parse::<u128>()
+                .map_add_err(|| format!("unexpected `query gov tally` result: {tally}"))
+        };
+        Ok(Self {
+            yes: field("yes")?,
+            no: field("no")?,
+            abstain: field("abstain")?,
+            no_with_veto: field("no_with_veto")?,
+        })
+    }
+
+    pub fn total(&self) -> u128 {
+        self.yes + self.no + self.abstain + self.no_with_veto
+    }
+
+    /// If the voted power is at least `quorum` (a decimal such as "0.334")
+    /// of `bonded_tokens`
+    pub fn reaches_quorum(&self, bonded_tokens: u128, quorum: &str) -> Result<bool> {
+        const E18: u128 = 1_000_000_000_000_000_000;
+        let (int, frac) = DecCoin::parse_amount(quorum)?;
+        let quorum = int
+            .checked_mul(E18)
+            .and_then(|q| q.checked_add(u128::from(frac)))
+            .map_add_err(|| format!("GovTally::reaches_quorum quorum {quorum} is too large"))?;
+        // avoid overflowing for realistic token amounts
+        let required = (bonded_tokens / E18) * quorum + ((bonded_tokens % E18) * quorum) / E18;
+        Ok(self.total() >= required)
+    }
+}
+
+pub async fn cosmovisor_gov_tally(proposal_id: u64) -> Result<GovTally> {
+    let tally = sh_cosmovisor_no_dbg("query gov tally", &[&proposal_id.to_string()])
+        .await
+        .map_add_err(|| format!("cosmovisor_gov_tally(proposal_id: {proposal_id})"))?;
+    GovTally::from_json(&yaml_str_to_json_value(&tally)?)
+}
+
+/// Returns if `proposal_id` has reached the quorum of the gov params
+pub async fn cosmovisor_gov_quorum_reached(proposal_id: u64) -> Result<bool> {
+    let err = || format!("cosmovisor_gov_quorum_reached(proposal_id: {proposal_id})");
+    let params = yaml_str_to_json_value(
+        &sh_cosmovisor_no_dbg("query gov params", &[])
+            .await
+            .map_add_err(err)?,
+    )?;
+    let quorum = json_inner(&params["tally_params"]["quorum"]);
+    let pool = yaml_str_to_json_value(
+        &sh_cosmovisor_no_dbg("query staking pool", &[])
+            .await
+            .map_add_err(err)?,
+    )?;
+    let bonded_tokens = json_inner(&pool["bonded_tokens"])
+        .parse::<u128>()
+        .map_add_err(err)?;
+    cosmovisor_gov_tally(proposal_id)
+        .await?
+        .reaches_quorum(bonded_tokens, &quorum)
+        .map_add_err(err)
+}
+
+/// Returns the option that `voter` voted on `proposal_id`, e.x.
+/// "VOTE_OPTION_YES"
+pub async fn cosmovisor_gov_vote_option(proposal_id: u64, voter: &str) -> Result<String> {
+    let vote = sh_cosmovisor_no_dbg("query gov vote", &[&proposal_id.to_string(), voter])
+        .await
+        .map_add_err(|| {
+            format!("cosmovisor_gov_vote_option(proposal_id: {proposal_id}, voter: {voter})")
+        })?;
+    parse_gov_vote_option(&yaml_str_to_json_value(&vote)?)
+}
+
+/// Parses the option out of the result of `query gov vote`, the deprecated
+/// single "option" is used if there are no weighted "options"
+pub fn parse_gov_vote_option(vote: &Value) -> Result<String> {
+    let option = match vote["options"]
+        .as_array()
+        .and_then(|options| options.first())
+    {
+        Some(option) => &option["option"],
+        None => &vote["option"],
+    };
+    option
+        .as_str()
+        .filter(|s| !s.is_empty() && (*s != "VOTE_OPTION_UNSPECIFIED"))
+        .map(|s| s.to_owned())
+        .map_add_err(|| format!("unexpected `query gov vote` result: {vote}"))
+}
+
+/// Votes `option` on `proposal_id` from each of `voters` in turn, checks that
+/// each vote was recorded, and then waits for the proposal to reach quorum.
+/// Returns the txhash of each vote.
+pub async fn cosmovisor_gov_vote_all(
+    voters: &[KeyInfo],
+    proposal_id: u64,
+    option: &str,
+    gas: &GasConfig,
+) -> Result<Vec<String>> {
+    let err = || format!("cosmovisor_gov_vote_all(proposal_id: {proposal_id}, option: {option})");
+    let expected_option = format!("VOTE_OPTION_{}", option.to_uppercase());
+    let mut txhashes = vec![];
+    for voter in voters {
+        let res = cosmovisor_gov_vote(proposal_id, option, &voter.name, gas)
+            .await
+            .map_add_err(err)?;
+        let voted = cosmovisor_gov_vote_option(proposal_id, &voter.address)
+            .await
+            .map_add_err(err)?;
+        if voted != expected_option {
+            return Err(Error::from(format!(
+                "{} {} voted {voted}",
+                err(),
+                voter.name
+            )))
+        }
+        txhashes.push(json_inner(&res["txhash"]));
+    }
+    poll_until(
+        || cosmovisor_gov_quorum_reached(proposal_id),
+        |reached| *reached,
+        TIMEOUT,
+        STD_DELAY,
+    )
+    .await
+    .map_add_err(|| format!("{} quorum was not reached", err()))?;
+    Ok(txhashes)
+}
+
+/// Returns the tendermint node ID of the local node
+pub async fn get_node_id() -> Result<String> {
     let s = sh_cosmovisor("tendermint show-node-id", &[]).await?;
-    let tendermint_id = s.trim();
-    Ok(format!("{tendermint_id}@{hostname}:26656"))
+    Ok(s.trim().to_owned())
+}
+
+pub async fn get_persistent_peer_info(hostname: &str) -> Result<String> {
+    let tendermint_id = get_node_id().await?;
+    Ok(format!("{tendermint_id}@{hostname}:{P2P_PORT}"))
+}
+
+/// Parses the node IDs of the peers from a `net_info` RPC response
+pub fn parse_net_info_peers(net_info: &Value) -> Result<Vec<String>> {
+    let peers = net_info["result"]["peers"]
+        .as_array()
+        .map_add_err(|| format!("unexpected net_info response: {net_info}"))?;
+    let mut res = vec![];
+    for peer in peers {
+        let id = peer["node_info"]["id"]
+            .as_str()
+            .map_add_err(|| format!("unexpected net_info peer: {peer}"))?;
+        res.push(id.to_owned());
+    }
+    Ok(res)
+}
+
+/// Returns the node IDs of the peers the local node is connected to
+pub async fn cosmovisor_peer_ids() -> Result<Vec<String>> {
+    let url = format!("http://localhost:{RPC_PORT}/net_info");
+    let response = sh_no_dbg("curl -s --fail", &[&url])
+        .await
+        .map_add_err(|| "cosmovisor_peer_ids could not reach the RPC")?;
+    let response: Value = serde_json::from_str(&response)
+        .map_add_err(|| format!("cosmovisor_peer_ids response was not json: {response}"))?;
+    parse_net_info_peers(&response)
+}
+
+/// Waits until the local node is connected to the peer with `node_id`, or
+/// returns an error after `timeout`
+pub async fn wait_for_peer(node_id: &str, timeout: Duration) -> Result<()> {
+    poll_until(
+        cosmovisor_peer_ids,
+        |peers| peers.iter().any(|id| id == node_id),
+        timeout,
+        Duration::from_millis(500),
+    )
+    .await
+    .map_add_err(|| format!("wait_for_peer(node_id: {node_id})"))?;
+    Ok(())
 }
 
 pub async fn get_cosmovisor_subprocess_path() -> Result<String> {
@@ -356,8 +1460,140 @@ pub async fn get_cosmovisor_subprocess_path() -> Result<String> {
     Ok(val)
 }
 
+#[derive(Debug, Clone, Default)]
 pub struct CosmovisorOptions {
     pub halt_height: Option<u64>,
+    /// If set, `cosmovisor_start` will not wait for the daemon to reach any
+    /// heights. This is needed for nodes that are state syncing and do not
+    /// have any blocks until the sync is complete.
+    pub no_wait: bool,
+    /// Serves the REST API on [REST_PORT], use [wait_for_cosmos_rest] before
+    /// querying it
+    pub enable_rest: bool,
+    /// Serves gRPC on [GRPC_PORT], use [wait_for_cosmos_grpc] before querying
+    /// it
+    pub enable_grpc: bool,
+    /// Serves the Go pprof endpoints on [PPROF_PORT], needed for
+    /// [cosmovisor_goroutine_count]
+    pub enable_pprof: bool,
+    /// The following are written into `$DAEMON_HOME/config/config.toml` before
+    /// starting, see [apply_block_production]. A short `timeout_commit` speeds
+    /// up tests but increases the number of blocks (and therefore the size
+    /// of logs and exports) for the same amount of wall-clock time.
+    pub timeout_commit: Option<Duration>,
+    /// If `Some(false)`, blocks are only produced when there are transactions
+    /// (or when `create_empty_blocks_interval` is reached). This makes heights
+    /// predictable relative to the transactions sent, but anything waiting on
+    /// heights (e.x. governance) will stall on an idle chain.
+    pub create_empty_blocks: Option<bool>,
+    pub create_empty_blocks_interval: Option<Duration>,
+    /// Runs the node as a seed node, which only crawls the network and hands
+    /// out peer addresses to nodes that connect to it. Seed nodes do not
+    /// sync blocks, so `cosmovisor_start` does not wait for any heights.
+    pub seed_mode: bool,
+    /// Seed nodes (in the format returned by [get_persistent_peer_info]) that
+    /// the node discovers its peers through. See [apply_p2p] for the other
+    /// settings that are changed when this or `seed_mode` is set.
+    pub seeds: Vec<String>,
+    /// Extra tracing of the daemon for debugging nondeterminism. This is very
+    /// verbose, so it should only be enabled while reproducing an issue.
+    pub trace: Option<TraceConfig>,
+}
+
+/// Tracing flags and environment for the daemon started by `cosmovisor_start`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceConfig {
+    /// Passed as `--log_level`, e.x. "consensus:debug,*:info"
+    pub log_level: Option<String>,
+    /// Passes `--trace` so that errors have full stack traces
+    pub stack_traces: bool,
+    /// Passed as `--trace-store`, every KV store operation is written to this
+    /// file. Use a path under "/logs" so that it is collected with the logs.
+    pub trace_store: Option<String>,
+    /// Extra environment variables for the daemon, e.x. `("GODEBUG",
+    /// "gctrace=1")`
+    pub env: Vec<(String, String)>,
+}
+
+impl TraceConfig {
+    /// Debug level consensus logs and full stack traces, with the KV store
+    /// trace written to "/logs/{name}_store_trace.log"
+    pub fn consensus(name: &str) -> Self {
+        Self {
+            log_level: Some("consensus:debug,*:info".to_owned()),
+            stack_traces: true,
+            trace_store: Some(format!("/logs/{name}_store_trace.log")),
+            env: vec![],
+        }
+    }
+
+    /// The flags for `cosmovisor run start`
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(ref log_level) = self.log_level {
+            args.push("--log_level".to_owned());
+            args.push(log_level.to_owned());
+        }
+        if self.stack_traces {
+            args.push("--trace".to_owned());
+        }
+        if let Some(ref trace_store) = self.trace_store {
+            args.push("--trace-store".to_owned());
+            args.push(trace_store.to_owned());
+        }
+        args
+    }
+
+    /// Returns the "env K=V " prefix for the command, which `cosmovisor`
+    /// passes on to the daemon
+    pub fn env_prefix(&self) -> Result<String> {
+        if self.env.is_empty() {
+            return Ok(String::new())
+        }
+        let mut res = "env".to_owned();
+        for (key, val) in &self.env {
+            if key.is_empty()
+                || key.contains('=')
+                || key.contains(char::is_whitespace)
+                || val.contains(char::is_whitespace)
+            {
+                return Err(Error::from(format!(
+                    "TraceConfig::env_prefix invalid environment variable {key}={val}"
+                )))
+            }
+            res += &format!(" {key}={val}");
+        }
+        res.push(' ');
+        Ok(res)
+    }
+}
+
+/// Applies the block production fields of `options` to a `config.toml`
+pub fn apply_block_production(config: &mut Document, options: &CosmovisorOptions) {
+    let fmt_dur = |dur: Duration| format!("{}ms", dur.as_millis());
+    if let Some(timeout_commit) = options.timeout_commit {
+        config["consensus"]["timeout_commit"] = value(fmt_dur(timeout_commit));
+    }
+    if let Some(create_empty_blocks) = options.create_empty_blocks {
+        config["consensus"]["create_empty_blocks"] = value(create_empty_blocks);
+    }
+    if let Some(interval) = options.create_empty_blocks_interval {
+        config["consensus"]["create_empty_blocks_interval"] = value(fmt_dur(interval));
+    }
+}
+
+/// Applies the peer discovery fields of `options` to a `config.toml`. If
+/// `seed_mode` is set or there are `seeds`, this also enables peer exchange
+/// and disables `addr_book_strict`, since the containers only have private
+/// addresses which a strict address book rejects.
+pub fn apply_p2p(config: &mut Document, options: &CosmovisorOptions) {
+    if !options.seed_mode && options.seeds.is_empty() {
+        return
+    }
+    config["p2p"]["pex"] = value(true);
+    config["p2p"]["addr_book_strict"] = value(false);
+    config["p2p"]["seed_mode"] = value(options.seed_mode);
+    config["p2p"]["seeds"] = value(options.seeds.join(","));
 }
 
 /// `cosmovisor run start` spawns the cosmos binary as a completely separate
@@ -391,25 +1627,61 @@ pub async fn cosmovisor_start(
 ) -> Result<CosmovisorRunner> {
     let cosmovisor_log = FileOptions::write2("/logs", log_file_name);
 
+    let rpc_laddr = format!("tcp://0.0.0.0:{RPC_PORT}");
+    let rest_laddr = format!("tcp://0.0.0.0:{REST_PORT}");
+    let grpc_laddr = format!("0.0.0.0:{GRPC_PORT}");
+    let pprof_laddr = format!("0.0.0.0:{PPROF_PORT}");
     let mut args = vec![];
 
     // this is required for our Hermes setups
     args.push("--rpc.laddr");
-    args.push("tcp://0.0.0.0:26657");
+    args.push(&rpc_laddr);
 
     //args.push("--p2p.laddr");
     //args.push("tcp://0.0.0.0:26656");
-    //args.push("--grpc.address");
-    //args.push("0.0.0.0:9090");
-    //args.push("--grpc.enable");
-    //args.push("true");
     /*if let Some(ref peer) = peer {
         args.push("--p2p.persistent_peers");
         args.push(peer);
     }*/
     let halt_height_s;
+    let mut trace_args = vec![];
+    let mut env_prefix = String::new();
     let mut quick_halt = false;
+    let mut no_wait = false;
+    let mut seed_mode = false;
     if let Some(options) = options {
+        no_wait = options.no_wait;
+        seed_mode = options.seed_mode;
+        if options.timeout_commit.is_some()
+            || options.create_empty_blocks.is_some()
+            || options.create_empty_blocks_interval.is_some()
+            || options.seed_mode
+            || !options.seeds.is_empty()
+        {
+            let daemon_home = std::env::var("DAEMON_HOME")
+                .ok()
+                .map_add_err(|| "cosmovisor_start needs `DAEMON_HOME` to edit config.toml")?;
+            edit_config_toml(&daemon_home, |config| {
+                apply_block_production(config, &options);
+                apply_p2p(config, &options);
+            })
+            .await?;
+        }
+        if options.enable_rest {
+            args.extend(["--api.enable", "true", "--api.address", &rest_laddr]);
+        }
+        if options.enable_grpc {
+            args.extend(["--grpc.enable", "true", "--grpc.address", &grpc_laddr]);
+        }
+        if options.enable_pprof {
+            args.extend(["--rpc.pprof_laddr", &pprof_laddr]);
+        }
+        if let Some(ref trace) = options.trace {
+            trace_args = trace.args();
+            env_prefix = trace.env_prefix()?;
+            info!("starting daemon {log_file_name} with tracing {trace:?}");
+        }
+        args.extend(trace_args.iter().map(|s| s.as_str()));
         if let Some(halt_height) = options.halt_height {
             if halt_height <= 2 {
                 quick_halt = true;
@@ -420,14 +1692,21 @@ pub async fn cosmovisor_start(
         }
     }
 
-    let cosmovisor_runner = Command::new("cosmovisor run start --inv-check-period  1", &args)
-        .stderr_log(&cosmovisor_log)
-        .stdout_log(&cosmovisor_log)
-        .run()
-        .await?;
+    let cosmovisor_runner = Command::new(
+        &format!("{env_prefix}cosmovisor run start --inv-check-period  1"),
+        &args,
+    )
+    .stderr_log(&cosmovisor_log)
+    .stdout_log(&cosmovisor_log)
+    .run()
+    .await?;
 
     if quick_halt {
         info!("skipping waiting because halt_height <= 2");
+    } else if no_wait {
+        info!("skipping waiting because of `no_wait`");
+    } else if seed_mode {
+        info!("skipping waiting because of `seed_mode`");
     } else {
         // wait for status to be ok and daemon to be running
         info!("waiting for daemon to run");
@@ -473,124 +1752,2175 @@ pub async fn cosmovisor_start(
     })
 }
 
-pub async fn cosmovisor_get_addr(key_name: &str) -> Result<String> {
-    let validator = yaml_str_to_json_value(
-        &sh_cosmovisor("keys show", &[key_name])
-            .await
-            .map_add_err(|| ())?,
-    )
-    .map_add_err(|| ())?;
-    Ok(json_inner(&validator[0]["address"]))
+/// Parses the "height=" field of a daemon log line
+fn parse_log_line_height(line: &str) -> Option<u64> {
+    let (_, rest) = line.split_once("height=")?;
+    let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
 }
 
-/// Returns a mapping of denoms to amounts
-pub async fn cosmovisor_get_balances(addr: &str) -> Result<BTreeMap<String, String>> {
-    let balances = sh_cosmovisor_no_dbg("query bank balances", &[addr])
-        .await
-        .map_add_err(|| ())?;
-    let balances = yaml_str_to_json_value(&balances)?;
-    let mut res = BTreeMap::new();
-    for balance in balances["balances"].as_array().map_add_err(|| ())? {
-        res.insert(
-            json_inner(&balance["denom"]),
-            json_inner(&balance["amount"]),
-        );
+/// A consensus failure found in the log of a validator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsensusFailure {
+    pub validator: String,
+    /// The height of the failing line, or else the last height logged before it
+    pub height: Option<u64>,
+    pub line: String,
+}
+
+/// Returns the height and line of the first apphash mismatch or consensus
+/// failure in `log`
+pub fn find_consensus_failure(log: &str) -> Option<(Option<u64>, String)> {
+    let mut last_height = None;
+    for line in log.lines() {
+        let height = parse_log_line_height(line);
+        if line.contains("wrong Block.Header.AppHash") || line.contains("CONSENSUS FAILURE") {
+            return Some((height.or(last_height), line.to_owned()))
+        }
+        if height.is_some() {
+            last_height = height;
+        }
     }
-    Ok(res)
+    None
 }
 
-/// This uses flags "-b block --gas auto --gas-adjustment 1.3 --gas-prices
-/// 1{denom}"
-pub async fn cosmovisor_bank_send(
-    src_addr: &str,
-    dst_addr: &str,
-    amount: &str,
-    denom: &str,
+/// Polls the logs of validators every `interval` for apphash mismatches or
+/// consensus failures. `logs` are pairs of validator names and log paths (e.x.
+/// `("onomyd", "./tests/logs/onomyd_runner.log")`). This only returns upon
+/// finding a failure, and the error includes all the validators that failed and
+/// at what heights. Use with `tokio::select!` to abort a test immediately.
+pub async fn watch_for_consensus_failure(logs: &[(&str, &str)], interval: Duration) -> Result<()> {
+    loop {
+        let mut failures = vec![];
+        for (validator, path) in logs {
+            // the log may not have been created yet
+            let Ok(log) = FileOptions::read_to_string(path).await else {
+                continue
+            };
+            if let Some((height, line)) = find_consensus_failure(&log) {
+                failures.push(ConsensusFailure {
+                    validator: (*validator).to_owned(),
+                    height,
+                    line,
+                });
+            }
+        }
+        if !failures.is_empty() {
+            let mut s = String::new();
+            for failure in &failures {
+                s += &format!(
+                    "\nvalidator {} at height {:?}: {}",
+                    failure.validator, failure.height, failure.line
+                );
+            }
+            return Err(Error::from(format!(
+                "watch_for_consensus_failure found consensus failures:{s}"
+            )))
+        }
+        sleep(interval).await;
+    }
+}
+
+/// What a daemon log shows about an upgrade halt, see [parse_upgrade_halt]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpgradeHalt {
+    /// The height in the `UPGRADE "{name}" NEEDED at height: {height}` panic
+    pub height: u64,
+    /// The highest height committed before the panic
+    pub last_committed: Option<u64>,
+    pub line: String,
+}
+
+/// Finds the first "UPGRADE NEEDED" panic in `log` along with the last height
+/// committed before it. Lines after the panic (e.x. from the binary Cosmovisor
+/// swapped to) are ignored.
+pub fn parse_upgrade_halt(log: &str) -> Option<UpgradeHalt> {
+    let mut last_committed = None;
+    for line in log.lines() {
+        if line.contains("UPGRADE") && line.contains("NEEDED at height: ") {
+            let (_, rest) = line.split_once("NEEDED at height: ")?;
+            let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+            return Some(UpgradeHalt {
+                height: digits.parse().ok()?,
+                last_committed,
+                line: line.to_owned(),
+            })
+        }
+        if line.contains("committed state") {
+            if let Some(height) = parse_log_line_height(line) {
+                last_committed = Some(last_committed.map_or(height, |h: u64| h.max(height)));
+            }
+        }
+    }
+    None
+}
+
+/// Waits up to `timeout` for the daemon writing to `log_path` (e.x.
+/// "/logs/onomyd_runner.log") to panic with "UPGRADE NEEDED", and asserts that
+/// the panic is for `expected_height` and that the last committed block was the
+/// one right before it, i.e. the chain halted exactly at the upgrade height.
+/// This checks the halt itself, independently of whether Cosmovisor then
+/// swaps binaries.
+pub async fn assert_halts_at_height(
+    log_path: &str,
+    expected_height: u64,
+    timeout: Duration,
 ) -> Result<()> {
-    sh_cosmovisor_tx(
-        &format!(
-            "bank send {src_addr} {dst_addr} {amount}{denom} -y -b block --gas auto \
-             --gas-adjustment 1.3 --gas-prices 1{denom}"
-        ),
-        &[],
+    let err = || {
+        format!("assert_halts_at_height(log_path: {log_path}, expected_height: {expected_height})")
+    };
+    let halt = poll_until(
+        move || async move {
+            // the log may not have been created yet
+            let log = FileOptions::read_to_string(log_path)
+                .await
+                .unwrap_or_default();
+            Ok(parse_upgrade_halt(&log))
+        },
+        |halt| halt.is_some(),
+        timeout,
+        STD_DELAY,
     )
     .await
-    .map_add_err(|| "cosmovisor_bank_send")?;
+    .map_add_err(err)?
+    .map_add_err(err)?;
+    if halt.height != expected_height {
+        return Err(Error::from(format!(
+            "{} halted at height {} instead: {}",
+            err(),
+            halt.height,
+            halt.line
+        )))
+    }
+    let expected_committed = expected_height.saturating_sub(1);
+    if halt.last_committed != Some(expected_committed) {
+        return Err(Error::from(format!(
+            "{} the last committed height before the halt was {:?}, expected {expected_committed}",
+            err(),
+            halt.last_committed,
+        )))
+    }
+    info!("daemon halted at upgrade height {expected_height}");
     Ok(())
 }
 
-pub async fn get_delegations_to(valoper_addr: &str) -> Result<String> {
-    sh_cosmovisor("query staking delegations-to", &[valoper_addr]).await
+/// Archives `{daemon_home}/data` into the gzipped tarball `dest`. The daemon
+/// must be stopped first. The WAL (`data/cs.wal`) and
+/// `data/priv_validator_state.json` are included, but the `LOCK` files of the
+/// databases are excluded since they are only valid for a running process.
+pub async fn snapshot_daemon(daemon_home: &str, dest: &str) -> Result<()> {
+    sh("tar --exclude=LOCK -czf", &[
+        dest,
+        "-C",
+        daemon_home,
+        "data",
+    ])
+    .await
+    .map_add_err(|| format!("snapshot_daemon(daemon_home: {daemon_home}, dest: {dest})"))?;
+    Ok(())
 }
 
-pub async fn get_treasury() -> Result<f64> {
-    let inner = json_inner(
-        &yaml_str_to_json_value(&sh_cosmovisor("query dao show-treasury", &[]).await?)?
-            ["treasury_balance"][0]["amount"],
-    );
-    anom_to_nom(&inner).map_add_err(|| format!("inner was: {inner}"))
+/// Replaces `{daemon_home}/data` with the contents of a tarball created by
+/// [snapshot_daemon]. The daemon must be stopped first.
+pub async fn restore_daemon(src: &str, daemon_home: &str) -> Result<()> {
+    sh("rm -rf", &[&format!("{daemon_home}/data")]).await?;
+    sh("tar -xzf", &[src, "-C", daemon_home])
+        .await
+        .map_add_err(|| format!("restore_daemon(src: {src}, daemon_home: {daemon_home})"))?;
+    Ok(())
 }
 
-pub async fn get_treasury_inflation_annual() -> Result<f64> {
-    wait_for_num_blocks(1).await?;
-    let start = get_treasury().await?;
-    wait_for_num_blocks(1).await?;
-    let end = get_treasury().await?;
-    // we assume 5 second blocks
-    Ok(((end - start) / (start * 5.0)) * (86400.0 * 365.0))
+/// Where the keyring volume shared between daemon containers is mounted. Each
+/// daemon should have its own keyring volume and only share keys through this
+/// with [cosmovisor_export_keyring] and [cosmovisor_import_keyring], so that
+/// the daemons never write to the same keyring files concurrently.
+pub const SHARED_KEYRING_DIR: &str = "/keyring-shared";
+
+async fn copy_keyring_files(src: &str, dst: &str) -> Result<()> {
+    let mut entries = tokio::fs::read_dir(src)
+        .await
+        .map_add_err(|| format!("copy_keyring_files could not read {src}"))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_add_err(|| format!("copy_keyring_files could not read an entry of {src}"))?
+    {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.ends_with(".info") || name.ends_with(".address") {
+            tokio::fs::copy(entry.path(), format!("{dst}/{name}"))
+                .await
+                .map_add_err(|| format!("copy_keyring_files failed to copy {name} to {dst}"))?;
+        }
+    }
+    Ok(())
 }
 
-#[derive(Debug)]
-pub struct DbgStakingPool {
-    pub bonded_tokens: f64,
-    pub unbonded_tokens: f64,
+/// Copies the keys of the test keyring of `daemon_home` to `shared_dir`
+/// (normally [SHARED_KEYRING_DIR]). This should be done before signaling
+/// the containers that call [cosmovisor_import_keyring].
+pub async fn cosmovisor_export_keyring(daemon_home: &str, shared_dir: &str) -> Result<()> {
+    copy_keyring_files(&format!("{daemon_home}/keyring-test"), shared_dir)
+        .await
+        .map_add_err(|| format!("cosmovisor_export_keyring(daemon_home: {daemon_home})"))
 }
 
-pub async fn get_staking_pool() -> Result<DbgStakingPool> {
-    let pool = sh_cosmovisor("query staking pool", &[]).await?;
-    let bonded_tokens = get_separated_val(&pool, "\n", "bonded_tokens", ":")?;
-    let bonded_tokens = bonded_tokens.trim_matches('"');
-    let bonded_tokens = anom_to_nom(bonded_tokens).map_add_err(|| ())?;
-    let unbonded_tokens = get_separated_val(&pool, "\n", "not_bonded_tokens", ":")?;
-    let unbonded_tokens = unbonded_tokens.trim_matches('"');
-    let unbonded_tokens = anom_to_nom(unbonded_tokens).map_add_err(|| ())?;
-    Ok(DbgStakingPool {
-        bonded_tokens,
-        unbonded_tokens,
-    })
+/// Copies the keys exported with [cosmovisor_export_keyring] into the test
+/// keyring of `daemon_home`
+pub async fn cosmovisor_import_keyring(shared_dir: &str, daemon_home: &str) -> Result<()> {
+    let keyring = format!("{daemon_home}/keyring-test");
+    tokio::fs::create_dir_all(&keyring)
+        .await
+        .map_add_err(|| format!("cosmovisor_import_keyring could not create {keyring}"))?;
+    copy_keyring_files(shared_dir, &keyring)
+        .await
+        .map_add_err(|| format!("cosmovisor_import_keyring(daemon_home: {daemon_home})"))
 }
 
-pub async fn get_outstanding_rewards(valoper_addr: &str) -> Result<f64> {
-    anom_to_nom(&json_inner(
-        &yaml_str_to_json_value(
-            &sh_cosmovisor("query distribution validator-outstanding-rewards", &[
-                valoper_addr,
-            ])
-            .await?,
-        )?["rewards"][0]["amount"],
-    ))
+/// Returns the hex encoded SHA-256 hash of the file at `path`
+pub async fn sha256_file(path: &str) -> Result<String> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_add_err(|| format!("sha256_file(path: {path})"))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .await
+            .map_add_err(|| format!("sha256_file(path: {path})"))?;
+        if n == 0 {
+            break
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex_encode(&hasher.finalize()))
 }
 
-pub async fn get_validator_delegated() -> Result<f64> {
-    let validator_addr = get_separated_val(
-        &sh_cosmovisor("keys show validator", &[]).await?,
-        "\n",
-        "address",
-        ":",
-    )?;
-    let s = sh_cosmovisor("query staking delegations", &[&validator_addr]).await?;
-    anom_to_nom(&json_inner(
-        &yaml_str_to_json_value(&s)?["delegation_responses"][0]["balance"]["amount"],
-    ))
+/// Returns an error if the SHA-256 hash of the file at `path` is not the hex
+/// encoded `expected`
+pub async fn verify_sha256(path: &str, expected: &str) -> Result<()> {
+    let actual = sha256_file(path).await?;
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(Error::from(format!(
+            "verify_sha256 checksum mismatch for {path}, expected {expected} but got {actual}"
+        )))
+    }
+    Ok(())
 }
 
-/// APR calculation is: [Amount(Rewards End) - Amount(Rewards
-/// Beg)]/Amount(Delegated) * # of Blocks/Blocks_per_year
-pub async fn get_apr_annual(valoper_addr: &str) -> Result<f64> {
-    wait_for_num_blocks(1).await?;
-    let delegated = get_validator_delegated().await?;
-    let reward_start = get_outstanding_rewards(valoper_addr).await?;
-    wait_for_num_blocks(1).await?;
-    let reward_end = get_outstanding_rewards(valoper_addr).await?;
-    Ok(((reward_end - reward_start) * 365.0 * 86400.0) / (delegated * 5.0))
+/// Restores `{daemon_home}/data` from a gzipped tarball in the format of
+/// [snapshot_daemon] and then starts the daemon, for testing against realistic
+/// state (e.x. a mainnet fork). `snapshot` can be a local path or an HTTP(S)
+/// URL which is downloaded first. If `sha256` is given, the tarball is checked
+/// against it before extraction. If `min_height` is given, the started node
+/// must be at or above that height.
+pub async fn cosmovisor_start_from_snapshot(
+    daemon_home: &str,
+    snapshot: &str,
+    sha256: Option<&str>,
+    min_height: Option<u64>,
+    log_file_name: &str,
+    options: Option<CosmovisorOptions>,
+) -> Result<CosmovisorRunner> {
+    let err = || format!("cosmovisor_start_from_snapshot(snapshot: {snapshot})");
+    let download = if snapshot.starts_with("http://") || snapshot.starts_with("https://") {
+        // a unique path so that concurrent runs cannot clobber each other
+        let path = sh_no_dbg("mktemp --suffix .tar.gz", &[])
+            .await
+            .map_add_err(err)?
+            .trim()
+            .to_owned();
+        info!("downloading snapshot {snapshot} to {path}");
+        // the progress bar goes to stderr which is in the logs
+        sh("curl -L --fail --progress-bar -o", &[&path, snapshot])
+            .await
+            .map_add_err(err)?;
+        Some(path)
+    } else {
+        None
+    };
+    let path = download.as_deref().unwrap_or(snapshot);
+    let res = async {
+        if let Some(expected) = sha256 {
+            info!("verifying checksum of {path}");
+            verify_sha256(path, expected).await?;
+        }
+        info!("extracting {path} into {daemon_home}/data");
+        restore_daemon(path, daemon_home).await
+    }
+    .await;
+    if let Some(ref download) = download {
+        let _ = tokio::fs::remove_file(download).await;
+    }
+    res.map_add_err(err)?;
+    info!("extracted snapshot, starting daemon");
+    let runner = cosmovisor_start(log_file_name, options)
+        .await
+        .map_add_err(err)?;
+    if let Some(min_height) = min_height {
+        let height = get_block_height().await.map_add_err(err)?;
+        if height < min_height {
+            return Err(Error::from(format!(
+                "cosmovisor_start_from_snapshot started at height {height} but the snapshot \
+                 should be at least at height {min_height}"
+            )))
+        }
+    }
+    Ok(runner)
+}
+
+pub async fn cosmovisor_get_addr(key_name: &str) -> Result<String> {
+    let validator = yaml_str_to_json_value(
+        &sh_cosmovisor("keys show", &[key_name])
+            .await
+            .map_add_err(|| format!("cosmovisor_get_addr(key_name: {key_name})"))?,
+    )
+    .locate()?;
+    Ok(json_inner(&validator[0]["address"]))
+}
+
+/// Returns the consensus address ("valcons" prefix) of the local node's
+/// validator key
+pub async fn cosmovisor_get_cons_addr() -> Result<String> {
+    let s = sh_cosmovisor("tendermint show-address", &[]).await?;
+    Ok(s.trim().to_owned())
+}
+
+/// The slashing signing info of a validator
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SigningInfo {
+    pub address: String,
+    /// Missed blocks in the current signing window
+    pub missed_blocks_counter: u64,
+    pub tombstoned: bool,
+    /// The epoch "1970-01-01T00:00:00Z" if the validator was never jailed
+    pub jailed_until: String,
+}
+
+impl SigningInfo {
+    /// Parses an entry of `query slashing signing-infos`. Zero valued fields
+    /// may be omitted, e.x. the counter before any blocks have been missed.
+    pub fn from_json(info: &Value) -> Result<Self> {
+        let address = info["address"]
+            .as_str()
+            .map_add_err(|| format!("unexpected signing info: {info}"))?;
+        let missed_blocks_counter = match &info["missed_blocks_counter"] {
+            Value::Null => 0,
+            counter => json_inner(counter)
+                .parse::<u64>()
+                .map_add_err(|| format!("unexpected signing info: {info}"))?,
+        };
+        let tombstoned = match &info["tombstoned"] {
+            Value::Bool(b) => *b,
+            Value::Null => false,
+            tombstoned => json_inner(tombstoned) == "true",
+        };
+        Ok(Self {
+            address: address.to_owned(),
+            missed_blocks_counter,
+            tombstoned,
+            jailed_until: info["jailed_until"].as_str().unwrap_or_default().to_owned(),
+        })
+    }
+
+    /// If the validator has ever been jailed
+    pub fn was_jailed(&self) -> bool {
+        !(self.jailed_until.is_empty() || self.jailed_until.starts_with("1970-01-01"))
+    }
+}
+
+fn find_signing_info(infos: &Value, cons_addr: &str) -> Result<Option<SigningInfo>> {
+    for info in infos["info"]
+        .as_array()
+        .map_add_err(|| format!("unexpected signing-infos result: {infos}"))?
+    {
+        let info = SigningInfo::from_json(info)?;
+        if info.address == cons_addr {
+            return Ok(Some(info))
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the signing info of the validator with the consensus address
+/// `cons_addr` (see [cosmovisor_get_cons_addr]). A validator has no signing
+/// info until it has signed its first block, in which case an error is
+/// returned.
+pub async fn cosmovisor_signing_info(cons_addr: &str) -> Result<SigningInfo> {
+    let infos = sh_cosmovisor_no_dbg("query slashing signing-infos --limit 1000", &[])
+        .await
+        .map_add_err(|| format!("cosmovisor_signing_info(cons_addr: {cons_addr})"))?;
+    let infos = yaml_str_to_json_value(&infos)?;
+    find_signing_info(&infos, cons_addr)?
+        .map_add_err(|| format!("cosmovisor_signing_info(cons_addr: {cons_addr}) not found"))
+}
+
+/// Returns the number of blocks a validator can miss in the signing window
+/// before it is jailed for downtime, from the slashing params
+pub async fn cosmovisor_downtime_threshold() -> Result<u64> {
+    let params = sh_cosmovisor_no_dbg("query slashing params", &[])
+        .await
+        .map_add_err(|| "cosmovisor_downtime_threshold")?;
+    downtime_threshold(&yaml_str_to_json_value(&params)?)
+}
+
+fn downtime_threshold(params: &Value) -> Result<u64> {
+    let window = json_inner(&params["signed_blocks_window"])
+        .parse::<u64>()
+        .map_add_err(|| format!("unexpected slashing params: {params}"))?;
+    let min_signed = json_inner(&params["min_signed_per_window"])
+        .parse::<f64>()
+        .map_err(|e| Error::boxed(Box::new(e)))
+        .map_add_err(|| format!("unexpected slashing params: {params}"))?;
+    Ok(window.saturating_sub(((window as f64) * min_signed).ceil() as u64))
+}
+
+/// Waits until the missed blocks counter of `cons_addr` reaches at least
+/// `min_missed`, or returns an error after `timeout`. Use this after stopping
+/// a validator, the counter only increases once its signatures are missing
+/// from blocks.
+pub async fn wait_for_missed_blocks(
+    cons_addr: &str,
+    min_missed: u64,
+    timeout: Duration,
+) -> Result<SigningInfo> {
+    poll_until(
+        || cosmovisor_signing_info(cons_addr),
+        |info| info.missed_blocks_counter >= min_missed,
+        timeout,
+        Duration::from_secs(1),
+    )
+    .await
+    .map_add_err(|| {
+        format!("wait_for_missed_blocks(cons_addr: {cons_addr}, min_missed: {min_missed})")
+    })
+}
+
+/// Returns a mapping of denoms to amounts
+pub async fn cosmovisor_get_balances(addr: &str) -> Result<BTreeMap<String, String>> {
+    cosmovisor_get_balances_at_height(addr, None).await
+}
+
+/// Returned by historical queries if the node no longer has the state at
+/// `height`, as opposed to the query succeeding with unexpected results
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrunedHeight {
+    pub height: u64,
+    pub message: String,
+}
+
+impl fmt::Display for PrunedHeight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the node has pruned the state at height {}, use a pruning setting that keeps it \
+             (e.x. `--pruning nothing`). error: {}",
+            self.height, self.message
+        )
+    }
+}
+
+impl std::error::Error for PrunedHeight {}
+
+/// Returns if `error` is the error of a query at a height that has been pruned
+pub fn is_pruned_height_error(error: &str) -> bool {
+    error.contains("version does not exist") || error.contains("is not available, lowest height is")
+}
+
+async fn query_balances(addr: &str, args: &[&str], height: Option<u64>) -> Result<String> {
+    let height_s = height.map(|h| h.to_string());
+    let mut args = args.to_vec();
+    if let Some(ref height_s) = height_s {
+        args.extend(["--height", height_s.as_str()]);
+    }
+    match sh_cosmovisor_no_dbg("query bank balances", &args).await {
+        Ok(balances) => Ok(balances),
+        Err(e) => {
+            let message = format!("{e:?}");
+            match height {
+                Some(height) if is_pruned_height_error(&message) => {
+                    Err(Error::boxed(Box::new(PrunedHeight { height, message })))
+                }
+                _ => Err(e).map_add_err(|| format!("query_balances(addr: {addr})")),
+            }
+        }
+    }
+}
+
+/// The same as [cosmovisor_get_balances] but at block `height` if it is not
+/// `None`. The node must not have pruned that height, in which case a
+/// [PrunedHeight] error is returned.
+pub async fn cosmovisor_get_balances_at_height(
+    addr: &str,
+    height: Option<u64>,
+) -> Result<BTreeMap<String, String>> {
+    #[cfg(feature = "grpc_support")]
+    if height.is_none() {
+        if let Ok(balances) = crate::grpc::grpc_get_balances(addr).await {
+            return Ok(balances)
+        }
+    }
+    let balances = query_balances(addr, &[addr], height)
+        .await
+        .map_add_err(|| format!("cosmovisor_get_balances_at_height(height: {height:?})"))?;
+    parse_balances(addr, &balances)
+}
+
+/// The same as [cosmovisor_get_balances_at_height] but queries the node at
+/// `node` (e.x. "tcp://marketd:26657"). Note that the local CLI validates the
+/// prefix of `addr`, so it may need to be reprefixed with
+/// [crate::reprefix_bech32].
+pub async fn cosmovisor_get_balances_at(
+    node: &str,
+    addr: &str,
+    height: Option<u64>,
+) -> Result<BTreeMap<String, String>> {
+    let balances = query_balances(addr, &[addr, "--node", node], height)
+        .await
+        .map_add_err(|| {
+            format!("cosmovisor_get_balances_at(node: {node}, addr: {addr}, height: {height:?})")
+        })?;
+    parse_balances(addr, &balances)
+}
+
+/// A node outside of the container network, e.x. a hosted testnet node behind
+/// a TLS terminating proxy. The URLs can be "tcp://", "http://", or
+/// "https://". The RPC URL is used for the Tendermint RPC by the CLI and
+/// `curl`, and the gRPC URL by `grpc::grpc_channel`, since hosted nodes
+/// usually serve them on different hosts or ports. Note that this does not
+/// cover the `web30` client of the geth tests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteNode {
+    rpc_url: String,
+    grpc_url: String,
+    /// A PEM file with the CA (or the self-signed certificate itself) to trust
+    /// in addition to the system roots
+    pub ca_cert: Option<String>,
+    /// Skip certificate verification entirely. Only `curl` based queries
+    /// support this, use `ca_cert` for the CLI and gRPC.
+    pub insecure: bool,
+}
+
+/// Returns `url` with the default port of its scheme if it has none, and an
+/// error if it does not have a supported scheme or host. `tcp_port` is the
+/// default for "tcp://".
+fn normalize_node_url(url: &str, tcp_port: u16) -> Result<String> {
+    let err = || format!("RemoteNode::new(url: {url})");
+    let (scheme, rest) = url
+        .split_once("://")
+        .map_add_err(|| format!("{} has no scheme", err()))?;
+    let default_port = match scheme {
+        "https" => 443,
+        "http" => 80,
+        "tcp" => tcp_port,
+        _ => return Err(Error::from(format!("{} has an unsupported scheme", err()))),
+    };
+    let authority = rest.trim_end_matches('/');
+    let host = match authority.rsplit_once(':') {
+        Some((host, port)) => {
+            port.parse::<u16>()
+                .map_add_err(|| format!("{} has an invalid port", err()))?;
+            host
+        }
+        None => authority,
+    };
+    if host.is_empty() || authority.contains('/') {
+        return Err(Error::from(format!(
+            "{} should be only a host and port",
+            err()
+        )))
+    }
+    if host.len() == authority.len() {
+        Ok(format!("{scheme}://{host}:{default_port}"))
+    } else {
+        Ok(format!("{scheme}://{authority}"))
+    }
+}
+
+impl RemoteNode {
+    /// Returns an error if a URL does not have a supported scheme or host. If
+    /// there is no port, 443 is used for "https://", 80 for "http://", and
+    /// [RPC_PORT] or [GRPC_PORT] for "tcp://".
+    pub fn new(rpc_url: &str, grpc_url: &str) -> Result<Self> {
+        Ok(Self {
+            rpc_url: normalize_node_url(rpc_url, RPC_PORT)?,
+            grpc_url: normalize_node_url(grpc_url, GRPC_PORT)?,
+            ca_cert: None,
+            insecure: false,
+        })
+    }
+
+    pub fn ca_cert(mut self, path: &str) -> Self {
+        self.ca_cert = Some(path.to_owned());
+        self
+    }
+
+    pub fn insecure(mut self) -> Self {
+        self.insecure = true;
+        self
+    }
+
+    /// The normalized Tendermint RPC URL, with a port
+    pub fn rpc_url(&self) -> &str {
+        &self.rpc_url
+    }
+
+    /// The normalized gRPC URL, with a port
+    pub fn grpc_url(&self) -> &str {
+        &self.grpc_url
+    }
+
+    /// Returns the json of a GET request to the Tendermint RPC `path` (e.x.
+    /// "/status"), the same as `curl {url}{path}`
+    pub async fn rpc_get(&self, path: &str) -> Result<Value> {
+        let url = format!("{}{path}", self.rpc_url.replacen("tcp://", "http://", 1));
+        let mut args = vec!["-s", "--fail"];
+        if self.insecure {
+            args.push("--insecure");
+        }
+        if let Some(ref ca_cert) = self.ca_cert {
+            args.extend(["--cacert", ca_cert]);
+        }
+        args.push(&url);
+        let response = sh_no_dbg("curl", &args)
+            .await
+            .map_add_err(|| format!("RemoteNode::rpc_get(url: {url})"))?;
+        serde_json::from_str(&response)
+            .map_add_err(|| format!("RemoteNode::rpc_get(url: {url}) response was not json"))
+    }
+
+    /// Runs [sh_cosmovisor_no_dbg] with `--node` set to this node. The Go CLI
+    /// reads extra roots from `SSL_CERT_FILE` and cannot skip verification.
+    pub async fn sh_cosmovisor(&self, cmd_with_args: &str, args: &[&str]) -> Result<String> {
+        let err = || {
+            format!(
+                "RemoteNode::sh_cosmovisor(url: {}, {cmd_with_args})",
+                self.rpc_url
+            )
+        };
+        if self.insecure {
+            return Err(Error::from(format!(
+                "{} the CLI does not support skipping certificate verification, use `ca_cert`",
+                err()
+            )))
+        }
+        let mut args = args.to_vec();
+        args.extend(["--node", &self.rpc_url]);
+        let Some(ref ca_cert) = self.ca_cert else {
+            return sh_cosmovisor_no_dbg(cmd_with_args, &args)
+                .await
+                .map_add_err(err)
+        };
+        let env = format!("SSL_CERT_FILE={ca_cert}");
+        let stdout = sh_no_dbg(&format!("env {env} cosmovisor run {cmd_with_args}"), &args)
+            .await
+            .map_add_err(err)?;
+        Ok(stdout
+            .split_once('\n')
+            .map_add_err(|| "cosmovisor run command did not have expected info line")?
+            .1
+            .to_owned())
+    }
+
+    /// Returns the latest block height of the node
+    pub async fn get_block_height(&self) -> Result<u64> {
+        let status = self.rpc_get("/status").await?;
+        json_inner(&status["result"]["sync_info"]["latest_block_height"])
+            .parse::<u64>()
+            .map_add_err(|| format!("unexpected /status result from {}: {status}", self.rpc_url))
+    }
+
+    /// Returns the balances of `addr` on the node, see
+    /// [cosmovisor_get_balances_at]
+    pub async fn get_balances(&self, addr: &str) -> Result<BTreeMap<String, String>> {
+        let balances = self
+            .sh_cosmovisor("query bank balances", &[addr])
+            .await
+            .map_add_err(|| format!("RemoteNode::get_balances(addr: {addr})"))?;
+        parse_balances(addr, &balances)
+    }
+}
+
+fn parse_balances(addr: &str, balances: &str) -> Result<BTreeMap<String, String>> {
+    let balances = yaml_str_to_json_value(balances)?;
+    let mut res = BTreeMap::new();
+    for balance in balances["balances"]
+        .as_array()
+        .map_add_err(|| format!("unexpected balances query result for {addr}: {balances}"))?
+    {
+        res.insert(
+            json_inner(&balance["denom"]),
+            json_inner(&balance["amount"]),
+        );
+    }
+    Ok(res)
+}
+
+/// Returns the bank denom metadata of `denom`
+pub async fn cosmovisor_get_denom_metadata(denom: &str) -> Result<DenomMetadata> {
+    let metadata = sh_cosmovisor_no_dbg("query bank denom-metadata --denom", &[denom])
+        .await
+        .map_add_err(|| format!("cosmovisor_get_denom_metadata(denom: {denom})"))?;
+    let metadata = yaml_str_to_json_value(&metadata)?;
+    DenomMetadata::from_json(&metadata["metadata"])
+}
+
+/// Returns the `denom` balance of `addr`, a missing denom is treated as a zero
+/// balance
+pub async fn cosmovisor_get_balance(addr: &str, denom: &str) -> Result<u128> {
+    match cosmovisor_get_balances(addr).await?.get(denom) {
+        Some(amount) => amount
+            .parse::<u128>()
+            .map_add_err(|| format!("balance of {addr} has bad amount {amount}{denom}")),
+        None => Ok(0),
+    }
+}
+
+/// Waits for the `denom` balance of `addr` to satisfy `pred`, returning the
+/// balance. A missing denom is treated as a zero balance.
+pub async fn wait_for_balance<P: FnMut(u128) -> bool>(
+    addr: &str,
+    denom: &str,
+    mut pred: P,
+    timeout: Duration,
+) -> Result<u128> {
+    let balance = || cosmovisor_get_balance(addr, denom);
+    poll_until(balance, |amount| pred(*amount), timeout, STD_DELAY)
+        .await
+        .map_add_err(|| format!("wait_for_balance(addr: {addr}, denom: {denom})"))
+}
+
+/// Queries the balances of all of `addrs` concurrently, with at most
+/// `max_concurrent` queries in flight at once. The amounts are parsed as
+/// `u128`s. An error for one address does not abort the others, the error is
+/// instead put in that address's entry.
+pub async fn cosmovisor_get_balances_many(
+    addrs: &[&str],
+    max_concurrent: usize,
+) -> BTreeMap<String, Result<BTreeMap<String, u128>>> {
+    async fn get_balances_u128(addr: &str) -> Result<BTreeMap<String, u128>> {
+        let mut res = BTreeMap::new();
+        for (denom, amount) in cosmovisor_get_balances(addr).await? {
+            let amount = amount
+                .parse::<u128>()
+                .map_add_err(|| format!("balance of {addr} has bad amount {amount}{denom}"))?;
+            res.insert(denom, amount);
+        }
+        Ok(res)
+    }
+    stream::iter(addrs)
+        .map(|addr| async move { ((*addr).to_owned(), get_balances_u128(addr).await) })
+        .buffer_unordered(max_concurrent.max(1))
+        .collect()
+        .await
+}
+
+/// This uses flags "-b block" and [GasConfig::auto] with `denom`
+pub async fn cosmovisor_bank_send(
+    src_addr: &str,
+    dst_addr: &str,
+    amount: &str,
+    denom: &str,
+) -> Result<()> {
+    cosmovisor_bank_send_with_gas(
+        src_addr,
+        dst_addr,
+        &format!("{amount}{denom}"),
+        &GasConfig::auto(denom),
+    )
+    .await
+    .map_add_err(|| "cosmovisor_bank_send")?;
+    Ok(())
+}
+
+/// Sends `coin` (e.x. "1000anom") from `src_addr` to `dst_addr` with the gas
+/// and fees determined by `gas`, use [GasConfig::fee_granter] to have another
+/// account pay the fees
+pub async fn cosmovisor_bank_send_with_gas(
+    src_addr: &str,
+    dst_addr: &str,
+    coin: &str,
+    gas: &GasConfig,
+) -> Result<Value> {
+    let gas_flags = gas.to_flags().await?;
+    let mut args = vec![src_addr, dst_addr, coin, "-y", "-b", "block"];
+    args.extend(gas_flags.iter().map(|s| s.as_str()));
+    sh_cosmovisor_tx("bank send", &args)
+        .await
+        .map_add_err(|| format!("cosmovisor_bank_send_with_gas(coin: {coin}, gas: {gas:?})"))
+}
+
+/// Grants `grantee` a basic fee allowance of up to `spend_limit` (e.x.
+/// "1000000anom") from `granter`, which pays for the grant transaction with
+/// `gas`
+pub async fn cosmovisor_feegrant(
+    granter: &str,
+    grantee: &str,
+    spend_limit: &str,
+    gas: &GasConfig,
+) -> Result<()> {
+    let gas_flags = gas.to_flags().await?;
+    let mut args = vec![
+        granter,
+        grantee,
+        "--spend-limit",
+        spend_limit,
+        "-y",
+        "-b",
+        "block",
+    ];
+    args.extend(gas_flags.iter().map(|s| s.as_str()));
+    sh_cosmovisor_tx("feegrant grant", &args)
+        .await
+        .map_add_err(|| format!("cosmovisor_feegrant(granter: {granter}, grantee: {grantee})"))?;
+    Ok(())
+}
+
+/// Delegates `coin` (e.x. "1000anom") from `from_key` to the validator with
+/// the operator address `valoper_addr`, with the gas and fees determined by
+/// `gas`
+pub async fn cosmovisor_delegate(
+    from_key: &str,
+    valoper_addr: &str,
+    coin: &str,
+    gas: &GasConfig,
+) -> Result<Value> {
+    let gas_flags = gas.to_flags().await?;
+    let mut args = vec![valoper_addr, coin, "-y", "-b", "block", "--from", from_key];
+    args.extend(gas_flags.iter().map(|s| s.as_str()));
+    sh_cosmovisor_tx("staking delegate", &args)
+        .await
+        .map_add_err(|| {
+            format!(
+                "cosmovisor_delegate(from_key: {from_key}, valoper_addr: {valoper_addr}, coin: \
+                 {coin})"
+            )
+        })
+}
+
+/// Sends `coin` (e.x. "1000anom") from `src_addr` to `dst_addr` and returns an
+/// error unless the chain rejects the transaction with `expected_code` (e.x.
+/// 5 for insufficient funds). A fixed gas amount is used, because gas
+/// simulation would fail before the transaction reaches the chain.
+pub async fn assert_bank_send_fails(
+    src_addr: &str,
+    dst_addr: &str,
+    coin: &str,
+    expected_code: u64,
+) -> Result<()> {
+    let (_, denom) = parse_dec_coin(coin)?;
+    let res = sh_cosmovisor_tx_result("bank send", &[
+        src_addr,
+        dst_addr,
+        coin,
+        "-y",
+        "-b",
+        "block",
+        "--gas",
+        "300000",
+        "--gas-prices",
+        &format!("1{denom}"),
+    ])
+    .await
+    .map_add_err(|| "assert_bank_send_fails")?;
+    match res {
+        Err(failure) if failure.code == expected_code => Ok(()),
+        Err(failure) => Err(Error::from(format!(
+            "assert_bank_send_fails expected code {expected_code}, got {failure}"
+        ))),
+        Ok(res) => Err(Error::from(format!(
+            "assert_bank_send_fails expected code {expected_code}, but the send succeeded: {res}"
+        ))),
+    }
+}
+
+/// The `-b` broadcast mode of a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadcastMode {
+    /// Waits for `CheckTx`
+    Sync,
+    /// Returns immediately
+    Async,
+    /// Waits for the transaction to be included in a block
+    Block,
+}
+
+impl BroadcastMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BroadcastMode::Sync => "sync",
+            BroadcastMode::Async => "async",
+            BroadcastMode::Block => "block",
+        }
+    }
+}
+
+pub type TxHash = String;
+
+/// Returns the account number and sequence of `addr`
+pub async fn cosmovisor_get_account_sequence(addr: &str) -> Result<(u64, u64)> {
+    let account = yaml_str_to_json_value(
+        &sh_cosmovisor_no_dbg("query auth account", &[addr])
+            .await
+            .map_add_err(|| format!("cosmovisor_get_account_sequence(addr: {addr})"))?,
+    )?;
+    let account_number = json_inner(&account["account_number"])
+        .parse::<u64>()
+        .map_add_err(|| format!("unexpected account query result: {account}"))?;
+    let sequence = json_inner(&account["sequence"])
+        .parse::<u64>()
+        .map_add_err(|| format!("unexpected account query result: {account}"))?;
+    Ok((account_number, sequence))
+}
+
+/// Submits a `bank send` from `from_addr` for each `(to_addr, coin)` in
+/// `sends`. The sequence numbers are incremented locally, so that the
+/// transactions do not have to wait on each other and do not cause sequence
+/// mismatches. `gas` should be a [GasConfig::Fixed] or
+/// [GasConfig::MinGasPrice], because gas simulation would fail for sequence
+/// numbers ahead of the chain's.
+///
+/// Returns the hashes of the transactions, use [wait_all_included] to wait for
+/// them to be included in blocks.
+pub async fn cosmovisor_bank_send_batch(
+    from_addr: &str,
+    sends: &[(&str, &str)],
+    mode: BroadcastMode,
+    gas: &GasConfig,
+) -> Result<Vec<TxHash>> {
+    if gas.is_auto() {
+        return Err(Error::from(format!(
+            "cosmovisor_bank_send_batch needs fixed gas, got {gas:?}"
+        )))
+    }
+    let (_, mut sequence) = cosmovisor_get_account_sequence(from_addr).await?;
+    let gas_flags = gas.to_flags().await?;
+    let mut txhashes = vec![];
+    for &(to_addr, coin) in sends {
+        let sequence_s = format!("{sequence}");
+        let mut args = vec![
+            from_addr,
+            to_addr,
+            coin,
+            "-y",
+            "-b",
+            mode.as_str(),
+            "--sequence",
+            &sequence_s,
+        ];
+        args.extend(gas_flags.iter().map(|s| s.as_str()));
+        let res = sh_cosmovisor_tx("bank send", &args).await.map_add_err(|| {
+            format!("cosmovisor_bank_send_batch failed on send to {to_addr} of {coin}")
+        })?;
+        txhashes.push(json_inner(&res["txhash"]));
+        sequence += 1;
+    }
+    Ok(txhashes)
+}
+
+/// Returns the result of `query tx {txhash}`
+pub async fn cosmovisor_query_tx(txhash: &str) -> Result<Value> {
+    let tx = sh_cosmovisor_no_dbg("query tx", &[txhash])
+        .await
+        .map_add_err(|| format!("cosmovisor_query_tx(txhash: {txhash})"))?;
+    yaml_str_to_json_value(&tx)
+}
+
+/// Waits for all of `txhashes` to be included in blocks, and returns the
+/// inclusion height of each one. Returns an error if any transaction had a
+/// nonzero result code or if `timeout` is reached.
+pub async fn wait_all_included(txhashes: &[TxHash], timeout: Duration) -> Result<Vec<u64>> {
+    async fn included_height(txhash: &str) -> Result<u64> {
+        let tx = cosmovisor_query_tx(txhash).await?;
+        if tx["code"].as_u64() != Some(0) {
+            return Err(Error::from(format!(
+                "transaction {txhash} failed with raw_log: {}",
+                tx["raw_log"]
+            )))
+        }
+        json_inner(&tx["height"])
+            .parse::<u64>()
+            .map_add_err(|| format!("unexpected tx query result: {tx}"))
+    }
+    let start = Instant::now();
+    let delay = Duration::from_millis(300);
+    let mut heights = vec![];
+    for txhash in txhashes {
+        let remaining = timeout.saturating_sub(start.elapsed());
+        let num_tries = u64::try_from(remaining.as_millis() / delay.as_millis())
+            .unwrap_or(u64::MAX)
+            .max(1);
+        heights.push(
+            wait_for_ok(num_tries, delay, || included_height(txhash))
+                .await
+                .map_add_err(|| format!("wait_all_included(timeout: {timeout:?})"))?,
+        );
+    }
+    Ok(heights)
+}
+
+/// Parses `gas_used` from a `query tx` result
+pub fn parse_gas_used(tx: &Value) -> Result<u64> {
+    let gas_used = &tx["gas_used"];
+    match gas_used.as_u64() {
+        Some(gas_used) => Ok(gas_used),
+        None => json_inner(gas_used)
+            .parse::<u64>()
+            .map_add_err(|| format!("parse_gas_used unexpected tx query result: {tx}")),
+    }
+}
+
+/// The result of [cosmovisor_simulate_tx]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimResult {
+    /// `None` if the simulation failed
+    pub gas_estimate: Option<u64>,
+    pub ok: bool,
+    /// The error output if the simulation failed
+    pub error: Option<String>,
+}
+
+impl SimResult {
+    /// Parses the output of a tx command run with `--dry-run`
+    pub fn from_output(success: bool, stdout: &str, stderr: &str) -> Self {
+        let gas_estimate = stdout.lines().chain(stderr.lines()).find_map(|line| {
+            line.trim()
+                .strip_prefix("gas estimate:")
+                .and_then(|gas| gas.trim().parse::<u64>().ok())
+        });
+        let ok = success && gas_estimate.is_some();
+        let error = if ok {
+            None
+        } else if stderr.trim().is_empty() {
+            Some(stdout.trim().to_owned())
+        } else {
+            Some(stderr.trim().to_owned())
+        };
+        Self {
+            gas_estimate,
+            ok,
+            error,
+        }
+    }
+}
+
+/// Simulates `tx {cmd_with_args}` with `--dry-run` instead of broadcasting it,
+/// for estimating gas and finding transactions that would fail. Failures of
+/// the simulation are returned in the [SimResult] rather than as an error.
+pub async fn cosmovisor_simulate_tx(cmd_with_args: &str, args: &[&str]) -> Result<SimResult> {
+    let mut args = args.to_vec();
+    args.push("--dry-run");
+    let comres = PipedCommand::new(&format!("cosmovisor run tx {cmd_with_args}"), &args)
+        .timeout(TIMEOUT)
+        .run_to_completion()
+        .await
+        .map_add_err(|| format!("cosmovisor_simulate_tx(cmd_with_args: {cmd_with_args})"))?;
+    Ok(SimResult::from_output(
+        comres.status.success(),
+        &comres.stdout,
+        &comres.stderr,
+    ))
+}
+
+/// Returns the gas used by an included transaction
+pub async fn cosmovisor_tx_gas_used(txhash: &str) -> Result<u64> {
+    parse_gas_used(&cosmovisor_query_tx(txhash).await?)
+        .map_add_err(|| format!("cosmovisor_tx_gas_used(txhash: {txhash})"))
+}
+
+/// Returns an error if the transaction used more than `max_gas`, for guarding
+/// against gas regressions
+pub async fn assert_gas_within(txhash: &str, max_gas: u64) -> Result<()> {
+    let gas_used = cosmovisor_tx_gas_used(txhash).await?;
+    if gas_used > max_gas {
+        return Err(Error::from(format!(
+            "assert_gas_within(txhash: {txhash}) used {gas_used} gas, more than the budget of \
+             {max_gas}"
+        )))
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MempoolStats {
+    pub n_txs: u64,
+    pub total_bytes: u64,
+}
+
+impl MempoolStats {
+    /// Parses the response of the `num_unconfirmed_txs` RPC endpoint
+    pub fn from_rpc_response(response: &Value) -> Result<Self> {
+        let result = &response["result"];
+        // `n_txs` is only the number of returned txs, `total` is the number in the
+        // mempool
+        let n_txs = if result["total"].is_null() {
+            &result["n_txs"]
+        } else {
+            &result["total"]
+        };
+        let n_txs = json_inner(n_txs)
+            .parse::<u64>()
+            .map_add_err(|| format!("unexpected num_unconfirmed_txs response: {response}"))?;
+        let total_bytes = json_inner(&result["total_bytes"])
+            .parse::<u64>()
+            .map_add_err(|| format!("unexpected num_unconfirmed_txs response: {response}"))?;
+        Ok(Self { n_txs, total_bytes })
+    }
+}
+
+/// Returns the mempool stats of the local node using the `num_unconfirmed_txs`
+/// RPC endpoint
+pub async fn cosmovisor_mempool_stats() -> Result<MempoolStats> {
+    let url = format!("http://localhost:{RPC_PORT}/num_unconfirmed_txs");
+    let response = sh_no_dbg("curl -s --fail", &[&url])
+        .await
+        .map_add_err(|| format!("could not reach {url}, the node is probably not running yet"))?;
+    let response: Value = serde_json::from_str(&response)
+        .map_add_err(|| format!("num_unconfirmed_txs response was not json: {response}"))?;
+    MempoolStats::from_rpc_response(&response)
+}
+
+fn parse_goroutine_count(profile: &str) -> Result<usize> {
+    // the first line is "goroutine profile: total {count}"
+    profile
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("goroutine profile: total "))
+        .and_then(|count| count.trim().parse().ok())
+        .map_add_err(|| format!("unexpected goroutine profile: {profile}"))
+}
+
+/// Returns the number of goroutines of the local daemon, sample this over time
+/// in soak tests to catch leaks. Requires [CosmovisorOptions::enable_pprof].
+pub async fn cosmovisor_goroutine_count() -> Result<usize> {
+    let url = &format!("http://localhost:{PPROF_PORT}/debug/pprof/goroutine?debug=1");
+    let profile = sh_no_dbg("curl -s --fail", &[url]).await.map_add_err(|| {
+        format!(
+            "could not reach {url}, the daemon must be started with \
+             `CosmovisorOptions::enable_pprof`"
+        )
+    })?;
+    parse_goroutine_count(&profile)
+}
+
+/// Waits up to `max_blocks` for the mempool to become empty
+pub async fn wait_for_mempool_drained(max_blocks: u64) -> Result<()> {
+    let mut stats = cosmovisor_mempool_stats().await?;
+    for _ in 0..max_blocks {
+        if stats.n_txs == 0 {
+            return Ok(())
+        }
+        wait_for_num_blocks(1).await?;
+        stats = cosmovisor_mempool_stats().await?;
+    }
+    if stats.n_txs == 0 {
+        Ok(())
+    } else {
+        Err(Error::from(format!(
+            "mempool was not drained after {max_blocks} blocks, stats: {stats:?}"
+        )))
+    }
+}
+
+/// Waits for the REST API at `addr` (e.x. "localhost:1317") to respond to the
+/// syncing query
+pub async fn wait_for_cosmos_rest(addr: &str, timeout: Duration) -> Result<()> {
+    let url = format!("http://{addr}/cosmos/base/tendermint/v1beta1/syncing");
+    let probe = || async {
+        let response = sh_no_dbg("curl -s --fail", &[&url]).await?;
+        let response: Value = serde_json::from_str(&response)
+            .map_add_err(|| format!("REST response was not json: {response}"))?;
+        if response["syncing"].is_boolean() {
+            Ok(())
+        } else {
+            Err(Error::from(format!("unexpected REST response: {response}")))
+        }
+    };
+    info!("waiting for REST API at {addr}");
+    let delay = Duration::from_millis(500);
+    let num_tries = u64::try_from(timeout.as_millis() / delay.as_millis())
+        .unwrap_or(u64::MAX)
+        .max(1);
+    wait_for_ok(num_tries, delay, probe)
+        .await
+        .map_add_err(|| format!("wait_for_cosmos_rest(addr: {addr}, timeout: {timeout:?})"))
+}
+
+/// Waits for the gRPC server at `addr` (e.x. "localhost:9090") to successfully
+/// respond to a `GetSyncing` call. This uses curl with HTTP/2 so that no gRPC
+/// tooling is needed in the container.
+pub async fn wait_for_cosmos_grpc(addr: &str, timeout: Duration) -> Result<()> {
+    // an empty `GetSyncingRequest` is a frame with a zero compression flag and a
+    // zero length
+    let frame_path = "/tmp/empty_grpc_frame";
+    FileOptions::write_str(frame_path, "\0\0\0\0\0")
+        .await
+        .map_add_err(|| format!("could not write {frame_path}"))?;
+    let url = format!("http://{addr}/cosmos.base.tendermint.v1beta1.Service/GetSyncing");
+    let data = format!("@{frame_path}");
+    let probe = || async {
+        let headers = sh_no_dbg(
+            "curl -s --fail --http2-prior-knowledge -o /dev/null -D - -H \
+             content-type:application/grpc --data-binary",
+            &[&data, &url],
+        )
+        .await?;
+        if !headers.contains("application/grpc") {
+            return Err(Error::from(format!("not a gRPC response: {headers}")))
+        }
+        // a failure status is put in the headers, success is in the trailers
+        for line in headers.lines() {
+            if let Some(status) = line.trim().strip_prefix("grpc-status:") {
+                if status.trim() != "0" {
+                    return Err(Error::from(format!("gRPC call failed: {headers}")))
+                }
+            }
+        }
+        Ok(())
+    };
+    info!("waiting for gRPC at {addr}");
+    let delay = Duration::from_millis(500);
+    let num_tries = u64::try_from(timeout.as_millis() / delay.as_millis())
+        .unwrap_or(u64::MAX)
+        .max(1);
+    wait_for_ok(num_tries, delay, probe)
+        .await
+        .map_add_err(|| format!("wait_for_cosmos_grpc(addr: {addr}, timeout: {timeout:?})"))
+}
+
+pub async fn get_delegations_to(valoper_addr: &str) -> Result<String> {
+    sh_cosmovisor("query staking delegations-to", &[valoper_addr]).await
+}
+
+pub async fn get_treasury() -> Result<f64> {
+    let inner = json_inner(
+        &yaml_str_to_json_value(&sh_cosmovisor("query dao show-treasury", &[]).await?)?
+            ["treasury_balance"][0]["amount"],
+    );
+    anom_to_nom(&inner).map_add_err(|| format!("inner was: {inner}"))
+}
+
+pub async fn get_treasury_inflation_annual() -> Result<f64> {
+    wait_for_num_blocks(1).await?;
+    let start = get_treasury().await?;
+    wait_for_num_blocks(1).await?;
+    let end = get_treasury().await?;
+    // we assume 5 second blocks
+    Ok(((end - start) / (start * 5.0)) * (86400.0 * 365.0))
+}
+
+#[derive(Debug)]
+pub struct DbgStakingPool {
+    pub bonded_tokens: f64,
+    pub unbonded_tokens: f64,
+}
+
+pub async fn get_staking_pool() -> Result<DbgStakingPool> {
+    let pool = sh_cosmovisor("query staking pool", &[]).await?;
+    let bonded_tokens = get_separated_val(&pool, "\n", "bonded_tokens", ":")?;
+    let bonded_tokens = bonded_tokens.trim_matches('"');
+    let bonded_tokens = anom_to_nom(bonded_tokens).locate()?;
+    let unbonded_tokens = get_separated_val(&pool, "\n", "not_bonded_tokens", ":")?;
+    let unbonded_tokens = unbonded_tokens.trim_matches('"');
+    let unbonded_tokens = anom_to_nom(unbonded_tokens).locate()?;
+    Ok(DbgStakingPool {
+        bonded_tokens,
+        unbonded_tokens,
+    })
+}
+
+/// A validator of a tendermint validator set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidatorPower {
+    /// The consensus address, the prefix of which depends on the chain
+    pub address: String,
+    /// The consensus public key, which is the same on the provider and
+    /// consumer chains unless a consumer key has been assigned
+    pub pub_key: String,
+    pub power: u64,
+}
+
+/// Parses the result of `query tendermint-validator-set`
+pub fn parse_valset(valset: &Value) -> Result<Vec<ValidatorPower>> {
+    let validators = valset["validators"]
+        .as_array()
+        .map_add_err(|| format!("unexpected tendermint-validator-set result: {valset}"))?;
+    let mut res = vec![];
+    for validator in validators {
+        let power = json_inner(&validator["voting_power"]);
+        let pub_key = &validator["pub_key"];
+        res.push(ValidatorPower {
+            address: json_inner(&validator["address"]),
+            pub_key: pub_key["key"]
+                .as_str()
+                .map(|s| s.to_owned())
+                .unwrap_or_else(|| pub_key.to_string()),
+            power: power
+                .parse::<u64>()
+                .map_add_err(|| format!("unexpected voting_power in {validator}"))?,
+        });
+    }
+    Ok(res)
+}
+
+/// Returns the current tendermint validator set of the local chain
+pub async fn cosmovisor_valset() -> Result<Vec<ValidatorPower>> {
+    let valset = sh_cosmovisor_no_dbg("query tendermint-validator-set", &[])
+        .await
+        .map_add_err(|| "cosmovisor_valset")?;
+    parse_valset(&yaml_str_to_json_value(&valset)?)
+}
+
+/// Returns the current tendermint validator set of the consumer chain served
+/// at `consumer_node` (e.x. "tcp://marketd:26657"), which reflects the last
+/// validator set change that the consumer received from the provider
+pub async fn cosmovisor_consumer_valset(consumer_node: &str) -> Result<Vec<ValidatorPower>> {
+    let valset = sh_cosmovisor_no_dbg("query tendermint-validator-set --node", &[consumer_node])
+        .await
+        .map_add_err(|| format!("cosmovisor_consumer_valset(consumer_node: {consumer_node})"))?;
+    parse_valset(&yaml_str_to_json_value(&valset)?)
+}
+
+/// Returns the total voting power of the current tendermint validator set. On
+/// a consumer chain this reflects the last validator set change that was
+/// received from the provider.
+pub async fn cosmovisor_total_voting_power() -> Result<u64> {
+    Ok(cosmovisor_valset()
+        .await?
+        .iter()
+        .map(|validator| validator.power)
+        .sum())
+}
+
+/// Returns the number of validators in the `query staking validators` output
+/// `validators` that are bonded
+pub fn count_bonded_validators(validators: &Value) -> Result<usize> {
+    let validators = validators["validators"]
+        .as_array()
+        .map_add_err(|| format!("count_bonded_validators({validators}) has no validators"))?;
+    Ok(validators
+        .iter()
+        .filter(|validator| {
+            // older versions output the enum as an integer
+            (validator["status"] == "BOND_STATUS_BONDED") || (validator["status"] == 3)
+        })
+        .count())
+}
+
+/// Returns the number of bonded validators
+pub async fn cosmovisor_num_bonded_validators() -> Result<usize> {
+    let validators = sh_cosmovisor_no_dbg("query staking validators --limit 1000", &[]).await?;
+    count_bonded_validators(&yaml_str_to_json_value(&validators)?)
+        .map_add_err(|| "cosmovisor_num_bonded_validators")
+}
+
+/// Waits until at least `count` validators are bonded, so that e.x. staking up
+/// more validators does not race against their bonding (and on the provider,
+/// the VSC propagation). Returns the bonded count, the timeout error includes
+/// the last observed count.
+pub async fn wait_for_bonded_validators(count: usize, timeout: Duration) -> Result<usize> {
+    poll_until(
+        cosmovisor_num_bonded_validators,
+        |bonded| *bonded >= count,
+        timeout,
+        STD_DELAY,
+    )
+    .await
+    .map_add_err(|| format!("wait_for_bonded_validators(count: {count})"))
+}
+
+pub async fn get_outstanding_rewards(valoper_addr: &str) -> Result<f64> {
+    anom_to_nom(&json_inner(
+        &yaml_str_to_json_value(
+            &sh_cosmovisor("query distribution validator-outstanding-rewards", &[
+                valoper_addr,
+            ])
+            .await?,
+        )?["rewards"][0]["amount"],
+    ))
+}
+
+/// A decimal coin as returned by the distribution module. The amount is kept
+/// exactly, as the integer part and the 18 fractional digits, since converting
+/// large `anom` amounts to `f64` loses precision.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecCoin {
+    pub denom: String,
+    pub int: u128,
+    /// The fractional part in units of 1e-18
+    pub frac: u64,
+}
+
+impl DecCoin {
+    /// Parses a decimal amount such as "1234.5678" into the integer part and
+    /// the fractional part in units of 1e-18
+    pub fn parse_amount(amount: &str) -> Result<(u128, u64)> {
+        let err = || format!("DecCoin::parse_amount(amount: {amount})");
+        let (int, frac) = amount.trim().split_once('.').unwrap_or((amount.trim(), ""));
+        if int.is_empty()
+            || (frac.len() > 18)
+            || !int.chars().chain(frac.chars()).all(|c| c.is_ascii_digit())
+        {
+            return Err(Error::from(err()))
+        }
+        let int = int.parse::<u128>().map_add_err(err)?;
+        let frac = if frac.is_empty() {
+            0
+        } else {
+            format!("{frac:0<18}").parse::<u64>().map_add_err(err)?
+        };
+        Ok((int, frac))
+    }
+
+    /// Parses a `{"denom": ..., "amount": ...}` object
+    pub fn from_json(coin: &Value) -> Result<Self> {
+        let denom = coin["denom"]
+            .as_str()
+            .map_add_err(|| format!("DecCoin::from_json(coin: {coin}) has no denom"))?;
+        let amount = coin["amount"]
+            .as_str()
+            .map_add_err(|| format!("DecCoin::from_json(coin: {coin}) has no amount"))?;
+        let (int, frac) = Self::parse_amount(amount)?;
+        Ok(Self {
+            denom: denom.to_owned(),
+            int,
+            frac,
+        })
+    }
+
+    /// The amount rounded down, which is what can actually be withdrawn
+    pub fn truncated(&self) -> u128 {
+        self.int
+    }
+
+    /// The approximate amount, for comparing ratios
+    pub fn to_f64(&self) -> f64 {
+        (self.int as f64) + ((self.frac as f64) / 1e18)
+    }
+}
+
+/// Parses a json array of [DecCoin]s, a missing array is no coins
+pub fn parse_dec_coins(coins: &Value) -> Result<Vec<DecCoin>> {
+    let Some(coins) = coins.as_array() else {
+        return Ok(vec![])
+    };
+    coins.iter().map(DecCoin::from_json).collect()
+}
+
+/// Returns the community pool from `query distribution community-pool`
+pub async fn cosmovisor_community_pool() -> Result<Vec<DecCoin>> {
+    let pool = sh_cosmovisor_no_dbg("query distribution community-pool", &[]).await?;
+    parse_dec_coins(&yaml_str_to_json_value(&pool)?["pool"])
+        .map_add_err(|| "cosmovisor_community_pool")
+}
+
+/// Returns the outstanding rewards of the validator `valoper_addr` (including
+/// the commission) from `query distribution validator-outstanding-rewards`
+pub async fn cosmovisor_validator_rewards(valoper_addr: &str) -> Result<Vec<DecCoin>> {
+    let rewards = sh_cosmovisor_no_dbg("query distribution validator-outstanding-rewards", &[
+        valoper_addr,
+    ])
+    .await?;
+    parse_dec_coins(&yaml_str_to_json_value(&rewards)?["rewards"])
+        .map_add_err(|| format!("cosmovisor_validator_rewards(valoper_addr: {valoper_addr})"))
+}
+
+pub async fn get_validator_delegated() -> Result<f64> {
+    let validator_addr = get_separated_val(
+        &sh_cosmovisor("keys show validator", &[]).await?,
+        "\n",
+        "address",
+        ":",
+    )?;
+    let s = sh_cosmovisor("query staking delegations", &[&validator_addr]).await?;
+    anom_to_nom(&json_inner(
+        &yaml_str_to_json_value(&s)?["delegation_responses"][0]["balance"]["amount"],
+    ))
+}
+
+/// APR calculation is: [Amount(Rewards End) - Amount(Rewards
+/// Beg)]/Amount(Delegated) * # of Blocks/Blocks_per_year
+pub async fn get_apr_annual(valoper_addr: &str) -> Result<f64> {
+    wait_for_num_blocks(1).await?;
+    let delegated = get_validator_delegated().await?;
+    let reward_start = get_outstanding_rewards(valoper_addr).await?;
+    wait_for_num_blocks(1).await?;
+    let reward_end = get_outstanding_rewards(valoper_addr).await?;
+    Ok(((reward_end - reward_start) * 365.0 * 86400.0) / (delegated * 5.0))
+}
+
+#[test]
+fn test_gov_tally() {
+    let v1beta1 =
+        yaml_str_to_json_value("abstain: \"0\"\nno: \"100\"\nno_with_veto: \"0\"\nyes: \"233\"\n")
+            .unwrap();
+    let tally = GovTally::from_json(&v1beta1).unwrap();
+    assert_eq!(tally.total(), 333);
+    assert!(!tally.reaches_quorum(1000, "0.334").unwrap());
+    assert!(tally.reaches_quorum(999, "0.334").unwrap());
+    let v1 = serde_json::json!({
+        "yes_count": "334000000000000000000000000",
+        "no_count": "0",
+        "abstain_count": "0",
+        "no_with_veto_count": "0"
+    });
+    let tally = GovTally::from_json(&v1).unwrap();
+    assert!(tally
+        .reaches_quorum(
+            1_000_000_000_000_000_000_000_000_000,
+            "0.334000000000000000"
+        )
+        .unwrap());
+    assert!(!tally
+        .reaches_quorum(
+            1_000_000_000_000_000_000_000_000_003,
+            "0.334000000000000000"
+        )
+        .unwrap());
+    assert!(GovTally::from_json(&Value::Null).is_err());
+
+    let vote = yaml_str_to_json_value(
+        r#"option: VOTE_OPTION_UNSPECIFIED
+options:
+- option: VOTE_OPTION_YES
+  weight: "1.000000000000000000"
+proposal_id: "1"
+voter: onomy1abcd
+"#,
+    )
+    .unwrap();
+    assert_eq!(parse_gov_vote_option(&vote).unwrap(), "VOTE_OPTION_YES");
+    let deprecated = serde_json::json!({"option": "VOTE_OPTION_NO", "options": []});
+    assert_eq!(
+        parse_gov_vote_option(&deprecated).unwrap(),
+        "VOTE_OPTION_NO"
+    );
+    assert!(
+        parse_gov_vote_option(&serde_json::json!({"option": "VOTE_OPTION_UNSPECIFIED"})).is_err()
+    );
+}
+
+#[test]
+fn test_remote_node() {
+    let node = RemoteNode::new(
+        "https://rpc.testnet.example.com",
+        "https://grpc.testnet.example.com",
+    )
+    .unwrap();
+    assert_eq!(node.rpc_url(), "https://rpc.testnet.example.com:443");
+    assert_eq!(node.grpc_url(), "https://grpc.testnet.example.com:443");
+    let node = RemoteNode::new("tcp://marketd", "tcp://marketd").unwrap();
+    assert_eq!(node.rpc_url(), "tcp://marketd:26657");
+    assert_eq!(node.grpc_url(), "tcp://marketd:9090");
+    let node = RemoteNode::new("http://localhost:8443/", "http://localhost:9443")
+        .unwrap()
+        .ca_cert("/resources/proxy.pem");
+    assert_eq!(node.rpc_url(), "http://localhost:8443");
+    assert_eq!(node.grpc_url(), "http://localhost:9443");
+    assert_eq!(node.ca_cert.as_deref(), Some("/resources/proxy.pem"));
+    let grpc = "tcp://localhost";
+    assert!(RemoteNode::new("localhost:26657", grpc).is_err());
+    assert!(RemoteNode::new("ftp://localhost", grpc).is_err());
+    assert!(RemoteNode::new("https://", grpc).is_err());
+    assert!(RemoteNode::new("https://localhost:abc", grpc).is_err());
+    assert!(RemoteNode::new("https://localhost/rpc", grpc).is_err());
+    assert!(RemoteNode::new(grpc, "localhost:9090").is_err());
+}
+
+/// Queries the RPC through a local TLS terminating server with a self-signed
+/// certificate
+#[tokio::test]
+#[ignore = "requires openssl and curl"]
+async fn test_remote_node_tls() {
+    let dir = std::env::temp_dir().join(format!("onomy_test_lib_tls_{}", std::process::id()));
+    let dir = dir.to_str().unwrap();
+    tokio::fs::create_dir_all(dir).await.unwrap();
+    let cert = format!("{dir}/cert.pem");
+    let key = format!("{dir}/key.pem");
+    sh_no_dbg("openssl req -x509 -newkey rsa:2048 -nodes -days 1", &[
+        "-keyout",
+        &key,
+        "-out",
+        &cert,
+        "-subj",
+        "/CN=localhost",
+        "-addext",
+        "subjectAltName=DNS:localhost",
+    ])
+    .await
+    .unwrap();
+    // `-WWW` serves the files of the working directory
+    FileOptions::write_str(
+        &format!("{dir}/status"),
+        r#"{"result": {"sync_info": {"latest_block_height": "42"}}}"#,
+    )
+    .await
+    .unwrap();
+    let port = 28443;
+    let mut server = tokio::process::Command::new("openssl")
+        .args([
+            "s_server", "-quiet", "-WWW", "-cert", &cert, "-key", &key, "-accept",
+        ])
+        .arg(port.to_string())
+        .current_dir(dir)
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let url = format!("https://localhost:{port}");
+    let node = RemoteNode::new(&url, &url).unwrap();
+    let trusted = node.clone().ca_cert(&cert);
+    let height = poll_until(
+        || trusted.get_block_height(),
+        |_| true,
+        Duration::from_secs(10),
+        Duration::from_millis(100),
+    )
+    .await;
+    let insecure = node.clone().insecure().get_block_height().await;
+    let untrusted = node.get_block_height().await;
+    server.kill().await.unwrap();
+    tokio::fs::remove_dir_all(dir).await.unwrap();
+    assert_eq!(height.unwrap(), 42);
+    assert_eq!(insecure.unwrap(), 42);
+    assert!(untrusted.is_err());
+}
+
+#[test]
+fn test_count_bonded_validators() {
+    let validators = yaml_str_to_json_value(
+        r#"pagination:
+  next_key: null
+  total: "0"
+validators:
+- jailed: false
+  operator_address: onomyvaloper1a
+  status: BOND_STATUS_BONDED
+  tokens: "1000000000000000000000000"
+- jailed: true
+  operator_address: onomyvaloper1b
+  status: BOND_STATUS_UNBONDING
+  tokens: "0"
+- jailed: false
+  operator_address: onomyvaloper1c
+  status: BOND_STATUS_BONDED
+  tokens: "225000000000000000000000"
+"#,
+    )
+    .unwrap();
+    assert_eq!(count_bonded_validators(&validators).unwrap(), 2);
+    assert_eq!(
+        count_bonded_validators(&serde_json::json!({"validators": [{"status": 3}, {"status": 1}]}))
+            .unwrap(),
+        1
+    );
+    assert!(count_bonded_validators(&Value::Null).is_err());
+}
+
+#[test]
+fn test_check_supply_delta() {
+    let total = yaml_str_to_json_value(
+        r#"pagination:
+  next_key: null
+  total: "0"
+supply:
+- amount: "104000000000000000000000000"
+  denom: anom
+- amount: "5000"
+  denom: ibc/ABCD
+"#,
+    )
+    .unwrap();
+    let before = parse_total_supply(&total).unwrap();
+    assert_eq!(before["anom"], 104000000000000000000000000);
+    let mut after = before.clone();
+    *after.get_mut("anom").unwrap() += 25;
+    after.remove("ibc/ABCD");
+    after.insert("afoo".to_owned(), 7);
+    // inflation of up to 10 per block over 3 blocks
+    check_supply_delta(&before, &after, 3, &[("ibc/ABCD", -5000), ("afoo", 7)], &[
+        ("anom", 10),
+    ])
+    .unwrap();
+    let e = format!(
+        "{:?}",
+        check_supply_delta(&before, &after, 2, &[("ibc/ABCD", -5000)], &[("anom", 10)])
+            .unwrap_err()
+    );
+    assert!(e.contains("anom: supply changed by 25 but expected 0"));
+    assert!(e.contains("afoo: supply changed by 7 but expected 0"));
+    assert!(!e.contains("ibc/ABCD"));
+}
+
+#[test]
+fn test_assert_exact_spend() {
+    let balances = |coins: &str| -> BTreeMap<String, String> {
+        parse_coins(coins)
+            .unwrap()
+            .into_iter()
+            .map(|(denom, amount)| (denom, amount.to_string()))
+            .collect()
+    };
+    assert_eq!(
+        parse_coins("100anom, 5ibc/ABCD,1anom").unwrap(),
+        BTreeMap::from([("anom".to_owned(), 101), ("ibc/ABCD".to_owned(), 5)])
+    );
+    assert!(parse_coins("").unwrap().is_empty());
+    assert!(parse_coins("anom").is_err());
+    let before = balances("1000000anom,1000ibc/ABCD,7afoo");
+    assert_exact_spend(
+        &before,
+        &balances("798663anom,900ibc/ABCD,7afoo"),
+        "1337anom",
+        "200000anom,100ibc/ABCD",
+    )
+    .unwrap();
+    // spending everything of a denom removes it from the balances
+    assert_exact_spend(
+        &before,
+        &balances("998663anom,1000ibc/ABCD"),
+        "7afoo",
+        "1337anom",
+    )
+    .unwrap();
+    // the fee was overcharged by 1
+    let e = assert_exact_spend(
+        &before,
+        &balances("798662anom,1000ibc/ABCD,7afoo"),
+        "1337anom",
+        "200000anom",
+    )
+    .unwrap_err();
+    assert!(format!("{e:?}").contains("anom: expected 798663 but got 798662 (-1)"));
+    assert!(assert_exact_spend(&before, &before, "1337anom", "1afoo").is_err());
+    assert!(assert_exact_spend(&before, &before, "", "8afoo").is_err());
+}
+
+#[test]
+fn test_parse_dec_coins() {
+    let pool = yaml_str_to_json_value(
+        r#"pool:
+- amount: "1234567890123456789012.345678901234567890"
+  denom: anom
+- amount: "0.750000000000000000"
+  denom: ibc/0EEDE4D6
+"#,
+    )
+    .unwrap();
+    let pool = parse_dec_coins(&pool["pool"]).unwrap();
+    assert_eq!(pool, vec![
+        DecCoin {
+            denom: "anom".to_owned(),
+            int: 1234567890123456789012,
+            frac: 345678901234567890,
+        },
+        DecCoin {
+            denom: "ibc/0EEDE4D6".to_owned(),
+            int: 0,
+            frac: 750000000000000000,
+        }
+    ]);
+    assert_eq!(pool[0].truncated(), 1234567890123456789012);
+    assert_eq!(pool[1].to_f64(), 0.75);
+    assert_eq!(DecCoin::parse_amount("5").unwrap(), (5, 0));
+    assert_eq!(
+        DecCoin::parse_amount("5.1").unwrap(),
+        (5, 100000000000000000)
+    );
+    assert!(DecCoin::parse_amount("5.1234567890123456789").is_err());
+    assert!(DecCoin::parse_amount(".5").is_err());
+    assert!(DecCoin::parse_amount("-5").is_err());
+    assert!(parse_dec_coins(&Value::Null).unwrap().is_empty());
+}
+
+#[test]
+fn test_parse_dec_coin() {
+    assert_eq!(parse_dec_coin("1anom").unwrap(), (1.0, "anom".to_owned()));
+    assert_eq!(
+        parse_dec_coin("0.25ibc/0EEDE4D6").unwrap(),
+        (0.25, "ibc/0EEDE4D6".to_owned())
+    );
+    assert!(parse_dec_coin("1000").is_err());
+    assert!(parse_dec_coin("anom").is_err());
+}
+
+#[test]
+fn test_apply_block_production() {
+    let config = "[consensus]\ntimeout_commit = \"5s\"\ncreate_empty_blocks = true\n";
+    let options = CosmovisorOptions {
+        timeout_commit: Some(Duration::from_millis(200)),
+        create_empty_blocks: Some(false),
+        create_empty_blocks_interval: Some(Duration::from_secs(2)),
+        ..Default::default()
+    };
+    let edited = edit_toml_str(config, |config| apply_block_production(config, &options)).unwrap();
+    let edited: toml::Value = toml::from_str(&edited).unwrap();
+    assert_eq!(
+        edited["consensus"]["timeout_commit"].as_str(),
+        Some("200ms")
+    );
+    assert_eq!(
+        edited["consensus"]["create_empty_blocks"].as_bool(),
+        Some(false)
+    );
+    assert_eq!(
+        edited["consensus"]["create_empty_blocks_interval"].as_str(),
+        Some("2000ms")
+    );
+}
+
+#[test]
+fn test_apply_p2p() {
+    let config = "[p2p]\npex = false\nseeds = \"\"\naddr_book_strict = true\n";
+    let unchanged = edit_toml_str(config, |config| {
+        apply_p2p(config, &CosmovisorOptions::default())
+    })
+    .unwrap();
+    assert_eq!(unchanged, config);
+    let options = CosmovisorOptions {
+        seeds: vec!["abcd@onomyd_seed:26656".to_owned()],
+        ..Default::default()
+    };
+    let edited = edit_toml_str(config, |config| apply_p2p(config, &options)).unwrap();
+    let edited: toml::Value = toml::from_str(&edited).unwrap();
+    assert_eq!(edited["p2p"]["pex"].as_bool(), Some(true));
+    assert_eq!(edited["p2p"]["addr_book_strict"].as_bool(), Some(false));
+    assert_eq!(edited["p2p"]["seed_mode"].as_bool(), Some(false));
+    assert_eq!(
+        edited["p2p"]["seeds"].as_str(),
+        Some("abcd@onomyd_seed:26656")
+    );
+    let peers = serde_json::json!({"result": {"n_peers": "1", "peers": [
+        {"node_info": {"id": "abcd", "moniker": "onomyd"}, "remote_ip": "172.18.0.2"}
+    ]}});
+    assert_eq!(parse_net_info_peers(&peers).unwrap(), vec![
+        "abcd".to_owned()
+    ]);
+}
+
+#[tokio::test]
+async fn test_set_minimum_gas_prices() {
+    let daemon_home =
+        std::env::temp_dir().join(format!("onomy_test_lib_gas_prices_{}", std::process::id()));
+    let daemon_home = daemon_home.to_str().unwrap();
+    tokio::fs::create_dir_all(format!("{daemon_home}/config"))
+        .await
+        .unwrap();
+    FileOptions::write_str(
+        &format!("{daemon_home}/config/app.toml"),
+        "# comment\nminimum-gas-prices = \"\"\n",
+    )
+    .await
+    .unwrap();
+    set_minimum_gas_prices(daemon_home, &["1anom", "1ibc/ABCD"])
+        .await
+        .unwrap();
+    let res = get_minimum_gas_price(daemon_home).await;
+    tokio::fs::remove_dir_all(daemon_home).await.unwrap();
+    assert_eq!(res.unwrap(), "1anom,1ibc/ABCD");
+}
+
+#[test]
+fn test_blocks_to_pass_duration() {
+    assert_eq!(parse_go_duration("1000ms").unwrap(), Duration::from_secs(1));
+    assert_eq!(parse_go_duration("1m30s").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_go_duration("0").unwrap(), Duration::ZERO);
+    assert!(parse_go_duration("5x").is_err());
+    assert_eq!(
+        blocks_to_pass_duration(Duration::from_secs(10), Duration::from_secs(1)),
+        11
+    );
+    assert_eq!(
+        blocks_to_pass_duration(Duration::from_millis(2500), Duration::from_secs(1)),
+        4
+    );
+}
+
+#[test]
+fn test_edit_toml_str() {
+    let config = r#"# This is a TOML config file.
+
+#######################################################
+###         Consensus Configuration Options         ###
+#######################################################
+[consensus]
+
+# How long we wait for a proposal block before prevoting nil
+timeout_propose = "3s"
+# How much timeout_propose increases with each round
+timeout_propose_delta = "500ms"
+"#;
+    let edited = edit_toml_str(config, |config| {
+        config["consensus"]["timeout_propose"] = value("600ms");
+    })
+    .unwrap();
+    assert_eq!(edited, config.replace("\"3s\"", "\"600ms\""));
+}
+
+#[test]
+fn test_mempool_stats() {
+    let response: Value = serde_json::from_str(
+        r#"{"result":{"n_txs":"3","total":"3","total_bytes":"1024","txs":null}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        MempoolStats::from_rpc_response(&response).unwrap(),
+        MempoolStats {
+            n_txs: 3,
+            total_bytes: 1024
+        }
+    );
+    assert!(MempoolStats::from_rpc_response(&Value::Null).is_err());
+}
+
+#[test]
+fn test_tx_failure() {
+    let ok: Value = serde_json::from_str(r#"{"code": 0, "raw_log": "[]"}"#).unwrap();
+    assert_eq!(TxFailure::from_tx_result(&ok).unwrap(), None);
+    let failed: Value =
+        serde_json::from_str(r#"{"code": 5, "codespace": "sdk", "raw_log": "insufficient funds"}"#)
+            .unwrap();
+    assert_eq!(TxFailure::from_tx_result(&failed).unwrap().unwrap().code, 5);
+    assert!(TxFailure::from_tx_result(&Value::Null).is_err());
+}
+
+#[tokio::test]
+async fn test_sha256_file() {
+    let path = std::env::temp_dir().join(format!("onomy_test_lib_sha256_{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    FileOptions::write_str(path, "abc").await.unwrap();
+    let hash = sha256_file(path).await;
+    tokio::fs::remove_file(path).await.unwrap();
+    assert_eq!(
+        hash.unwrap(),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+}
+
+#[tokio::test]
+async fn test_verify_sha256() {
+    let path = std::env::temp_dir().join(format!(
+        "onomy_test_lib_verify_sha256_{}",
+        std::process::id()
+    ));
+    let path = path.to_str().unwrap();
+    FileOptions::write_str(path, "abc").await.unwrap();
+    let good = verify_sha256(
+        path,
+        "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD\n",
+    )
+    .await;
+    let bad = verify_sha256(
+        path,
+        "0000000000000000000000000000000000000000000000000000000000000000",
+    )
+    .await;
+    tokio::fs::remove_file(path).await.unwrap();
+    good.unwrap();
+    let e = format!("{:?}", bad.unwrap_err());
+    assert!(e.contains("checksum mismatch"), "{e}");
+}
+
+#[test]
+fn test_sim_result() {
+    let res = SimResult::from_output(true, "info: running app\n", "gas estimate: 84215\n");
+    assert!(res.ok);
+    assert_eq!(res.gas_estimate, Some(84215));
+    assert_eq!(res.error, None);
+    let res = SimResult::from_output(
+        false,
+        "",
+        "Error: rpc error: code = Unknown desc = 5anom is smaller than 10anom: insufficient \
+         funds\n",
+    );
+    assert!(!res.ok);
+    assert_eq!(res.gas_estimate, None);
+    assert!(res.error.unwrap().contains("insufficient funds"));
+}
+
+#[test]
+fn test_is_pruned_height_error() {
+    assert!(is_pruned_height_error(
+        "Error: rpc error: code = InvalidArgument desc = failed to load state at height 5; \
+         version does not exist (latest height: 120): invalid request"
+    ));
+    assert!(is_pruned_height_error(
+        "Error: height 5 is not available, lowest height is 60"
+    ));
+    assert!(!is_pruned_height_error(
+        "Error: rpc error: code = NotFound desc = account not found"
+    ));
+}
+
+#[test]
+fn test_signing_info() {
+    let infos = serde_json::json!({"info": [
+        {"address": "onomyvalcons1aaaa", "start_height": "0", "index_offset": "4",
+         "jailed_until": "1970-01-01T00:00:00Z", "tombstoned": false},
+        {"address": "onomyvalcons1bbbb", "start_height": "0", "index_offset": "90",
+         "jailed_until": "2023-07-11T12:10:00Z", "tombstoned": false,
+         "missed_blocks_counter": "51"}
+    ], "pagination": {"next_key": null, "total": "0"}});
+    let info = find_signing_info(&infos, "onomyvalcons1aaaa")
+        .unwrap()
+        .unwrap();
+    assert_eq!(info.missed_blocks_counter, 0);
+    assert!(!info.was_jailed());
+    let info = find_signing_info(&infos, "onomyvalcons1bbbb")
+        .unwrap()
+        .unwrap();
+    assert_eq!(info.missed_blocks_counter, 51);
+    assert!(info.was_jailed());
+    assert!(find_signing_info(&infos, "onomyvalcons1cccc")
+        .unwrap()
+        .is_none());
+    let params = serde_json::json!({"signed_blocks_window": "100",
+        "min_signed_per_window": "0.500000000000000000"});
+    assert_eq!(downtime_threshold(&params).unwrap(), 50);
+}
+
+#[test]
+fn test_parse_gas_used() {
+    let tx = serde_json::json!({"gas_used": "64872", "gas_wanted": "200000"});
+    assert_eq!(parse_gas_used(&tx).unwrap(), 64872);
+    let tx = serde_json::json!({"gas_used": 64872});
+    assert_eq!(parse_gas_used(&tx).unwrap(), 64872);
+    assert!(parse_gas_used(&Value::Null).is_err());
+}
+
+#[test]
+fn test_find_consensus_failure() {
+    let log = "INF executed block height=41 module=state\nINF committed state height=41 \
+               module=state\nERR CONSENSUS FAILURE!!! err=\"+2/3 committed an invalid block: \
+               wrong Block.Header.AppHash.  Expected 0A, got 0B\" module=consensus\n";
+    let (height, line) = find_consensus_failure(log).unwrap();
+    assert_eq!(height, Some(41));
+    assert!(line.contains("Expected 0A, got 0B"));
+    assert_eq!(
+        find_consensus_failure("INF executed block height=5 module=state\n"),
+        None
+    );
+}
+
+#[test]
+fn test_parse_upgrade_halt() {
+    let log = "INF committed state app_hash=0A height=11 module=state num_txs=0\nINF committed \
+               state app_hash=0B height=12 module=state num_txs=1\nERR UPGRADE \"v1.1.2\" NEEDED \
+               at height: 13: {} module=x/upgrade\npanic: UPGRADE \"v1.1.2\" NEEDED at height: \
+               13: {}\nINF committed state app_hash=0C height=13 module=state num_txs=0\n";
+    let halt = parse_upgrade_halt(log).unwrap();
+    assert_eq!(halt.height, 13);
+    assert_eq!(halt.last_committed, Some(12));
+    assert!(halt.line.starts_with("ERR UPGRADE"));
+    assert_eq!(
+        parse_upgrade_halt("INF committed state height=5 module=state\n"),
+        None
+    );
+}
+
+#[test]
+fn test_parse_app_hash() {
+    let response: Value = serde_json::from_str(
+        r#"{"result":{"block_id":{},"block":{"header":{"height":"7","app_hash":"0A1B"}}}}"#,
+    )
+    .unwrap();
+    assert_eq!(parse_app_hash(&response).unwrap(), "0A1B");
+    assert!(parse_app_hash(&Value::Null).is_err());
+}
+
+#[test]
+fn test_parse_goroutine_count() {
+    let profile = "goroutine profile: total 123\n47 @ 0x43e436 0x44e7e5\n";
+    assert_eq!(parse_goroutine_count(profile).unwrap(), 123);
+    assert!(parse_goroutine_count("404 page not found").is_err());
+}
+
+#[test]
+fn test_trace_config() {
+    assert!(TraceConfig::default().args().is_empty());
+    assert_eq!(TraceConfig::default().env_prefix().unwrap(), "");
+    let mut trace = TraceConfig::consensus("onomyd");
+    assert_eq!(trace.args(), vec![
+        "--log_level",
+        "consensus:debug,*:info",
+        "--trace",
+        "--trace-store",
+        "/logs/onomyd_store_trace.log"
+    ]);
+    trace
+        .env
+        .push(("GODEBUG".to_owned(), "gctrace=1".to_owned()));
+    assert_eq!(trace.env_prefix().unwrap(), "env GODEBUG=gctrace=1 ");
+    trace.env.push(("BAD".to_owned(), "with space".to_owned()));
+    assert!(trace.env_prefix().is_err());
+}
+
+#[tokio::test]
+async fn test_gas_config_fee_granter() {
+    let gas = GasConfig::Fixed {
+        gas: 200_000,
+        fees: "200000anom".to_owned(),
+    }
+    .fee_granter("onomy1granter");
+    assert_eq!(gas.to_flags().await.unwrap(), vec![
+        "--gas",
+        "200000",
+        "--fees",
+        "200000anom",
+        "--fee-granter",
+        "onomy1granter"
+    ]);
+}
+
+#[tokio::test]
+async fn test_gas_config_flags() {
+    let auto = GasConfig::auto("anom");
+    assert!(auto.is_auto());
+    assert_eq!(auto.to_flags().await.unwrap(), vec![
+        "--gas",
+        "auto",
+        "--gas-adjustment",
+        "1.3",
+        "--gas-prices",
+        "1anom"
+    ]);
+    let heavy = GasConfig::Auto {
+        adjustment: 2.5,
+        price: 0.025,
+        denom: "afootoken".to_owned(),
+    };
+    let flags = heavy.to_flags().await.unwrap();
+    assert_eq!(flags[3], "2.5");
+    assert_eq!(flags[5], "0.025afootoken");
+    let fixed = GasConfig::Fixed {
+        gas: 300_000,
+        fees: "300000anom".to_owned(),
+    };
+    assert!(!fixed.is_auto());
+    assert_eq!(fixed.to_flags().await.unwrap(), vec![
+        "--gas",
+        "300000",
+        "--fees",
+        "300000anom"
+    ]);
+    let capped = fixed.clone().max_gas(300_000).fee_granter("onomy1granter");
+    assert!(!capped.is_auto());
+    assert_eq!(capped.to_flags().await.unwrap(), vec![
+        "--gas",
+        "300000",
+        "--fees",
+        "300000anom",
+        "--fee-granter",
+        "onomy1granter"
+    ]);
+    let e = format!("{:?}", fixed.max_gas(200_000).to_flags().await.unwrap_err());
+    assert!(e.contains("more than the max_gas of 200000"));
+    let capped = auto.max_gas(200_000);
+    assert!(!capped.is_auto());
+    assert_eq!(capped.to_flags().await.unwrap(), vec![
+        "--gas",
+        "200000",
+        "--gas-prices",
+        "1anom"
+    ]);
 }
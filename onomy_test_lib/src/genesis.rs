@@ -0,0 +1,694 @@
+//! Reusable genesis modifications that can be passed to the setup functions,
+//! so that tests can customize genesis without copying a whole setup
+
+use std::{collections::HashMap, time::Duration};
+
+use serde_json::{json, Value};
+use super_orchestrator::stacked_errors::{Error, MapAddError, Result};
+
+use crate::logs::parse_log_timestamp;
+
+/// A modification of the `genesis.json` of a chain. Setups apply these after
+/// their standard edits and before writing back the genesis.
+pub type GenesisPatch = Box<dyn Fn(&mut Value) + Send + Sync>;
+
+/// Applies `patches` in order
+pub fn apply_genesis_patches(genesis: &mut Value, patches: &[GenesisPatch]) {
+    for patch in patches {
+        patch(genesis);
+    }
+}
+
+/// Formats `dur` as a duration string that the daemons accept in genesis
+pub fn genesis_duration(dur: Duration) -> String {
+    format!("{}ns", dur.as_nanos())
+}
+
+/// Sets the governance voting period
+pub fn set_voting_period(dur: Duration) -> GenesisPatch {
+    Box::new(move |genesis| {
+        genesis["app_state"]["gov"]["voting_params"]["voting_period"] =
+            genesis_duration(dur).into();
+    })
+}
+
+/// Sets the governance max deposit period
+pub fn set_deposit_period(dur: Duration) -> GenesisPatch {
+    Box::new(move |genesis| {
+        genesis["app_state"]["gov"]["deposit_params"]["max_deposit_period"] =
+            genesis_duration(dur).into();
+    })
+}
+
+/// Sets the staking unbonding time. Note that IBC clients need a trusting
+/// period shorter than this.
+pub fn set_unbonding_time(dur: Duration) -> GenesisPatch {
+    Box::new(move |genesis| {
+        genesis["app_state"]["staking"]["params"]["unbonding_time"] = genesis_duration(dur).into();
+    })
+}
+
+/// Sets the governance voting and max deposit periods to `dur`
+pub fn short_voting_period(dur: Duration) -> GenesisPatch {
+    let voting = set_voting_period(dur);
+    let deposit = set_deposit_period(dur);
+    Box::new(move |genesis| {
+        voting(genesis);
+        deposit(genesis);
+    })
+}
+
+/// Zeroes the current inflation and the inflation bounds
+pub fn disable_inflation() -> GenesisPatch {
+    Box::new(|genesis| {
+        genesis["app_state"]["mint"]["minter"]["inflation"] = "0.0".into();
+        genesis["app_state"]["mint"]["params"]["inflation_min"] = "0.0".into();
+        genesis["app_state"]["mint"]["params"]["inflation_max"] = "0.0".into();
+        genesis["app_state"]["mint"]["params"]["inflation_rate_change"] = "0.0".into();
+    })
+}
+
+/// Pins `genesis_time` to the RFC 3339 UTC time `time` (e.x.
+/// "2023-01-01T00:00:00Z") instead of the time of `init`, so that exports and
+/// hashes are reproducible across runs. The chain waits for this time before
+/// producing blocks, so it should be in the past.
+pub fn set_genesis_time(time: &str) -> Result<GenesisPatch> {
+    if parse_log_timestamp(time).is_none() || !time.ends_with('Z') {
+        return Err(Error::from(format!(
+            "set_genesis_time(time: {time}) is not an RFC 3339 UTC time"
+        )))
+    }
+    let time = time.to_owned();
+    Ok(Box::new(move |genesis| {
+        genesis["genesis_time"] = time.clone().into();
+    }))
+}
+
+/// Bank denom metadata, IBC denoms have none by default
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DenomMetadata {
+    pub name: String,
+    pub symbol: String,
+    pub description: String,
+    pub base: String,
+    pub display: String,
+    /// `(denom, exponent)` pairs, the base denom should have exponent 0
+    pub denom_units: Vec<(String, u32)>,
+}
+
+impl DenomMetadata {
+    /// Metadata with a `base` unit and a `display` unit with `exponent`
+    pub fn new(base: &str, display: &str, exponent: u32) -> Self {
+        Self {
+            name: display.to_owned(),
+            symbol: display.to_uppercase(),
+            description: display.to_owned(),
+            base: base.to_owned(),
+            display: display.to_owned(),
+            denom_units: vec![(base.to_owned(), 0), (display.to_owned(), exponent)],
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let denom_units: Vec<Value> = self
+            .denom_units
+            .iter()
+            .map(|(denom, exponent)| json!({"denom": denom, "exponent": exponent}))
+            .collect();
+        json!({
+            "name": self.name,
+            "symbol": self.symbol,
+            "description": self.description,
+            "base": self.base,
+            "display": self.display,
+            "denom_units": denom_units,
+        })
+    }
+
+    /// Parses a single metadata entry as found in genesis or in the
+    /// `metadata` of a `query bank denom-metadata`
+    pub fn from_json(metadata: &Value) -> Result<Self> {
+        let field = |key: &str| {
+            metadata[key]
+                .as_str()
+                .map(|s| s.to_owned())
+                .map_add_err(|| format!("DenomMetadata is missing \"{key}\": {metadata}"))
+        };
+        let mut denom_units = vec![];
+        for unit in metadata["denom_units"]
+            .as_array()
+            .map_add_err(|| format!("DenomMetadata is missing \"denom_units\": {metadata}"))?
+        {
+            let denom = unit["denom"]
+                .as_str()
+                .map_add_err(|| format!("unexpected denom unit: {unit}"))?;
+            // the base unit may omit the exponent, and yaml outputs may quote it
+            let exponent = match &unit["exponent"] {
+                Value::Null => 0,
+                Value::String(s) => s
+                    .parse::<u32>()
+                    .map_add_err(|| format!("unexpected denom unit: {unit}"))?,
+                exponent => exponent
+                    .as_u64()
+                    .and_then(|x| u32::try_from(x).ok())
+                    .map_add_err(|| format!("unexpected denom unit: {unit}"))?,
+            };
+            denom_units.push((denom.to_owned(), exponent));
+        }
+        Ok(Self {
+            name: field("name")?,
+            symbol: field("symbol")?,
+            description: field("description")?,
+            base: field("base")?,
+            display: field("display")?,
+            denom_units,
+        })
+    }
+
+    /// The exponent of the `display` unit
+    pub fn display_exponent(&self) -> Result<u32> {
+        self.denom_units
+            .iter()
+            .find(|(denom, _)| *denom == self.display)
+            .map(|(_, exponent)| *exponent)
+            .map_add_err(|| format!("DenomMetadata has no denom unit for {}", self.display))
+    }
+
+    /// The symbol if there is one, otherwise the display denom
+    fn human_unit(&self) -> &str {
+        if self.symbol.is_empty() {
+            &self.display
+        } else {
+            &self.symbol
+        }
+    }
+}
+
+fn pow10(exponent: u32) -> Result<u128> {
+    10u128
+        .checked_pow(exponent)
+        .map_add_err(|| format!("denom exponent {exponent} is too large"))
+}
+
+/// Renders a coin in the base denom of `metadata` in its display unit, e.x.
+/// "1500000000000000000anom" becomes "1.5 NOM" for an exponent of 18
+pub fn format_coin_human(coin: &str, metadata: &DenomMetadata) -> Result<String> {
+    let coin = coin.trim();
+    let amount = coin
+        .strip_suffix(metadata.base.as_str())
+        .map_add_err(|| format!("format_coin_human({coin}) is not in {}", metadata.base))?;
+    let amount = amount
+        .parse::<u128>()
+        .map_add_err(|| format!("format_coin_human({coin}) has a bad amount"))?;
+    let exponent = metadata.display_exponent()?;
+    let one = pow10(exponent)?;
+    let integer = amount / one;
+    let fraction = amount % one;
+    let unit = metadata.human_unit();
+    if fraction == 0 {
+        Ok(format!("{integer} {unit}"))
+    } else {
+        let fraction = format!("{fraction:0>width$}", width = exponent as usize);
+        Ok(format!(
+            "{integer}.{} {unit}",
+            fraction.trim_end_matches('0')
+        ))
+    }
+}
+
+/// The inverse of [format_coin_human], parses e.x. "1.5 NOM" or "1.5nom"
+/// (the symbol or display denom) into "1500000000000000000anom"
+pub fn parse_coin_human(s: &str, metadata: &DenomMetadata) -> Result<String> {
+    let trimmed = s.trim();
+    let i = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .map_add_err(|| format!("parse_coin_human({s}) has no unit"))?;
+    let (number, unit) = trimmed.split_at(i);
+    let unit = unit.trim();
+    if (unit != metadata.symbol) && (unit != metadata.display) {
+        return Err(Error::from(format!(
+            "parse_coin_human({s}) expected unit {} or {}",
+            metadata.symbol, metadata.display
+        )))
+    }
+    let exponent = metadata.display_exponent()?;
+    let (integer, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if integer.is_empty() || fraction.contains('.') || (fraction.len() > exponent as usize) {
+        return Err(Error::from(format!(
+            "parse_coin_human({s}) is not a number with at most {exponent} decimal places"
+        )))
+    }
+    let integer = integer
+        .parse::<u128>()
+        .map_add_err(|| format!("parse_coin_human({s}) has a bad amount"))?;
+    let fraction: u128 = if fraction.is_empty() {
+        0
+    } else {
+        fraction
+            .parse::<u128>()
+            .map_add_err(|| format!("parse_coin_human({s}) has a bad amount"))?
+            * pow10(exponent - fraction.len() as u32)?
+    };
+    let amount = integer
+        .checked_mul(pow10(exponent)?)
+        .and_then(|x| x.checked_add(fraction))
+        .map_add_err(|| format!("parse_coin_human({s}) overflowed"))?;
+    Ok(format!("{amount}{}", metadata.base))
+}
+
+/// Adds `metadata` to the bank denom metadata of `genesis`, replacing any
+/// existing metadata with the same base denom
+pub fn set_denom_metadata(genesis: &mut Value, metadata: &DenomMetadata) {
+    let list = &mut genesis["app_state"]["bank"]["denom_metadata"];
+    if !list.is_array() {
+        *list = json!([]);
+    }
+    let list = list.as_array_mut().unwrap();
+    list.retain(|entry| entry["base"].as_str() != Some(&metadata.base));
+    list.push(metadata.to_json());
+}
+
+/// [set_denom_metadata] as a [GenesisPatch]
+pub fn denom_metadata_patch(metadata: DenomMetadata) -> GenesisPatch {
+    Box::new(move |genesis| set_denom_metadata(genesis, &metadata))
+}
+
+/// The `ccvconsumer` genesis state of a consumer chain, as returned by `query
+/// provider consumer-genesis`. This is validated on construction so that a
+/// malformed consumer genesis fails with a clear error instead of deep inside
+/// the startup of the consumer daemon.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CcvConsumerGenesis {
+    state: Value,
+}
+
+impl CcvConsumerGenesis {
+    pub fn from_json(state: Value) -> Result<Self> {
+        let err = |msg: &str| Err(Error::from(format!("CcvConsumerGenesis {msg}: {state}")));
+        if !state["params"].is_object() {
+            return err("is missing \"params\"")
+        }
+        let chain_id = state["provider_client_state"]["chain_id"].as_str();
+        if chain_id.map(|s| s.is_empty()).unwrap_or(true) {
+            return err("is missing \"provider_client_state.chain_id\"")
+        }
+        if !state["provider_consensus_state"].is_object() {
+            return err("is missing \"provider_consensus_state\"")
+        }
+        if state["initial_val_set"]
+            .as_array()
+            .map(|a| a.is_empty())
+            .unwrap_or(true)
+        {
+            return err("has an empty \"initial_val_set\"")
+        }
+        Ok(Self { state })
+    }
+
+    pub fn from_json_str(s: &str) -> Result<Self> {
+        let state: Value =
+            serde_json::from_str(s).map_add_err(|| "CcvConsumerGenesis::from_json_str")?;
+        Self::from_json(state)
+    }
+
+    pub fn to_json_string(&self) -> String {
+        self.state.to_string()
+    }
+
+    pub fn as_json(&self) -> &Value {
+        &self.state
+    }
+
+    /// The chain id of the provider client state
+    pub fn provider_chain_id(&self) -> &str {
+        self.state["provider_client_state"]["chain_id"]
+            .as_str()
+            .unwrap_or_default()
+    }
+
+    /// The initial validator set, each entry has a "pub_key" and "power"
+    pub fn initial_val_set(&self) -> &[Value] {
+        self.state["initial_val_set"]
+            .as_array()
+            .map(|a| a.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+/// Returns the serialized size in bytes of each module in the `app_state` of
+/// an exported genesis. This is a coarse measure of how much state each module
+/// holds.
+pub fn genesis_module_sizes(export: &Value) -> HashMap<String, usize> {
+    let mut res = HashMap::new();
+    if let Some(app_state) = export["app_state"].as_object() {
+        for (module, state) in app_state {
+            res.insert(module.clone(), state.to_string().len());
+        }
+    }
+    res
+}
+
+/// The change in size of a module between two exports, see
+/// [diff_module_sizes]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleSizeDiff {
+    pub module: String,
+    /// Zero if the module did not exist before
+    pub before: usize,
+    /// Zero if the module does not exist after
+    pub after: usize,
+}
+
+impl ModuleSizeDiff {
+    /// `after / before`, infinite for new modules
+    pub fn ratio(&self) -> f64 {
+        (self.after as f64) / (self.before as f64)
+    }
+}
+
+/// Compares the [genesis_module_sizes] of two exports (e.x. from before and
+/// after an upgrade), ordered from the largest growth ratio to the smallest
+pub fn diff_module_sizes(before: &Value, after: &Value) -> Vec<ModuleSizeDiff> {
+    let before = genesis_module_sizes(before);
+    let after = genesis_module_sizes(after);
+    let mut modules: Vec<&String> = before.keys().chain(after.keys()).collect();
+    modules.sort();
+    modules.dedup();
+    let mut res: Vec<ModuleSizeDiff> = modules
+        .into_iter()
+        .map(|module| ModuleSizeDiff {
+            module: module.clone(),
+            before: before.get(module).copied().unwrap_or(0),
+            after: after.get(module).copied().unwrap_or(0),
+        })
+        .collect();
+    res.sort_by(|a, b| b.ratio().total_cmp(&a.ratio()));
+    res
+}
+
+/// Returns the modules that grew to more than `factor` times their size, e.x.
+/// a factor of 2.0 flags the modules that more than doubled
+pub fn modules_grown_by(before: &Value, after: &Value, factor: f64) -> Vec<ModuleSizeDiff> {
+    diff_module_sizes(before, after)
+        .into_iter()
+        .filter(|diff| diff.ratio() > factor)
+        .collect()
+}
+
+/// The paths of an export that differ between otherwise identical runs,
+/// because they depend on the randomly generated consensus keys or on the
+/// height that the export was made at
+pub const VOLATILE_EXPORT_PATHS: &[&str] = &[
+    "/app_hash",
+    "/initial_height",
+    "/validators/*/address",
+    "/validators/*/pub_key",
+    "/app_state/genutil/gen_txs",
+    "/app_state/slashing",
+    "/app_state/staking/validators/*/consensus_pubkey",
+];
+
+fn path_is_ignored(path: &[String], ignore_paths: &[&str]) -> bool {
+    ignore_paths.iter().any(|ignore| {
+        let ignore: Vec<&str> = ignore.split('/').filter(|s| !s.is_empty()).collect();
+        (ignore.len() <= path.len())
+            && ignore
+                .iter()
+                .zip(path)
+                .all(|(ignore, segment)| (*ignore == "*") || (ignore == segment))
+    })
+}
+
+fn first_difference_inner(
+    a: &Value,
+    b: &Value,
+    path: &mut Vec<String>,
+    ignore_paths: &[&str],
+) -> Option<String> {
+    if path_is_ignored(path, ignore_paths) {
+        return None
+    }
+    let mut diff_child = |key: String, a: &Value, b: &Value| {
+        path.push(key);
+        let res = first_difference_inner(a, b, path, ignore_paths);
+        path.pop();
+        res
+    };
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            keys.into_iter().find_map(|key| {
+                let null = Value::Null;
+                diff_child(
+                    key.clone(),
+                    a.get(key).unwrap_or(&null),
+                    b.get(key).unwrap_or(&null),
+                )
+            })
+        }
+        (Value::Array(a), Value::Array(b)) => (0..a.len().max(b.len())).find_map(|i| {
+            let null = Value::Null;
+            diff_child(
+                i.to_string(),
+                a.get(i).unwrap_or(&null),
+                b.get(i).unwrap_or(&null),
+            )
+        }),
+        _ if a == b => None,
+        _ => Some(format!("/{}: {a} != {b}", path.join("/"))),
+    }
+}
+
+/// Returns the JSON pointer path of the first difference between `a` and `b`
+/// with both values, or `None` if they are equal. The paths in
+/// `ignore_paths` (e.x. [VOLATILE_EXPORT_PATHS]) and everything under them
+/// are skipped, a "*" segment matches any key or index.
+pub fn first_difference(a: &Value, b: &Value, ignore_paths: &[&str]) -> Option<String> {
+    first_difference_inner(a, b, &mut vec![], ignore_paths)
+}
+
+/// Returns an error with the first differing path if the exports `a` and `b`
+/// (e.x. from two runs of the same scenario with a pinned genesis time and
+/// deterministic keys) are not equivalent outside of `ignore_paths`
+pub fn assert_exports_equivalent(a: &Value, b: &Value, ignore_paths: &[&str]) -> Result<()> {
+    match first_difference(a, b, ignore_paths) {
+        None => Ok(()),
+        Some(diff) => Err(Error::from(format!(
+            "assert_exports_equivalent exports differ at {diff}"
+        ))),
+    }
+}
+
+/// Short governance periods and unbonding time so that governance and
+/// unbonding tests complete in seconds
+pub fn fast_test_genesis() -> Vec<GenesisPatch> {
+    vec![
+        set_voting_period(Duration::from_millis(800)),
+        set_deposit_period(Duration::from_millis(800)),
+        set_unbonding_time(Duration::from_secs(60)),
+    ]
+}
+
+#[test]
+fn test_genesis_patches() {
+    let mut genesis = serde_json::json!({"app_state": {"mint": {"minter": {"inflation": "0.13"}}}});
+    apply_genesis_patches(&mut genesis, &[
+        short_voting_period(Duration::from_millis(800)),
+        disable_inflation(),
+    ]);
+    assert_eq!(
+        genesis["app_state"]["gov"]["voting_params"]["voting_period"],
+        "800000000ns"
+    );
+    assert_eq!(genesis["app_state"]["mint"]["minter"]["inflation"], "0.0");
+    assert_eq!(
+        genesis["app_state"]["mint"]["params"]["inflation_max"],
+        "0.0"
+    );
+}
+
+#[test]
+fn test_set_genesis_time() {
+    let mut genesis = serde_json::json!({"genesis_time": "2023-07-11T12:34:56.789Z"});
+    apply_genesis_patches(&mut genesis, &[
+        set_genesis_time("2023-01-01T00:00:00Z").unwrap()
+    ]);
+    assert_eq!(genesis["genesis_time"], "2023-01-01T00:00:00Z");
+    assert!(set_genesis_time("2023-01-01").is_err());
+    assert!(set_genesis_time("2023-01-01T00:00:00+01:00").is_err());
+}
+
+#[test]
+fn test_fast_test_genesis() {
+    let mut genesis = serde_json::json!({"app_state": {
+        "gov": {"voting_params": {"voting_period": "172800s"}},
+        "staking": {"params": {"unbonding_time": "1814400s"}}
+    }});
+    apply_genesis_patches(&mut genesis, &fast_test_genesis());
+    let app_state = &genesis["app_state"];
+    assert_eq!(
+        app_state["gov"]["voting_params"]["voting_period"],
+        "800000000ns"
+    );
+    assert_eq!(
+        app_state["gov"]["deposit_params"]["max_deposit_period"],
+        "800000000ns"
+    );
+    assert_eq!(
+        app_state["staking"]["params"]["unbonding_time"],
+        "60000000000ns"
+    );
+}
+
+#[test]
+fn test_denom_metadata() {
+    let metadata = DenomMetadata::new("ibc/ABCD", "ibcnom", 18);
+    assert_eq!(
+        DenomMetadata::from_json(&metadata.to_json()).unwrap(),
+        metadata
+    );
+    let mut genesis = serde_json::json!({"app_state": {"bank": {"denom_metadata": [
+        {"base": "anative"}, {"base": "ibc/ABCD"}
+    ]}}});
+    set_denom_metadata(&mut genesis, &metadata);
+    let list = genesis["app_state"]["bank"]["denom_metadata"]
+        .as_array()
+        .unwrap();
+    assert_eq!(list.len(), 2);
+    assert_eq!(DenomMetadata::from_json(&list[1]).unwrap(), metadata);
+    // the shape of a `query bank denom-metadata` after yaml conversion
+    let queried = serde_json::json!({"base": "ibc/ABCD", "denom_units": [
+        {"aliases": [], "denom": "ibc/ABCD"},
+        {"aliases": [], "denom": "ibcnom", "exponent": 18}
+    ], "description": "ibcnom", "display": "ibcnom", "name": "ibcnom", "symbol": "IBCNOM"});
+    assert_eq!(DenomMetadata::from_json(&queried).unwrap(), metadata);
+}
+
+#[test]
+fn test_format_coin_human() {
+    let nom = DenomMetadata::new("anom", "nom", 18);
+    assert_eq!(
+        format_coin_human("1500000000000000000anom", &nom).unwrap(),
+        "1.5 NOM"
+    );
+    assert_eq!(
+        format_coin_human("1anom", &nom).unwrap(),
+        "0.000000000000000001 NOM"
+    );
+    assert_eq!(format_coin_human("0anom", &nom).unwrap(), "0 NOM");
+    assert_eq!(
+        parse_coin_human("1.5 NOM", &nom).unwrap(),
+        "1500000000000000000anom"
+    );
+    assert_eq!(
+        parse_coin_human("0.000000000000000001nom", &nom).unwrap(),
+        "1anom"
+    );
+    assert!(parse_coin_human("0.0000000000000000001 NOM", &nom).is_err());
+    assert!(format_coin_human("1ausdc", &nom).is_err());
+    let usdc = DenomMetadata::new("uusdc", "usdc", 6);
+    assert_eq!(
+        format_coin_human("2000001uusdc", &usdc).unwrap(),
+        "2.000001 USDC"
+    );
+    assert_eq!(
+        format_coin_human("250000uusdc", &usdc).unwrap(),
+        "0.25 USDC"
+    );
+    assert_eq!(parse_coin_human("0.25 USDC", &usdc).unwrap(), "250000uusdc");
+    assert_eq!(parse_coin_human("7 usdc", &usdc).unwrap(), "7000000uusdc");
+    assert!(parse_coin_human("1.5 NOM", &usdc).is_err());
+    assert!(parse_coin_human("0.0000001 USDC", &usdc).is_err());
+}
+
+#[test]
+fn test_ccvconsumer_genesis() {
+    let state = serde_json::json!({
+        "params": {"enabled": true},
+        "new_chain": true,
+        "provider_client_state": {"chain_id": "onomy"},
+        "provider_consensus_state": {"root": {}},
+        "initial_val_set": [{"pub_key": {"ed25519": "AAAA"}, "power": "1000"}]
+    });
+    let genesis = CcvConsumerGenesis::from_json_str(&state.to_string()).unwrap();
+    assert_eq!(genesis.provider_chain_id(), "onomy");
+    assert_eq!(genesis.initial_val_set().len(), 1);
+    assert_eq!(
+        CcvConsumerGenesis::from_json_str(&genesis.to_json_string()).unwrap(),
+        genesis
+    );
+    let mut empty_val_set = state.clone();
+    empty_val_set["initial_val_set"] = serde_json::json!([]);
+    assert!(CcvConsumerGenesis::from_json(empty_val_set).is_err());
+    assert!(CcvConsumerGenesis::from_json_str("{}").is_err());
+    assert!(CcvConsumerGenesis::from_json_str("").is_err());
+}
+
+#[test]
+fn test_module_sizes() {
+    let before = serde_json::json!({"app_state": {
+        "auth": {"accounts": [{"address": "onomy1abcd"}]},
+        "bank": {"balances": [{"address": "onomy1abcd", "coins": []}]},
+        "gov": {"proposals": []}
+    }});
+    let mut after = before.clone();
+    after["app_state"]["bank"]["balances"] = serde_json::json!([
+        {"address": "onomy1abcd", "coins": []},
+        {"address": "onomy1efgh", "coins": []},
+        {"address": "onomy1ijkl", "coins": []}
+    ]);
+    after["app_state"]["ccvconsumer"] = serde_json::json!({"params": {}});
+    let sizes = genesis_module_sizes(&before);
+    assert_eq!(sizes.len(), 3);
+    assert_eq!(sizes["gov"], r#"{"proposals":[]}"#.len());
+    let diff = diff_module_sizes(&before, &after);
+    assert_eq!(diff.len(), 4);
+    // new modules come first
+    assert_eq!(diff[0].module, "ccvconsumer");
+    assert_eq!(diff[0].before, 0);
+    assert_eq!(diff[1].module, "bank");
+    assert!(diff[1].ratio() > 2.0);
+    assert_eq!(diff[2].ratio(), 1.0);
+    let grown = modules_grown_by(&before, &after, 2.0);
+    let grown: Vec<&str> = grown.iter().map(|diff| diff.module.as_str()).collect();
+    assert_eq!(grown, vec!["ccvconsumer", "bank"]);
+}
+
+#[test]
+fn test_first_difference() {
+    let a = serde_json::json!({
+        "app_hash": "AAAA",
+        "app_state": {"bank": {"balances": [{"address": "onomy1abcd", "amount": "5"}]}},
+        "validators": [{"address": "1234", "power": "10"}]
+    });
+    assert_eq!(first_difference(&a, &a, &[]), None);
+    let mut b = a.clone();
+    b["app_hash"] = "BBBB".into();
+    b["validators"][0]["address"] = "5678".into();
+    assert_eq!(
+        first_difference(&a, &b, &[]).unwrap(),
+        r#"/app_hash: "AAAA" != "BBBB""#
+    );
+    assert!(assert_exports_equivalent(&a, &b, VOLATILE_EXPORT_PATHS).is_ok());
+    b["app_state"]["bank"]["balances"][0]["amount"] = "6".into();
+    b["validators"][0]["power"] = "11".into();
+    let err = assert_exports_equivalent(&a, &b, VOLATILE_EXPORT_PATHS).unwrap_err();
+    assert!(format!("{err:?}").contains("/app_state/bank/balances/0/amount"));
+    assert_eq!(
+        first_difference(&a, &b, &[
+            "/app_state",
+            "/app_hash",
+            "/validators/*/address"
+        ])
+        .unwrap(),
+        r#"/validators/0/power: "10" != "11""#
+    );
+    // missing keys and elements compare as null
+    b["app_state"]["bank"]["balances"] = serde_json::json!([]);
+    assert_eq!(
+        first_difference(&a["app_state"], &b["app_state"], &[]).unwrap(),
+        r#"/bank/balances/0: {"address":"onomy1abcd","amount":"5"} != null"#
+    );
+}
@@ -0,0 +1,161 @@
+//! Queries over gRPC instead of the CLI, which avoids spawning a process per
+//! query. The node needs to be started with [CosmovisorOptions::enable_grpc],
+//! the functions in `cosmovisor` that use these fall back to the CLI if gRPC is
+//! not reachable.
+//!
+//! [CosmovisorOptions::enable_grpc]: crate::cosmovisor::CosmovisorOptions
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use cosmos_sdk_proto::cosmos::{
+    bank::v1beta1::{query_client::QueryClient as BankQueryClient, QueryAllBalancesRequest},
+    base::tendermint::v1beta1::{service_client::ServiceClient, GetLatestBlockRequest},
+    gov::v1beta1::{
+        query_client::QueryClient as GovQueryClient, ProposalStatus, QueryProposalRequest,
+    },
+};
+use super_orchestrator::{
+    stacked_errors::{Error, MapAddError, Result},
+    FileOptions,
+};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig};
+
+use crate::{
+    cosmovisor::{sh_cosmovisor_no_dbg, RemoteNode},
+    ports::GRPC_PORT,
+};
+
+/// The address that [CosmovisorOptions::enable_grpc] serves on
+///
+/// [CosmovisorOptions::enable_grpc]: crate::cosmovisor::CosmovisorOptions
+pub fn grpc_url() -> String {
+    format!("http://localhost:{GRPC_PORT}")
+}
+
+pub async fn grpc_get_block_height() -> Result<u64> {
+    let mut client = ServiceClient::connect(grpc_url())
+        .await
+        .map_add_err(|| "grpc_get_block_height")?;
+    let block = client
+        .get_latest_block(GetLatestBlockRequest {})
+        .await
+        .map_add_err(|| "grpc_get_block_height")?
+        .into_inner();
+    let height = block
+        .block
+        .and_then(|block| block.header)
+        .map_add_err(|| "GetLatestBlock response had no header")?
+        .height;
+    u64::try_from(height).map_add_err(|| format!("negative block height {height}"))
+}
+
+/// Returns a mapping of denoms to amounts
+pub async fn grpc_get_balances(addr: &str) -> Result<BTreeMap<String, String>> {
+    let mut client = BankQueryClient::connect(grpc_url())
+        .await
+        .map_add_err(|| format!("grpc_get_balances(addr: {addr})"))?;
+    let balances = client
+        .all_balances(QueryAllBalancesRequest {
+            address: addr.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_add_err(|| format!("grpc_get_balances(addr: {addr})"))?
+        .into_inner();
+    Ok(balances
+        .balances
+        .into_iter()
+        .map(|coin| (coin.denom, coin.amount))
+        .collect())
+}
+
+/// Connects to the gRPC URL of `node`, using TLS for "https://" URLs. tonic
+/// cannot skip certificate verification, so `node.ca_cert` should be used for
+/// self-signed endpoints.
+pub async fn grpc_channel(node: &RemoteNode) -> Result<Channel> {
+    let url = node.grpc_url().replacen("tcp://", "http://", 1);
+    let err = || format!("grpc_channel(url: {url})");
+    if node.insecure {
+        return Err(Error::from(format!(
+            "{} skipping certificate verification is not supported, use `ca_cert`",
+            err()
+        )))
+    }
+    let mut endpoint = Channel::from_shared(url.clone()).map_add_err(err)?;
+    if url.starts_with("https://") {
+        let mut tls = ClientTlsConfig::new();
+        if let Some(ref ca_cert) = node.ca_cert {
+            let pem = FileOptions::read_to_string(ca_cert)
+                .await
+                .map_add_err(err)?;
+            tls = tls.ca_certificate(Certificate::from_pem(pem));
+        }
+        endpoint = endpoint.tls_config(tls).map_add_err(err)?;
+    }
+    endpoint.connect().await.map_add_err(err)
+}
+
+/// [grpc_get_balances] but from `node`
+pub async fn grpc_get_balances_at(
+    node: &RemoteNode,
+    addr: &str,
+) -> Result<BTreeMap<String, String>> {
+    let mut client = BankQueryClient::new(grpc_channel(node).await?);
+    let balances = client
+        .all_balances(QueryAllBalancesRequest {
+            address: addr.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .map_add_err(|| {
+            format!(
+                "grpc_get_balances_at(url: {}, addr: {addr})",
+                node.grpc_url()
+            )
+        })?
+        .into_inner();
+    Ok(balances
+        .balances
+        .into_iter()
+        .map(|coin| (coin.denom, coin.amount))
+        .collect())
+}
+
+/// Returns the status of a proposal, e.x. "PROPOSAL_STATUS_PASSED"
+pub async fn grpc_get_proposal_status(proposal_id: u64) -> Result<String> {
+    let mut client = GovQueryClient::connect(grpc_url())
+        .await
+        .map_add_err(|| format!("grpc_get_proposal_status(proposal_id: {proposal_id})"))?;
+    let proposal = client
+        .proposal(QueryProposalRequest { proposal_id })
+        .await
+        .map_add_err(|| format!("grpc_get_proposal_status(proposal_id: {proposal_id})"))?
+        .into_inner()
+        .proposal
+        .map_add_err(|| format!("proposal {proposal_id} not found"))?;
+    let status = ProposalStatus::from_i32(proposal.status)
+        .map_add_err(|| format!("unknown proposal status {}", proposal.status))?;
+    Ok(status.as_str_name().to_owned())
+}
+
+/// Times `num_queries` sequential balance queries of `addr` through the CLI and
+/// then through gRPC, returning `(cli, grpc)`. Polling heavy tests (e.x.
+/// `wait_for_balance`) should only enable `grpc_support` if this shows a
+/// material difference.
+pub async fn bench_balance_queries(addr: &str, num_queries: u32) -> Result<(Duration, Duration)> {
+    let start = Instant::now();
+    for _ in 0..num_queries {
+        sh_cosmovisor_no_dbg("query bank balances", &[addr])
+            .await
+            .map_add_err(|| format!("bench_balance_queries(addr: {addr})"))?;
+    }
+    let cli = start.elapsed();
+    let start = Instant::now();
+    for _ in 0..num_queries {
+        grpc_get_balances(addr).await?;
+    }
+    Ok((cli, start.elapsed()))
+}
@@ -1,39 +1,226 @@
-use std::time::Duration;
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
 
 use log::info;
 use serde_json::Value;
 use super_orchestrator::{
     sh, sh_no_dbg,
     stacked_errors::{Error, MapAddError, Result},
-    Command, CommandRunner, FileOptions,
+    wait_for_ok, Command, CommandRunner, FileOptions,
 };
+use tokio::time::sleep;
 
-use crate::json_inner;
 pub use crate::{hermes_config::*, ibc::IbcPair};
+use crate::{json_inner, retry_with_backoff, TIMEOUT};
 
-/// A wrapper around `super_orchestrator::sh` that prefixes "hermes --json". The
-/// last line is parsed as a `Value` and the inner "result" is returned.
+/// Returns the "result" of the output of a `hermes --json` command. Hermes
+/// interleaves json log lines (and with some versions plain text) on stdout,
+/// so this finds the last line with a "result", and returns an error if its
+/// "status" is "error".
+pub fn parse_hermes_json(stdout: &str) -> Result<Value> {
+    let res = stdout
+        .lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str::<Value>(line.trim()).ok())
+        .find(|line| line.get("result").is_some())
+        .map_add_err(|| format!("hermes did not output a result line: {stdout}"))?;
+    if res["status"] == "error" {
+        return Err(Error::from(format!(
+            "hermes returned an error: {}",
+            res["result"]
+        )))
+    }
+    Ok(res["result"].to_owned())
+}
+
+/// A wrapper around `super_orchestrator::sh` that prefixes "hermes --json" and
+/// returns the [parse_hermes_json] result
 pub async fn sh_hermes(cmd_with_args: &str, args: &[&str]) -> Result<Value> {
     info!("running hermes({cmd_with_args}, {args:?})");
     let stdout = sh(&format!("hermes --json {cmd_with_args}"), args).await?;
-    let res = stdout.lines().last().map_add_err(|| ())?;
-    let res: Value = serde_json::from_str(res).map_add_err(|| ())?;
-    let res = res.get("result").map_add_err(|| ())?.to_owned();
-    Ok(res)
+    parse_hermes_json(&stdout).map_add_err(|| format!("sh_hermes({cmd_with_args}, {args:?})"))
 }
 
-pub async fn sh_hermes_no_dbg(cmd_with_args: &str, args: &[&str]) -> Result<Value> {
+/// The same as [sh_hermes] without the debug output, for queries
+pub async fn hermes_json(cmd_with_args: &str, args: &[&str]) -> Result<Value> {
     let stdout = sh_no_dbg(&format!("hermes --json {cmd_with_args}"), args).await?;
-    let res = stdout.lines().last().map_add_err(|| ())?;
-    let res: Value = serde_json::from_str(res).map_add_err(|| ())?;
-    let res = res.get("result").map_add_err(|| ())?.to_owned();
+    parse_hermes_json(&stdout).map_add_err(|| format!("hermes_json({cmd_with_args}, {args:?})"))
+}
+
+/// The unreceived packets and acks of one side of a `query packet pending`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PendingSide {
+    pub unreceived_packets: Vec<u64>,
+    pub unreceived_acks: Vec<u64>,
+}
+
+/// The result of `query packet pending`, `src` is the side of the queried
+/// chain
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PendingPackets {
+    pub src: PendingSide,
+    pub dst: PendingSide,
+}
+
+impl PendingPackets {
+    pub fn from_json(pending: &Value) -> Result<Self> {
+        let err = || format!("unexpected `query packet pending` result: {pending}");
+        let seqs = |side: &str, kind: &str| -> Result<Vec<u64>> {
+            let mut res = vec![];
+            for seq in pending[side][kind].as_array().map_add_err(err)? {
+                res.push(json_inner(seq).parse::<u64>().map_add_err(err)?);
+            }
+            Ok(res)
+        };
+        let side = |side: &str| -> Result<PendingSide> {
+            Ok(PendingSide {
+                unreceived_packets: seqs(side, "unreceived_packets")?,
+                unreceived_acks: seqs(side, "unreceived_acks")?,
+            })
+        };
+        Ok(Self {
+            src: side("src")?,
+            dst: side("dst")?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        [&self.src, &self.dst]
+            .iter()
+            .all(|side| side.unreceived_packets.is_empty() && side.unreceived_acks.is_empty())
+    }
+}
+
+/// Queries the [PendingPackets] of `port`/`channel` on `chain`
+pub async fn hermes_pending_packets(
+    chain: &str,
+    port: &str,
+    channel: &str,
+) -> Result<PendingPackets> {
+    let pending = hermes_json("query packet pending --chain", &[
+        chain,
+        "--port",
+        port,
+        "--channel",
+        channel,
+    ])
+    .await?;
+    PendingPackets::from_json(&pending)
+}
+
+/// Parses the addresses out of the result of `hermes --json keys list`. Older
+/// versions of hermes map key names directly to address strings, newer
+/// versions map them to objects with an "account" field.
+pub fn parse_hermes_keys_list(keys: &Value) -> Result<Vec<String>> {
+    let entries: Vec<&Value> = match keys {
+        Value::Object(map) => map.values().collect(),
+        Value::Array(list) => list.iter().collect(),
+        _ => {
+            return Err(Error::from(format!(
+                "unexpected `keys list` result: {keys}"
+            )))
+        }
+    };
+    let mut res = vec![];
+    for entry in entries {
+        let addr = match entry {
+            Value::String(addr) => addr,
+            _ => entry["account"]
+                .as_str()
+                .map_add_err(|| format!("unexpected `keys list` entry: {entry}"))?,
+        };
+        res.push(addr.to_owned());
+    }
     Ok(res)
 }
 
+/// Returns the address of the single key hermes has for `chain`
+pub async fn hermes_key_address(chain: &str) -> Result<String> {
+    let keys = hermes_json("keys list --chain", &[chain]).await?;
+    let addrs = parse_hermes_keys_list(&keys)?;
+    if addrs.len() != 1 {
+        return Err(Error::from(format!(
+            "hermes_key_address(chain: {chain}) expected one key, found {addrs:?}"
+        )))
+    }
+    Ok(addrs[0].clone())
+}
+
+/// Returns an error if the relayer key for `chain` is not `expected_addr`. If
+/// the mnemonic or HD path differs from what the chain expects, `keys add`
+/// still succeeds but relaying will silently fail.
+pub async fn hermes_assert_key_address(chain: &str, expected_addr: &str) -> Result<()> {
+    let addr = hermes_key_address(chain).await?;
+    if addr != expected_addr {
+        return Err(Error::from(format!(
+            "hermes key for {chain} has address {addr}, expected {expected_addr}"
+        )))
+    }
+    Ok(())
+}
+
+/// Parses the result of `keys balance` into the amount and denom
+pub fn parse_hermes_balance(balance: &Value) -> Result<(u128, String)> {
+    let err = || format!("unexpected `keys balance` result: {balance}");
+    let amount = balance["amount"]
+        .as_str()
+        .map_add_err(err)?
+        .parse::<u128>()
+        .map_add_err(err)?;
+    let denom = balance["denom"].as_str().map_add_err(err)?.to_owned();
+    Ok((amount, denom))
+}
+
+/// Returns a "relayer underfunded" error if `amount` is less than
+/// `min_balance`
+pub fn check_key_funded(chain: &str, amount: u128, denom: &str, min_balance: u128) -> Result<()> {
+    if amount < min_balance {
+        return Err(Error::from(format!(
+            "relayer underfunded: hermes key for {chain} has {amount}{denom}, needs at least \
+             {min_balance}{denom} to pay fees"
+        )))
+    }
+    Ok(())
+}
+
+/// Returns an error if the relayer key for `chain` has less than `min_balance`
+/// of the gas price denom. A relayer that runs out of fee tokens silently
+/// stops relaying.
+pub async fn hermes_assert_key_funded(chain: &str, min_balance: u128) -> Result<()> {
+    let balance = hermes_json("keys balance --chain", &[chain]).await?;
+    let (amount, denom) = parse_hermes_balance(&balance)?;
+    check_key_funded(chain, amount, &denom, min_balance)
+}
+
+/// Polls `health-check` until it succeeds or `timeout`, then checks that the
+/// relayer keys of `chains` are funded with at least `min_balance` (note that
+/// one transaction can cost up to `max_gas` times the gas price)
+pub async fn hermes_wait_healthy(
+    chains: &[&str],
+    min_balance: u128,
+    timeout: Duration,
+) -> Result<()> {
+    let delay = Duration::from_millis(500);
+    let num_tries = u64::try_from(timeout.as_millis() / delay.as_millis())
+        .unwrap_or(u64::MAX)
+        .max(1);
+    wait_for_ok(num_tries, delay, || async {
+        hermes_json("health-check", &[]).await.map(|_| ())
+    })
+    .await
+    .map_add_err(|| format!("hermes_wait_healthy(chains: {chains:?})"))?;
+    for chain in chains {
+        hermes_assert_key_funded(chain, min_balance).await?;
+    }
+    Ok(())
+}
+
 /// Returns a single client if it exists. Returns an error if two redundant
 /// clients were found.
 pub async fn get_client(host_chain: &str, reference_chain: &str) -> Result<String> {
-    let clients = sh_hermes_no_dbg("query clients --host-chain", &[host_chain])
+    let clients = hermes_json("query clients --host-chain", &[host_chain])
         .await
         .map_add_err(|| "failed to query for host chain")?;
     let clients = clients.as_array().map_add_err(|| ())?;
@@ -61,7 +248,7 @@ pub async fn get_client(host_chain: &str, reference_chain: &str) -> Result<Strin
 /// Returns a single connection if it exists. Returns an error if two redundant
 /// connections were found.
 pub async fn get_connection(host_chain: &str, reference_chain: &str) -> Result<String> {
-    let clients = sh_hermes_no_dbg("query clients --host-chain", &[host_chain])
+    let clients = hermes_json("query clients --host-chain", &[host_chain])
         .await
         .map_add_err(|| "failed to query for host chain")?;
     let clients = clients.as_array().map_add_err(|| ())?;
@@ -127,6 +314,81 @@ pub async fn create_client_pair(a_chain: &str, b_chain: &str) -> Result<(String,
     Ok((client0, client1))
 }
 
+/// Substrings (in lowercase) of hermes errors that retrying a handshake step
+/// cannot fix, e.x. a port without a bound module or a version mismatch
+pub const PERMANENT_HANDSHAKE_ERRORS: &[&str] = &[
+    "invalid port",
+    "unknown port",
+    "port not found",
+    "invalid identifier",
+    "invalid version",
+    "unsupported version",
+    "no common version",
+    "version mismatch",
+];
+
+/// If the `Debug` representation of a handshake error has any of the
+/// [PERMANENT_HANDSHAKE_ERRORS]
+pub fn is_permanent_handshake_error(err: &str) -> bool {
+    let err = err.to_lowercase();
+    PERMANENT_HANDSHAKE_ERRORS.iter().any(|s| err.contains(s))
+}
+
+/// The retry budget of the hermes handshake steps, which can fail transiently
+/// (e.x. "no client found") if a chain is not quite ready. Note that a step
+/// that fails partway through may leave a half open connection or channel
+/// behind, which only [create_or_resume_channel_pair_with_retry] reuses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HandshakeRetry {
+    /// The total number of attempts, 1 means no retries
+    pub num_tries: u64,
+    pub initial_delay: Duration,
+    /// The delay is multiplied by this after every failure
+    pub multiplier: f64,
+    pub max_delay: Duration,
+}
+
+impl Default for HandshakeRetry {
+    fn default() -> Self {
+        Self {
+            num_tries: 5,
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl HandshakeRetry {
+    /// Runs `step` with [retry_with_backoff], except that errors for which
+    /// [is_permanent_handshake_error] is true are returned immediately
+    pub async fn run<F, Fut, T>(&self, mut step: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        retry_with_backoff(
+            self.num_tries,
+            self.initial_delay,
+            self.multiplier,
+            self.max_delay,
+            || {
+                let fut = step();
+                async move {
+                    match fut.await {
+                        Ok(o) => Ok(Ok(o)),
+                        // stop retrying by returning the error as a success
+                        Err(e) if is_permanent_handshake_error(&format!("{e:?}")) => Ok(Err(e)),
+                        Err(e) => Err(e),
+                    }
+                }
+            },
+        )
+        .await?
+        .map_add_err(|| "HandshakeRetry::run permanent error, not retrying")
+    }
+}
+
 /// Returns the connection-x of the new connection on the side of `a_chain` and
 /// `b_chain`.
 pub async fn create_connection_pair(a_chain: &str, b_chain: &str) -> Result<(String, String)> {
@@ -194,10 +456,333 @@ pub async fn create_channel_pair(
     ))
 }
 
+/// [create_connection_pair] with transient errors retried according to `retry`
+pub async fn create_connection_pair_with_retry(
+    a_chain: &str,
+    b_chain: &str,
+    retry: &HandshakeRetry,
+) -> Result<(String, String)> {
+    retry
+        .run(|| create_connection_pair(a_chain, b_chain))
+        .await
+        .map_add_err(|| format!("create_connection_pair_with_retry(retry: {retry:?})"))
+}
+
+/// [create_channel_pair] with transient errors retried according to `retry`.
+/// Every retry starts a new handshake, for channels of which there can only be
+/// one (e.x. the ICS channel) use [create_or_resume_channel_pair_with_retry].
+pub async fn create_channel_pair_with_retry(
+    a_chain: &str,
+    a_connection: &str,
+    a_port: &str,
+    b_port: &str,
+    ordered: bool,
+    retry: &HandshakeRetry,
+) -> Result<(String, String)> {
+    retry
+        .run(|| create_channel_pair(a_chain, a_connection, a_port, b_port, ordered))
+        .await
+        .map_add_err(|| format!("create_channel_pair_with_retry(retry: {retry:?})"))
+}
+
+/// One side of a channel handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeSide<'a> {
+    pub chain: &'a str,
+    pub connection: &'a str,
+    pub port: &'a str,
+}
+
+/// Returns the channel-x on `b` of the channel with the counterparty
+/// `a_channel`, if the handshake has reached `b`
+async fn counterparty_of(
+    a: &HandshakeSide<'_>,
+    a_channel: &str,
+    b: &HandshakeSide<'_>,
+) -> Result<Option<String>> {
+    Ok(channel_ends(b.chain, b.connection, b.port, a.port)
+        .await?
+        .into_iter()
+        .find(|(_, end)| end.counterparty_channel.as_deref() == Some(a_channel))
+        .map(|(channel, _)| channel))
+}
+
+/// Runs the handshake step `step` (e.x. "chan-open-ack") from `src` to `dst`
+async fn chan_open_step(
+    step: &str,
+    dst: &HandshakeSide<'_>,
+    dst_channel: Option<&str>,
+    src: &HandshakeSide<'_>,
+    src_channel: &str,
+) -> Result<()> {
+    let mut args = vec![
+        dst.chain,
+        "--src-chain",
+        src.chain,
+        "--dst-connection",
+        dst.connection,
+        "--dst-port",
+        dst.port,
+        "--src-port",
+        src.port,
+        "--src-channel",
+        src_channel,
+    ];
+    if let Some(dst_channel) = dst_channel {
+        args.extend(["--dst-channel", dst_channel]);
+    }
+    sh_hermes(&format!("tx {step} --dst-chain"), &args)
+        .await
+        .map_add_err(|| format!("chan_open_step({step}, dst: {dst:?}, src: {src:?})"))?;
+    Ok(())
+}
+
+/// The same as [create_channel_pair], except that if there already is a
+/// channel between the ports of `a` and `b` (e.x. left partially open by a
+/// failed earlier attempt) its handshake is resumed instead of starting a new
+/// one. Returns an error if there are several such channels.
+pub async fn create_or_resume_channel_pair(
+    a: &HandshakeSide<'_>,
+    b: &HandshakeSide<'_>,
+    ordered: bool,
+) -> Result<(String, String)> {
+    let err = || format!("create_or_resume_channel_pair(a: {a:?}, b: {b:?})");
+    let mut ends = channel_ends(a.chain, a.connection, a.port, b.port)
+        .await
+        .map_add_err(err)?;
+    ends.retain(|(_, end)| end.state != "Closed");
+    let (a_channel, a_end) = match ends.len() {
+        0 => return create_channel_pair(a.chain, a.connection, a.port, b.port, ordered).await,
+        1 => ends.pop().unwrap(),
+        _ => {
+            return Err(Error::from(format!(
+                "{} found several channels: {ends:?}",
+                err()
+            )))
+        }
+    };
+    info!(
+        "resuming the handshake of {} {a_channel} in state {}",
+        a.chain, a_end.state
+    );
+    let b_channel = match counterparty_of(a, &a_channel, b).await.map_add_err(err)? {
+        Some(b_channel) => b_channel,
+        None => {
+            chan_open_step("chan-open-try", b, None, a, &a_channel)
+                .await
+                .map_add_err(err)?;
+            counterparty_of(a, &a_channel, b)
+                .await
+                .map_add_err(err)?
+                .map_add_err(|| format!("{} chan-open-try did not create a channel", err()))?
+        }
+    };
+    if !a_end.is_open() {
+        chan_open_step("chan-open-ack", a, Some(&a_channel), b, &b_channel)
+            .await
+            .map_add_err(err)?;
+    }
+    let b_end = hermes_json("query channel end --chain", &[
+        b.chain,
+        "--port",
+        b.port,
+        "--channel",
+        &b_channel,
+    ])
+    .await
+    .map_add_err(err)?;
+    if !ChannelEnd::from_json(&b_end).map_add_err(err)?.is_open() {
+        chan_open_step("chan-open-confirm", b, Some(&b_channel), a, &a_channel)
+            .await
+            .map_add_err(err)?;
+    }
+    Ok((a_channel, b_channel))
+}
+
+/// [create_or_resume_channel_pair] with transient errors retried according to
+/// `retry`. Every retry resumes the handshake of the previous attempt, so this
+/// can be used for channels of which there can only be one, such as the ICS
+/// channel.
+pub async fn create_or_resume_channel_pair_with_retry(
+    a: &HandshakeSide<'_>,
+    b: &HandshakeSide<'_>,
+    ordered: bool,
+    retry: &HandshakeRetry,
+) -> Result<(String, String)> {
+    retry
+        .run(|| create_or_resume_channel_pair(a, b, ordered))
+        .await
+        .map_add_err(|| format!("create_or_resume_channel_pair_with_retry(retry: {retry:?})"))
+}
+
+/// Returns an error if there are any unreceived packets or acks in either
+/// direction of `port`/`channel` on `chain`
+pub async fn hermes_no_pending_packets(chain: &str, port: &str, channel: &str) -> Result<()> {
+    let pending = hermes_pending_packets(chain, port, channel).await?;
+    if !pending.is_empty() {
+        return Err(Error::from(format!(
+            "{chain} {port}/{channel} has pending packets or acks: {pending:?}"
+        )))
+    }
+    Ok(())
+}
+
+/// The parts of a `query channel end` result needed to identify a channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelEnd {
+    pub connection: String,
+    pub counterparty_port: String,
+    /// Not known yet by the initiating end while it is in the "Init" state
+    pub counterparty_channel: Option<String>,
+    /// The handshake state, e.x. "Init", "TryOpen", "Open", or "Closed"
+    pub state: String,
+}
+
+impl ChannelEnd {
+    pub fn from_json(end: &Value) -> Result<Self> {
+        let err = || format!("unexpected `query channel end` result: {end}");
+        let field = |v: &Value| v.as_str().map(|s| s.to_owned()).map_add_err(err);
+        Ok(Self {
+            connection: field(&end["connection_hops"][0])?,
+            counterparty_port: field(&end["remote"]["port_id"])?,
+            counterparty_channel: end["remote"]["channel_id"].as_str().map(|s| s.to_owned()),
+            state: field(&end["state"])?,
+        })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state == "Open"
+    }
+}
+
+/// Returns the `(port, channel)` of every channel in the result of
+/// `query channels`
+pub fn parse_channel_ids(channels: &Value) -> Result<Vec<(String, String)>> {
+    let err = || format!("unexpected `query channels` result: {channels}");
+    let mut res = vec![];
+    for channel in channels.as_array().map_add_err(err)? {
+        res.push((
+            channel["port_id"].as_str().map_add_err(err)?.to_owned(),
+            channel["channel_id"].as_str().map_add_err(err)?.to_owned(),
+        ));
+    }
+    Ok(res)
+}
+
+/// Returns the channel-x and end of every channel on `chain` over `connection`
+/// between `port` and `counterparty_port`, in any state
+pub async fn channel_ends(
+    chain: &str,
+    connection: &str,
+    port: &str,
+    counterparty_port: &str,
+) -> Result<Vec<(String, ChannelEnd)>> {
+    let err = || {
+        format!(
+            "channel_ends(chain: {chain}, connection: {connection}, port: {port}, \
+             counterparty_port: {counterparty_port})"
+        )
+    };
+    let channels = hermes_json("query channels --chain", &[chain])
+        .await
+        .map_add_err(err)?;
+    let mut res = vec![];
+    for (channel_port, channel) in parse_channel_ids(&channels).map_add_err(err)? {
+        if channel_port != port {
+            continue
+        }
+        let end = hermes_json("query channel end --chain", &[
+            chain,
+            "--port",
+            port,
+            "--channel",
+            &channel,
+        ])
+        .await
+        .map_add_err(err)?;
+        let end = ChannelEnd::from_json(&end).map_add_err(err)?;
+        if (end.connection == connection) && (end.counterparty_port == counterparty_port) {
+            res.push((channel, end));
+        }
+    }
+    Ok(res)
+}
+
+/// Returns the channel-x of the open channel on `chain` over `connection`
+/// between `port` and `counterparty_port`, and the channel-x of its
+/// counterparty. Channel ids are assigned sequentially, so this should be used
+/// instead of hardcoding e.x. "channel-0". Returns an error if none or two
+/// matching channels were found.
+pub async fn find_channel(
+    chain: &str,
+    connection: &str,
+    port: &str,
+    counterparty_port: &str,
+) -> Result<(String, String)> {
+    let err = || {
+        format!(
+            "find_channel(chain: {chain}, connection: {connection}, port: {port}, \
+             counterparty_port: {counterparty_port})"
+        )
+    };
+    let mut found = None;
+    for (channel, end) in channel_ends(chain, connection, port, counterparty_port).await? {
+        if !end.is_open() {
+            continue
+        }
+        if found.is_some() {
+            // same as with clients, we don't want to need to handle ambiguity
+            return Err(Error::from(format!("{} found two channels", err())))
+        }
+        let counterparty_channel = end
+            .counterparty_channel
+            .map_add_err(|| format!("{} open channel {channel} has no counterparty", err()))?;
+        found = Some((channel, counterparty_channel));
+    }
+    found.map_add_err(|| format!("{} could not find a channel", err()))
+}
+
 impl IbcPair {
+    /// Looks up the channel between `port` on the `a` side and
+    /// `counterparty_port` on the `b` side, returning the `(a, b)` channel-x
+    /// ids
+    pub async fn find_channel(
+        &self,
+        port: &str,
+        counterparty_port: &str,
+    ) -> Result<(String, String)> {
+        find_channel(
+            &self.a.chain_id,
+            &self.a.connection,
+            port,
+            counterparty_port,
+        )
+        .await
+    }
+
+    /// Returns an error if the channel ids of this pair (e.x. from
+    /// [IbcPair::load] of a previous run) do not match the channels on chain
+    /// as looked up by their ports
+    pub async fn assert_channels_match(&self) -> Result<()> {
+        for (port, counterparty_port) in [("transfer", "transfer"), ("consumer", "provider")] {
+            let expected = (
+                self.a.channel_for_port(port),
+                self.b.channel_for_port(counterparty_port),
+            );
+            let found = self.find_channel(port, counterparty_port).await?;
+            if expected != (Some(found.0.as_str()), Some(found.1.as_str())) {
+                return Err(Error::from(format!(
+                    "IbcPair::assert_channels_match {port}/{counterparty_port} channels are \
+                     {found:?} but the pair has {expected:?}"
+                )))
+            }
+        }
+        Ok(())
+    }
+
     pub async fn hermes_check_acks(&self) -> Result<()> {
         // check all channels on both sides
-        sh_hermes_no_dbg("query packet acks --chain", &[
+        hermes_json("query packet acks --chain", &[
             &self.b.chain_id,
             "--port",
             "transfer",
@@ -205,7 +790,7 @@ impl IbcPair {
             &self.a.transfer_channel,
         ])
         .await?;
-        sh_hermes_no_dbg("query packet acks --chain", &[
+        hermes_json("query packet acks --chain", &[
             &self.a.chain_id,
             "--port",
             "transfer",
@@ -213,7 +798,7 @@ impl IbcPair {
             &self.b.transfer_channel,
         ])
         .await?;
-        sh_hermes_no_dbg("query packet acks --chain", &[
+        hermes_json("query packet acks --chain", &[
             &self.b.chain_id,
             "--port",
             "provider",
@@ -221,7 +806,7 @@ impl IbcPair {
             &self.a.ics_channel,
         ])
         .await?;
-        sh_hermes_no_dbg("query packet acks --chain", &[
+        hermes_json("query packet acks --chain", &[
             &self.a.chain_id,
             "--port",
             "consumer",
@@ -231,6 +816,152 @@ impl IbcPair {
         .await?;
         Ok(())
     }
+
+    /// Polls until there are no unreceived packets or acks in either direction
+    /// on both the transfer and ICS channels, or returns an error after
+    /// `timeout`. This should be called before any final balance assertions
+    /// and before the termination handshake.
+    pub async fn assert_drained(&self, timeout: Duration) -> Result<()> {
+        let delay = Duration::from_millis(500);
+        let num_tries = u64::try_from(timeout.as_millis() / delay.as_millis())
+            .unwrap_or(u64::MAX)
+            .max(1);
+        wait_for_ok(num_tries, delay, || async {
+            hermes_no_pending_packets(&self.a.chain_id, "transfer", &self.a.transfer_channel)
+                .await?;
+            hermes_no_pending_packets(&self.a.chain_id, "consumer", &self.a.ics_channel).await
+        })
+        .await
+        .map_add_err(|| format!("IbcPair::assert_drained(timeout: {timeout:?})"))
+    }
+
+    /// Registers `payee` on the counterparty of `chain` to receive the ICS-29
+    /// fees earned by the Hermes relayer key of `chain` for relaying packets on
+    /// the transfer `channel`. `relayer_addr` is checked against the Hermes
+    /// key, since Hermes always registers for its own key.
+    pub async fn register_counterparty_payee(
+        &self,
+        chain: &str,
+        channel: &str,
+        relayer_addr: &str,
+        payee: &str,
+    ) -> Result<()> {
+        if (chain != self.a.chain_id) && (chain != self.b.chain_id) {
+            return Err(Error::from(format!(
+                "IbcPair::register_counterparty_payee chain {chain} is not in the pair {self:?}"
+            )))
+        }
+        hermes_assert_key_address(chain, relayer_addr).await?;
+        sh_hermes("fee register-counterparty-payee --chain", &[
+            chain,
+            "--port",
+            "transfer",
+            "--channel",
+            channel,
+            "--counterparty-payee",
+            payee,
+        ])
+        .await
+        .map_add_err(|| {
+            format!(
+                "IbcPair::register_counterparty_payee(chain: {chain}, channel: {channel}, payee: \
+                 {payee})"
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Returns the sequences of the transfer packets (sent in either direction)
+    /// that have not been received yet
+    pub async fn unreceived_transfer_packets(&self) -> Result<Vec<u64>> {
+        let pending =
+            hermes_pending_packets(&self.a.chain_id, "transfer", &self.a.transfer_channel).await?;
+        let mut res = pending.src.unreceived_packets;
+        res.extend(pending.dst.unreceived_packets);
+        Ok(res)
+    }
+
+    /// The negative counterpart to [IbcPair::assert_drained]. Checks that the
+    /// transfer packet with sequence `seq` (sent in either direction) stays
+    /// unreceived for the whole `within` duration. This is used to prove
+    /// that the relayer was actually stopped.
+    pub async fn assert_not_relayed(&self, seq: u64, within: Duration) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            if !self.unreceived_transfer_packets().await?.contains(&seq) {
+                return Err(Error::from(format!(
+                    "IbcPair::assert_not_relayed(seq: {seq}, within: {within:?}) packet was \
+                     relayed after {:?}",
+                    start.elapsed()
+                )))
+            }
+            if start.elapsed() >= within {
+                return Ok(())
+            }
+            sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    /// Polls until none of the transfer packets in `seqs` are unreceived, or
+    /// returns an error after `timeout`
+    pub async fn wait_for_packets_relayed(&self, seqs: &[u64], timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            let unreceived = self.unreceived_transfer_packets().await?;
+            let remaining: Vec<u64> = seqs
+                .iter()
+                .copied()
+                .filter(|seq| unreceived.contains(seq))
+                .collect();
+            if remaining.is_empty() {
+                return Ok(())
+            }
+            if start.elapsed() >= timeout {
+                return Err(Error::from(format!(
+                    "IbcPair::wait_for_packets_relayed(seqs: {seqs:?}, timeout: {timeout:?}) \
+                     packets {remaining:?} were never relayed"
+                )))
+            }
+            sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    /// Checks that transfer packets sent while Hermes was stopped are relayed
+    /// after it is started again. This should be called after Hermes has been
+    /// terminated and at least one transfer has been sent. Returns an error if
+    /// there are no in-flight packets (so that the check is not vacuous),
+    /// otherwise runs `start` (which should apply any config changes and call
+    /// [hermes_start]) and waits for the in-flight packets to be relayed.
+    pub async fn assert_packets_survive_restart<F, Fut>(
+        &self,
+        start: F,
+        timeout: Duration,
+    ) -> Result<HermesRunner>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<HermesRunner>>,
+    {
+        let in_flight = self
+            .unreceived_transfer_packets()
+            .await
+            .map_add_err(|| "IbcPair::assert_packets_survive_restart")?;
+        if in_flight.is_empty() {
+            return Err(Error::from(
+                "IbcPair::assert_packets_survive_restart there are no in-flight packets, a \
+                 transfer should be sent while Hermes is stopped"
+                    .to_owned(),
+            ))
+        }
+        info!("restarting Hermes with in-flight packets {in_flight:?}");
+        let mut runner = start()
+            .await
+            .map_add_err(|| "IbcPair::assert_packets_survive_restart")?;
+        if let Err(e) = self.wait_for_packets_relayed(&in_flight, timeout).await {
+            runner.terminate(TIMEOUT).await?;
+            return Err(e).map_add_err(|| "IbcPair::assert_packets_survive_restart")
+        }
+        Ok(runner)
+    }
 }
 
 pub struct HermesRunner {
@@ -282,3 +1013,147 @@ pub async fn hermes_set_gas_price_denom(
     FileOptions::write_str(&config_path, &config_s).await?;
     Ok(())
 }
+
+#[test]
+fn test_parse_hermes_keys_list() {
+    let old: Value = serde_json::from_str(r#"{"relayer": "onomy1abc"}"#).unwrap();
+    assert_eq!(parse_hermes_keys_list(&old).unwrap(), vec![
+        "onomy1abc".to_owned()
+    ]);
+    let new: Value = serde_json::from_str(
+        r#"{"relayer": {"account": "onomy1abc", "address": [1, 2], "coin_type": 118}}"#,
+    )
+    .unwrap();
+    assert_eq!(parse_hermes_keys_list(&new).unwrap(), vec![
+        "onomy1abc".to_owned()
+    ]);
+    assert!(parse_hermes_keys_list(&Value::Null).is_err());
+}
+
+#[test]
+fn test_channel_end() {
+    let channels: Value = serde_json::from_str(
+        r#"[{"channel_id": "channel-0", "port_id": "provider"},
+            {"channel_id": "channel-1", "port_id": "transfer"}]"#,
+    )
+    .unwrap();
+    assert_eq!(parse_channel_ids(&channels).unwrap(), vec![
+        ("provider".to_owned(), "channel-0".to_owned()),
+        ("transfer".to_owned(), "channel-1".to_owned()),
+    ]);
+    assert!(parse_channel_ids(&Value::Null).is_err());
+    let end: Value = serde_json::from_str(
+        r#"{"connection_hops": ["connection-0"], "ordering": "Unordered",
+            "remote": {"channel_id": "channel-2", "port_id": "transfer"},
+            "state": "Open", "version": "ics20-1"}"#,
+    )
+    .unwrap();
+    assert_eq!(ChannelEnd::from_json(&end).unwrap(), ChannelEnd {
+        connection: "connection-0".to_owned(),
+        counterparty_port: "transfer".to_owned(),
+        counterparty_channel: Some("channel-2".to_owned()),
+        state: "Open".to_owned(),
+    });
+    let init: Value = serde_json::from_str(
+        r#"{"connection_hops": ["connection-0"], "ordering": "Ordered",
+            "remote": {"channel_id": null, "port_id": "provider"},
+            "state": "Init", "version": "1"}"#,
+    )
+    .unwrap();
+    let init = ChannelEnd::from_json(&init).unwrap();
+    assert_eq!(init.counterparty_channel, None);
+    assert!(!init.is_open());
+    assert!(ChannelEnd::from_json(&Value::Null).is_err());
+}
+
+#[test]
+fn test_parse_hermes_json() {
+    let stdout = concat!(
+        r#"{"timestamp":"Jan 01 00:00:00.000","level":"INFO","#,
+        r#""fields":{"message":"using default configuration"}}"#,
+        "\n",
+        "some plain text warning\n",
+        r#"{"result":{"src":{"unreceived_packets":[3,"4"],"unreceived_acks":[]},"#,
+        r#""dst":{"unreceived_packets":[],"unreceived_acks":[1]}},"status":"success"}"#,
+        "\n",
+    );
+    let pending = PendingPackets::from_json(&parse_hermes_json(stdout).unwrap()).unwrap();
+    assert_eq!(pending.src.unreceived_packets, vec![3, 4]);
+    assert_eq!(pending.dst.unreceived_acks, vec![1]);
+    assert!(!pending.is_empty());
+    assert!(PendingPackets::default().is_empty());
+    let error = r#"{"result":"channel not found","status":"error"}"#;
+    let e = format!("{:?}", parse_hermes_json(error).unwrap_err());
+    assert!(e.contains("channel not found"));
+    assert!(parse_hermes_json("no json here\n").is_err());
+    assert!(PendingPackets::from_json(&Value::Null).is_err());
+}
+
+#[test]
+fn test_key_funded() {
+    let balance: Value = serde_json::from_str(r#"{"amount": "400000", "denom": "anom"}"#).unwrap();
+    let (amount, denom) = parse_hermes_balance(&balance).unwrap();
+    assert_eq!((amount, denom.as_str()), (400_000, "anom"));
+    check_key_funded("onomy", amount, &denom, 400_000).unwrap();
+    // a drained relayer key
+    let e = format!(
+        "{:?}",
+        check_key_funded("onomy", 0, &denom, 400_000).unwrap_err()
+    );
+    assert!(e.contains("relayer underfunded"));
+    assert!(parse_hermes_balance(&Value::Null).is_err());
+}
+
+#[tokio::test]
+async fn test_handshake_retry() {
+    assert!(is_permanent_handshake_error(
+        "hermes returned an error: Invalid port identifier"
+    ));
+    assert!(is_permanent_handshake_error("Unknown port `foo`"));
+    assert!(is_permanent_handshake_error("channel Version Mismatch"));
+    assert!(!is_permanent_handshake_error(
+        "no client found for chain onomy"
+    ));
+    let retry = HandshakeRetry {
+        num_tries: 5,
+        initial_delay: Duration::ZERO,
+        multiplier: 2.0,
+        max_delay: Duration::ZERO,
+    };
+    // transient errors are retried
+    let mut calls = 0;
+    let res = retry
+        .run(|| {
+            calls += 1;
+            let calls = calls;
+            async move {
+                if calls < 3 {
+                    Err(Error::from("no client found".to_owned()))
+                } else {
+                    Ok(calls)
+                }
+            }
+        })
+        .await;
+    assert_eq!(res.unwrap(), 3);
+    // permanent errors fail fast
+    let mut calls = 0;
+    let res: Result<()> = retry
+        .run(|| {
+            calls += 1;
+            async { Err(Error::from("invalid port".to_owned())) }
+        })
+        .await;
+    assert!(res.is_err());
+    assert_eq!(calls, 1);
+    // the budget is respected
+    let mut calls = 0;
+    let res: Result<()> = retry
+        .run(|| {
+            calls += 1;
+            async { Err(Error::from("no client found".to_owned())) }
+        })
+        .await;
+    assert!(res.is_err());
+    assert_eq!(calls, 5);
+}
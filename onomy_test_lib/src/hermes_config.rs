@@ -1,5 +1,7 @@
 use super_orchestrator::{stacked_errors::Result, FileOptions};
 
+use crate::ports::{GRPC_PORT, RPC_PORT};
+
 #[rustfmt::skip]
 const HEADER: &str = r##"# The global section has parameters that apply globally to the relayer operation.
 [global]
@@ -115,14 +117,14 @@ id = 'onomy'
 ccv_consumer_chain = false
 
 # Specify the RPC address and port where the chain RPC server listens on. Required
-rpc_addr = 'http://onomyd:26657'
+rpc_addr = 'http://onomyd:{RPC_PORT}'
 
 # Specify the GRPC address and port where the chain GRPC server listens on. Required
-grpc_addr = 'http://onomyd:9090'
+grpc_addr = 'http://onomyd:{GRPC_PORT}'
 
 # Specify the WebSocket address and port where the chain WebSocket server
 # listens on. Required
-websocket_addr = 'ws://onomyd:26657/websocket'
+websocket_addr = 'ws://onomyd:{RPC_PORT}/websocket'
 
 # Specify the maximum amount of time (duration) that the RPC requests should
 # take before timing out. Default: 10s (10 seconds)
@@ -306,12 +308,44 @@ memo_prefix = ''
 # fee_granter = ''
 */
 
+/// The `[chains.packet_filter]` of a chain, the lists are of `(port,
+/// channel)` pairs which may use wildcards (e.x. `("transfer", "*")`)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PacketFilter {
+    /// Relays on all channels, no filter is written
+    #[default]
+    AllowAll,
+    /// Only relays on the listed channels
+    Allow(Vec<(String, String)>),
+    /// Relays on all channels except the listed ones
+    Deny(Vec<(String, String)>),
+}
+
+impl PacketFilter {
+    /// Returns the `[chains.packet_filter]` table, or an empty string for
+    /// [PacketFilter::AllowAll]
+    pub fn to_toml(&self) -> String {
+        let (policy, list) = match self {
+            PacketFilter::AllowAll => return String::new(),
+            PacketFilter::Allow(list) => ("allow", list),
+            PacketFilter::Deny(list) => ("deny", list),
+        };
+        let mut s = format!("[chains.packet_filter]\npolicy = '{policy}'\nlist = [\n");
+        for (port, channel) in list {
+            s += &format!("  ['{port}', '{channel}'],\n");
+        }
+        s += "]\n";
+        s
+    }
+}
+
 pub struct HermesChainConfig {
     chain_id: String,
     account_prefix: String,
     ccv_consumer_chain: bool,
     gas_denom: String,
     fast_block_times: bool,
+    packet_filter: PacketFilter,
 }
 
 impl HermesChainConfig {
@@ -328,8 +362,16 @@ impl HermesChainConfig {
             ccv_consumer_chain,
             gas_denom: gas_denom.to_owned(),
             fast_block_times,
+            packet_filter: PacketFilter::AllowAll,
         }
     }
+
+    /// Restricts which channels Hermes relays on for this chain, which cuts
+    /// down on noise in tests with many channels
+    pub fn packet_filter(mut self, packet_filter: PacketFilter) -> Self {
+        self.packet_filter = packet_filter;
+        self
+    }
 }
 
 impl ToString for HermesChainConfig {
@@ -339,13 +381,14 @@ impl ToString for HermesChainConfig {
         let ccv_consumer_chain = self.ccv_consumer_chain;
         let gas_denom = &self.gas_denom;
         let max_block_time = if self.fast_block_times { "2s" } else { "30s" };
+        let packet_filter = self.packet_filter.to_toml();
         format!(
             r##"[[chains]]
 id = '{chain_id}'
 ccv_consumer_chain = {ccv_consumer_chain}
-rpc_addr = 'http://{chain_id}d:26657'
-grpc_addr = 'http://{chain_id}d:9090'
-websocket_addr = 'ws://{chain_id}d:26657/websocket'
+rpc_addr = 'http://{chain_id}d:{RPC_PORT}'
+grpc_addr = 'http://{chain_id}d:{GRPC_PORT}'
+websocket_addr = 'ws://{chain_id}d:{RPC_PORT}/websocket'
 rpc_timeout = '10s'
 account_prefix = '{account_prefix}'
 key_name = 'validator'
@@ -362,7 +405,7 @@ max_block_time = '{max_block_time}'
 trusting_period = '14days'
 trust_threshold = {{ numerator = '1', denominator = '3' }}
 address_type = {{ derivation = 'cosmos' }}
-"##
+{packet_filter}"##
         )
     }
 }
@@ -377,3 +420,35 @@ pub async fn write_hermes_config(
     }
     FileOptions::write_str(&format!("{write_dir}/__tmp_hermes_config.toml"), &s).await
 }
+
+#[test]
+fn test_packet_filter() {
+    let filtered = HermesChainConfig::new("onomy", "onomy", false, "anom", true).packet_filter(
+        PacketFilter::Allow(vec![
+            ("transfer".to_owned(), "channel-0".to_owned()),
+            ("provider".to_owned(), "*".to_owned()),
+        ]),
+    );
+    let s = filtered.to_string();
+    assert!(s.contains(
+        "[chains.packet_filter]\npolicy = 'allow'\nlist = [\n  ['transfer', 'channel-0'],\n  \
+         ['provider', '*'],\n]\n"
+    ));
+    let unfiltered = HermesChainConfig::new("market", "onomy", true, "anative", true);
+    let config: toml::Value =
+        toml::from_str(&format!("{HEADER}{s}{}", unfiltered.to_string())).unwrap();
+    let chains = config["chains"].as_array().unwrap();
+    assert_eq!(chains[0]["packet_filter"]["policy"].as_str(), Some("allow"));
+    assert_eq!(
+        chains[0]["packet_filter"]["list"][1],
+        toml::Value::Array(vec!["provider".into(), "*".into()])
+    );
+    assert!(chains[1].get("packet_filter").is_none());
+    assert_eq!(
+        PacketFilter::Deny(vec![("transfer".to_owned(), "channel-1".to_owned())])
+            .to_toml()
+            .lines()
+            .nth(1),
+        Some("policy = 'deny'")
+    );
+}
@@ -1,16 +1,1065 @@
-use std::time::Duration;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, Instant},
+};
 
+use base64::Engine;
 use log::info;
+use serde_json::{json, Value};
 pub use super_orchestrator::stacked_errors::Result;
-use super_orchestrator::{get_separated_val, stacked_errors::MapAddError};
+use super_orchestrator::{
+    get_separated_val, sh_no_dbg,
+    stacked_errors::{Error, MapAddError},
+    FileOptions, STD_DELAY,
+};
 use tokio::time::sleep;
 
 pub use crate::types::{IbcPair, IbcSide};
 use crate::{
-    cosmovisor::{sh_cosmovisor_no_dbg, sh_cosmovisor_tx},
-    hermes::{create_channel_pair, create_connection_pair},
+    cosmovisor::{
+        cosmovisor_consumer_valset, cosmovisor_delegate, cosmovisor_get_balance,
+        cosmovisor_get_balances_at, cosmovisor_start, cosmovisor_total_supply_at,
+        cosmovisor_valset, get_block_height, parse_coins, sh_cosmovisor_no_dbg, sh_cosmovisor_tx,
+        wait_for_balance, wait_for_num_blocks, CosmovisorRunner, GasConfig, ValidatorPower,
+    },
+    hermes::{
+        create_channel_pair_with_retry, create_connection_pair_with_retry,
+        create_or_resume_channel_pair_with_retry, HandshakeRetry, HandshakeSide,
+    },
+    json_inner, poll_until,
+    ports::RPC_PORT,
+    yaml_str_to_json_value, MapAddContext, TIMEOUT,
 };
 
+/// The origin of an IBC denom
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DenomTrace {
+    /// The ports and channels the tokens came through, e.x.
+    /// "transfer/channel-0"
+    pub path: String,
+    pub base_denom: String,
+}
+
+impl DenomTrace {
+    /// Parses the result of `query ibc-transfer denom-trace`
+    pub fn from_json(res: &Value) -> Result<Self> {
+        Self::from_trace_json(&res["denom_trace"])
+            .map_add_err(|| format!("unexpected `query ibc-transfer denom-trace` result: {res}"))
+    }
+
+    fn from_trace_json(trace: &Value) -> Result<Self> {
+        let field = |key: &str| {
+            trace[key]
+                .as_str()
+                .map(|s| s.to_owned())
+                .map_add_err(|| format!("denom trace is missing \"{key}\""))
+        };
+        Ok(Self {
+            path: field("path")?,
+            base_denom: field("base_denom")?,
+        })
+    }
+}
+
+/// Parses the result of `query ibc-transfer denom-traces`
+pub fn parse_denom_traces(res: &Value) -> Result<Vec<DenomTrace>> {
+    let err = || format!("unexpected `query ibc-transfer denom-traces` result: {res}");
+    let mut traces = vec![];
+    for trace in res["denom_traces"].as_array().map_add_err(err)? {
+        traces.push(DenomTrace::from_trace_json(trace).map_add_err(err)?);
+    }
+    Ok(traces)
+}
+
+/// Returns an error unless `traces` has exactly one entry for
+/// `{path}/{base_denom}`, more than one means the same denom was registered
+/// under diverging traces
+pub fn check_single_denom_trace(traces: &[DenomTrace], path: &str, base_denom: &str) -> Result<()> {
+    let n = traces
+        .iter()
+        .filter(|trace| (trace.path == path) && (trace.base_denom == base_denom))
+        .count();
+    if n != 1 {
+        return Err(Error::from(format!(
+            "expected exactly one denom trace for {path}/{base_denom}, found {n} in {traces:?}"
+        )))
+    }
+    Ok(())
+}
+
+impl fmt::Display for DenomTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.path, self.base_denom)
+    }
+}
+
+/// Returns all the denom traces of the chain
+pub async fn cosmovisor_denom_traces() -> Result<Vec<DenomTrace>> {
+    // there should not be more than a handful of traces in a test
+    let traces = sh_cosmovisor_no_dbg("query ibc-transfer denom-traces --limit", &["1000"])
+        .await
+        .map_add_err(|| "cosmovisor_denom_traces")?;
+    parse_denom_traces(&yaml_str_to_json_value(&traces)?)
+}
+
+/// Returns the trace of `ibc_denom`, which can be either "ibc/{hash}" or just
+/// the hash
+pub async fn cosmovisor_denom_trace(ibc_denom: &str) -> Result<DenomTrace> {
+    let hash = ibc_denom.strip_prefix("ibc/").unwrap_or(ibc_denom);
+    let trace = sh_cosmovisor_no_dbg("query ibc-transfer denom-trace", &[hash])
+        .await
+        .map_add_err(|| format!("cosmovisor_denom_trace(ibc_denom: {ibc_denom})"))?;
+    DenomTrace::from_json(&yaml_str_to_json_value(&trace)?)
+}
+
+/// ICS-20 fungible token packet data
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FungibleTokenPacketData {
+    /// The full denom trace, e.x. "transfer/channel-0/anom"
+    pub denom: String,
+    pub amount: String,
+    pub sender: String,
+    pub receiver: String,
+    pub memo: String,
+}
+
+impl FungibleTokenPacketData {
+    pub fn from_json(data: &Value) -> Result<Self> {
+        let field = |key: &str| -> Result<String> {
+            data[key]
+                .as_str()
+                .map(|s| s.to_owned())
+                .map_add_err(|| format!("FungibleTokenPacketData::from_json({data}) has no {key}"))
+        };
+        Ok(Self {
+            denom: field("denom")?,
+            amount: field("amount")?,
+            sender: field("sender")?,
+            receiver: field("receiver")?,
+            // older versions of ICS-20 have no memo
+            memo: data["memo"].as_str().unwrap_or_default().to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for FungibleTokenPacketData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{} from {} to {}",
+            self.amount, self.denom, self.sender, self.receiver
+        )?;
+        if !self.memo.is_empty() {
+            write!(f, " with memo {}", self.memo)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes ICS-20 packet data, which can be the raw json (e.x. the
+/// `packet_data` event attribute), hex encoded json (`packet_data_hex`), or
+/// base64 encoded json (e.x. from `query ibc channel` or the RPC)
+pub fn decode_ibc_packet_data(raw: &str) -> Result<FungibleTokenPacketData> {
+    let err = || format!("decode_ibc_packet_data(raw: {raw})");
+    let raw = raw.trim();
+    let bytes = if raw.starts_with('{') {
+        raw.as_bytes().to_owned()
+    } else if ((raw.len() & 1) == 0) && raw.chars().all(|c| c.is_ascii_hexdigit()) {
+        (0..raw.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&raw[i..(i + 2)], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_add_err(err)?
+    } else {
+        base64::engine::general_purpose::STANDARD
+            .decode(raw)
+            .map_err(|e| Error::boxed(Box::new(e)))
+            .map_add_err(err)?
+    };
+    let data: Value = serde_json::from_slice(&bytes).map_add_err(err)?;
+    FungibleTokenPacketData::from_json(&data).map_add_err(err)
+}
+
+/// Returns the decoded data of the ICS-20 packets sent by the transaction
+/// result `tx_res` (as returned by `sh_cosmovisor_tx`)
+pub fn sent_packet_data(tx_res: &Value) -> Vec<FungibleTokenPacketData> {
+    let mut res = vec![];
+    let Some(logs) = tx_res["logs"].as_array() else {
+        return res
+    };
+    for event in logs
+        .iter()
+        .filter_map(|log| log["events"].as_array())
+        .flatten()
+    {
+        if event["type"] != "send_packet" {
+            continue
+        }
+        let Some(attributes) = event["attributes"].as_array() else {
+            continue
+        };
+        for attribute in attributes {
+            if (attribute["key"] == "packet_data") || (attribute["key"] == "packet_data_hex") {
+                if let Some(data) = attribute["value"]
+                    .as_str()
+                    .and_then(|raw| decode_ibc_packet_data(raw).ok())
+                {
+                    res.push(data);
+                    // both encodings of the same packet can be present
+                    break
+                }
+            }
+        }
+    }
+    res
+}
+
+/// Returns the sequences of the packets sent by the transaction result
+/// `tx_res` (as returned by `sh_cosmovisor_tx`)
+pub fn sent_packet_sequences(tx_res: &Value) -> Vec<u64> {
+    let Some(logs) = tx_res["logs"].as_array() else {
+        return vec![]
+    };
+    logs.iter()
+        .filter_map(|log| log["events"].as_array())
+        .flatten()
+        .filter(|event| event["type"] == "send_packet")
+        .filter_map(|event| event["attributes"].as_array())
+        .filter_map(|attributes| {
+            attributes
+                .iter()
+                .find(|attribute| attribute["key"] == "packet_sequence")
+                .and_then(|attribute| attribute["value"].as_str()?.parse().ok())
+        })
+        .collect()
+}
+
+/// One hop of a packet-forward-middleware route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PfmHop {
+    pub receiver: String,
+    pub port: String,
+    pub channel: String,
+    pub next: Option<Box<PfmHop>>,
+}
+
+impl PfmHop {
+    fn to_json(&self) -> Value {
+        let mut forward = json!({
+            "receiver": self.receiver,
+            "port": self.port,
+            "channel": self.channel,
+        });
+        if let Some(ref next) = self.next {
+            forward["next"] = next.to_json();
+        }
+        json!({ "forward": forward })
+    }
+
+    fn from_json(memo: &Value) -> Result<Self> {
+        let forward = &memo["forward"];
+        let field = |key: &str| {
+            forward[key]
+                .as_str()
+                .map(|s| s.to_owned())
+                .map_add_err(|| format!("PFM memo is missing \"forward.{key}\": {memo}"))
+        };
+        let next = match &forward["next"] {
+            Value::Null => None,
+            // older versions of the middleware take the next memo as a string
+            Value::String(s) => Some(Box::new(Self::from_memo(s)?)),
+            next => Some(Box::new(Self::from_json(next)?)),
+        };
+        Ok(Self {
+            receiver: field("receiver")?,
+            port: field("port")?,
+            channel: field("channel")?,
+            next,
+        })
+    }
+
+    /// Serializes to the memo format the middleware expects
+    pub fn to_memo(&self) -> String {
+        self.to_json().to_string()
+    }
+
+    /// Parses a memo produced by [PfmHop::to_memo] or [pfm_memo]
+    pub fn from_memo(memo: &str) -> Result<Self> {
+        let memo: Value = serde_json::from_str(memo)
+            .map_add_err(|| format!("PFM memo is not valid json: {memo}"))?;
+        if !memo.is_object() {
+            return Err(Error::from(format!("PFM memo is not an object: {memo}")))
+        }
+        Self::from_json(&memo)
+    }
+}
+
+/// Returns the packet-forward-middleware memo for forwarding a received
+/// transfer to `receiver` over `port`/`channel`, with `next` for further hops.
+/// The receiver of the transfer on the intermediate chain can be any address.
+pub fn pfm_memo(receiver: &str, port: &str, channel: &str, next: Option<Box<PfmHop>>) -> String {
+    PfmHop {
+        receiver: receiver.to_owned(),
+        port: port.to_owned(),
+        channel: channel.to_owned(),
+        next,
+    }
+    .to_memo()
+}
+
+/// Balances of one account on each side of an IBC connection, see
+/// [dual_chain_balance_snapshot]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BalanceSnapshot {
+    pub provider: BTreeMap<String, u128>,
+    pub consumer: BTreeMap<String, u128>,
+}
+
+/// The change in balances between two [BalanceSnapshot]s
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BalanceDiff {
+    pub provider: BTreeMap<String, i128>,
+    pub consumer: BTreeMap<String, i128>,
+}
+
+impl BalanceSnapshot {
+    /// Returns `later - self` for every denom in either snapshot, denoms that
+    /// did not change are left out
+    pub fn diff(&self, later: &Self) -> BalanceDiff {
+        fn diff_side(
+            before: &BTreeMap<String, u128>,
+            after: &BTreeMap<String, u128>,
+        ) -> BTreeMap<String, i128> {
+            let mut res = BTreeMap::new();
+            for denom in before.keys().chain(after.keys()) {
+                let before = before.get(denom).copied().unwrap_or(0) as i128;
+                let after = after.get(denom).copied().unwrap_or(0) as i128;
+                if after != before {
+                    res.insert(denom.clone(), after - before);
+                }
+            }
+            res
+        }
+        BalanceDiff {
+            provider: diff_side(&self.provider, &later.provider),
+            consumer: diff_side(&self.consumer, &later.consumer),
+        }
+    }
+}
+
+/// Queries the `denoms` balances of `addr_provider` on `provider_node` and of
+/// `addr_consumer` on `consumer_node` (e.x. "tcp://onomyd:26657"). Missing
+/// denoms are recorded as zero. This allows asserting conservation across
+/// the IBC boundary, e.x. that what is escrowed on the provider is what was
+/// minted on the consumer.
+///
+/// Note: the two queries are concurrent but not atomic, the relayer should be
+/// quiesced first (e.x. with [IbcPair::assert_drained]) so that nothing is in
+/// flight between them.
+pub async fn dual_chain_balance_snapshot(
+    provider_node: &str,
+    consumer_node: &str,
+    addr_provider: &str,
+    addr_consumer: &str,
+    denoms: &[&str],
+) -> Result<BalanceSnapshot> {
+    let select = |balances: BTreeMap<String, String>| -> Result<BTreeMap<String, u128>> {
+        let mut res = BTreeMap::new();
+        for denom in denoms {
+            let amount = match balances.get(*denom) {
+                Some(amount) => amount
+                    .parse::<u128>()
+                    .map_add_err(|| format!("bad amount {amount}{denom}"))?,
+                None => 0,
+            };
+            res.insert((*denom).to_owned(), amount);
+        }
+        Ok(res)
+    };
+    let (provider, consumer) = tokio::join!(
+        cosmovisor_get_balances_at(provider_node, addr_provider, None),
+        cosmovisor_get_balances_at(consumer_node, addr_consumer, None)
+    );
+    Ok(BalanceSnapshot {
+        provider: select(provider.map_add_err(|| "dual_chain_balance_snapshot")?)?,
+        consumer: select(consumer.map_add_err(|| "dual_chain_balance_snapshot")?)?,
+    })
+}
+
+/// The fees escrowed for a packet by the ICS-29 fee middleware, coins are in
+/// the usual "{amount}{denom}" format
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PacketFee {
+    pub recv_fee: Vec<String>,
+    pub ack_fee: Vec<String>,
+    pub timeout_fee: Vec<String>,
+    pub refund_address: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IncentivizedPacket {
+    pub port_id: String,
+    pub channel_id: String,
+    pub sequence: u64,
+    pub packet_fees: Vec<PacketFee>,
+}
+
+/// Parses the result of `query ibc-fee packets-for-channel`
+pub fn parse_incentivized_packets(res: &Value) -> Result<Vec<IncentivizedPacket>> {
+    let err = || format!("unexpected incentivized packets query result: {res}");
+    let coins = |coins: &Value| -> Result<Vec<String>> {
+        let mut v = vec![];
+        // an empty list may be null after yaml conversion
+        for coin in coins.as_array().map(|a| a.as_slice()).unwrap_or(&[]) {
+            v.push(format!(
+                "{}{}",
+                coin["amount"].as_str().map_add_err(err)?,
+                coin["denom"].as_str().map_add_err(err)?
+            ));
+        }
+        Ok(v)
+    };
+    let mut packets = vec![];
+    for packet in res["incentivized_packets"]
+        .as_array()
+        .map(|a| a.as_slice())
+        .unwrap_or(&[])
+    {
+        let id = &packet["packet_id"];
+        let mut packet_fees = vec![];
+        for fee in packet["packet_fees"].as_array().map_add_err(err)? {
+            packet_fees.push(PacketFee {
+                recv_fee: coins(&fee["fee"]["recv_fee"])?,
+                ack_fee: coins(&fee["fee"]["ack_fee"])?,
+                timeout_fee: coins(&fee["fee"]["timeout_fee"])?,
+                refund_address: fee["refund_address"].as_str().map_add_err(err)?.to_owned(),
+            });
+        }
+        packets.push(IncentivizedPacket {
+            port_id: id["port_id"].as_str().map_add_err(err)?.to_owned(),
+            channel_id: id["channel_id"].as_str().map_add_err(err)?.to_owned(),
+            sequence: json_inner(&id["sequence"])
+                .parse::<u64>()
+                .map_add_err(err)?,
+            packet_fees,
+        });
+    }
+    Ok(packets)
+}
+
+/// Returns the packets on `port`/`channel` that have unpaid ICS-29 fees
+/// escrowed. Requires a chain with the fee middleware.
+pub async fn cosmovisor_query_incentivized_packets(
+    port: &str,
+    channel: &str,
+) -> Result<Vec<IncentivizedPacket>> {
+    let res = sh_cosmovisor_no_dbg("query ibc-fee packets-for-channel", &[port, channel])
+        .await
+        .map_add_err(|| {
+            format!("cosmovisor_query_incentivized_packets(port: {port}, channel: {channel})")
+        })?;
+    parse_incentivized_packets(&yaml_str_to_json_value(&res)?)
+}
+
+/// The ICS state of a consumer chain. Fields are `None` if the running
+/// interchain-security version does not report them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CcvConsumerState {
+    pub provider_chain_id: Option<String>,
+    /// The ICS channel on the provider side
+    pub provider_channel_id: Option<String>,
+    /// The ICS channel on the consumer side
+    pub consumer_channel_id: Option<String>,
+    /// The number of slash and VSC matured packets queued to be sent to the
+    /// provider
+    pub pending_packets: Option<usize>,
+}
+
+impl CcvConsumerState {
+    /// Parses the results of `query ccvconsumer provider-info` and, if
+    /// available, `query ccvconsumer throttle-state`
+    pub fn from_queries(provider_info: &Value, throttle_state: Option<&Value>) -> Result<Self> {
+        // the key casing differs between versions
+        let get = |info: &Value, keys: &[&str]| -> Option<String> {
+            keys.iter()
+                .find_map(|key| info[key].as_str())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_owned())
+        };
+        let provider = &provider_info["provider"];
+        let consumer = &provider_info["consumer"];
+        if provider.is_null() && consumer.is_null() {
+            return Err(Error::from(format!(
+                "unexpected `query ccvconsumer provider-info` result: {provider_info}"
+            )))
+        }
+        let pending_packets = throttle_state.and_then(|state| {
+            state["packet_data_queue"]
+                .as_array()
+                .map(|a| a.len())
+                .or_else(|| state["packet_data_queue"].is_null().then_some(0))
+        });
+        Ok(Self {
+            provider_chain_id: get(provider, &["chainID", "chain_id"]),
+            provider_channel_id: get(provider, &["channelID", "channel_id"]),
+            consumer_channel_id: get(consumer, &["channelID", "channel_id"]),
+            pending_packets,
+        })
+    }
+
+    /// Returns an error if the consumer side ICS channel is not
+    /// `expected_channel` (e.x. the `ics_channel` of the consumer [IbcSide])
+    pub fn assert_consumer_channel(&self, expected_channel: &str) -> Result<()> {
+        if self.consumer_channel_id.as_deref() != Some(expected_channel) {
+            return Err(Error::from(format!(
+                "CcvConsumerState::assert_consumer_channel expected {expected_channel}: {self:?}"
+            )))
+        }
+        Ok(())
+    }
+
+    /// Returns an error if there are packets queued for the provider. Passes
+    /// if the version does not report the queue.
+    pub fn assert_no_pending_packets(&self) -> Result<()> {
+        if let Some(n) = self.pending_packets {
+            if n != 0 {
+                return Err(Error::from(format!(
+                    "CcvConsumerState::assert_no_pending_packets has {n} pending packets: {self:?}"
+                )))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Queries the ICS state of the local consumer chain
+pub async fn cosmovisor_query_ccvconsumer() -> Result<CcvConsumerState> {
+    let provider_info = sh_cosmovisor_no_dbg("query ccvconsumer provider-info", &[])
+        .await
+        .map_add_err(|| "cosmovisor_query_ccvconsumer")?;
+    let provider_info = yaml_str_to_json_value(&provider_info)?;
+    // not all versions have this query
+    let throttle_state = match sh_cosmovisor_no_dbg("query ccvconsumer throttle-state", &[]).await {
+        Ok(s) => Some(yaml_str_to_json_value(&s)?),
+        Err(_) => None,
+    };
+    CcvConsumerState::from_queries(&provider_info, throttle_state.as_ref())
+}
+
+/// Parses the chain ids out of the result of `query provider
+/// list-consumer-chains`
+pub fn parse_consumer_chains(res: &Value) -> Result<Vec<String>> {
+    let chains = &res["chains"];
+    if chains.is_null() {
+        return Ok(vec![])
+    }
+    let err = || format!("unexpected `query provider list-consumer-chains` result: {res}");
+    let mut ids = vec![];
+    for chain in chains.as_array().map_add_err(err)? {
+        ids.push(chain["chain_id"].as_str().map_add_err(err)?.to_owned());
+    }
+    Ok(ids)
+}
+
+/// Returns the chain ids of the consumers of the local provider chain
+pub async fn cosmovisor_list_consumer_chains() -> Result<Vec<String>> {
+    let res = sh_cosmovisor_no_dbg("query provider list-consumer-chains", &[])
+        .await
+        .map_add_err(|| "cosmovisor_list_consumer_chains")?;
+    parse_consumer_chains(&yaml_str_to_json_value(&res)?)
+}
+
+/// Waits for `consumer_id` to no longer be a consumer of the local provider
+/// chain, e.x. after a `consumer-removal` proposal
+pub async fn wait_for_consumer_removed(consumer_id: &str, timeout: Duration) -> Result<()> {
+    poll_until(
+        cosmovisor_list_consumer_chains,
+        |chains| !chains.iter().any(|id| id == consumer_id),
+        timeout,
+        STD_DELAY,
+    )
+    .await
+    .map_add_err(|| format!("wait_for_consumer_removed(consumer_id: {consumer_id})"))?;
+    Ok(())
+}
+
+/// Parses the state (e.x. "STATE_OPEN" or "STATE_CLOSED") out of the result of
+/// `query ibc channel end`
+pub fn parse_channel_state(res: &Value) -> Result<String> {
+    res["channel"]["state"]
+        .as_str()
+        .map(|s| s.to_owned())
+        .map_add_err(|| format!("unexpected `query ibc channel end` result: {res}"))
+}
+
+/// Returns the state of `port`/`channel` on the local chain
+pub async fn cosmovisor_channel_state(port: &str, channel: &str) -> Result<String> {
+    let res = sh_cosmovisor_no_dbg("query ibc channel end", &[port, channel])
+        .await
+        .map_add_err(|| format!("cosmovisor_channel_state(port: {port}, channel: {channel})"))?;
+    parse_channel_state(&yaml_str_to_json_value(&res)?)
+}
+
+/// The same as [cosmovisor_channel_state] but for the chain served at `node`
+/// (e.x. "tcp://onomyd:26657")
+pub async fn cosmovisor_channel_state_at(node: &str, port: &str, channel: &str) -> Result<String> {
+    let res = sh_cosmovisor_no_dbg("query ibc channel end", &[port, channel, "--node", node])
+        .await
+        .map_add_err(|| {
+            format!("cosmovisor_channel_state_at(node: {node}, port: {port}, channel: {channel})")
+        })?;
+    parse_channel_state(&yaml_str_to_json_value(&res)?)
+}
+
+/// This should be run from a consumer after the provider served at
+/// `provider_node` (e.x. "tcp://onomyd:26657") removed it. Returns an error
+/// unless the ccv state of the consumer still refers to the provider, and the
+/// ICS channel it has recorded is closed on the provider side. Note that the
+/// consumer side of the channel only closes if the close handshake is
+/// relayed, after which the consumer halts.
+pub async fn assert_consumer_removed(provider_node: &str) -> Result<CcvConsumerState> {
+    let err = || format!("assert_consumer_removed(provider_node: {provider_node})");
+    let ccv_state = cosmovisor_query_ccvconsumer().await.map_add_err(err)?;
+    let provider_channel = ccv_state.provider_channel_id.as_deref().map_add_err(|| {
+        format!(
+            "{} the consumer has no provider channel: {ccv_state:?}",
+            err()
+        )
+    })?;
+    let state = cosmovisor_channel_state_at(provider_node, "provider", provider_channel)
+        .await
+        .map_add_err(err)?;
+    if state != "STATE_CLOSED" {
+        return Err(Error::from(format!(
+            "{} the provider channel {provider_channel} is {state}",
+            err()
+        )))
+    }
+    Ok(ccv_state)
+}
+
+/// Parses the ordering (e.x. "ORDER_ORDERED" or "ORDER_UNORDERED") out of the
+/// result of `query ibc channel end`
+pub fn parse_channel_ordering(res: &Value) -> Result<String> {
+    res["channel"]["ordering"]
+        .as_str()
+        .map(|s| s.to_owned())
+        .map_add_err(|| format!("unexpected `query ibc channel end` result: {res}"))
+}
+
+/// Returns the ordering of `port`/`channel` on the local chain
+pub async fn cosmovisor_channel_ordering(port: &str, channel: &str) -> Result<String> {
+    let res = sh_cosmovisor_no_dbg("query ibc channel end", &[port, channel])
+        .await
+        .map_add_err(|| format!("cosmovisor_channel_ordering(port: {port}, channel: {channel})"))?;
+    parse_channel_ordering(&yaml_str_to_json_value(&res)?)
+}
+
+/// Waits for `port`/`channel` on the local chain to have `state`, e.x.
+/// "STATE_CLOSED" for the ICS channel after the consumer was removed
+pub async fn wait_for_channel_state(
+    port: &str,
+    channel: &str,
+    state: &str,
+    timeout: Duration,
+) -> Result<()> {
+    poll_until(
+        || cosmovisor_channel_state(port, channel),
+        |s| s == state,
+        timeout,
+        STD_DELAY,
+    )
+    .await
+    .map_add_err(|| {
+        format!("wait_for_channel_state(port: {port}, channel: {channel}, state: {state})")
+    })?;
+    Ok(())
+}
+
+/// A VSC matured packet that the consumer has queued for the provider
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VscPacket {
+    pub valset_update_id: u64,
+}
+
+/// Parses the VSC matured packets from the `packet_data_queue` of a `query
+/// ccvconsumer throttle-state` result, ignoring slash packets
+pub fn parse_pending_vsc(throttle_state: &Value) -> Result<Vec<VscPacket>> {
+    let queue = &throttle_state["packet_data_queue"];
+    if queue.is_null() {
+        return Ok(vec![])
+    }
+    let queue = queue
+        .as_array()
+        .map_add_err(|| format!("unexpected `packet_data_queue`: {throttle_state}"))?;
+    let mut res = vec![];
+    for packet in queue {
+        // the key casing differs between versions
+        let data = ["vscMaturedPacketData", "vsc_matured_packet_data"]
+            .iter()
+            .map(|key| &packet[key])
+            .find(|data| !data.is_null());
+        if let Some(data) = data {
+            let id = json_inner(&data["valset_update_id"]);
+            res.push(VscPacket {
+                valset_update_id: id
+                    .parse::<u64>()
+                    .map_add_err(|| format!("unexpected VSC matured packet: {packet}"))?,
+            });
+        }
+    }
+    Ok(res)
+}
+
+/// Returns the VSC matured packets that the local consumer chain has queued
+/// but not yet sent to the provider
+pub async fn cosmovisor_query_pending_vsc() -> Result<Vec<VscPacket>> {
+    let throttle_state = sh_cosmovisor_no_dbg("query ccvconsumer throttle-state", &[])
+        .await
+        .map_add_err(|| "cosmovisor_query_pending_vsc")?;
+    parse_pending_vsc(&yaml_str_to_json_value(&throttle_state)?)
+}
+
+/// Returns the number of packets sent on `port`/`channel` of the local chain
+/// that have not been acknowledged or timed out yet
+pub async fn cosmovisor_num_packet_commitments(port: &str, channel: &str) -> Result<usize> {
+    let res = sh_cosmovisor_no_dbg("query ibc channel packet-commitments", &[port, channel])
+        .await
+        .map_add_err(|| {
+            format!("cosmovisor_num_packet_commitments(port: {port}, channel: {channel})")
+        })?;
+    let res = yaml_str_to_json_value(&res)?;
+    let commitments = &res["commitments"];
+    if commitments.is_null() {
+        return Ok(0)
+    }
+    Ok(commitments
+        .as_array()
+        .map_add_err(|| format!("unexpected packet-commitments result: {res}"))?
+        .len())
+}
+
+/// Waits on the local consumer chain until there are no queued VSC matured
+/// packets and every packet sent on the ICS channel has been acknowledged by
+/// the provider. Note that a VSC only matures after the consumer unbonding
+/// period, VSCs that have not matured yet are not visible to this.
+pub async fn wait_for_vsc_maturity(timeout: Duration) -> Result<()> {
+    let channel = cosmovisor_query_ccvconsumer()
+        .await?
+        .consumer_channel_id
+        .map_add_err(|| "wait_for_vsc_maturity the consumer has no ICS channel yet")?;
+    let pending = || async {
+        Ok((
+            cosmovisor_query_pending_vsc().await?,
+            cosmovisor_num_packet_commitments("consumer", &channel).await?,
+        ))
+    };
+    poll_until(
+        pending,
+        |(vsc, unacked)| vsc.is_empty() && (*unacked == 0),
+        timeout,
+        STD_DELAY,
+    )
+    .await
+    .map_add_err(|| "wait_for_vsc_maturity")?;
+    Ok(())
+}
+
+/// Checks that the consumer validator set has the same validators as the
+/// provider validator set, with voting powers that differ by at most
+/// `tolerance` to account for rounding. Validators are matched by consensus
+/// public key, and provider validators without power are ignored.
+pub fn assert_valset_matches(
+    provider_valset: &[ValidatorPower],
+    consumer_valset: &[ValidatorPower],
+    tolerance: u64,
+) -> Result<()> {
+    let mut mismatches = vec![];
+    for provider in provider_valset.iter().filter(|v| v.power != 0) {
+        match consumer_valset
+            .iter()
+            .find(|v| v.pub_key == provider.pub_key)
+        {
+            Some(consumer) => {
+                let diff = provider.power.abs_diff(consumer.power);
+                if diff > tolerance {
+                    mismatches.push(format!(
+                        "validator {} has power {} on the provider but {} on the consumer",
+                        provider.pub_key, provider.power, consumer.power
+                    ));
+                }
+            }
+            None => mismatches.push(format!(
+                "validator {} is missing from the consumer",
+                provider.pub_key
+            )),
+        }
+    }
+    for consumer in consumer_valset {
+        if !provider_valset
+            .iter()
+            .any(|v| (v.power != 0) && (v.pub_key == consumer.pub_key))
+        {
+            mismatches.push(format!(
+                "validator {} is on the consumer but not the provider",
+                consumer.pub_key
+            ));
+        }
+    }
+    if !mismatches.is_empty() {
+        return Err(Error::from(format!(
+            "assert_valset_matches(tolerance: {tolerance}) mismatches: {mismatches:#?}"
+        )))
+    }
+    Ok(())
+}
+
+/// Waits up to `num_blocks` blocks of the local provider chain for the
+/// validator set of the consumer at `consumer_node` (e.x.
+/// "tcp://marketd:26657") to match the provider validator set (see
+/// [assert_valset_matches])
+pub async fn wait_for_consumer_valset(
+    consumer_node: &str,
+    tolerance: u64,
+    num_blocks: u64,
+) -> Result<()> {
+    let mut blocks = 0;
+    loop {
+        let provider_valset = cosmovisor_valset().await?;
+        let consumer_valset = cosmovisor_consumer_valset(consumer_node).await?;
+        match assert_valset_matches(&provider_valset, &consumer_valset, tolerance) {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                if blocks >= num_blocks {
+                    return Err(e).map_add_err(|| {
+                        format!(
+                            "wait_for_consumer_valset(consumer_node: {consumer_node}) did not \
+                             match after {num_blocks} blocks"
+                        )
+                    })
+                }
+            }
+        }
+        wait_for_num_blocks(1).await?;
+        blocks += 1;
+    }
+}
+
+/// Tendermint 0.34 base64 encodes event attributes, 0.37 does not
+fn event_attribute(attr: &Value, key: &str) -> Option<String> {
+    let decode = |v: &Value| -> Option<String> {
+        let s = v.as_str()?;
+        base64::engine::general_purpose::STANDARD
+            .decode(s)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+    };
+    if attr["key"] == key {
+        attr["value"].as_str().map(|s| s.to_owned())
+    } else if decode(&attr["key"]).as_deref() == Some(key) {
+        decode(&attr["value"])
+    } else {
+        None
+    }
+}
+
+/// Returns the sequences of the `recv_packet` events for `port`/`channel` in
+/// the order they appear in a `tx_search` result of the Tendermint RPC
+pub fn parse_received_sequences(tx_search: &Value, port: &str, channel: &str) -> Result<Vec<u64>> {
+    let err = || format!("unexpected `tx_search` result: {tx_search}");
+    let mut res = vec![];
+    for tx in tx_search["result"]["txs"].as_array().map_add_err(err)? {
+        let events = tx["tx_result"]["events"].as_array().map_add_err(err)?;
+        for event in events.iter().filter(|event| event["type"] == "recv_packet") {
+            let attrs = event["attributes"].as_array().map_add_err(err)?;
+            let get = |key: &str| attrs.iter().find_map(|attr| event_attribute(attr, key));
+            if (get("packet_dst_port").as_deref() != Some(port))
+                || (get("packet_dst_channel").as_deref() != Some(channel))
+            {
+                continue
+            }
+            let seq = get("packet_sequence").map_add_err(err)?;
+            res.push(seq.parse::<u64>().map_add_err(err)?);
+        }
+    }
+    Ok(res)
+}
+
+/// Returns an error unless the sequences `first..(first + n)` are in
+/// `received` and were received in increasing order
+pub fn check_ordered_delivery(received: &[u64], first: u64, n: u64) -> Result<()> {
+    let received: Vec<u64> = received
+        .iter()
+        .copied()
+        .filter(|seq| (first..(first + n)).contains(seq))
+        .collect();
+    let expected: Vec<u64> = (first..(first + n)).collect();
+    if received != expected {
+        return Err(Error::from(format!(
+            "check_ordered_delivery expected the sequences {expected:?} in order but received \
+             {received:?}"
+        )))
+    }
+    Ok(())
+}
+
+/// Returns the next sequence to be received on `port`/`channel` of the local
+/// chain. This is only maintained for ORDERED channels such as the ICS
+/// channel, on UNORDERED channels such as "transfer" it never advances and
+/// packet receipts have to be used instead.
+pub async fn cosmovisor_next_sequence_receive(port: &str, channel: &str) -> Result<u64> {
+    let res = sh_cosmovisor_no_dbg("query ibc channel next-sequence-receive", &[port, channel])
+        .await
+        .map_add_err(|| {
+            format!("cosmovisor_next_sequence_receive(port: {port}, channel: {channel})")
+        })?;
+    parse_next_sequence_receive(&res)
+}
+
+/// The same as [cosmovisor_next_sequence_receive] but for the chain served at
+/// `node` (e.x. "tcp://marketd:26657"), likewise only for ORDERED channels
+pub async fn cosmovisor_next_sequence_receive_at(
+    node: &str,
+    port: &str,
+    channel: &str,
+) -> Result<u64> {
+    let res = sh_cosmovisor_no_dbg("query ibc channel next-sequence-receive", &[
+        port, channel, "--node", node,
+    ])
+    .await
+    .map_add_err(|| {
+        format!(
+            "cosmovisor_next_sequence_receive_at(node: {node}, port: {port}, channel: {channel})"
+        )
+    })?;
+    parse_next_sequence_receive(&res)
+}
+
+/// Returns if the packet with `sequence` sent to `port`/`channel` of the chain
+/// served at `node` (e.x. "tcp://marketd:26657") has been received. Unlike
+/// [cosmovisor_next_sequence_receive_at] this works for UNORDERED channels
+/// such as "transfer".
+pub async fn cosmovisor_packet_received_at(
+    node: &str,
+    port: &str,
+    channel: &str,
+    sequence: u64,
+) -> Result<bool> {
+    let res = sh_cosmovisor_no_dbg("query ibc channel packet-receipt", &[
+        port,
+        channel,
+        &sequence.to_string(),
+        "--node",
+        node,
+    ])
+    .await
+    .map_add_err(|| {
+        format!(
+            "cosmovisor_packet_received_at(node: {node}, port: {port}, channel: {channel}, \
+             sequence: {sequence})"
+        )
+    })?;
+    parse_packet_receipt(&res)
+}
+
+fn parse_packet_receipt(res: &str) -> Result<bool> {
+    let res = yaml_str_to_json_value(res)?;
+    match &res["received"] {
+        Value::Bool(received) => Ok(*received),
+        // absent when false with some versions
+        Value::Null => Ok(false),
+        received => json_inner(received)
+            .parse::<bool>()
+            .map_err(|e| Error::boxed(Box::new(e)))
+            .map_add_err(|| format!("unexpected packet-receipt result: {res}")),
+    }
+}
+
+fn parse_next_sequence_receive(res: &str) -> Result<u64> {
+    let res = yaml_str_to_json_value(res)?;
+    json_inner(&res["next_sequence_receive"])
+        .parse::<u64>()
+        .map_add_err(|| format!("unexpected next-sequence-receive result: {res}"))
+}
+
+/// Returns the address of the account that escrows the tokens sent out over
+/// `port`/`channel` of the local chain
+pub async fn cosmovisor_escrow_address(port: &str, channel: &str) -> Result<String> {
+    let res = sh_cosmovisor_no_dbg("query ibc-transfer escrow-address", &[port, channel])
+        .await
+        .map_add_err(|| format!("cosmovisor_escrow_address(port: {port}, channel: {channel})"))?;
+    Ok(res.trim().to_owned())
+}
+
+/// Waits for the `base_denom` escrowed for `port`/`channel` of the local chain
+/// to equal the total supply of the corresponding `ibc_denom` on the
+/// counterparty chain at `counterparty_node` (e.x. "tcp://marketd:26657"), and
+/// returns the escrowed amount. The vouchers are only minted and burned against
+/// the escrow, so this holds exactly whenever no transfer is in flight.
+pub async fn wait_for_escrow_matching_supply(
+    port: &str,
+    channel: &str,
+    base_denom: &str,
+    counterparty_node: &str,
+    ibc_denom: &str,
+    timeout: Duration,
+) -> Result<u128> {
+    let escrow = &cosmovisor_escrow_address(port, channel).await?;
+    let (escrowed, _) = poll_until(
+        move || escrowed_and_supply(escrow, base_denom, counterparty_node, ibc_denom),
+        |(escrowed, supply)| escrowed == supply,
+        timeout,
+        STD_DELAY,
+    )
+    .await
+    .map_add_err(|| {
+        format!(
+            "wait_for_escrow_matching_supply(port: {port}, channel: {channel}, base_denom: \
+             {base_denom}, ibc_denom: {ibc_denom}) the escrow of {escrow} never matched the \
+             counterparty supply"
+        )
+    })?;
+    Ok(escrowed)
+}
+
+async fn escrowed_and_supply(
+    escrow: &str,
+    base_denom: &str,
+    counterparty_node: &str,
+    ibc_denom: &str,
+) -> Result<(u128, u128)> {
+    let escrowed = cosmovisor_get_balance(escrow, base_denom).await?;
+    let supply = cosmovisor_total_supply_at(counterparty_node)
+        .await?
+        .get(ibc_denom)
+        .copied()
+        .unwrap_or(0);
+    Ok((escrowed, supply))
+}
+
+/// Waits for the `denom` balance of `sender` to become exactly `amount`,
+/// which is returned by [IbcSide::ibc_transfer_expecting_timeout]. This
+/// should be called after the relayer has been restarted. `sender` should be
+/// an account that nothing else uses in the meantime, in particular not the
+/// key of the relayer which pays fees for relaying the timeout.
+pub async fn assert_refunded(
+    sender: &str,
+    amount: u128,
+    denom: &str,
+    timeout: Duration,
+) -> Result<()> {
+    wait_for_balance(sender, denom, |balance| balance == amount, timeout)
+        .await
+        .map_add_err(|| {
+            format!(
+                "assert_refunded(sender: {sender}, amount: {amount}{denom}) the escrowed tokens \
+                 were not refunded"
+            )
+        })?;
+    Ok(())
+}
+
 impl IbcSide {
     /// This call needs to be made on the source side
     pub async fn cosmovisor_ibc_transfer_with_flags(
@@ -19,22 +1068,35 @@ impl IbcSide {
         coins_to_send: &str,
         flags: &[&str],
     ) -> Result<()> {
+        self.ibc_transfer_tx(target_addr, coins_to_send, flags)
+            .await?;
+        Ok(())
+    }
+
+    async fn ibc_transfer_tx(
+        &self,
+        target_addr: &str,
+        coins_to_send: &str,
+        flags: &[&str],
+    ) -> Result<Value> {
         // note: tx ibc-transfer --help is wrong, it should be
         // tx ibc-transfer transfer transfer [channel to right chain]
         // [target cosmos addr] [coins to send] [gas flags] --from [source key name]
 
-        sh_cosmovisor_tx(
+        let tx_res = sh_cosmovisor_tx(
             "ibc-transfer transfer transfer",
             &[&[&self.transfer_channel, target_addr, coins_to_send], flags].concat(),
         )
         .await?;
+        for data in sent_packet_data(&tx_res) {
+            info!("sent packet over {}: {data}", self.transfer_channel);
+        }
 
-        Ok(())
+        Ok(tx_res)
     }
 
     /// Sends `denom` and uses same `denom` for gas. Uses the flags
-    /// "-b block --gas auto --gas-adjustment 1.3 --gas-prices 1{denom} --from
-    /// {from_key}"
+    /// "-b block --from {from_key}" and [GasConfig::auto] with `denom`
     pub async fn cosmovisor_ibc_transfer(
         &self,
         from_key: &str,
@@ -42,46 +1104,501 @@ impl IbcSide {
         amount: &str,
         denom: &str,
     ) -> Result<()> {
+        self.cosmovisor_ibc_transfer_with_gas(
+            from_key,
+            target_addr,
+            &format!("{amount}{denom}"),
+            &GasConfig::auto(denom),
+        )
+        .await
+    }
+
+    /// Sends `coins_to_send` with the gas and fees determined by `gas`. Use
+    /// [GasConfig::MinGasPrice] to pay fees in whatever denom the source chain
+    /// currently accepts.
+    pub async fn cosmovisor_ibc_transfer_with_gas(
+        &self,
+        from_key: &str,
+        target_addr: &str,
+        coins_to_send: &str,
+        gas: &GasConfig,
+    ) -> Result<()> {
+        let gas_flags = gas.to_flags().await?;
+        let mut flags: Vec<&str> = vec!["-y", "-b", "block", "--from", from_key];
+        flags.extend(gas_flags.iter().map(|s| s.as_str()));
+        self.cosmovisor_ibc_transfer_with_flags(target_addr, coins_to_send, &flags)
+            .await
+    }
+
+    /// The same as [IbcSide::cosmovisor_ibc_transfer_with_gas] but returns the
+    /// sequence of the sent packet, see [cosmovisor_packet_received_at]
+    pub async fn cosmovisor_ibc_transfer_for_sequence(
+        &self,
+        from_key: &str,
+        target_addr: &str,
+        coins_to_send: &str,
+        gas: &GasConfig,
+    ) -> Result<u64> {
+        let gas_flags = gas.to_flags().await?;
+        let mut flags: Vec<&str> = vec!["-y", "-b", "block", "--from", from_key];
+        flags.extend(gas_flags.iter().map(|s| s.as_str()));
+        let tx_res = self
+            .ibc_transfer_tx(target_addr, coins_to_send, &flags)
+            .await?;
+        sent_packet_sequences(&tx_res)
+            .first()
+            .copied()
+            .map_add_err(|| format!("no packet was sent by {tx_res}"))
+    }
+
+    /// The same as [IbcSide::cosmovisor_ibc_transfer_with_gas] but also sets
+    /// `--memo`, use [pfm_memo] to construct a multi-hop transfer
+    pub async fn cosmovisor_ibc_transfer_with_memo(
+        &self,
+        from_key: &str,
+        target_addr: &str,
+        coins_to_send: &str,
+        memo: &str,
+        gas: &GasConfig,
+    ) -> Result<()> {
+        let gas_flags = gas.to_flags().await?;
+        let mut flags: Vec<&str> = vec!["-y", "-b", "block", "--from", from_key, "--memo", memo];
+        flags.extend(gas_flags.iter().map(|s| s.as_str()));
+        self.cosmovisor_ibc_transfer_with_flags(target_addr, coins_to_send, &flags)
+            .await
+    }
+
+    /// Sends `amount` of `denom` from `sender_addr` with a packet timeout of
+    /// `timeout` from now and no timeout height, then sleeps until the packet
+    /// has timed out. The relayer should be stopped before calling this,
+    /// otherwise the packet may be received before the timeout. Returns the
+    /// balance that `sender_addr` should have after the timeout is relayed
+    /// and the escrowed tokens are refunded (see [assert_refunded]).
+    pub async fn ibc_transfer_expecting_timeout(
+        &self,
+        sender_addr: &str,
+        target_addr: &str,
+        amount: u128,
+        denom: &str,
+        timeout: Duration,
+        gas: &GasConfig,
+    ) -> Result<u128> {
         let coins_to_send = format!("{amount}{denom}");
-        let base = format!("1{denom}");
-        sh_cosmovisor_tx("ibc-transfer transfer transfer", &[
+        let timeout_ns = format!("{}", timeout.as_nanos());
+        let gas_flags = gas.to_flags().await?;
+        let mut flags: Vec<&str> = vec![
+            "-y",
+            "-b",
+            "block",
+            "--from",
+            sender_addr,
+            "--packet-timeout-height",
+            "0-0",
+            "--packet-timeout-timestamp",
+            &timeout_ns,
+        ];
+        flags.extend(gas_flags.iter().map(|s| s.as_str()));
+        self.cosmovisor_ibc_transfer_with_flags(target_addr, &coins_to_send, &flags)
+            .await
+            .map_add_err(|| {
+                format!(
+                    "IbcSide::ibc_transfer_expecting_timeout(coins: {coins_to_send}, timeout: \
+                     {timeout:?})"
+                )
+            })?;
+        // the escrow and any fees have been taken at this point
+        let balance = cosmovisor_get_balance(sender_addr, denom).await?;
+        sleep(timeout).await;
+        Ok(balance + amount)
+    }
+
+    /// Escrows ICS-29 relayer fees for the already sent transfer packet with
+    /// `sequence`. The fees are paid to the payee of the relayer (see
+    /// [IbcPair::register_counterparty_payee]) when the packet is relayed.
+    pub async fn cosmovisor_pay_packet_fee(
+        &self,
+        from_key: &str,
+        sequence: u64,
+        recv_fee: &str,
+        ack_fee: &str,
+        timeout_fee: &str,
+        gas: &GasConfig,
+    ) -> Result<()> {
+        let gas_flags = gas.to_flags().await?;
+        let sequence = format!("{sequence}");
+        let mut args = vec![
+            "transfer",
             &self.transfer_channel,
-            target_addr,
-            &coins_to_send,
+            &sequence,
+            "--recv-fee",
+            recv_fee,
+            "--ack-fee",
+            ack_fee,
+            "--timeout-fee",
+            timeout_fee,
             "-y",
             "-b",
             "block",
-            "--gas",
-            "auto",
-            "--gas-adjustment",
-            "1.3",
-            "--gas-prices",
-            &base,
             "--from",
             from_key,
-        ])
-        .await?;
+        ];
+        args.extend(gas_flags.iter().map(|s| s.as_str()));
+        sh_cosmovisor_tx("ibc-fee pay-packet-fee", &args)
+            .await
+            .map_add_err(|| format!("cosmovisor_pay_packet_fee(sequence: {sequence})"))?;
+        Ok(())
+    }
 
+    /// Returns the channel of this side bound to `port`, "transfer" for the
+    /// `transfer_channel` and "consumer" or "provider" for the `ics_channel`
+    pub fn channel_for_port(&self, port: &str) -> Option<&str> {
+        match port {
+            "transfer" => Some(&self.transfer_channel),
+            "consumer" | "provider" => Some(&self.ics_channel),
+            _ => None,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "chain_id": self.chain_id,
+            "connection": self.connection,
+            "transfer_channel": self.transfer_channel,
+            "ics_channel": self.ics_channel,
+        })
+    }
+
+    fn from_json(side: &Value) -> Result<Self> {
+        let field = |key: &str| {
+            side[key]
+                .as_str()
+                .map(|s| s.to_owned())
+                .map_add_err(|| format!("IbcSide is missing \"{key}\": {side}"))
+        };
+        Ok(Self {
+            chain_id: field("chain_id")?,
+            connection: field("connection")?,
+            transfer_channel: field("transfer_channel")?,
+            ics_channel: field("ics_channel")?,
+        })
+    }
+
+    /// Returns an error if the chain does not trace `ibc_denom` back to
+    /// `base_denom` received over the `transfer_channel` of this side
+    pub async fn assert_denom_trace(&self, ibc_denom: &str, base_denom: &str) -> Result<()> {
+        let expected = DenomTrace {
+            path: format!("transfer/{}", self.transfer_channel),
+            base_denom: base_denom.to_owned(),
+        };
+        let trace = cosmovisor_denom_trace(ibc_denom).await?;
+        if trace != expected {
+            return Err(Error::from(format!(
+                "IbcSide::assert_denom_trace {ibc_denom} traces to {trace} instead of {expected}"
+            )))
+        }
         Ok(())
     }
 
+    /// Returns an error unless the denom trace store of the chain has exactly
+    /// one trace for `base_denom` received over the `transfer_channel` of this
+    /// side. Call this after multiple transfers to check that they reused the
+    /// trace of the first.
+    pub async fn assert_single_denom_trace(&self, base_denom: &str) -> Result<()> {
+        let traces = cosmovisor_denom_traces().await?;
+        let path = format!("transfer/{}", self.transfer_channel);
+        check_single_denom_trace(&traces, &path, base_denom)
+            .map_add_err(|| "IbcSide::assert_single_denom_trace")
+    }
+
     pub async fn get_ibc_denom(&self, leaf_denom: &str) -> Result<String> {
         let hash = sh_cosmovisor_no_dbg("query ibc-transfer denom-hash", &[&format!(
             "transfer/{}/{}",
             self.transfer_channel, leaf_denom
         )])
         .await
-        .map_add_err(|| ())?;
-        let hash = get_separated_val(&hash, "\n", "hash", ":").map_add_err(|| ())?;
+        .map_add_err(|| format!("get_ibc_denom(leaf_denom: {leaf_denom})"))?;
+        let hash = get_separated_val(&hash, "\n", "hash", ":").locate()?;
         Ok(format!("ibc/{hash}"))
     }
 }
 
 impl IbcPair {
+    pub fn to_json_string(&self) -> String {
+        json!({ "a": self.a.to_json(), "b": self.b.to_json() }).to_string()
+    }
+
+    pub fn from_json_str(s: &str) -> Result<Self> {
+        let pair: Value =
+            serde_json::from_str(s).map_add_err(|| format!("IbcPair is not valid json: {s}"))?;
+        Ok(Self {
+            a: IbcSide::from_json(&pair["a"])?,
+            b: IbcSide::from_json(&pair["b"])?,
+        })
+    }
+
+    /// Writes the connection and channel ids to `path`, so that the pair can be
+    /// reconstructed with [IbcPair::load] after a relayer restart without
+    /// rerunning the handshakes
+    pub async fn persist(&self, path: &str) -> Result<()> {
+        FileOptions::write_str(path, &self.to_json_string())
+            .await
+            .map_add_err(|| format!("IbcPair::persist(path: {path})"))
+    }
+
+    pub async fn load(path: &str) -> Result<Self> {
+        let s = FileOptions::read_to_string(path)
+            .await
+            .map_add_err(|| format!("IbcPair::load(path: {path})"))?;
+        Self::from_json_str(&s)
+    }
+
+    /// Returns the `(source, destination)` sides for packets sent from the
+    /// chain `src_chain_id`
+    pub fn sides_from(&self, src_chain_id: &str) -> Result<(&IbcSide, &IbcSide)> {
+        if self.a.chain_id == src_chain_id {
+            Ok((&self.a, &self.b))
+        } else if self.b.chain_id == src_chain_id {
+            Ok((&self.b, &self.a))
+        } else {
+            Err(Error::from(format!(
+                "IbcPair::sides_from(src_chain_id: {src_chain_id}) is not a chain of {self:?}"
+            )))
+        }
+    }
+
+    /// Sends `coin` from `from_key` on the local chain `src_chain_id` to
+    /// `to_addr` on the other chain, and returns the time it took for the
+    /// packet to be received on the other chain according to its packet
+    /// receipt. The destination chain is queried at "tcp://{chain_id}d:26657",
+    /// the same as the Hermes config assumes. Returns an error if the packet
+    /// was not received within `timeout`.
+    pub async fn measure_relay_latency(
+        &self,
+        src_chain_id: &str,
+        from_key: &str,
+        to_addr: &str,
+        coin: &str,
+        timeout: Duration,
+    ) -> Result<Duration> {
+        let (src, dst) = self.sides_from(src_chain_id)?;
+        let dst_node = &format!("tcp://{}d:{RPC_PORT}", dst.chain_id);
+        let send_height = get_block_height().await?;
+        let start = Instant::now();
+        let denom = parse_coins(coin)?
+            .into_keys()
+            .next()
+            .map_add_err(|| format!("measure_relay_latency coin {coin} has no denom"))?;
+        let sequence = src
+            .cosmovisor_ibc_transfer_for_sequence(from_key, to_addr, coin, &GasConfig::auto(&denom))
+            .await?;
+        poll_until(
+            move || {
+                cosmovisor_packet_received_at(dst_node, "transfer", &dst.transfer_channel, sequence)
+            },
+            |received| *received,
+            timeout,
+            Duration::from_millis(100),
+        )
+        .await
+        .map_add_err(|| {
+            format!(
+                "IbcPair::measure_relay_latency(src_chain_id: {src_chain_id}, coin: {coin}) \
+                 packet {sequence} was not received"
+            )
+        })?;
+        let latency = start.elapsed();
+        info!(
+            "packet sent at height {send_height} on {src_chain_id} was received on {} after \
+             {latency:?}",
+            dst.chain_id
+        );
+        Ok(latency)
+    }
+
+    /// Returns the next sequence to be received by the other side from `port`
+    /// of the chain `src_chain_id`. Packets sent afterwards start at this
+    /// sequence. This is only maintained for ORDERED channels.
+    pub async fn next_sequence_from(&self, src_chain_id: &str, port: &str) -> Result<u64> {
+        let (src, dst) = self.sides_from(src_chain_id)?;
+        let (dst_port, dst_channel) = Self::counterparty(src, dst, port)?;
+        let dst_node = &format!("tcp://{}d:{RPC_PORT}", dst.chain_id);
+        cosmovisor_next_sequence_receive_at(dst_node, dst_port, dst_channel).await
+    }
+
+    fn counterparty<'a>(
+        src: &IbcSide,
+        dst: &'a IbcSide,
+        port: &str,
+    ) -> Result<(&'static str, &'a str)> {
+        let dst_port = match port {
+            "transfer" => "transfer",
+            "consumer" => "provider",
+            "provider" => "consumer",
+            _ => {
+                return Err(Error::from(format!(
+                    "IbcPair has no channel for port {port}"
+                )))
+            }
+        };
+        // make sure `src` is actually bound on this port
+        src.channel_for_port(port)
+            .map_add_err(|| format!("{} has no channel for port {port}", src.chain_id))?;
+        let dst_channel = dst
+            .channel_for_port(dst_port)
+            .map_add_err(|| format!("{} has no channel for port {dst_port}", dst.chain_id))?;
+        Ok((dst_port, dst_channel))
+    }
+
+    /// Sends `n` packets over the ORDERED ICS channel from the provider chain
+    /// `src_chain_id`, which has to be the local chain, and returns an error
+    /// if the other side did not receive them in order within `timeout`. This
+    /// checks the semantics of the ordered channel and that the relayer does
+    /// not reorder packets. Each packet is the VSC packet of delegating `coin`
+    /// from `from_key` to `valoper_addr` in its own block, which assumes the
+    /// provider sends a VSC packet at the end of every block with validator
+    /// updates. The destination RPC is at "http://{chain_id}d:26657".
+    pub async fn assert_ordered_delivery(
+        &self,
+        src_chain_id: &str,
+        from_key: &str,
+        valoper_addr: &str,
+        coin: &str,
+        n: u64,
+        timeout: Duration,
+    ) -> Result<()> {
+        let port = "provider";
+        let err = || {
+            format!(
+                "IbcPair::assert_ordered_delivery(src_chain_id: {src_chain_id}, coin: {coin}, n: \
+                 {n})"
+            )
+        };
+        let (src, dst) = self.sides_from(src_chain_id)?;
+        let ordering = cosmovisor_channel_ordering(port, &src.ics_channel)
+            .await
+            .map_add_err(err)?;
+        if ordering != "ORDER_ORDERED" {
+            return Err(Error::from(format!(
+                "{} channel {} is {ordering} instead of ORDER_ORDERED",
+                err(),
+                src.ics_channel
+            )))
+        }
+        if n == 0 {
+            return Ok(())
+        }
+        let (dst_port, dst_channel) = Self::counterparty(src, dst, port)?;
+        // packets that are still in flight are part of the same ordered stream and
+        // are checked along with the new ones
+        let first_sequence = self
+            .next_sequence_from(src_chain_id, port)
+            .await
+            .map_add_err(err)?;
+        let denom = parse_coins(coin)?
+            .into_keys()
+            .next()
+            .map_add_err(|| format!("{} coin has no denom", err()))?;
+        for i in 0..n {
+            cosmovisor_delegate(from_key, valoper_addr, coin, &GasConfig::auto(&denom))
+                .await
+                .map_add_err(err)?;
+            info!("sent packet {i} of {n} over {}", src.ics_channel);
+            // the next validator set change has to be in a different VSC packet
+            wait_for_num_blocks(1).await.map_add_err(err)?;
+        }
+        poll_until(
+            move || self.next_sequence_from(src_chain_id, port),
+            |seq| *seq >= (first_sequence + n),
+            timeout,
+            Duration::from_millis(500),
+        )
+        .await
+        .map_add_err(|| format!("{} packets were not received", err()))?;
+        let query = format!(
+            "query=\"recv_packet.packet_dst_port='{dst_port}' AND \
+             recv_packet.packet_dst_channel='{dst_channel}'\""
+        );
+        let url = format!("http://{}d:{RPC_PORT}/tx_search", dst.chain_id);
+        let mut received = vec![];
+        let mut page: u64 = 1;
+        // the results are in ascending order of height, so this only needs to go
+        // until the last packet is seen
+        while !received.contains(&(first_sequence + n - 1)) {
+            let page_s = format!("page={page}");
+            let res = sh_no_dbg("curl -s --fail -G", &[
+                &url,
+                "--data-urlencode",
+                &query,
+                "--data-urlencode",
+                "per_page=100",
+                "--data-urlencode",
+                "order_by=\"asc\"",
+                "--data-urlencode",
+                &page_s,
+            ])
+            .await
+            .map_add_err(err)?;
+            let res: Value = serde_json::from_str(&res).map_add_err(err)?;
+            received
+                .extend(parse_received_sequences(&res, dst_port, dst_channel).map_add_err(err)?);
+            // Tendermint errors on pages past the end
+            let total = json_inner(&res["result"]["total_count"])
+                .parse::<u64>()
+                .map_add_err(err)?;
+            if (page * 100) >= total {
+                break
+            }
+            page += 1;
+        }
+        check_ordered_delivery(&received, first_sequence, n).map_add_err(err)
+    }
+
+    /// Restarts the daemon of `runner` (the daemon of the `src_chain_id` side,
+    /// which must be running in this container) with a new log at
+    /// "{src_chain_id}d_restarted_runner.log", and then checks that a transfer
+    /// of `coin` is relayed within `timeout` by Hermes without Hermes
+    /// itself being restarted, by waiting for the packet receipt of the
+    /// transfer on the other side (see [IbcPair::measure_relay_latency]). This
+    /// catches daemon restarts that orphan the relayer. Returns the relay
+    /// latency after the restart.
+    pub async fn assert_relays_after_restart(
+        &self,
+        src_chain_id: &str,
+        runner: &mut CosmovisorRunner,
+        from_key: &str,
+        to_addr: &str,
+        coin: &str,
+        timeout: Duration,
+    ) -> Result<Duration> {
+        let err = || format!("IbcPair::assert_relays_after_restart(src_chain_id: {src_chain_id})");
+        info!("restarting the {src_chain_id} daemon while Hermes keeps running");
+        runner.terminate(TIMEOUT).await.map_add_err(err)?;
+        *runner = cosmovisor_start(&format!("{src_chain_id}d_restarted_runner.log"), None)
+            .await
+            .map_add_err(err)?;
+        // make sure the restarted daemon is producing blocks before the transfer
+        wait_for_num_blocks(1).await.map_add_err(err)?;
+        self.measure_relay_latency(src_chain_id, from_key, to_addr, coin, timeout)
+            .await
+            .map_add_err(|| format!("{} Hermes did not resume relaying", err()))
+    }
+
     /// Sets up transfer and consumer-provider IBC channels. This function
     /// assumes ICS setup has been performed, which creates a client pair
-    /// automatically.
+    /// automatically. The handshake steps are retried with the default
+    /// [HandshakeRetry].
     pub async fn hermes_setup_pair(consumer: &str, provider: &str) -> Result<IbcPair> {
+        Self::hermes_setup_pair_with_retry(consumer, provider, &HandshakeRetry::default()).await
+    }
+
+    /// [IbcPair::hermes_setup_pair] with the retry budget of the handshake
+    /// steps set by `retry`. There can only be one ICS channel, so its retries
+    /// resume the handshake of a failed attempt instead of starting a new one.
+    pub async fn hermes_setup_pair_with_retry(
+        consumer: &str,
+        provider: &str,
+        retry: &HandshakeRetry,
+    ) -> Result<IbcPair> {
         // https://hermes.informal.systems/tutorials/local-chains/add-a-new-relay-path.html
 
         // Note: For ICS, there is a point where a handshake must be initiated by the
@@ -94,20 +1611,21 @@ impl IbcPair {
         //let client_pair = create_client_pair(a_chain, b_chain).await?;
         // create one client and connection pair that will be used for IBC transfer and
         // ICS communication
-        let connection_pair = create_connection_pair(&a_chain, &b_chain).await?;
+        let connection_pair = create_connection_pair_with_retry(&a_chain, &b_chain, retry).await?;
 
         // this results in some mismatch errors but we use it for now for speeding up
         // things
-        let tmp = (a_chain.clone(), connection_pair.clone());
+        let tmp = (a_chain.clone(), connection_pair.clone(), *retry);
         let transfer_task = tokio::task::spawn(async move {
-            let (a_chain, connection_pair) = tmp;
+            let (a_chain, connection_pair, retry) = tmp;
             // a_chain<->b_chain transfer<->transfer
-            create_channel_pair(
+            create_channel_pair_with_retry(
                 &a_chain.clone(),
                 &connection_pair.0.clone(),
                 "transfer",
                 "transfer",
                 false,
+                &retry,
             )
             .await
             .unwrap()
@@ -118,8 +1636,21 @@ impl IbcPair {
         sleep(Duration::from_secs(1)).await;
 
         // a_chain<->b_chain consumer<->provider
-        let ics_channel_pair =
-            create_channel_pair(&a_chain, &connection_pair.0, "consumer", "provider", true).await?;
+        let ics_channel_pair = create_or_resume_channel_pair_with_retry(
+            &HandshakeSide {
+                chain: &a_chain,
+                connection: &connection_pair.0,
+                port: "consumer",
+            },
+            &HandshakeSide {
+                chain: &b_chain,
+                connection: &connection_pair.1,
+                port: "provider",
+            },
+            true,
+            retry,
+        )
+        .await?;
 
         let transfer_channel_pair = transfer_task.await?;
 
@@ -141,3 +1672,390 @@ impl IbcPair {
         })
     }
 }
+
+#[test]
+fn test_pfm_memo() {
+    let next = PfmHop {
+        receiver: "onomy1c".to_owned(),
+        port: "transfer".to_owned(),
+        channel: "channel-1".to_owned(),
+        next: None,
+    };
+    let memo = pfm_memo(
+        "onomy1b",
+        "transfer",
+        "channel-0",
+        Some(Box::new(next.clone())),
+    );
+    let v: Value = serde_json::from_str(&memo).unwrap();
+    assert_eq!(v["forward"]["receiver"], "onomy1b");
+    assert_eq!(v["forward"]["channel"], "channel-0");
+    assert_eq!(v["forward"]["next"]["forward"]["channel"], "channel-1");
+    let hop = PfmHop::from_memo(&memo).unwrap();
+    assert_eq!(hop.next, Some(Box::new(next)));
+    assert_eq!(hop.to_memo(), memo);
+    assert!(PfmHop::from_memo(r#"{"forward":{"receiver":"a"}}"#).is_err());
+}
+
+#[test]
+fn test_decode_ibc_packet_data() {
+    let expected = FungibleTokenPacketData {
+        denom: "transfer/channel-0/anom".to_owned(),
+        amount: "1337".to_owned(),
+        sender: "onomy1sender".to_owned(),
+        receiver: "onomy1receiver".to_owned(),
+        memo: String::new(),
+    };
+    let json = concat!(
+        r#"{"amount":"1337","denom":"transfer/channel-0/anom","#,
+        r#""receiver":"onomy1receiver","sender":"onomy1sender"}"#
+    );
+    let base64 = concat!(
+        "eyJhbW91bnQiOiIxMzM3IiwiZGVub20iOiJ0cmFuc2Zlci9jaGFubmVsLTAvYW5vbSIsInJlY2VpdmVy",
+        "Ijoib25vbXkxcmVjZWl2ZXIiLCJzZW5kZXIiOiJvbm9teTFzZW5kZXIifQ==",
+    );
+    let hex = concat!(
+        "7b22616d6f756e74223a2231333337222c2264656e6f6d223a227472616e736665722f6368616e6e",
+        "656c2d302f616e6f6d222c227265636569766572223a226f6e6f6d79317265636569766572222c22",
+        "73656e646572223a226f6e6f6d793173656e646572227d",
+    );
+    for raw in [json, base64, hex] {
+        assert_eq!(decode_ibc_packet_data(raw).unwrap(), expected);
+    }
+    assert_eq!(
+        expected.to_string(),
+        "1337transfer/channel-0/anom from onomy1sender to onomy1receiver"
+    );
+    assert!(decode_ibc_packet_data("not packet data").is_err());
+    assert!(decode_ibc_packet_data(r#"{"amount":"1"}"#).is_err());
+
+    let tx_res = json!({"logs": [{"events": [
+        {"type": "message", "attributes": [{"key": "action", "value": "transfer"}]},
+        {"type": "send_packet", "attributes": [
+            {"key": "packet_data", "value": json},
+            {"key": "packet_data_hex", "value": hex},
+            {"key": "packet_sequence", "value": "7"},
+        ]},
+    ]}]});
+    assert_eq!(sent_packet_data(&tx_res), vec![expected]);
+    assert!(sent_packet_data(&Value::Null).is_empty());
+    assert_eq!(sent_packet_sequences(&tx_res), vec![7]);
+    assert!(sent_packet_sequences(&Value::Null).is_empty());
+}
+
+#[test]
+fn test_parse_packet_receipt() {
+    let res = concat!(
+        "proof: null\nproof_height:\n  revision_height: \"52\"\n",
+        "  revision_number: \"0\"\nreceived: true\n"
+    );
+    assert!(parse_packet_receipt(res).unwrap());
+    assert!(!parse_packet_receipt("received: false\n").unwrap());
+    assert!(parse_packet_receipt("received: maybe\n").is_err());
+}
+
+#[test]
+fn test_ibc_pair_sides_from() {
+    let side = |chain_id: &str| IbcSide {
+        chain_id: chain_id.to_owned(),
+        connection: "connection-0".to_owned(),
+        transfer_channel: "channel-0".to_owned(),
+        ics_channel: "channel-1".to_owned(),
+    };
+    let pair = IbcPair {
+        a: side("market"),
+        b: side("onomy"),
+    };
+    let (src, dst) = pair.sides_from("onomy").unwrap();
+    assert_eq!(
+        (src.chain_id.as_str(), dst.chain_id.as_str()),
+        ("onomy", "market")
+    );
+    let (src, dst) = pair.sides_from("market").unwrap();
+    assert_eq!(
+        (src.chain_id.as_str(), dst.chain_id.as_str()),
+        ("market", "onomy")
+    );
+    assert!(pair.sides_from("gravity").is_err());
+}
+
+#[test]
+fn test_ibc_pair_json() {
+    let side = |chain_id: &str, n: u64| IbcSide {
+        chain_id: chain_id.to_owned(),
+        connection: format!("connection-{n}"),
+        transfer_channel: format!("channel-{n}"),
+        ics_channel: format!("channel-{}", n + 1),
+    };
+    let pair = IbcPair {
+        a: side("market", 0),
+        b: side("onomy", 2),
+    };
+    let loaded = IbcPair::from_json_str(&pair.to_json_string()).unwrap();
+    assert_eq!(format!("{loaded:?}"), format!("{pair:?}"));
+    assert!(IbcPair::from_json_str(r#"{"a": {}}"#).is_err());
+}
+
+/// `resources/ibc_pair.json` was written by [IbcPair::persist] in an earlier
+/// run, this makes sure that a restarted process can still load it
+#[tokio::test]
+async fn test_ibc_pair_load_fixture() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/ibc_pair.json");
+    let loaded = IbcPair::load(path).await.unwrap();
+    assert_eq!(loaded.a.chain_id, "market");
+    assert_eq!(loaded.a.connection, "connection-0");
+    assert_eq!(loaded.a.transfer_channel, "channel-0");
+    assert_eq!(loaded.a.ics_channel, "channel-1");
+    assert_eq!(loaded.b.chain_id, "onomy");
+    assert_eq!(loaded.b.connection, "connection-2");
+    assert_eq!(loaded.b.transfer_channel, "channel-2");
+    assert_eq!(loaded.b.ics_channel, "channel-3");
+    assert!(IbcPair::load(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/resources/missing.json"
+    ))
+    .await
+    .is_err());
+}
+
+#[test]
+fn test_balance_snapshot_diff() {
+    let before = BalanceSnapshot {
+        provider: BTreeMap::from([("anom".to_owned(), 100)]),
+        consumer: BTreeMap::from([("ibc/nom".to_owned(), 0)]),
+    };
+    let after = BalanceSnapshot {
+        provider: BTreeMap::from([("anom".to_owned(), 40)]),
+        consumer: BTreeMap::from([("ibc/nom".to_owned(), 60), ("anative".to_owned(), 0)]),
+    };
+    let diff = before.diff(&after);
+    assert_eq!(diff.provider, BTreeMap::from([("anom".to_owned(), -60)]));
+    assert_eq!(diff.consumer, BTreeMap::from([("ibc/nom".to_owned(), 60)]));
+}
+
+#[test]
+fn test_parse_incentivized_packets() {
+    let res = json!({"incentivized_packets": [{
+        "packet_id": {"port_id": "transfer", "channel_id": "channel-1", "sequence": "3"},
+        "packet_fees": [{
+            "fee": {
+                "recv_fee": [{"denom": "anative", "amount": "100"}],
+                "ack_fee": [{"denom": "anative", "amount": "50"}],
+                "timeout_fee": null
+            },
+            "refund_address": "onomy1abc",
+            "relayers": []
+        }]
+    }]});
+    assert_eq!(parse_incentivized_packets(&res).unwrap(), vec![
+        IncentivizedPacket {
+            port_id: "transfer".to_owned(),
+            channel_id: "channel-1".to_owned(),
+            sequence: 3,
+            packet_fees: vec![PacketFee {
+                recv_fee: vec!["100anative".to_owned()],
+                ack_fee: vec!["50anative".to_owned()],
+                timeout_fee: vec![],
+                refund_address: "onomy1abc".to_owned(),
+            }],
+        }
+    ]);
+    assert!(
+        parse_incentivized_packets(&json!({"incentivized_packets": null}))
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_ccvconsumer_state() {
+    let provider_info = json!({
+        "consumer": {"chainID": "market", "clientID": "07-tendermint-0", "channelID": "channel-0"},
+        "provider": {"chainID": "onomy", "clientID": "07-tendermint-0", "channelID": "channel-1"}
+    });
+    let state = CcvConsumerState::from_queries(
+        &provider_info,
+        Some(&json!({"slash_record": null, "packet_data_queue": []})),
+    )
+    .unwrap();
+    assert_eq!(state, CcvConsumerState {
+        provider_chain_id: Some("onomy".to_owned()),
+        provider_channel_id: Some("channel-1".to_owned()),
+        consumer_channel_id: Some("channel-0".to_owned()),
+        pending_packets: Some(0),
+    });
+    state.assert_consumer_channel("channel-0").unwrap();
+    assert!(state.assert_consumer_channel("channel-1").is_err());
+    state.assert_no_pending_packets().unwrap();
+    let state = CcvConsumerState::from_queries(
+        &provider_info,
+        Some(&json!({"packet_data_queue": [{"type": "SlashPacket"}]})),
+    )
+    .unwrap();
+    assert!(state.assert_no_pending_packets().is_err());
+    assert!(CcvConsumerState::from_queries(&json!({}), None).is_err());
+}
+
+#[test]
+fn test_parse_pending_vsc() {
+    let throttle_state = json!({
+        "slash_record": null,
+        "packet_data_queue": [
+            {"type": "CONSUMER_PACKET_TYPE_SLASH", "slashPacketData": {"valset_update_id": "2"}},
+            {"vscMaturedPacketData": {"valset_update_id": "3"}},
+            {"vsc_matured_packet_data": {"valset_update_id": "4"}}
+        ]
+    });
+    assert_eq!(parse_pending_vsc(&throttle_state).unwrap(), vec![
+        VscPacket {
+            valset_update_id: 3
+        },
+        VscPacket {
+            valset_update_id: 4
+        }
+    ]);
+    assert!(parse_pending_vsc(&json!({"packet_data_queue": null}))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_consumer_removal_queries() {
+    let res = yaml_str_to_json_value(
+        r#"chains:
+- chain_id: market
+  client_id: 07-tendermint-0
+"#,
+    )
+    .unwrap();
+    assert_eq!(parse_consumer_chains(&res).unwrap(), vec![
+        "market".to_owned()
+    ]);
+    let res = yaml_str_to_json_value("chains: []\n").unwrap();
+    assert!(parse_consumer_chains(&res).unwrap().is_empty());
+    assert!(parse_consumer_chains(&serde_json::json!({"chains": [{}]})).is_err());
+    let res = yaml_str_to_json_value(
+        r#"channel:
+  connection_hops:
+  - connection-0
+  counterparty:
+    channel_id: channel-0
+    port_id: consumer
+  ordering: ORDER_ORDERED
+  state: STATE_CLOSED
+  version: "1"
+proof: null
+"#,
+    )
+    .unwrap();
+    assert_eq!(parse_channel_state(&res).unwrap(), "STATE_CLOSED");
+    assert!(parse_channel_state(&Value::Null).is_err());
+    assert_eq!(parse_channel_ordering(&res).unwrap(), "ORDER_ORDERED");
+    assert!(parse_channel_ordering(&Value::Null).is_err());
+}
+
+#[test]
+fn test_denom_trace() {
+    let res = yaml_str_to_json_value(
+        r#"denom_trace:
+  base_denom: anom
+  path: transfer/channel-1
+"#,
+    )
+    .unwrap();
+    let trace = DenomTrace::from_json(&res).unwrap();
+    assert_eq!(trace, DenomTrace {
+        path: "transfer/channel-1".to_owned(),
+        base_denom: "anom".to_owned(),
+    });
+    assert_eq!(trace.to_string(), "transfer/channel-1/anom");
+    assert!(DenomTrace::from_json(&serde_json::json!({"base_denom": "anom"})).is_err());
+    let res = yaml_str_to_json_value(
+        r#"denom_traces:
+- base_denom: anom
+  path: transfer/channel-1
+- base_denom: afootoken
+  path: transfer/channel-1
+pagination:
+  next_key: null
+  total: "0"
+"#,
+    )
+    .unwrap();
+    let mut traces = parse_denom_traces(&res).unwrap();
+    assert_eq!(traces[0], trace);
+    check_single_denom_trace(&traces, "transfer/channel-1", "anom").unwrap();
+    assert!(check_single_denom_trace(&traces, "transfer/channel-0", "anom").is_err());
+    traces.push(trace);
+    let e = format!(
+        "{:?}",
+        check_single_denom_trace(&traces, "transfer/channel-1", "anom").unwrap_err()
+    );
+    assert!(e.contains("found 2"));
+    assert!(parse_denom_traces(&Value::Null).is_err());
+}
+
+#[test]
+fn test_assert_valset_matches() {
+    use crate::cosmovisor::parse_valset;
+
+    let valset = |powers: &[(&str, u64)]| {
+        let validators: Vec<Value> = powers
+            .iter()
+            .map(|(key, power)| {
+                json!({
+                    "address": "onomyvalcons1abcd",
+                    "pub_key": {"@type": "/cosmos.crypto.ed25519.PubKey", "key": key},
+                    "proposer_priority": "0",
+                    "voting_power": power.to_string()
+                })
+            })
+            .collect();
+        parse_valset(&json!({"block_height": "10", "validators": validators})).unwrap()
+    };
+    let provider = valset(&[("a", 1000), ("b", 2000), ("c", 0)]);
+    assert_eq!(provider[1].pub_key, "b");
+    assert_eq!(provider[1].power, 2000);
+    assert_valset_matches(&provider, &valset(&[("b", 2000), ("a", 1000)]), 0).unwrap();
+    assert_valset_matches(&provider, &valset(&[("a", 999), ("b", 2001)]), 1).unwrap();
+    assert!(assert_valset_matches(&provider, &valset(&[("a", 999), ("b", 2001)]), 0).is_err());
+    assert!(assert_valset_matches(&provider, &valset(&[("a", 1000)]), 0).is_err());
+    assert!(
+        assert_valset_matches(&provider, &valset(&[("a", 1000), ("b", 2000), ("c", 1)]), 0)
+            .is_err()
+    );
+}
+
+#[test]
+fn test_ordered_delivery() {
+    // a 0.37 style event and a 0.34 style base64 encoded one
+    let tx_search = serde_json::json!({"result": {"txs": [
+        {"height": "10", "tx_result": {"events": [
+            {"type": "recv_packet", "attributes": [
+                {"key": "packet_sequence", "value": "3"},
+                {"key": "packet_dst_port", "value": "provider"},
+                {"key": "packet_dst_channel", "value": "channel-0"}
+            ]},
+            {"type": "recv_packet", "attributes": [
+                {"key": "packet_sequence", "value": "7"},
+                {"key": "packet_dst_port", "value": "transfer"},
+                {"key": "packet_dst_channel", "value": "channel-1"}
+            ]}
+        ]}},
+        {"height": "11", "tx_result": {"events": [
+            {"type": "recv_packet", "attributes": [
+                {"key": "cGFja2V0X3NlcXVlbmNl", "value": "NA=="},
+                {"key": "cGFja2V0X2RzdF9wb3J0", "value": "cHJvdmlkZXI="},
+                {"key": "cGFja2V0X2RzdF9jaGFubmVs", "value": "Y2hhbm5lbC0w"}
+            ]}
+        ]}}
+    ], "total_count": "2"}});
+    let seqs = parse_received_sequences(&tx_search, "provider", "channel-0").unwrap();
+    assert_eq!(seqs, vec![3, 4]);
+    assert!(check_ordered_delivery(&seqs, 3, 2).is_ok());
+    // earlier packets are ignored
+    assert!(check_ordered_delivery(&[1, 2, 3, 4], 3, 2).is_ok());
+    assert!(check_ordered_delivery(&[4, 3], 3, 2).is_err());
+    assert!(check_ordered_delivery(&[3], 3, 2).is_err());
+    assert!(parse_received_sequences(&Value::Null, "provider", "channel-0").is_err());
+}
@@ -1,12 +1,24 @@
+pub mod command;
+pub mod containers;
 pub mod cosmovisor;
 pub mod dockerfiles;
+pub mod genesis;
+#[cfg(feature = "grpc_support")]
+pub mod grpc;
 pub mod hermes;
 mod hermes_config;
 pub mod ibc;
+pub mod logs;
 mod misc;
+pub mod net;
+pub mod ports;
 pub mod setups;
+pub mod stages;
+pub mod timing;
 mod types;
 
+#[doc(hidden)]
+pub use futures;
 pub use misc::*;
 /// Reexported to reduce dependency wrangling
 pub use super_orchestrator;
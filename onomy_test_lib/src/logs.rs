@@ -0,0 +1,163 @@
+//! Assertions on the ordering of events across the logs of multiple
+//! containers
+
+use regex::Regex;
+use super_orchestrator::{
+    stacked_errors::{Error, MapAddError, Result},
+    FileOptions,
+};
+
+/// A line of a merged log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLine {
+    /// The name the log was added under
+    pub source: String,
+    /// Nanoseconds since the Unix epoch, lines without their own timestamp
+    /// inherit the timestamp of the previous line from the same source
+    pub timestamp: Option<i128>,
+    pub line: String,
+}
+
+/// Parses an RFC 3339 UTC timestamp at the start of `line` (e.x.
+/// "2023-07-11T12:34:56.789Z INFO ..." from Hermes or "[2023-07-11T12:34:56Z
+/// INFO ...]" from `env_logger`) into nanoseconds since the Unix epoch
+pub fn parse_log_timestamp(line: &str) -> Option<i128> {
+    let token = line.trim_start().trim_start_matches('[');
+    let token = token.split_whitespace().next()?.trim_end_matches('Z');
+    let (date, time) = token.split_once('T')?;
+    let mut date = date.split('-').map(|s| s.parse::<i64>().ok());
+    let (y, m, d) = (date.next()??, date.next()??, date.next()??);
+    let (time, frac) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.split(':').map(|s| s.parse::<i64>().ok());
+    let (h, min, s) = (time.next()??, time.next()??, time.next()??);
+    if !((1..=12).contains(&m) && (1..=31).contains(&d) && (h < 24) && (min < 60) && (s < 61)) {
+        return None
+    }
+    let nanos: i128 = if frac.is_empty() {
+        0
+    } else {
+        if !frac.chars().all(|c| c.is_ascii_digit()) {
+            return None
+        }
+        format!("{frac:0<9}").get(..9)?.parse().ok()?
+    };
+    // days from the civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let secs = days * 86400 + h * 3600 + min * 60 + s;
+    Some(i128::from(secs) * 1_000_000_000 + nanos)
+}
+
+/// Merges timestamped logs from multiple sources so that tests can assert on
+/// the ordering of events, e.x. that the consumer started before Hermes
+/// connected
+#[derive(Debug, Clone, Default)]
+pub struct LogAssertions {
+    lines: Vec<LogLine>,
+}
+
+impl LogAssertions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the lines of `log` under `source`
+    pub fn add_log(&mut self, source: &str, log: &str) {
+        let mut last = None;
+        for line in log.lines() {
+            if let Some(timestamp) = parse_log_timestamp(line) {
+                last = Some(timestamp);
+            }
+            self.lines.push(LogLine {
+                source: source.to_owned(),
+                timestamp: last,
+                line: line.to_owned(),
+            });
+        }
+        // stable, so lines with the same timestamp keep their order
+        self.lines.sort_by_key(|line| line.timestamp);
+    }
+
+    /// Adds the log file at `path` under `source`
+    pub async fn add_file(&mut self, source: &str, path: &str) -> Result<()> {
+        let log = FileOptions::read_to_string(path)
+            .await
+            .map_add_err(|| format!("LogAssertions::add_file(source: {source})"))?;
+        self.add_log(source, &log);
+        Ok(())
+    }
+
+    /// The merged log ordered by timestamp
+    pub fn merged(&self) -> &[LogLine] {
+        &self.lines
+    }
+
+    /// Returns the first line matching the regex `pattern`
+    pub fn first_match(&self, pattern: &str) -> Result<Option<&LogLine>> {
+        let re = Regex::new(pattern)
+            .map_err(|e| Error::boxed(Box::new(e)))
+            .map_add_err(|| format!("invalid regex {pattern}"))?;
+        Ok(self.lines.iter().find(|line| re.is_match(&line.line)))
+    }
+
+    /// Returns an error unless the first line matching `before` has an earlier
+    /// or equal timestamp than the first line matching `after`. Lines without
+    /// timestamps cannot be ordered and cause an error.
+    pub fn assert_before(&self, before: &str, after: &str) -> Result<()> {
+        let find = |pattern: &str| -> Result<(&LogLine, i128)> {
+            let line = self
+                .first_match(pattern)?
+                .map_add_err(|| format!("no log line matches {pattern}"))?;
+            let timestamp = line.timestamp.map_add_err(|| {
+                format!("log line matching {pattern} has no timestamp: {line:?}")
+            })?;
+            Ok((line, timestamp))
+        };
+        let (line_before, t_before) =
+            find(before).map_add_err(|| "LogAssertions::assert_before")?;
+        let (line_after, t_after) = find(after).map_add_err(|| "LogAssertions::assert_before")?;
+        if t_before > t_after {
+            return Err(Error::from(format!(
+                "LogAssertions::assert_before expected {before} (at {t_before}ns) to be before \
+                 {after} (at {t_after}ns), but it was {}ms later. lines: {line_before:?}, \
+                 {line_after:?}",
+                (t_before - t_after) / 1_000_000
+            )))
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_log_assertions() {
+    assert_eq!(
+        parse_log_timestamp("1970-01-01T00:00:01Z x"),
+        Some(1_000_000_000)
+    );
+    assert_eq!(
+        parse_log_timestamp("[2000-03-01T00:00:00.5Z INFO x]"),
+        Some(951_868_800_500_000_000)
+    );
+    assert_eq!(parse_log_timestamp("3:04PM INF x"), None);
+    let mut logs = LogAssertions::new();
+    logs.add_log(
+        "market",
+        "[2023-07-11T12:00:01Z INFO onomy_test_lib] consumer started\nno timestamp\n",
+    );
+    logs.add_log(
+        "hermes",
+        "2023-07-11T12:00:00.250Z INFO connecting\n2023-07-11T12:00:02.000Z INFO connected\n",
+    );
+    assert_eq!(logs.merged()[0].source, "hermes");
+    assert_eq!(logs.merged()[2].line, "no timestamp");
+    logs.assert_before("consumer started", "connected$")
+        .unwrap();
+    assert!(logs
+        .assert_before("consumer started", "connecting")
+        .is_err());
+    assert!(logs.assert_before("consumer started", "missing").is_err());
+}
@@ -1,15 +1,200 @@
-use std::{env, time::Duration};
+use std::{
+    env, fmt,
+    fmt::Debug,
+    future::Future,
+    panic::Location,
+    pin::Pin,
+    time::{Duration, Instant},
+};
 
 use awint::awi::*;
+use bech32::ToBase32;
 use clap::Parser;
+use log::info;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use ripemd::Ripemd160;
 use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use super_orchestrator::{
+    sh_no_dbg,
     stacked_errors::{Error, MapAddError, Result},
     std_init,
 };
+use tokio::time::sleep;
+
+use crate::cosmovisor::apply_daemon_override;
 
 pub const TIMEOUT: Duration = Duration::from_secs(1000);
 
+/// Extension trait for `Result`s and `Option`s that records the
+/// `file:line:column` of the caller. `.locate()` is meant to be used instead of
+/// the bare `.map_add_err(|| ())`, messages should still be added with
+/// `.map_add_err(|| format!(...))`.
+pub trait MapAddContext<T> {
+    /// Adds the location of the caller
+    fn locate(self) -> Result<T>;
+}
+
+impl<T> MapAddContext<T> for Result<T> {
+    #[track_caller]
+    fn locate(self) -> Result<T> {
+        let location = Location::caller();
+        self.map_add_err(|| format!("at {location}"))
+    }
+}
+
+impl<T> MapAddContext<T> for Option<T> {
+    #[track_caller]
+    fn locate(self) -> Result<T> {
+        let location = Location::caller();
+        self.map_add_err(|| format!("at {location}"))
+    }
+}
+
+/// If this environment variable is set to a `u64`, it is used to seed the RNG
+/// used for retry jitter so that test timing is reproducible
+pub const JITTER_SEED_ENV: &str = "ONOMY_TEST_JITTER_SEED";
+
+fn jitter_rng() -> StdRng {
+    match env::var(JITTER_SEED_ENV)
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+    {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// The same as `super_orchestrator::wait_for_ok`, except that a random jitter
+/// in the range `0..=max_jitter` is added onto each delay so that many waiters
+/// do not all retry at the same time. See [JITTER_SEED_ENV] for making the
+/// jitter deterministic.
+pub async fn wait_for_ok_jitter<F, Fut, T>(
+    num_tries: u64,
+    base_delay: Duration,
+    max_jitter: Duration,
+    mut f: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut rng = jitter_rng();
+    let max_jitter = u64::try_from(max_jitter.as_nanos()).unwrap_or(u64::MAX);
+    let mut i = num_tries;
+    loop {
+        match f().await {
+            Ok(o) => return Ok(o),
+            Err(e) => {
+                i = i.saturating_sub(1);
+                if i == 0 {
+                    return Err(e).map_add_err(|| {
+                        format!("wait_for_ok_jitter(num_tries: {num_tries}) timeout")
+                    })
+                }
+            }
+        }
+        let jitter = Duration::from_nanos(rng.gen_range(0..=max_jitter));
+        sleep(base_delay + jitter).await;
+    }
+}
+
+/// Retries `f` up to `num_tries` times, starting with `initial_delay` and
+/// multiplying the delay by `multiplier` after every failure, capping it at
+/// `max_delay`
+pub async fn retry_with_backoff<F, Fut, T>(
+    num_tries: u64,
+    initial_delay: Duration,
+    multiplier: f64,
+    max_delay: Duration,
+    mut f: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut delay = initial_delay;
+    let mut i = num_tries;
+    loop {
+        match f().await {
+            Ok(o) => return Ok(o),
+            Err(e) => {
+                i = i.saturating_sub(1);
+                if i == 0 {
+                    return Err(e).map_add_err(|| {
+                        format!("retry_with_backoff(num_tries: {num_tries}) timeout")
+                    })
+                }
+            }
+        }
+        sleep(delay).await;
+        delay = delay.mul_f64(multiplier).min(max_delay);
+    }
+}
+
+/// Calls `query` every `interval` until `pred` returns true for its output,
+/// returning that output. Errors from `query` are treated the same as a false
+/// `pred`. If `timeout` is reached, the returned error includes the last
+/// observed output or error.
+pub async fn poll_until<T, F, Fut, P>(
+    mut query: F,
+    mut pred: P,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<T>
+where
+    T: Debug,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+    P: FnMut(&T) -> bool,
+{
+    let start = Instant::now();
+    loop {
+        let last = match query().await {
+            Ok(o) => {
+                if pred(&o) {
+                    return Ok(o)
+                }
+                format!("last observed value: {o:?}")
+            }
+            Err(e) => format!("last observed error: {e:?}"),
+        };
+        if start.elapsed() >= timeout {
+            return Err(Error::from(format!(
+                "poll_until(timeout: {timeout:?}) timeout, {last}"
+            )))
+        }
+        sleep(interval).await;
+    }
+}
+
+/// Awaits `query`, adding `label` to the error. Used by [join_queries].
+pub async fn labeled_query<T, Fut: Future<Output = Result<T>>>(
+    label: &str,
+    query: Fut,
+) -> Result<T> {
+    query.await.map_add_err(|| format!("query {label} failed"))
+}
+
+/// Runs independent queries concurrently and returns a tuple of their outputs
+/// in the same order, e.x.
+///
+/// ```text
+/// let (metadata, balances) = join_queries!(
+///     "denom metadata" => cosmovisor_get_denom_metadata(denom),
+///     "balances" => cosmovisor_get_balances(addr),
+/// )?;
+/// ```
+///
+/// The first query to fail cancels the rest, and its label is included in the
+/// returned error.
+#[macro_export]
+macro_rules! join_queries {
+    ($($label:expr => $query:expr),+ $(,)?) => {
+        $crate::futures::try_join!($($crate::labeled_query($label, $query)),+)
+    };
+}
+
 // the `json` macro does not support const
 
 pub fn nom_denom() -> Value {
@@ -46,8 +231,9 @@ pub struct Args {
     #[arg(long, default_value_t = String::new())]
     pub bin_name: String,
     /// If left `None`, the container runner program runs, otherwise this
-    /// specifies the entry_name to run
-    #[arg(long)]
+    /// specifies the entry_name to run. `--entrypoint` is accepted as an alias
+    /// for older container definitions.
+    #[arg(long, alias = "entrypoint")]
     pub entry_name: Option<String>,
     /// Used by Cosmovisor for the name of the Daemon (e.x. `onomyd`)
     #[arg(long, env)]
@@ -61,6 +247,83 @@ pub struct Args {
     pub onomy_current_version: Option<String>,
     #[arg(long, env)]
     pub onomy_upgrade_version: Option<String>,
+    /// Keeps the container network alive after the test for interactive
+    /// debugging, until Ctrl-C is pressed. This is passed through to the
+    /// containers, where the entries block at [hold_for_keep_running] before
+    /// tearing down their daemons (or after their handler fails).
+    #[arg(long, default_value_t = false)]
+    pub keep_running: bool,
+    /// Rebuilds the daemon binaries even if their sources are unchanged since
+    /// the last build
+    #[arg(long, default_value_t = false)]
+    pub force_build: bool,
+    /// Instead of running the container network, writes an equivalent
+    /// docker-compose file to this path
+    #[arg(long)]
+    pub compose: Option<String>,
+    /// Skips the `preflight_check` of docker and the toolchain, for
+    /// environments that manage these externally
+    #[arg(long, default_value_t = false)]
+    pub skip_preflight: bool,
+    /// Mounts this daemon binary into the daemon containers with the same
+    /// daemon name as the file name (e.x. "./onomyd" overrides `onomyd`), which
+    /// is used instead of the binary in the image. This allows bisecting
+    /// without rebuilding images.
+    #[arg(long)]
+    pub daemon_binary: Option<String>,
+    /// Comma separated `provider_version:consumer_version` pairs. Runners that
+    /// support it run their scenario once per pair with the images of those
+    /// versions instead of building the daemons from source.
+    #[arg(long, value_delimiter = ',')]
+    pub version_matrix: Vec<String>,
+    /// CIDR subnet of the docker network (e.x. "10.123.0.0/16"), for
+    /// environments where the default ranges collide with e.x. a VPN
+    #[arg(long)]
+    pub subnet: Option<String>,
+    /// Gateway of the docker network, must be within `--subnet`
+    #[arg(long)]
+    pub gateway: Option<String>,
+    /// Comma separated extra DNS servers for the containers
+    #[arg(long, value_delimiter = ',')]
+    pub dns: Vec<String>,
+}
+
+/// The flag that [Args::entry_name] is parsed from
+pub const ENTRY_NAME_FLAG: &str = "--entry-name";
+
+/// The flag that [Args::keep_running] is parsed from
+pub const KEEP_RUNNING_FLAG: &str = "--keep-running";
+
+/// Builds the container arguments that select an entry, this should be used
+/// instead of raw arg slices so that the flag is always the one [Args] parses,
+/// e.x. `Container::new(..., &EntryArgs::new("hermes").as_args())`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryArgs<'a> {
+    pub entry_name: &'a str,
+    pub keep_running: bool,
+}
+
+impl<'a> EntryArgs<'a> {
+    pub fn new(entry_name: &'a str) -> Self {
+        Self {
+            entry_name,
+            keep_running: false,
+        }
+    }
+
+    /// Should be set from the runner's [Args::keep_running]
+    pub fn keep_running(mut self, keep_running: bool) -> Self {
+        self.keep_running = keep_running;
+        self
+    }
+
+    pub fn as_args(&self) -> Vec<&'a str> {
+        let mut args = vec![ENTRY_NAME_FLAG, self.entry_name];
+        if self.keep_running {
+            args.push(KEEP_RUNNING_FLAG);
+        }
+        args
+    }
 }
 
 /// Calls [super_orchestrator::std_init] and returns the result of
@@ -70,7 +333,7 @@ pub fn onomy_std_init() -> Result<Args> {
     let mut args = Args::parse();
     args.bin_name = env::args()
         .next()
-        .map_add_err(|| ())?
+        .map_add_err(|| "no first argument to get the binary name from")?
         .split('/')
         .last()
         .unwrap()
@@ -78,6 +341,74 @@ pub fn onomy_std_init() -> Result<Args> {
     Ok(args)
 }
 
+/// If `--keep-running` was passed, blocks forever with a log message. Entries
+/// should call this right before terminating their daemons (or before sending
+/// the termination signal to the other entries), so that the chains are still
+/// running while they are inspected. The host side tears down the containers
+/// on Ctrl-C.
+pub async fn hold_for_keep_running(args: &Args) {
+    if args.keep_running {
+        info!("the network is still running, blocking for --keep-running");
+        std::future::pending::<()>().await;
+    }
+}
+
+/// An entry handler for [dispatch_entry]. Non-capturing closures coerce to
+/// this, e.x. `|args| Box::pin(onomyd_runner(args))`
+pub type EntryFn = for<'a> fn(&'a Args) -> Pin<Box<dyn Future<Output = Result<()>> + 'a>>;
+
+/// Returned by [dispatch_entry] when the entry name does not match any of the
+/// registered handlers
+#[derive(Debug, Clone)]
+pub struct UnknownEntryName {
+    pub got: String,
+    pub expected: Vec<String>,
+}
+
+impl fmt::Display for UnknownEntryName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "entry_name \"{}\" is not recognized, expected one of {:?}",
+            self.got, self.expected
+        )
+    }
+}
+
+impl std::error::Error for UnknownEntryName {}
+
+/// Runs the handler in `handlers` whose name matches `args.entry_name`.
+/// Returns an [UnknownEntryName] error listing the valid names if there is no
+/// match.
+pub async fn dispatch_entry(args: &Args, handlers: &[(&str, EntryFn)]) -> Result<()> {
+    let entry_name = args
+        .entry_name
+        .as_ref()
+        .map_add_err(|| "dispatch_entry called without an entry_name")?;
+    for (name, handler) in handlers {
+        if name == entry_name {
+            apply_daemon_override().await?;
+            let res = handler(args)
+                .await
+                .map_add_err(|| format!("entry_name \"{entry_name}\" handler failed"));
+            if args.keep_running {
+                // entries that succeed normally block earlier in `hold_for_keep_running`,
+                // the runner removes the container when it is done
+                info!("entry \"{entry_name}\" finished with {res:?}, blocking for --keep-running");
+                std::future::pending::<()>().await;
+            }
+            return res
+        }
+    }
+    Err(Error::boxed(Box::new(UnknownEntryName {
+        got: entry_name.to_owned(),
+        expected: handlers
+            .iter()
+            .map(|(name, _)| (*name).to_owned())
+            .collect(),
+    })))
+}
+
 /// Given `units_of_nom` in units of NOM, returns a string of the decimal number
 /// of aNOM appended with "anom"
 pub fn nom(units_of_nom: f64) -> String {
@@ -132,7 +463,8 @@ pub fn yaml_str_to_json_value(yaml_input: &str) -> Result<serde_json::Value> {
     let deserializer = serde_yaml::Deserializer::from_str(yaml_input);
     let mut json_v = vec![];
     let mut serializer = serde_json::Serializer::new(&mut json_v);
-    serde_transcode::transcode(deserializer, &mut serializer).map_add_err(|| ())?;
+    serde_transcode::transcode(deserializer, &mut serializer)
+        .map_add_err(|| format!("yaml_str_to_json_value(yaml_input: {yaml_input})"))?;
     let json_s = String::from_utf8(json_v).map_add_err(|| ())?;
     let tmp: serde_json::Value = serde_json::from_str(&json_s).map_add_err(|| ())?;
     Ok(tmp)
@@ -157,6 +489,84 @@ pub fn reprefix_bech32(s: &str, new_prefix: &str) -> Result<String> {
     Ok(encoded)
 }
 
+/// The HD path used by `keys add`
+pub const COSMOS_HD_PATH: &str = "m/44'/118'/0'/0/0";
+
+/// A well known test mnemonic ("abandon" 23 times then "art"). No test should
+/// ever rely on this key holding value outside of a test network.
+pub const KNOWN_TEST_KEY: &str = "abandon abandon abandon abandon abandon abandon abandon abandon \
+                                  abandon abandon abandon abandon abandon abandon abandon abandon \
+                                  abandon abandon abandon abandon abandon abandon abandon art";
+
+/// Derives the account address of `mnemonic` with the standard
+/// [COSMOS_HD_PATH], the same as `keys add --recover` would. Only 24 word
+/// mnemonics are supported.
+pub fn mnemonic_to_address(mnemonic: &str, prefix: &str) -> Result<String> {
+    let mnemonic = bip32::Mnemonic::new(mnemonic.trim(), bip32::Language::English)
+        .map_err(|e| Error::boxed(Box::new(e)))?;
+    let path = COSMOS_HD_PATH
+        .parse()
+        .map_err(|e: bip32::Error| Error::boxed(Box::new(e)))?;
+    let xprv = bip32::XPrv::derive_from_path(mnemonic.to_seed(""), &path)
+        .map_err(|e| Error::boxed(Box::new(e)))?;
+    let pubkey_hash = Ripemd160::digest(Sha256::digest(xprv.public_key().to_bytes()));
+    bech32::encode(prefix, pubkey_hash.to_base32(), bech32::Variant::Bech32)
+        .map_err(|e| Error::boxed(Box::new(e)))
+}
+
+/// The address of [KNOWN_TEST_KEY] under `prefix`, used as a destination whose
+/// balance can be asserted on. This is derived rather than hardcoded so that
+/// the asserted account is always the one the key controls.
+pub fn known_test_addr(prefix: &str) -> Result<String> {
+    mnemonic_to_address(KNOWN_TEST_KEY, prefix)
+}
+
+/// Lowercase hex encoding of `bytes`, e.x. for displaying digests
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Returns a hash of the state of the git repository at `dir`, including the
+/// HEAD commit, uncommitted changes, and the contents of untracked files. This
+/// changes whenever something that could affect a build from `dir` changes.
+/// Returns `None` if `dir` is not within a git work tree (e.x. an extracted
+/// source tarball), in which case there is no way to tell if the sources
+/// changed and callers should always rebuild.
+pub async fn source_fingerprint(dir: &str) -> Result<Option<String>> {
+    let err = || format!("source_fingerprint(dir: {dir})");
+    if sh_no_dbg("git -C", &[dir, "rev-parse", "--is-inside-work-tree"])
+        .await
+        .is_err()
+    {
+        return Ok(None)
+    }
+    let mut hasher = Sha256::new();
+    let head = sh_no_dbg("git -C", &[dir, "rev-parse", "HEAD"])
+        .await
+        .map_add_err(err)?;
+    hasher.update(head.as_bytes());
+    let diff = sh_no_dbg("git -C", &[dir, "diff", "HEAD", "--binary"])
+        .await
+        .map_add_err(err)?;
+    hasher.update(diff.as_bytes());
+    let untracked = sh_no_dbg("git -C", &[
+        dir,
+        "ls-files",
+        "--others",
+        "--exclude-standard",
+    ])
+    .await
+    .map_add_err(err)?;
+    for file in untracked.lines() {
+        hasher.update(file.as_bytes());
+        let contents = tokio::fs::read(format!("{dir}/{file}"))
+            .await
+            .map_add_err(err)?;
+        hasher.update(&contents);
+    }
+    Ok(Some(hex_encode(&hasher.finalize())))
+}
+
 #[test]
 fn test_nom() {
     assert_eq!(&nom(1.0), "1000000000000000000anom");
@@ -179,6 +589,21 @@ fn test_nom() {
     assert_eq!(anom_to_nom("1000000000000000000.00000anom").unwrap(), 1.0);
 }
 
+#[test]
+fn test_map_add_context() {
+    let none: Option<()> = None;
+    let e = format!("{:?}", none.locate().unwrap_err());
+    assert!(e.contains(file!()));
+    let e = format!(
+        "{:?}",
+        Err::<(), _>(Error::from("inner".to_owned()))
+            .locate()
+            .unwrap_err()
+    );
+    assert!(e.contains("inner"));
+    assert!(e.contains(file!()));
+}
+
 #[test]
 fn test_reprefix_bech32() {
     assert_eq!(
@@ -186,3 +611,76 @@ fn test_reprefix_bech32() {
         "cosmos1a69w3hfjqere4crkgyee79x2mxq0w2pfgyl2m7".to_owned()
     );
 }
+
+#[test]
+fn test_entry_args() {
+    let args = Args::try_parse_from(
+        ["test"]
+            .into_iter()
+            .chain(EntryArgs::new("hermes").as_args()),
+    )
+    .unwrap();
+    assert_eq!(args.entry_name.as_deref(), Some("hermes"));
+    assert!(!args.keep_running);
+    let args = Args::try_parse_from(["test", "--entrypoint", "hermes"]).unwrap();
+    assert_eq!(args.entry_name.as_deref(), Some("hermes"));
+    let args = Args::try_parse_from(
+        ["test"]
+            .into_iter()
+            .chain(EntryArgs::new("hermes").keep_running(true).as_args()),
+    )
+    .unwrap();
+    assert!(args.keep_running);
+}
+
+#[test]
+fn test_known_test_key() {
+    assert_eq!(
+        known_test_addr("onomy").unwrap(),
+        "onomy1r5v5srda7xfth3hn2s26txvrcrntldjup2ndx6"
+    );
+    assert_eq!(
+        mnemonic_to_address(KNOWN_TEST_KEY, "cosmos").unwrap(),
+        "cosmos1r5v5srda7xfth3hn2s26txvrcrntldjumt8mhl"
+    );
+}
+
+#[tokio::test]
+async fn test_join_queries() {
+    let (a, b) = join_queries!(
+        "a" => async { Ok(1u64) },
+        "b" => async { Ok("b".to_owned()) },
+    )
+    .unwrap();
+    assert_eq!((a, b), (1, "b".to_owned()));
+    let start = Instant::now();
+    let e = join_queries!(
+        "slow" => async {
+            sleep(Duration::from_secs(60)).await;
+            Ok(())
+        },
+        "failing" => async { Err::<(), Error>(Error::from("bad denom".to_owned())) },
+    )
+    .unwrap_err();
+    // the slow query was cancelled
+    assert!(start.elapsed() < Duration::from_secs(10));
+    let e = format!("{e:?}");
+    assert!(e.contains("query failing failed"));
+    assert!(e.contains("bad denom"));
+}
+
+#[test]
+fn test_hex_encode() {
+    assert_eq!(hex_encode(&[]), "");
+    assert_eq!(hex_encode(&[0x00, 0x0f, 0xab, 0xff]), "000fabff");
+}
+
+#[tokio::test]
+async fn test_source_fingerprint_outside_git() {
+    let dir =
+        std::env::temp_dir().join(format!("onomy_test_lib_fingerprint_{}", std::process::id()));
+    tokio::fs::create_dir_all(&dir).await.unwrap();
+    let fingerprint = source_fingerprint(dir.to_str().unwrap()).await;
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+    assert!(fingerprint.unwrap().is_none());
+}
@@ -0,0 +1,341 @@
+//! Helpers for identifying peers and coordinating more than two runners over
+//! `NetMessenger`s
+
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    ops::{Deref, DerefMut},
+    time::{Duration, Instant},
+};
+
+use log::info;
+use musli::Decode;
+use super_orchestrator::{
+    net_message::NetMessenger,
+    stacked_errors::{Error, MapAddError, Result},
+};
+use tokio::time::sleep;
+
+/// Peer ids are plain strings, e.x. the name of the runner or container
+pub type PeerId = String;
+
+/// The newest handshake version that we support. Version 0 is the anonymous
+/// handshake where the peer id is defaulted to the remote address.
+pub const HANDSHAKE_VERSION: u32 = 1;
+
+const HANDSHAKE_PREFIX: &str = "onomy_test_handshake";
+
+fn handshake_msg(version: u32, peer_id: &str) -> String {
+    if version == 0 {
+        format!("{HANDSHAKE_PREFIX} v0")
+    } else {
+        format!("{HANDSHAKE_PREFIX} v{version} {peer_id}")
+    }
+}
+
+/// Returns the version and the peer id (if not anonymous), or `None` if `msg`
+/// is not a handshake at all because the peer is a legacy `NetMessenger`
+fn parse_handshake(msg: &str) -> Result<Option<(u32, Option<PeerId>)>> {
+    let mut iter = msg.splitn(3, ' ');
+    if iter.next() != Some(HANDSHAKE_PREFIX) {
+        return Ok(None)
+    }
+    let version: u32 = iter
+        .next()
+        .and_then(|v| v.strip_prefix('v'))
+        .and_then(|v| v.parse().ok())
+        .map_add_err(|| format!("parse_handshake -> invalid version in {msg}"))?;
+    let peer_id = iter
+        .next()
+        .filter(|id| !id.is_empty())
+        .map(|id| id.to_owned());
+    if (version != 0) && peer_id.is_none() {
+        return Err(Error::from(format!(
+            "parse_handshake -> handshake {msg} has no peer id"
+        )))
+    }
+    Ok(Some((version, peer_id)))
+}
+
+/// A `NetMessenger` that knows the identity of the peer on the other side.
+/// This derefs to the `NetMessenger`, so `send` and `recv` can be used
+/// directly.
+pub struct PeerMessenger {
+    pub nm: NetMessenger,
+    peer_id: PeerId,
+    version: u32,
+    /// The first message of a legacy peer, which was received in place of the
+    /// handshake
+    pending: Option<String>,
+}
+
+impl Deref for PeerMessenger {
+    type Target = NetMessenger;
+
+    fn deref(&self) -> &Self::Target {
+        &self.nm
+    }
+}
+
+impl DerefMut for PeerMessenger {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.nm
+    }
+}
+
+impl PeerMessenger {
+    /// Wraps a `NetMessenger` whose peer does not perform the handshake, the
+    /// peer id is defaulted to `addr`
+    pub fn legacy(nm: NetMessenger, addr: &str) -> Self {
+        Self {
+            nm,
+            peer_id: addr.to_owned(),
+            version: 0,
+            pending: None,
+        }
+    }
+
+    /// The id that the peer identified itself with, or the remote address if
+    /// the handshake was anonymous
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    /// The handshake version negotiated with the peer
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Performs the handshake over an already connected `nm`. Both sides send
+    /// the newest version they support along with their id, and the lower of
+    /// the two versions is used. If the negotiated version is 0 the peer id
+    /// defaults to `addr`. Passing `our_id: None` performs the anonymous
+    /// handshake. If the first message of the peer is not a handshake, the
+    /// peer is treated as [PeerMessenger::legacy] and the message is returned
+    /// by the next [PeerMessenger::recv].
+    pub async fn handshake(mut nm: NetMessenger, addr: &str, our_id: Option<&str>) -> Result<Self> {
+        let our_version = if our_id.is_some() {
+            HANDSHAKE_VERSION
+        } else {
+            0
+        };
+        nm.send::<String>(&handshake_msg(our_version, our_id.unwrap_or("")))
+            .await
+            .map_add_err(|| format!("PeerMessenger::handshake(addr: {addr})"))?;
+        let msg = nm
+            .recv::<String>()
+            .await
+            .map_add_err(|| format!("PeerMessenger::handshake(addr: {addr})"))?;
+        let Some((their_version, their_id)) = parse_handshake(&msg)
+            .map_add_err(|| format!("PeerMessenger::handshake(addr: {addr})"))?
+        else {
+            info!("peer at {addr} did not send a handshake, treating it as a legacy peer");
+            let mut pm = Self::legacy(nm, addr);
+            pm.pending = Some(msg);
+            return Ok(pm)
+        };
+        let version = our_version.min(their_version);
+        let peer_id = match their_id {
+            Some(id) if version != 0 => id,
+            _ => addr.to_owned(),
+        };
+        Ok(Self {
+            nm,
+            peer_id,
+            version,
+            pending: None,
+        })
+    }
+
+    /// `NetMessenger::recv` that first returns the message that a legacy peer
+    /// sent in place of the handshake (see [PeerMessenger::handshake]), which
+    /// is always a `String`
+    pub async fn recv<T: for<'de> Decode<'de> + 'static>(&mut self) -> Result<T> {
+        if let Some(msg) = self.pending.take() {
+            let msg: Box<dyn Any> = Box::new(msg);
+            return msg.downcast::<T>().map(|msg| *msg).map_err(|_| {
+                Error::from(format!(
+                    "PeerMessenger::recv -> the legacy peer {} sent a `String` first but a \
+                     different type was expected",
+                    self.peer_id
+                ))
+            })
+        }
+        self.nm.recv::<T>().await
+    }
+
+    /// `NetMessenger::connect` followed by [PeerMessenger::handshake]. The
+    /// whole connection is retried up to `num_retries` times if the handshake
+    /// fails, because [listen_multi] only accepts one connection at a time and
+    /// may drop queued connections.
+    pub async fn connect(
+        num_retries: u64,
+        delay: Duration,
+        addr: &str,
+        our_id: Option<&str>,
+    ) -> Result<Self> {
+        let mut i = 0;
+        loop {
+            let res = async {
+                // the retries are all done by this loop
+                let nm = NetMessenger::connect(0, delay, addr).await?;
+                Self::handshake(nm, addr, our_id).await
+            }
+            .await;
+            match res {
+                Ok(pm) => {
+                    info!("connected to peer {} at {addr}", pm.peer_id());
+                    return Ok(pm)
+                }
+                Err(e) => {
+                    if i >= num_retries {
+                        return Err(e).map_add_err(|| {
+                            format!("PeerMessenger::connect(addr: {addr}, our_id: {our_id:?})")
+                        })
+                    }
+                }
+            }
+            i += 1;
+            sleep(delay).await;
+        }
+    }
+
+    /// `NetMessenger::listen_single_connect` followed by
+    /// [PeerMessenger::handshake]
+    pub async fn listen_single_connect(
+        addr: &str,
+        timeout: Duration,
+        our_id: Option<&str>,
+    ) -> Result<Self> {
+        let nm = NetMessenger::listen_single_connect(addr, timeout)
+            .await
+            .map_add_err(|| format!("PeerMessenger::listen_single_connect(addr: {addr})"))?;
+        let pm = Self::handshake(nm, addr, our_id).await?;
+        info!("peer {} connected to {addr}", pm.peer_id());
+        Ok(pm)
+    }
+}
+
+/// Connects to the listener at `addr` (see [listen_multi]) and identifies
+/// ourselves as `peer_id`
+pub async fn connect_as(
+    num_retries: u64,
+    delay: Duration,
+    addr: &str,
+    peer_id: &str,
+) -> Result<PeerMessenger> {
+    PeerMessenger::connect(num_retries, delay, addr, Some(peer_id)).await
+}
+
+/// Accepts `expected_peers` connections on `addr` from peers using
+/// [connect_as], identifying ourselves as `our_id`, and returns the messengers
+/// keyed by the peer ids. The connections are accepted one at a time, so peers
+/// should connect with retries. Returns an error if two peers have the same id
+/// or if not all peers connected within `timeout`.
+pub async fn listen_multi(
+    addr: &str,
+    our_id: &str,
+    expected_peers: usize,
+    timeout: Duration,
+) -> Result<BTreeMap<PeerId, PeerMessenger>> {
+    let start = Instant::now();
+    let mut peers = BTreeMap::new();
+    while peers.len() < expected_peers {
+        let remaining = timeout.checked_sub(start.elapsed()).map_add_err(|| {
+            format!(
+                "listen_multi(addr: {addr}, expected_peers: {expected_peers}) timeout with only \
+                 peers {:?}",
+                peers.keys().collect::<Vec<_>>()
+            )
+        })?;
+        let pm = PeerMessenger::listen_single_connect(addr, remaining, Some(our_id))
+            .await
+            .map_add_err(|| format!("listen_multi(addr: {addr})"))?;
+        let peer_id = pm.peer_id().to_owned();
+        if peers.insert(peer_id.clone(), pm).is_some() {
+            return Err(Error::from(format!(
+                "listen_multi(addr: {addr}) peer id {peer_id} connected more than once"
+            )))
+        }
+    }
+    Ok(peers)
+}
+
+#[test]
+fn test_handshake_msg() {
+    assert_eq!(
+        parse_handshake(&handshake_msg(1, "hermes")).unwrap(),
+        Some((1, Some("hermes".to_owned())))
+    );
+    assert_eq!(
+        parse_handshake(&handshake_msg(2, "onomyd 0")).unwrap(),
+        Some((2, Some("onomyd 0".to_owned())))
+    );
+    assert_eq!(
+        parse_handshake(&handshake_msg(0, "ignored")).unwrap(),
+        Some((0, None))
+    );
+    assert_eq!(parse_handshake("mnemonic words").unwrap(), None);
+    assert!(parse_handshake("onomy_test_handshake v1").is_err());
+    assert!(parse_handshake("onomy_test_handshake vx hermes").is_err());
+}
+
+#[tokio::test]
+async fn test_legacy_peer() {
+    let addr = "127.0.0.1:26098";
+    let handle = tokio::spawn(async move {
+        let mut nm = NetMessenger::listen_single_connect(addr, Duration::from_secs(10))
+            .await
+            .unwrap();
+        nm.send::<String>(&"mnemonic words".to_owned())
+            .await
+            .unwrap();
+        nm.send::<u64>(&1337).await.unwrap();
+        // the handshake is received as a regular message
+        nm.recv::<String>().await.unwrap()
+    });
+    let mut nm = PeerMessenger::connect(100, Duration::from_millis(50), addr, Some("onomyd"))
+        .await
+        .unwrap();
+    assert_eq!(nm.peer_id(), addr);
+    assert_eq!(nm.version(), 0);
+    assert_eq!(nm.recv::<String>().await.unwrap(), "mnemonic words");
+    assert_eq!(nm.recv::<u64>().await.unwrap(), 1337);
+    assert_eq!(
+        handle.await.unwrap(),
+        handshake_msg(HANDSHAKE_VERSION, "onomyd")
+    );
+}
+
+#[tokio::test]
+async fn test_listen_multi() {
+    let addr = "127.0.0.1:26099";
+    let mut handles = vec![];
+    for peer_id in ["onomyd_0", "onomyd_1", "onomyd_2"] {
+        handles.push(tokio::spawn(async move {
+            let mut nm = connect_as(100, Duration::from_millis(50), addr, peer_id)
+                .await
+                .unwrap();
+            assert_eq!(nm.peer_id(), "hermes");
+            assert_eq!(nm.version(), HANDSHAKE_VERSION);
+            assert_eq!(
+                nm.recv::<String>().await.unwrap(),
+                format!("hello {peer_id}")
+            );
+        }));
+    }
+    let mut peers = listen_multi(addr, "hermes", 3, Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(peers.keys().collect::<Vec<_>>(), vec![
+        "onomyd_0", "onomyd_1", "onomyd_2"
+    ]);
+    for (peer_id, nm) in &mut peers {
+        nm.send::<String>(&format!("hello {peer_id}"))
+            .await
+            .unwrap();
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+//! Named ports used by the test containers. The daemon ports are what the
+//! chain daemons serve on, the [OrchestratorPorts] are only used by the
+//! runners to coordinate with each other over `NetMessenger`s.
+
+/// Tendermint P2P
+pub const P2P_PORT: u16 = 26656;
+/// Tendermint RPC, also serves the websocket at "/websocket"
+pub const RPC_PORT: u16 = 26657;
+/// Tendermint Prometheus metrics (`instrumentation.prometheus_listen_addr`),
+/// note that this is distinct from [GRPC_PORT]
+pub const PROMETHEUS_PORT: u16 = 26660;
+/// Cosmos-SDK gRPC
+pub const GRPC_PORT: u16 = 9090;
+/// Cosmos-SDK REST API
+pub const REST_PORT: u16 = 1317;
+/// Go pprof endpoints of the daemon (`--rpc.pprof_laddr`)
+pub const PPROF_PORT: u16 = 6060;
+
+/// The `NetMessenger` ports that runners listen on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrchestratorPorts {
+    /// The port that the Hermes runner listens on
+    pub hermes: u16,
+    /// The port that consumer chain runners and other secondary daemon runners
+    /// (e.x. seed or state sync nodes) listen on
+    pub node: u16,
+}
+
+pub const ORCHESTRATOR_PORTS: OrchestratorPorts = OrchestratorPorts {
+    hermes: 26000,
+    node: 26001,
+};
+
+impl Default for OrchestratorPorts {
+    fn default() -> Self {
+        ORCHESTRATOR_PORTS
+    }
+}
+
+impl OrchestratorPorts {
+    /// The address for the Hermes runner to listen on
+    pub fn hermes_listen_addr(&self) -> String {
+        format!("0.0.0.0:{}", self.hermes)
+    }
+
+    /// The address to connect to the Hermes runner with, assuming the container
+    /// is named "hermes"
+    pub fn hermes_addr(&self) -> String {
+        format!("hermes:{}", self.hermes)
+    }
+
+    /// The address for a secondary runner to listen on
+    pub fn node_listen_addr(&self) -> String {
+        format!("0.0.0.0:{}", self.node)
+    }
+
+    /// The address to connect to the secondary runner in the `host` container
+    pub fn node_addr(&self, host: &str) -> String {
+        format!("{host}:{}", self.node)
+    }
+}
+
+#[test]
+fn test_orchestrator_ports() {
+    let ports = OrchestratorPorts::default();
+    assert_ne!(ports.hermes, ports.node);
+    for port in [ports.hermes, ports.node] {
+        assert!(![
+            P2P_PORT,
+            RPC_PORT,
+            PROMETHEUS_PORT,
+            GRPC_PORT,
+            REST_PORT,
+            PPROF_PORT,
+        ]
+        .contains(&port));
+    }
+    assert_eq!(ports.hermes_listen_addr(), "0.0.0.0:26000");
+    assert_eq!(ports.hermes_addr(), "hermes:26000");
+    assert_eq!(ports.node_addr("marketd"), "marketd:26001");
+}
@@ -8,10 +8,15 @@ use tokio::time::sleep;
 
 use crate::{
     cosmovisor::{
-        cosmovisor_get_addr, cosmovisor_gov_file_proposal, fast_block_times, force_chain_id,
-        set_minimum_gas_price, sh_cosmovisor, sh_cosmovisor_no_dbg, sh_cosmovisor_tx,
-        wait_for_num_blocks,
+        cosmovisor_get_addr, cosmovisor_get_num_proposals, cosmovisor_gov_vote,
+        cosmovisor_keys_add, cosmovisor_keys_recover, cosmovisor_run_with_keyring,
+        cosmovisor_set_keyring_backend, cosmovisor_submit_gov_file_proposal, fast_block_times,
+        force_chain_id, set_minimum_gas_price, sh_cosmovisor, sh_cosmovisor_no_dbg,
+        sh_cosmovisor_tx, wait_for_num_blocks, wait_for_proposal_status, GasConfig, KeyInfo,
+        KeyringBackend,
     },
+    genesis::{apply_genesis_patches, CcvConsumerGenesis, GenesisPatch},
+    ibc::wait_for_consumer_removed,
     json_inner, native_denom, nom, nom_denom, token18, ONOMY_IBC_NOM, TIMEOUT,
 };
 
@@ -26,10 +31,67 @@ fn _unused() {
 ///
 /// This additionally returns the single validator mnemonic
 pub async fn onomyd_setup(daemon_home: &str) -> Result<String> {
+    let (mnemonic, _) =
+        onomyd_setup_inner(daemon_home, &KeyringBackend::Test, None, &[], &[]).await?;
+    Ok(mnemonic)
+}
+
+/// The same as [onomyd_setup] but with a configurable keyring backend
+pub async fn onomyd_setup_with_keyring(
+    daemon_home: &str,
+    keyring: &KeyringBackend,
+) -> Result<String> {
+    let (mnemonic, _) = onomyd_setup_inner(daemon_home, keyring, None, &[], &[]).await?;
+    Ok(mnemonic)
+}
+
+/// The same as [onomyd_setup] but the validator key is recovered from
+/// `mnemonic`, so that its address is the same across runs
+pub async fn onomyd_setup_with_mnemonic(daemon_home: &str, mnemonic: &str) -> Result<String> {
+    let (mnemonic, _) =
+        onomyd_setup_inner(daemon_home, &KeyringBackend::Test, Some(mnemonic), &[], &[]).await?;
+    Ok(mnemonic)
+}
+
+/// The same as [onomyd_setup] but additionally creates a key with a genesis
+/// balance for each `(name, coins)` in `accounts`, e.x. `&[("whale",
+/// &nom(50.0e6)), ("dust", "1anom")]`. Returns the validator mnemonic and the
+/// created keys in the same order as `accounts`.
+pub async fn onomyd_setup_with_accounts(
+    daemon_home: &str,
+    accounts: &[(&str, &str)],
+) -> Result<(String, Vec<KeyInfo>)> {
+    onomyd_setup_inner(daemon_home, &KeyringBackend::Test, None, accounts, &[]).await
+}
+
+/// The same as [onomyd_setup], but `genesis_patches` (e.x.
+/// `set_genesis_time`) are applied after the standard genesis edits
+pub async fn onomyd_setup_with_patches(
+    daemon_home: &str,
+    genesis_patches: &[GenesisPatch],
+) -> Result<String> {
+    let (mnemonic, _) = onomyd_setup_inner(
+        daemon_home,
+        &KeyringBackend::Test,
+        None,
+        &[],
+        genesis_patches,
+    )
+    .await?;
+    Ok(mnemonic)
+}
+
+async fn onomyd_setup_inner(
+    daemon_home: &str,
+    keyring: &KeyringBackend,
+    mnemonic: Option<&str>,
+    accounts: &[(&str, &str)],
+    genesis_patches: &[GenesisPatch],
+) -> Result<(String, Vec<KeyInfo>)> {
     let chain_id = "onomy";
     let global_min_self_delegation = &token18(225.0e3, "");
     sh_cosmovisor("config chain-id", &[chain_id]).await?;
-    sh_cosmovisor("config keyring-backend test", &[]).await?;
+    cosmovisor_set_keyring_backend(keyring).await?;
     sh_cosmovisor_no_dbg("init --overwrite", &[chain_id]).await?;
 
     let genesis_file_path = format!("{daemon_home}/config/genesis.json");
@@ -63,6 +125,8 @@ pub async fn onomyd_setup(daemon_home: &str) -> Result<String> {
     genesis["app_state"]["gov"]["voting_params"]["voting_period"] = gov_period.clone();
     genesis["app_state"]["gov"]["deposit_params"]["max_deposit_period"] = gov_period;
 
+    apply_genesis_patches(&mut genesis, genesis_patches);
+
     // write back genesis
     let genesis_s = serde_json::to_string(&genesis)?;
     FileOptions::write_str(&genesis_file_path, &genesis_s).await?;
@@ -72,37 +136,53 @@ pub async fn onomyd_setup(daemon_home: &str) -> Result<String> {
 
     set_minimum_gas_price(daemon_home, "1anom").await?;
 
-    // we need the stderr to get the mnemonic
-    let comres = Command::new("cosmovisor run keys add validator", &[])
-        .run_to_completion()
-        .await?;
-    comres.assert_success()?;
-    let mnemonic = comres
-        .stderr
-        .trim()
-        .lines()
-        .last()
-        .map_add_err(|| "no last line")?
-        .trim()
-        .to_owned();
-    sh_cosmovisor("add-genesis-account validator", &[&nom(2.0e6)]).await?;
+    let mnemonic = if let Some(mnemonic) = mnemonic {
+        cosmovisor_keys_recover("validator", mnemonic, keyring).await?;
+        mnemonic.to_owned()
+    } else {
+        cosmovisor_keys_add("validator", keyring).await?
+    };
+    cosmovisor_run_with_keyring("add-genesis-account validator", &[&nom(2.0e6)], keyring)
+        .await?
+        .assert_success()?;
 
     // unconditionally needed for some Arc tests
-    sh_cosmovisor("keys add orchestrator", &[]).await?;
-    sh_cosmovisor("add-genesis-account orchestrator", &[&nom(2.0e6)]).await?;
-
-    sh_cosmovisor("gentx validator", &[
-        &nom(1.0e6),
-        "--chain-id",
-        chain_id,
-        "--min-self-delegation",
-        global_min_self_delegation,
-    ])
-    .await?;
+    cosmovisor_keys_add("orchestrator", keyring).await?;
+    cosmovisor_run_with_keyring("add-genesis-account orchestrator", &[&nom(2.0e6)], keyring)
+        .await?
+        .assert_success()?;
+
+    let mut keys = vec![];
+    for (name, coins) in accounts {
+        let mnemonic = cosmovisor_keys_add(name, keyring).await?;
+        cosmovisor_run_with_keyring(&format!("add-genesis-account {name}"), &[coins], keyring)
+            .await?
+            .assert_success()
+            .map_add_err(|| format!("onomyd_setup_with_accounts(name: {name}, coins: {coins})"))?;
+        keys.push(KeyInfo {
+            name: (*name).to_owned(),
+            address: cosmovisor_get_addr(name).await?,
+            mnemonic,
+        });
+    }
+
+    cosmovisor_run_with_keyring(
+        "gentx validator",
+        &[
+            &nom(1.0e6),
+            "--chain-id",
+            chain_id,
+            "--min-self-delegation",
+            global_min_self_delegation,
+        ],
+        keyring,
+    )
+    .await?
+    .assert_success()?;
 
     sh_cosmovisor_no_dbg("collect-gentxs", &[]).await?;
 
-    Ok(mnemonic)
+    Ok((mnemonic, keys))
 }
 
 pub async fn market_standaloned_setup(daemon_home: &str) -> Result<String> {
@@ -245,8 +325,41 @@ pub async fn gravity_standalone_setup(daemon_home: &str) -> Result<String> {
     Ok(mnemonic)
 }
 
+/// The `--gas-adjustment` of the votes on the ICS proposals, market ICS needs
+/// this for some reason
+const ICS_VOTE_GAS_ADJUSTMENT: f64 = 2.3;
+
+/// The same as [crate::cosmovisor::cosmovisor_gov_file_proposal], except
+/// that the vote uses [ICS_VOTE_GAS_ADJUSTMENT]
+async fn ics_gov_file_proposal(
+    daemon_home: &str,
+    proposal_type: &str,
+    proposal_s: &str,
+) -> Result<()> {
+    cosmovisor_submit_gov_file_proposal(
+        daemon_home,
+        proposal_type,
+        proposal_s,
+        &GasConfig::auto("anom"),
+    )
+    .await
+    .map_add_err(|| format!("ics_gov_file_proposal(proposal_type: {proposal_type})"))?;
+    let proposal_id = cosmovisor_get_num_proposals().await?;
+    // the deposit is done as part of the proposal
+    cosmovisor_gov_vote(proposal_id, "yes", "validator", &GasConfig::Auto {
+        adjustment: ICS_VOTE_GAS_ADJUSTMENT,
+        price: 1.0,
+        denom: "anom".to_owned(),
+    })
+    .await?;
+    Ok(())
+}
+
 /// This should be run from the provider. Returns the ccv state.
-pub async fn cosmovisor_add_consumer(daemon_home: &str, consumer_id: &str) -> Result<String> {
+pub async fn cosmovisor_add_consumer(
+    daemon_home: &str,
+    consumer_id: &str,
+) -> Result<CcvConsumerGenesis> {
     // `json!` doesn't like large literals beyond i32.
     // note: when changing this, check market_genesis.json
     // to see if changes are going all the way through.
@@ -276,7 +389,7 @@ pub async fn cosmovisor_add_consumer(daemon_home: &str, consumer_id: &str) -> Re
         "reward_denoms": []
     }}"#
     );
-    cosmovisor_gov_file_proposal(daemon_home, "consumer-addition", proposal_s, "1anom").await?;
+    ics_gov_file_proposal(daemon_home, "consumer-addition", proposal_s).await?;
     wait_for_num_blocks(1).await?;
 
     let tendermint_key: Value = serde_json::from_str(
@@ -320,15 +433,52 @@ pub async fn cosmovisor_add_consumer(daemon_home: &str, consumer_id: &str) -> Re
     // `consumer-genesis` currently does not handle all keys, we have to set
     // `soft_opt_out_threshold` here.
     state["params"]["soft_opt_out_threshold"] = "0.0".into();
-    let ccvconsumer_state = serde_json::to_string(&state)?;
 
-    Ok(ccvconsumer_state)
+    CcvConsumerGenesis::from_json(state)
+}
+
+/// This should be run from the provider. Passes a `consumer-removal` proposal
+/// for `consumer_id` and waits for the consumer to be removed, which also
+/// closes the provider side of the ICS channel. `stop_time` is an RFC 3339
+/// time, a time in the past removes the consumer as soon as the proposal
+/// passes.
+pub async fn cosmovisor_remove_consumer(
+    daemon_home: &str,
+    consumer_id: &str,
+    stop_time: &str,
+) -> Result<()> {
+    let proposal_s = &format!(
+        r#"{{
+        "title": "Propose the removal of a consumer chain",
+        "description": "remove consumer chain",
+        "chain_id": "{consumer_id}",
+        "stop_time": "{stop_time}",
+        "deposit": "2000000000000000000000anom"
+    }}"#
+    );
+    ics_gov_file_proposal(daemon_home, "consumer-removal", proposal_s)
+        .await
+        .map_add_err(|| format!("cosmovisor_remove_consumer(consumer_id: {consumer_id})"))?;
+    let proposal_id = cosmovisor_get_num_proposals().await?;
+    wait_for_proposal_status(proposal_id, "PROPOSAL_STATUS_PASSED", TIMEOUT).await?;
+    wait_for_consumer_removed(consumer_id, TIMEOUT).await
 }
 
 pub async fn marketd_setup(
     daemon_home: &str,
     chain_id: &str,
-    ccvconsumer_state_s: &str,
+    ccvconsumer_genesis: &CcvConsumerGenesis,
+) -> Result<()> {
+    marketd_setup_with_patches(daemon_home, chain_id, ccvconsumer_genesis, &[]).await
+}
+
+/// The same as [marketd_setup], but `genesis_patches` are applied after the
+/// standard genesis edits
+pub async fn marketd_setup_with_patches(
+    daemon_home: &str,
+    chain_id: &str,
+    ccvconsumer_genesis: &CcvConsumerGenesis,
+    genesis_patches: &[GenesisPatch],
 ) -> Result<()> {
     sh_cosmovisor("config chain-id", &[chain_id]).await?;
     sh_cosmovisor("config keyring-backend test", &[]).await?;
@@ -343,8 +493,7 @@ pub async fn marketd_setup(
 
     force_chain_id(daemon_home, &mut genesis, chain_id).await?;
 
-    let ccvconsumer_state: Value = serde_json::from_str(ccvconsumer_state_s)?;
-    genesis["app_state"]["ccvconsumer"] = ccvconsumer_state;
+    genesis["app_state"]["ccvconsumer"] = ccvconsumer_genesis.as_json().clone();
 
     // decrease the governing period for fast tests
     let gov_period = "800ms";
@@ -369,6 +518,8 @@ pub async fn marketd_setup(
     genesis["app_state"]["mint"]["params"]["inflation_max"] = "0.0".into();
     genesis["app_state"]["mint"]["params"]["inflation_rate_change"] = "0.0".into();
 
+    apply_genesis_patches(&mut genesis, genesis_patches);
+
     let genesis_s = genesis.to_string();
 
     FileOptions::write_str(&genesis_file_path, &genesis_s).await?;
@@ -394,7 +545,7 @@ pub async fn marketd_setup(
 pub async fn arc_consumer_setup(
     daemon_home: &str,
     chain_id: &str,
-    ccvconsumer_state_s: &str,
+    ccvconsumer_genesis: &CcvConsumerGenesis,
 ) -> Result<()> {
     sh_cosmovisor("config chain-id", &[chain_id]).await?;
     sh_cosmovisor("config keyring-backend test", &[]).await?;
@@ -409,8 +560,7 @@ pub async fn arc_consumer_setup(
 
     force_chain_id(daemon_home, &mut genesis, chain_id).await?;
 
-    let ccvconsumer_state: Value = serde_json::from_str(ccvconsumer_state_s)?;
-    genesis["app_state"]["ccvconsumer"] = ccvconsumer_state;
+    genesis["app_state"]["ccvconsumer"] = ccvconsumer_genesis.as_json().clone();
 
     // write back genesis
     let genesis_s = serde_json::to_string(&genesis)?;
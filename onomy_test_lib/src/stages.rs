@@ -0,0 +1,237 @@
+//! Named stages of the ICS flow, so that tests can run extra assertions and
+//! queries at specific points without editing the runners
+
+use std::{fmt, future::Future, pin::Pin};
+
+use log::info;
+use super_orchestrator::stacked_errors::{Error, MapAddError, Result};
+
+/// The stages of an ICS test in the order they are reached. A runner only
+/// enters the stages it observes, e.x. the hermes runner has nothing to do
+/// with `ConsumerProposed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IcsStage {
+    /// The provider chain is producing blocks
+    ProviderUp,
+    /// The consumer addition proposal has passed and the consumer genesis is
+    /// available
+    ConsumerProposed,
+    /// The consumer chain is producing blocks
+    ConsumerLaunched,
+    /// The IBC connection and the transfer and ICS channels are open
+    ChannelsOpen,
+    /// Tokens have been transferred from the provider to the consumer
+    TransferredOut,
+    /// Tokens have been transferred back and everything has been relayed
+    RelayedBack,
+}
+
+impl IcsStage {
+    /// All the stages in order
+    pub const ALL: [IcsStage; 6] = [
+        IcsStage::ProviderUp,
+        IcsStage::ConsumerProposed,
+        IcsStage::ConsumerLaunched,
+        IcsStage::ChannelsOpen,
+        IcsStage::TransferredOut,
+        IcsStage::RelayedBack,
+    ];
+}
+
+impl fmt::Display for IcsStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+pub type StageHook = Box<dyn Fn() -> Pin<Box<dyn Future<Output = Result<()>>>>>;
+
+pub type StageFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + 'a>>;
+
+/// The orchestration of one side of the ICS flow, driven by
+/// [IcsStages::drive]
+pub trait IcsFlow {
+    /// Does whatever is needed to reach `stage` from the previous stage
+    fn reach(&mut self, stage: IcsStage) -> StageFuture<'_>;
+}
+
+/// Tracks the current [IcsStage] of a runner and runs the hooks registered
+/// with [IcsStages::on_stage] when a stage is entered
+#[derive(Default)]
+pub struct IcsStages {
+    hooks: Vec<(IcsStage, StageHook)>,
+    current: Option<IcsStage>,
+}
+
+impl fmt::Debug for IcsStages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hooks: Vec<IcsStage> = self.hooks.iter().map(|(stage, _)| *stage).collect();
+        f.debug_struct("IcsStages")
+            .field("hooks", &hooks)
+            .field("current", &self.current)
+            .finish()
+    }
+}
+
+impl IcsStages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `hook` to be run when `stage` is entered. Hooks for the same
+    /// stage run in the order they were registered.
+    pub fn on_stage<F, Fut>(&mut self, stage: IcsStage, hook: F) -> &mut Self
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = Result<()>> + 'static,
+    {
+        self.hooks.push((stage, Box::new(move || Box::pin(hook()))));
+        self
+    }
+
+    /// The last stage entered
+    pub fn current(&self) -> Option<IcsStage> {
+        self.current
+    }
+
+    /// Enters `stage` and runs its hooks. Stages may be skipped, but returns
+    /// an error if `stage` is not after the current stage.
+    pub async fn enter(&mut self, stage: IcsStage) -> Result<()> {
+        if let Some(current) = self.current {
+            if stage <= current {
+                return Err(Error::from(format!(
+                    "IcsStages::enter({stage}) but the current stage is already {current}"
+                )))
+            }
+        }
+        info!("entering stage {stage}");
+        self.current = Some(stage);
+        for (hook_stage, hook) in &self.hooks {
+            if *hook_stage == stage {
+                hook()
+                    .await
+                    .map_add_err(|| format!("hook for stage {stage} failed"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drives `flow` through the remaining stages in order, entering each
+    /// stage (and running its hooks) as soon as `flow` has reached it
+    pub async fn drive<F: IcsFlow>(&mut self, flow: &mut F) -> Result<()> {
+        let current = self.current;
+        for stage in IcsStage::ALL
+            .into_iter()
+            .filter(|stage| current.is_none_or(|current| *stage > current))
+        {
+            flow.reach(stage)
+                .await
+                .map_add_err(|| format!("IcsStages::drive failed to reach stage {stage}"))?;
+            self.enter(stage).await?;
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_ics_stages() {
+    use std::{cell::RefCell, rc::Rc};
+
+    let order = Rc::new(RefCell::new(vec![]));
+    let mut stages = IcsStages::new();
+    let tmp = order.clone();
+    stages.on_stage(IcsStage::ChannelsOpen, move || {
+        let tmp = tmp.clone();
+        async move {
+            tmp.borrow_mut().push(1);
+            Ok(())
+        }
+    });
+    let tmp = order.clone();
+    stages.on_stage(IcsStage::ChannelsOpen, move || {
+        let tmp = tmp.clone();
+        async move {
+            tmp.borrow_mut().push(2);
+            Ok(())
+        }
+    });
+    stages.on_stage(IcsStage::RelayedBack, || async {
+        Err(Error::from("custom assertion".to_owned()))
+    });
+    stages.enter(IcsStage::ProviderUp).await.unwrap();
+    assert!(order.borrow().is_empty());
+    stages.enter(IcsStage::ChannelsOpen).await.unwrap();
+    assert_eq!(*order.borrow(), vec![1, 2]);
+    assert_eq!(stages.current(), Some(IcsStage::ChannelsOpen));
+    assert!(stages.enter(IcsStage::ConsumerLaunched).await.is_err());
+    assert!(stages.enter(IcsStage::ChannelsOpen).await.is_err());
+    let e = format!(
+        "{:?}",
+        stages.enter(IcsStage::RelayedBack).await.unwrap_err()
+    );
+    assert!(e.contains("custom assertion"));
+}
+
+#[tokio::test]
+async fn test_ics_stages_drive() {
+    use std::{cell::RefCell, rc::Rc};
+
+    struct Flow {
+        log: Rc<RefCell<Vec<String>>>,
+        fail_at: Option<IcsStage>,
+    }
+
+    impl IcsFlow for Flow {
+        fn reach(&mut self, stage: IcsStage) -> StageFuture<'_> {
+            Box::pin(async move {
+                if self.fail_at == Some(stage) {
+                    return Err(Error::from("flow failure".to_owned()))
+                }
+                self.log.borrow_mut().push(format!("reach {stage}"));
+                Ok(())
+            })
+        }
+    }
+
+    let log = Rc::new(RefCell::new(vec![]));
+    let mut stages = IcsStages::new();
+    let tmp = log.clone();
+    stages.on_stage(IcsStage::ConsumerLaunched, move || {
+        let tmp = tmp.clone();
+        async move {
+            tmp.borrow_mut().push("hook".to_owned());
+            Ok(())
+        }
+    });
+    let mut flow = Flow {
+        log: log.clone(),
+        fail_at: None,
+    };
+    stages.drive(&mut flow).await.unwrap();
+    assert_eq!(stages.current(), Some(IcsStage::RelayedBack));
+    // hooks run after their stage is reached and before the next one
+    assert_eq!(log.borrow()[..4], [
+        "reach ProviderUp",
+        "reach ConsumerProposed",
+        "reach ConsumerLaunched",
+        "hook"
+    ]);
+    assert_eq!(log.borrow().len(), 7);
+
+    // resumes after the current stage and stops at the first failure
+    let log = Rc::new(RefCell::new(vec![]));
+    let mut stages = IcsStages::new();
+    stages.enter(IcsStage::ConsumerProposed).await.unwrap();
+    let mut flow = Flow {
+        log: log.clone(),
+        fail_at: Some(IcsStage::TransferredOut),
+    };
+    let e = format!("{:?}", stages.drive(&mut flow).await.unwrap_err());
+    assert!(e.contains("flow failure"), "{e}");
+    assert!(e.contains("TransferredOut"), "{e}");
+    assert_eq!(*log.borrow(), vec![
+        "reach ConsumerLaunched".to_owned(),
+        "reach ChannelsOpen".to_owned()
+    ]);
+    assert_eq!(stages.current(), Some(IcsStage::ChannelsOpen));
+}
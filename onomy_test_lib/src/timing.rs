@@ -0,0 +1,108 @@
+//! Durations of the phases of a test (e.x. building, booting, the IBC
+//! handshakes), so that it is visible where the test time goes
+
+use std::{
+    fmt,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use log::info;
+use serde_json::{json, Value};
+use super_orchestrator::{
+    stacked_errors::{MapAddError, Result},
+    FileOptions,
+};
+
+/// Records the durations of named phases in the order they were recorded
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PhaseTimer {
+    phases: Vec<(String, Duration)>,
+}
+
+impl PhaseTimer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, name: &str, duration: Duration) {
+        self.phases.push((name.to_owned(), duration));
+    }
+
+    /// Awaits `fut` and records how long it took under `name`, the output is
+    /// returned regardless of if it is an error. See also [crate::phase].
+    pub async fn phase<T, F: Future<Output = T>>(&mut self, name: &str, fut: F) -> T {
+        let start = Instant::now();
+        let res = fut.await;
+        let duration = start.elapsed();
+        info!("phase \"{name}\" took {duration:?}");
+        self.record(name, duration);
+        res
+    }
+
+    pub fn phases(&self) -> &[(String, Duration)] {
+        &self.phases
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|(_, duration)| *duration).sum()
+    }
+
+    /// e.x. `{"phases": [{"name": "build", "secs": 120.5}], "total_secs":
+    /// 120.5}`, for tracking trends in CI
+    pub fn to_json(&self) -> Value {
+        let phases: Vec<Value> = self
+            .phases
+            .iter()
+            .map(|(name, duration)| json!({"name": name, "secs": duration.as_secs_f64()}))
+            .collect();
+        json!({"phases": phases, "total_secs": self.total().as_secs_f64()})
+    }
+
+    pub async fn write_json(&self, path: &str) -> Result<()> {
+        FileOptions::write_str(path, &self.to_json().to_string())
+            .await
+            .map_add_err(|| format!("PhaseTimer::write_json(path: {path})"))
+    }
+}
+
+/// e.x. "build 120.5s, onomyd boot 8.0s"
+impl fmt::Display for PhaseTimer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, duration)) in self.phases.iter().enumerate() {
+            if i != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{name} {:.1}s", duration.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the async `body` as a phase of `timer`, e.x.
+///
+/// ```text
+/// let ibc_pair = phase!(timer, "handshake", {
+///     IbcPair::hermes_setup_pair(CONSUMER_ID, "onomy").await
+/// })?;
+/// ```
+#[macro_export]
+macro_rules! phase {
+    ($timer:expr, $name:expr, $body:block) => {
+        $timer.phase($name, async $body).await
+    };
+}
+
+#[test]
+fn test_phase_timer() {
+    let mut timer = PhaseTimer::new();
+    assert_eq!(timer.to_string(), "");
+    timer.record("build", Duration::from_millis(120_040));
+    timer.record("onomyd boot", Duration::from_secs(8));
+    assert_eq!(timer.to_string(), "build 120.0s, onomyd boot 8.0s");
+    assert_eq!(timer.total(), Duration::from_millis(128_040));
+    let v = timer.to_json();
+    assert_eq!(v["phases"][1]["name"], "onomyd boot");
+    assert_eq!(v["phases"][1]["secs"], 8.0);
+    assert_eq!(v["total_secs"], 128.04);
+}
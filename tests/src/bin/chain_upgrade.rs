@@ -1,30 +1,44 @@
+use common::{
+    build_runner, container_network, host_ports, preflight, publish_host_ports, wait_for_network,
+    CONTAINER_TARGET,
+};
 use log::info;
 use onomy_test_lib::{
+    containers::ComposeService,
     cosmovisor::{
-        cosmovisor_gov_proposal, cosmovisor_start, get_block_height, get_staking_pool,
-        get_treasury, get_treasury_inflation_annual, sh_cosmovisor, wait_for_height,
+        assert_halts_at_height, cosmovisor_gov_proposal, cosmovisor_start, get_block_height,
+        get_persistent_peer_info, get_staking_pool, get_treasury, get_treasury_inflation_annual,
+        set_minimum_gas_price, set_persistent_peers, sh_cosmovisor, sh_cosmovisor_no_dbg,
+        wait_for_height, GasConfig,
     },
-    nom, onomy_std_init,
-    setups::onomyd_setup,
+    dispatch_entry,
+    genesis::{assert_exports_equivalent, modules_grown_by, set_genesis_time},
+    hold_for_keep_running, nom, onomy_std_init,
+    ports::ORCHESTRATOR_PORTS,
+    setups::onomyd_setup_with_patches,
     super_orchestrator::{
-        docker::{Container, ContainerNetwork, Dockerfile},
-        sh,
+        net_message::NetMessenger,
         stacked_errors::{MapAddError, Result},
-        STD_DELAY, STD_TRIES,
+        FileOptions, STD_DELAY, STD_TRIES,
     },
-    Args, TIMEOUT,
+    yaml_str_to_json_value, Args, EntryArgs, MapAddContext, TIMEOUT,
 };
 
+/// Pinned so that exports are reproducible across runs
+const GENESIS_TIME: &str = "2023-01-01T00:00:00Z";
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = onomy_std_init()?;
 
-    if let Some(ref s) = args.entry_name {
-        match s.as_str() {
-            "onomyd" => onomyd_runner(&args).await,
-            _ => format!("entry_name \"{s}\" is not recognized").map_add_err(|| ()),
-        }
+    if args.entry_name.is_some() {
+        dispatch_entry(&args, &[
+            ("onomyd", |args| Box::pin(onomyd_runner(args))),
+            ("onomyd_full", |args| Box::pin(onomyd_full_runner(args))),
+        ])
+        .await
     } else {
+        preflight(&args, &host_ports(&args)).await?;
         /*sh("make --directory ./../onomy/ build", &[]).await?;
         // copy to dockerfile resources (docker cannot use files from outside cwd)
         sh(
@@ -40,34 +54,35 @@ async fn container_runner(args: &Args) -> Result<()> {
     let logs_dir = "./tests/logs";
     let dockerfiles_dir = "./tests/dockerfiles";
     let bin_entrypoint = &args.bin_name;
-    let container_target = "x86_64-unknown-linux-gnu";
+    let container_target = CONTAINER_TARGET;
 
     // build internal runner
-    sh("cargo build --release --bin", &[
-        bin_entrypoint,
-        "--target",
-        container_target,
-    ])
-    .await?;
-
-    let mut cn = ContainerNetwork::new(
-        "test",
-        vec![Container::new(
-            "onomyd",
-            Dockerfile::Path(format!("{dockerfiles_dir}/chain_upgrade.dockerfile")),
-            Some(&format!(
-                "./target/{container_target}/release/{bin_entrypoint}"
-            )),
-            &["--entry-name", "onomyd"],
-        )],
-        None,
-        true,
-        logs_dir,
-    )?
-    .add_common_volumes(&[(logs_dir, "/logs")]);
+    build_runner(args, container_target, &[]).await?;
+
+    let dockerfile =
+        FileOptions::read_to_string(&format!("{dockerfiles_dir}/chain_upgrade.dockerfile")).await?;
+    let entrypoint = format!("./target/{container_target}/release/{bin_entrypoint}");
+    // a full node follows the validator through the upgrade so that their
+    // exports can be compared
+    let services: Vec<ComposeService> = ["onomyd", "onomyd_full"]
+        .into_iter()
+        .map(|name| {
+            ComposeService::new(
+                name,
+                &dockerfile,
+                Some(&entrypoint),
+                &EntryArgs::new(name)
+                    .keep_running(args.keep_running)
+                    .as_args(),
+            )
+        })
+        .collect();
+    let services = publish_host_ports(args, services, "onomyd");
+    let Some(mut cn) = container_network(args, services, dockerfiles_dir, logs_dir).await? else {
+        return Ok(())
+    };
     cn.run_all(true).await?;
-    cn.wait_with_timeout_all(true, TIMEOUT).await.unwrap();
-    Ok(())
+    wait_for_network(args, &mut cn, &["onomyd", "onomyd_full"]).await
 }
 
 async fn onomyd_runner(args: &Args) -> Result<()> {
@@ -77,9 +92,23 @@ async fn onomyd_runner(args: &Args) -> Result<()> {
 
     info!("current version: {onomy_current_version}, upgrade version: {onomy_upgrade_version}");
 
-    onomyd_setup(daemon_home).await?;
+    let nm_full_addr = ORCHESTRATOR_PORTS.node_addr("onomyd_full");
+    let mut nm_full = NetMessenger::connect(STD_TRIES, STD_DELAY, &nm_full_addr)
+        .await
+        .map_add_err(|| format!("while connecting to {nm_full_addr}"))?;
+
+    onomyd_setup_with_patches(daemon_home, &[set_genesis_time(GENESIS_TIME)?]).await?;
     let mut cosmovisor_runner = cosmovisor_start("onomyd_runner.log", None).await?;
 
+    nm_full
+        .send::<String>(
+            &FileOptions::read_to_string(&format!("{daemon_home}/config/genesis.json")).await?,
+        )
+        .await?;
+    nm_full
+        .send::<String>(&get_persistent_peer_info("onomyd").await?)
+        .await?;
+
     assert_eq!(
         sh_cosmovisor("version", &[]).await?.trim(),
         onomy_current_version
@@ -102,10 +131,18 @@ async fn onomyd_runner(args: &Args) -> Result<()> {
             upgrade_height,
         ],
         &nom(2000.0),
-        "1anom",
+        &GasConfig::auto("anom"),
     )
     .await?;
 
+    // the old binary should halt exactly at the upgrade height before Cosmovisor
+    // swaps to the new one
+    assert_halts_at_height(
+        "/logs/onomyd_runner.log",
+        upgrade_prepare_start + 4,
+        TIMEOUT,
+    )
+    .await?;
     wait_for_height(STD_TRIES, STD_DELAY, upgrade_prepare_start + 5).await?;
 
     assert_eq!(
@@ -117,6 +154,73 @@ async fn onomyd_runner(args: &Args) -> Result<()> {
     info!("{}", get_treasury().await?);
     info!("{}", get_treasury_inflation_annual().await?);
 
+    // the full node exports the same height after it has followed the upgrade
+    let export_height = get_block_height().await?;
+    nm_full.send::<u64>(&export_height).await?;
+    let full_exported = nm_full.recv::<String>().await?;
+
+    hold_for_keep_running(args).await;
     cosmovisor_runner.terminate(TIMEOUT).await?;
+
+    // show which modules grew over the upgrade
+    let genesis = yaml_str_to_json_value(
+        &FileOptions::read_to_string(&format!("{daemon_home}/config/genesis.json")).await?,
+    )?;
+    let exported = sh_cosmovisor_no_dbg("export", &[]).await?;
+    FileOptions::write_str("/logs/onomyd_upgraded_export.json", &exported).await?;
+    let exported = yaml_str_to_json_value(&exported)?;
+    // the pinned time should survive the upgrade so that exports are comparable
+    assert_eq!(exported["genesis_time"], GENESIS_TIME);
+    // the independently computed state of the full node must be the same
+    let height = export_height.to_string();
+    let exported_at_height =
+        yaml_str_to_json_value(&sh_cosmovisor_no_dbg("export --height", &[&height]).await?)?;
+    assert_exports_equivalent(
+        &exported_at_height,
+        &yaml_str_to_json_value(&full_exported)?,
+        &[],
+    )
+    .map_add_err(|| format!("the exports of the validator and full node at height {height}"))?;
+    for diff in modules_grown_by(&genesis, &exported, 2.0) {
+        info!(
+            "module {} grew from {} to {} bytes",
+            diff.module, diff.before, diff.after
+        );
+    }
+
+    Ok(())
+}
+
+async fn onomyd_full_runner(args: &Args) -> Result<()> {
+    let daemon_home = args.daemon_home.as_ref().locate()?;
+    let mut nm_onomyd =
+        NetMessenger::listen_single_connect(&ORCHESTRATOR_PORTS.node_listen_addr(), TIMEOUT)
+            .await?;
+
+    sh_cosmovisor("config chain-id onomy", &[]).await?;
+    sh_cosmovisor("config keyring-backend test", &[]).await?;
+    sh_cosmovisor_no_dbg("init --overwrite onomy", &[]).await?;
+
+    let genesis_s = nm_onomyd.recv::<String>().await?;
+    FileOptions::write_str(&format!("{daemon_home}/config/genesis.json"), &genesis_s).await?;
+    let peer = nm_onomyd.recv::<String>().await?;
+    set_minimum_gas_price(daemon_home, "1anom").await?;
+    set_persistent_peers(daemon_home, &[&peer]).await?;
+
+    // Cosmovisor swaps to the upgrade binary at the upgrade height, the same as
+    // for the validator
+    let mut cosmovisor_runner = cosmovisor_start("onomyd_full_runner.log", None).await?;
+
+    let export_height = nm_onomyd.recv::<u64>().await?;
+    wait_for_height(STD_TRIES, STD_DELAY, export_height).await?;
+    cosmovisor_runner.terminate(TIMEOUT).await?;
+    assert_eq!(
+        sh_cosmovisor("version", &[]).await?.trim(),
+        args.onomy_upgrade_version.as_ref().locate()?
+    );
+    let exported = sh_cosmovisor_no_dbg("export --height", &[&export_height.to_string()]).await?;
+    FileOptions::write_str("/logs/onomyd_full_export.json", &exported).await?;
+    nm_onomyd.send::<String>(&exported).await?;
+
     Ok(())
 }
@@ -1,12 +1,18 @@
 // for temporary tests
 
+use common::{clean_keyring_dirs, clean_stale_containers};
+use log::warn;
 use onomy_test_lib::super_orchestrator::{remove_files_in_dir, stacked_errors::Result, std_init};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     std_init()?;
 
-    remove_files_in_dir("./tests/dockerfiles", &["__tmp.dockerfile"]).await?;
+    remove_files_in_dir("./tests/dockerfiles", &[
+        "__tmp.dockerfile",
+        "__compose.dockerfile",
+    ])
+    .await?;
     remove_files_in_dir("./tests/dockerfiles/dockerfile_resources", &[
         "__tmp_hermes_config.toml",
         "onomyd",
@@ -18,7 +24,11 @@ async fn main() -> Result<()> {
     ])
     .await?;
     remove_files_in_dir("./tests/logs", &[".log", ".json", ".toml"]).await?;
-    remove_files_in_dir("./tests/resources/keyring-test/", &[".address", ".info"]).await?;
+    clean_keyring_dirs().await?;
+    // docker may not be available where only files need to be cleaned
+    if let Err(e) = clean_stale_containers().await {
+        warn!("could not clean stale containers: {e:?}");
+    }
 
     Ok(())
 }
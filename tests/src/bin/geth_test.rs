@@ -1,18 +1,21 @@
 use std::{str::FromStr, time::Duration};
 
 use clarity::Address;
+use common::{build_runner, container_network, preflight, wait_for_network, CONTAINER_TARGET};
 use log::info;
 use onomy_test_lib::{
+    containers::ComposeService,
+    dispatch_entry,
     dockerfiles::ONOMY_STD,
     onomy_std_init,
+    ports::ORCHESTRATOR_PORTS,
     super_orchestrator::{
-        docker::{Container, ContainerNetwork, Dockerfile},
         net_message::NetMessenger,
         sh,
-        stacked_errors::{Error, MapAddError, Result},
-        wait_for_ok, Command, FileOptions, STD_DELAY, STD_TRIES,
+        stacked_errors::{Error, Result},
+        Command, FileOptions, STD_DELAY, STD_TRIES,
     },
-    Args, TIMEOUT,
+    wait_for_ok_jitter, Args, EntryArgs, TIMEOUT,
 };
 use web30::client::Web3;
 
@@ -20,13 +23,14 @@ use web30::client::Web3;
 async fn main() -> Result<()> {
     let args = onomy_std_init()?;
 
-    if let Some(ref s) = args.entry_name {
-        match s.as_str() {
-            "geth" => geth_runner().await,
-            "test" => test_runner().await,
-            _ => format!("entry_name \"{s}\" is not recognized").map_add_err(|| ()),
-        }
+    if args.entry_name.is_some() {
+        dispatch_entry(&args, &[
+            ("geth", |_| Box::pin(geth_runner())),
+            ("test", |_| Box::pin(test_runner())),
+        ])
+        .await
     } else {
+        preflight(&args, &[]).await?;
         container_runner(&args).await
     }
 }
@@ -42,58 +46,50 @@ async fn container_runner(args: &Args) -> Result<()> {
     let logs_dir = "./tests/logs";
     let dockerfiles_dir = "./tests/dockerfiles";
     let bin_entrypoint = &args.bin_name;
-    let container_target = "x86_64-unknown-linux-gnu";
+    let container_target = CONTAINER_TARGET;
 
     // build internal runner with `--release`
-    sh("cargo build --release --bin", &[
-        bin_entrypoint,
-        "--target",
-        container_target,
-        "--features",
-        "geth",
-    ])
-    .await?;
+    build_runner(args, container_target, &["geth"]).await?;
 
     let entrypoint = Some(format!(
         "./target/{container_target}/release/{bin_entrypoint}"
     ));
     let entrypoint = entrypoint.as_deref();
 
-    let mut cn = ContainerNetwork::new(
-        "test",
-        vec![
-            Container::new(
-                "geth",
-                Dockerfile::Contents(format!("{ONOMY_STD} {GETH}")),
-                entrypoint,
-                &["--entry-name", "geth"],
-            ),
-            Container::new(
-                "test",
-                Dockerfile::Contents(ONOMY_STD.to_owned()),
-                entrypoint,
-                &["--entry-name", "test"],
-            ),
-            /*Container::new(
-                "prometheus",
-                Dockerfile::NameTag("prom/prometheus:v2.44.0".to_owned()),
-                None,
-                &[],
-            )
-            .create_args(&["-p", "9090:9090"]),*/
-        ],
-        Some(dockerfiles_dir),
-        true,
-        logs_dir,
-    )?
-    .add_common_volumes(&[(logs_dir, "/logs")]);
+    let services = vec![
+        ComposeService::new(
+            "geth",
+            &format!("{ONOMY_STD} {GETH}"),
+            entrypoint,
+            &EntryArgs::new("geth")
+                .keep_running(args.keep_running)
+                .as_args(),
+        ),
+        ComposeService::new(
+            "test",
+            ONOMY_STD,
+            entrypoint,
+            &EntryArgs::new("test")
+                .keep_running(args.keep_running)
+                .as_args(),
+        ),
+        /*ComposeService::new(
+            "prometheus",
+            "FROM prom/prometheus:v2.44.0",
+            None,
+            &[],
+        ),*/
+    ];
+    let Some(mut cn) = container_network(args, services, dockerfiles_dir, logs_dir).await? else {
+        return Ok(())
+    };
     cn.run_all(true).await?;
-    cn.wait_with_timeout_all(true, TIMEOUT).await?;
-    Ok(())
+    wait_for_network(args, &mut cn, &["geth", "test"]).await
 }
 
 async fn test_runner() -> Result<()> {
-    let mut nm_geth = NetMessenger::connect(STD_TRIES, STD_DELAY, "geth:26000").await?;
+    let mut nm_geth =
+        NetMessenger::connect(STD_TRIES, STD_DELAY, &ORCHESTRATOR_PORTS.node_addr("geth")).await?;
 
     // manual HTTP request
     /*
@@ -129,7 +125,7 @@ async fn test_runner() -> Result<()> {
             .map(|_| ())
             .map_err(|e| Error::boxed(Box::new(e)))
     }
-    wait_for_ok(STD_TRIES, STD_DELAY, || is_eth_up(&web3)).await?;
+    wait_for_ok_jitter(STD_TRIES, STD_DELAY, STD_DELAY, || is_eth_up(&web3)).await?;
     info!("geth is running");
 
     dbg!(web3
@@ -175,7 +171,9 @@ const ETH_GENESIS: &str = r#"
 "#;
 
 async fn geth_runner() -> Result<()> {
-    let mut nm_test = NetMessenger::listen_single_connect("0.0.0.0:26000", TIMEOUT).await?;
+    let mut nm_test =
+        NetMessenger::listen_single_connect(&ORCHESTRATOR_PORTS.node_listen_addr(), TIMEOUT)
+            .await?;
 
     let genesis_file = "/resources/eth_genesis.json";
     FileOptions::write_str(genesis_file, ETH_GENESIS).await?;
@@ -1,14 +1,15 @@
 use std::time::Duration;
 
-use common::container_runner;
+use common::{container_runner, host_ports, preflight};
 use log::info;
 use onomy_test_lib::{
     cosmovisor::{
         cosmovisor_get_addr, cosmovisor_start, get_apr_annual, get_delegations_to,
         get_staking_pool, sh_cosmovisor,
     },
+    dispatch_entry,
     dockerfiles::onomy_std_cosmos_daemon,
-    onomy_std_init, reprefix_bech32,
+    hold_for_keep_running, onomy_std_init, reprefix_bech32,
     setups::gravity_standalone_setup,
     super_orchestrator::{
         sh,
@@ -22,12 +23,10 @@ use tokio::time::sleep;
 async fn main() -> Result<()> {
     let args = onomy_std_init()?;
 
-    if let Some(ref s) = args.entry_name {
-        match s.as_str() {
-            "gravity" => gravity_runner(&args).await,
-            _ => format!("entry_name \"{s}\" is not recognized").map_add_err(|| ()),
-        }
+    if args.entry_name.is_some() {
+        dispatch_entry(&args, &[("gravity", |args| Box::pin(gravity_runner(args)))]).await
     } else {
+        preflight(&args, &host_ports(&args)).await?;
         sh("make --directory ./../arc/module clean", &[]).await?;
         sh("make --directory ./../arc/module build", &[]).await?;
         sh(
@@ -55,6 +54,7 @@ async fn gravity_runner(args: &Args) -> Result<()> {
     info!("{}", get_delegations_to(valoper_addr).await?);
     info!("{:?}", get_staking_pool().await?);
 
+    hold_for_keep_running(args).await;
     sleep(Duration::from_secs(3)).await;
     cosmovisor_runner.terminate(TIMEOUT).await?;
     // test that exporting works
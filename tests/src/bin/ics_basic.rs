@@ -1,79 +1,141 @@
 use std::time::Duration;
 
-use common::dockerfile_onomyd;
-use log::info;
+use common::{
+    build_runner, clean_dockerfile_artifacts, clean_keyring_dirs, container_network,
+    dockerfile_onomyd, dockerfile_onomyd_version, host_ports, make_and_copy, preflight,
+    prepare_keyring_dirs, publish_host_ports, reject_compose, wait_for_network_with_startup_report,
+    NetworkHooks, VersionMatrix, CONTAINER_TARGET,
+};
+use log::{info, warn};
 use onomy_test_lib::{
+    containers::{wait_for_log, wait_for_log_file, ComposeService, NetworkPartition},
     cosmovisor::{
-        cosmovisor_bank_send, cosmovisor_get_addr, cosmovisor_get_balances, cosmovisor_start,
-        set_minimum_gas_price, sh_cosmovisor_no_dbg, wait_for_num_blocks,
+        assert_bank_send_fails, cosmovisor_bank_send, cosmovisor_delegate,
+        cosmovisor_export_keyring, cosmovisor_get_addr, cosmovisor_get_balances,
+        cosmovisor_get_denom_metadata, cosmovisor_import_keyring, cosmovisor_keys_add,
+        cosmovisor_start, cosmovisor_total_voting_power, set_minimum_gas_price,
+        sh_cosmovisor_no_dbg, wait_for_balance, wait_for_num_blocks, watch_for_consensus_failure,
+        CosmovisorRunner, GasConfig, KeyringBackend, SHARED_KEYRING_DIR,
     },
+    dispatch_entry,
     dockerfiles::{dockerfile_hermes, onomy_std_cosmos_daemon},
+    genesis::{denom_metadata_patch, CcvConsumerGenesis, DenomMetadata},
     hermes::{
-        hermes_set_gas_price_denom, hermes_start, sh_hermes, write_hermes_config,
-        HermesChainConfig, IbcPair,
+        hermes_assert_key_address, hermes_set_gas_price_denom, hermes_start, hermes_wait_healthy,
+        sh_hermes, write_hermes_config, HermesChainConfig, IbcPair,
+    },
+    hold_for_keep_running,
+    ibc::{
+        assert_consumer_removed, assert_refunded, cosmovisor_next_sequence_receive,
+        cosmovisor_packet_received_at, cosmovisor_query_ccvconsumer, dual_chain_balance_snapshot,
+        pfm_memo, wait_for_channel_state, wait_for_consumer_valset,
+        wait_for_escrow_matching_supply, wait_for_vsc_maturity,
+    },
+    join_queries, known_test_addr,
+    logs::LogAssertions,
+    net::PeerMessenger,
+    nom, onomy_std_init, phase, poll_until,
+    ports::{ORCHESTRATOR_PORTS, RPC_PORT},
+    reprefix_bech32,
+    setups::{
+        cosmovisor_add_consumer, cosmovisor_remove_consumer, marketd_setup_with_patches,
+        onomyd_setup,
     },
-    onomy_std_init, reprefix_bech32,
-    setups::{cosmovisor_add_consumer, marketd_setup, onomyd_setup},
+    stages::{IcsFlow, IcsStage, IcsStages, StageFuture},
     super_orchestrator::{
-        docker::{Container, ContainerNetwork, Dockerfile},
-        net_message::NetMessenger,
-        remove_files_in_dir, sh,
-        stacked_errors::{MapAddError, Result},
+        stacked_errors::{Error, MapAddError, Result},
         FileOptions, STD_DELAY, STD_TRIES,
     },
-    token18, Args, ONOMY_IBC_NOM, TIMEOUT,
+    timing::PhaseTimer,
+    token18, Args, EntryArgs, MapAddContext, ONOMY_IBC_NOM, TIMEOUT,
 };
-use tokio::time::sleep;
+use regex::Regex;
+use tokio::{sync::Mutex, time::sleep};
 
 const CONSUMER_ID: &str = "market";
 const PROVIDER_ACCOUNT_PREFIX: &str = "onomy";
 const CONSUMER_ACCOUNT_PREFIX: &str = "onomy";
+/// Hermes should relay a transfer within this time
+const RELAY_LATENCY_BUDGET: Duration = Duration::from_secs(30);
+/// The daemons should be serving RPC within this time of their container
+/// starting, this includes the genesis setup and for the consumer waiting on
+/// the provider
+const STARTUP_BUDGET: Duration = Duration::from_secs(600);
+/// Logged by the onomyd runner when the host should partition Hermes from the
+/// consumer, and when the partition should heal. The runner cannot run
+/// `docker` itself, the host reports back through [PARTITION_STATE_FILE].
+const PARTITION_REQUEST: &str = "requesting the relayer partition";
+const HEAL_REQUEST: &str = "requesting the relayer partition to heal";
+/// The file in the logs directory with a line for each completed partition step
+const PARTITION_STATE_FILE: &str = "ics_basic_partition_state.txt";
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = onomy_std_init()?;
 
-    if let Some(ref s) = args.entry_name {
-        match s.as_str() {
-            "onomyd" => onomyd_runner(&args).await,
-            "consumer" => consumer(&args).await,
-            "hermes" => hermes_runner(&args).await,
-            _ => format!("entry_name \"{s}\" is not recognized").map_add_err(|| ()),
-        }
+    if args.entry_name.is_some() {
+        dispatch_entry(&args, &[
+            ("onomyd", |args| Box::pin(onomyd_runner(args))),
+            ("consumer", |args| Box::pin(consumer(args))),
+            ("hermes", |args| Box::pin(hermes_runner(args))),
+        ])
+        .await
+    } else if !args.version_matrix.is_empty() {
+        // the daemons are not built from source, see `Args::version_matrix`
+        reject_compose(&args, "--version-matrix runs a network per version pair")?;
+        preflight(&args, &host_ports(&args)).await?;
+        let report = VersionMatrix::from_args(&args)?
+            .run(|provider_version, consumer_version| {
+                let args = &args;
+                async move {
+                    container_runner(
+                        args,
+                        &mut PhaseTimer::new(),
+                        Some((&provider_version, &consumer_version)),
+                    )
+                    .await
+                }
+            })
+            .await;
+        report.assert_all_passed()
     } else {
-        sh("make --directory ./../onomy/ build", &[]).await?;
-        sh("make --directory ./../market/ build", &[]).await?;
-        // copy to dockerfile resources (docker cannot use files from outside cwd)
-        sh(
-            "cp ./../onomy/onomyd ./tests/dockerfiles/dockerfile_resources/onomyd",
-            &[],
-        )
-        .await?;
-        sh(
-            "cp ./../market/marketd ./tests/dockerfiles/dockerfile_resources/marketd",
-            &[],
-        )
-        .await?;
-        container_runner(&args).await
+        preflight(&args, &host_ports(&args)).await?;
+        let mut timer = PhaseTimer::new();
+        phase!(timer, "onomyd build", {
+            make_and_copy(&args, "./../onomy", "build", "onomyd", "onomyd").await
+        })?;
+        phase!(timer, "marketd build", {
+            make_and_copy(&args, "./../market", "build", "marketd", "marketd").await
+        })?;
+        let res = container_runner(&args, &mut timer, None).await;
+        info!("{timer}");
+        // the timing is only informational and should not hide the result
+        if let Err(e) = timer.write_json("./tests/logs/ics_basic_timing.json").await {
+            warn!("could not write the phase timing: {e:?}");
+        }
+        res
     }
 }
 
-async fn container_runner(args: &Args) -> Result<()> {
+/// If `versions` is set, the `(provider_version, consumer_version)` images are
+/// used instead of the ones built from source
+async fn container_runner(
+    args: &Args,
+    timer: &mut PhaseTimer,
+    versions: Option<(&str, &str)>,
+) -> Result<()> {
     let logs_dir = "./tests/logs";
     let dockerfiles_dir = "./tests/dockerfiles";
     let bin_entrypoint = &args.bin_name;
-    let container_target = "x86_64-unknown-linux-gnu";
+    let container_target = CONTAINER_TARGET;
 
     // build internal runner with `--release`
-    sh("cargo build --release --bin", &[
-        bin_entrypoint,
-        "--target",
-        container_target,
-    ])
-    .await?;
+    phase!(timer, "runner build", {
+        build_runner(args, container_target, &[]).await
+    })?;
 
     // prepare volumed resources
-    remove_files_in_dir("./tests/resources/keyring-test/", &[".address", ".info"]).await?;
+    prepare_keyring_dirs(&["onomyd", "marketd"]).await?;
 
     // prepare hermes config
     write_hermes_config(
@@ -89,55 +151,149 @@ async fn container_runner(args: &Args) -> Result<()> {
         "./target/{container_target}/release/{bin_entrypoint}"
     ));
     let entrypoint = entrypoint.as_deref();
-
-    let mut cn = ContainerNetwork::new(
-        "test",
-        vec![
-            Container::new(
-                "hermes",
-                Dockerfile::Contents(dockerfile_hermes("__tmp_hermes_config.toml")),
-                entrypoint,
-                &["--entry-name", "hermes"],
+    let (onomyd_dockerfile, marketd_dockerfile) = match versions {
+        Some((provider_version, consumer_version)) => (
+            dockerfile_onomyd_version(provider_version),
+            onomy_std_cosmos_daemon(
+                "marketd",
+                ".onomy_market",
+                consumer_version,
+                &format!("marketd_{consumer_version}"),
             ),
-            Container::new(
-                "onomyd",
-                Dockerfile::Contents(dockerfile_onomyd()),
-                entrypoint,
-                &["--entry-name", "onomyd"],
-            )
-            .volumes(&[(
-                "./tests/resources/keyring-test",
+        ),
+        None => (
+            dockerfile_onomyd(),
+            onomy_std_cosmos_daemon("marketd", ".onomy_market", "v0.1.0", "marketd"),
+        ),
+    };
+
+    let services = vec![
+        ComposeService::new(
+            "hermes",
+            &dockerfile_hermes("__tmp_hermes_config.toml"),
+            entrypoint,
+            &EntryArgs::new("hermes")
+                .keep_running(args.keep_running)
+                .as_args(),
+        ),
+        ComposeService::new(
+            "onomyd",
+            &onomyd_dockerfile,
+            entrypoint,
+            &EntryArgs::new("onomyd")
+                .keep_running(args.keep_running)
+                .as_args(),
+        )
+        .volumes(&[
+            (
+                "./tests/resources/keyring-test/onomyd",
                 "/root/.onomy/keyring-test",
-            )]),
-            Container::new(
-                "marketd",
-                Dockerfile::Contents(onomy_std_cosmos_daemon(
-                    "marketd",
-                    ".onomy_market",
-                    "v0.1.0",
-                    "marketd",
-                )),
-                entrypoint,
-                &["--entry-name", "consumer"],
-            )
-            .volumes(&[(
-                "./tests/resources/keyring-test",
+            ),
+            ("./tests/resources/keyring-test/shared", SHARED_KEYRING_DIR),
+        ]),
+        ComposeService::new(
+            "marketd",
+            &marketd_dockerfile,
+            entrypoint,
+            &EntryArgs::new("consumer")
+                .keep_running(args.keep_running)
+                .as_args(),
+        )
+        .volumes(&[
+            (
+                "./tests/resources/keyring-test/marketd",
                 "/root/.onomy_market/keyring-test",
-            )]),
-        ],
-        Some(dockerfiles_dir),
-        true,
-        logs_dir,
-    )?
-    .add_common_volumes(&[(logs_dir, "/logs")]);
-    cn.run_all(true).await?;
-    cn.wait_with_timeout_all(true, TIMEOUT).await?;
-    Ok(())
+            ),
+            ("./tests/resources/keyring-test/shared", SHARED_KEYRING_DIR),
+        ]),
+    ];
+    let services = publish_host_ports(args, services, "onomyd");
+    let Some(mut cn) = container_network(args, services, dockerfiles_dir, logs_dir).await? else {
+        return Ok(())
+    };
+    // healed from a hook in case the network ends before `partition_relayer` heals
+    // it
+    let partition = Mutex::new(None);
+    // the keys and the temporary artifacts are only needed while the network runs
+    let network = NetworkHooks::new()
+        .after(heal_partition(&partition))
+        .after(clean_keyring_dirs())
+        .after(clean_dockerfile_artifacts(dockerfiles_dir))
+        .run(async {
+            cn.run_all(true).await?;
+            let validator_logs = [
+                ("onomyd", format!("{logs_dir}/onomyd_runner.log")),
+                (
+                    "marketd",
+                    format!("{logs_dir}/marketd_bootstrap_runner.log"),
+                ),
+                ("marketd", format!("{logs_dir}/marketd_runner.log")),
+            ];
+            let validator_logs: Vec<(&str, &str)> = validator_logs
+                .iter()
+                .map(|(name, path)| (*name, path.as_str()))
+                .collect();
+            // abort right away on an apphash mismatch instead of timing out later
+            tokio::select! {
+                res = wait_for_network_with_startup_report(
+                    args,
+                    &mut cn,
+                    &["hermes", "onomyd", "marketd"],
+                    logs_dir,
+                    &[("onomyd", RPC_PORT), ("marketd", RPC_PORT)],
+                    STARTUP_BUDGET,
+                ) => res,
+                res = watch_for_consensus_failure(&validator_logs, Duration::from_secs(1)) => res,
+                res = partition_relayer(logs_dir, &partition) => res,
+            }
+        });
+    timer.phase("network", network).await
+}
+
+/// The host side of [relays_after_partition], this only returns early with an
+/// error. The partition is kept in `partition` so that it can also be healed
+/// by [heal_partition] if this does not get to it.
+async fn partition_relayer(
+    logs_dir: &str,
+    partition: &Mutex<Option<NetworkPartition>>,
+) -> Result<()> {
+    let state_file = format!("{logs_dir}/{PARTITION_STATE_FILE}");
+    // clear the state of earlier runs
+    FileOptions::write_str(&state_file, "").await?;
+    let pattern = |s: &str| {
+        Regex::new(s)
+            .map_err(|e| Error::boxed(Box::new(e)))
+            .map_add_err(|| format!("invalid regex {s}"))
+    };
+    wait_for_log("onomyd", &pattern(PARTITION_REQUEST)?, TIMEOUT).await?;
+    *partition.lock().await = Some(NetworkPartition::partition(&["hermes"], &["marketd"]).await?);
+    FileOptions::write_str(&state_file, "partitioned\n").await?;
+    let requested = wait_for_log("onomyd", &pattern(HEAL_REQUEST)?, TIMEOUT).await;
+    // heal regardless, so the rest of the test is not affected
+    heal_partition(partition).await?;
+    requested?;
+    FileOptions::write_str(&state_file, "partitioned\nhealed\n").await?;
+    // the network finishing ends the `select!`
+    std::future::pending().await
+}
+
+/// Heals the partition of [partition_relayer] if there is one, this can be
+/// called any number of times
+async fn heal_partition(partition: &Mutex<Option<NetworkPartition>>) -> Result<()> {
+    match partition.lock().await.as_mut() {
+        Some(partition) => partition.heal().await,
+        None => Ok(()),
+    }
 }
 
 async fn hermes_runner(args: &Args) -> Result<()> {
-    let hermes_home = args.hermes_home.as_ref().map_add_err(|| ())?;
-    let mut nm_onomyd = NetMessenger::listen_single_connect("0.0.0.0:26000", TIMEOUT).await?;
+    let hermes_home = args.hermes_home.as_ref().locate()?;
+    let mut nm_onomyd = PeerMessenger::listen_single_connect(
+        &ORCHESTRATOR_PORTS.hermes_listen_addr(),
+        TIMEOUT,
+        Some("hermes"),
+    )
+    .await?;
 
     // get mnemonic from onomyd
     let mnemonic: String = nm_onomyd.recv().await?;
@@ -153,11 +309,24 @@ async fn hermes_runner(args: &Args) -> Result<()> {
         &[],
     )
     .await?;
+    // make sure the relayer keys are the ones the chains expect
+    let addr = nm_onomyd.recv::<String>().await?;
+    hermes_assert_key_address("onomy", &addr).await?;
+    hermes_assert_key_address(
+        CONSUMER_ID,
+        &reprefix_bech32(&addr, CONSUMER_ACCOUNT_PREFIX)?,
+    )
+    .await?;
 
     // wait for setup
     nm_onomyd.recv::<()>().await?;
+    // enough for at least one `max_gas` transaction at a gas price of 1
+    hermes_wait_healthy(&["onomy"], 400_000, TIMEOUT).await?;
 
-    let ibc_pair = IbcPair::hermes_setup_pair(CONSUMER_ID, "onomy").await?;
+    let mut timer = PhaseTimer::new();
+    let ibc_pair = phase!(timer, "handshake", {
+        IbcPair::hermes_setup_pair(CONSUMER_ID, "onomy").await
+    })?;
     let mut hermes_runner = hermes_start("/logs/hermes_bootstrap_runner.log").await?;
     ibc_pair.hermes_check_acks().await?;
 
@@ -166,94 +335,394 @@ async fn hermes_runner(args: &Args) -> Result<()> {
 
     // signal to update gas denom
     let ibc_nom = nm_onomyd.recv::<String>().await?;
+    let ibc_pair_path = format!("{hermes_home}/ibc_pair.json");
+    ibc_pair.persist(&ibc_pair_path).await?;
     hermes_runner.terminate(TIMEOUT).await?;
     hermes_set_gas_price_denom(hermes_home, CONSUMER_ID, &ibc_nom).await?;
+    // have a packet sent while we are stopped
+    nm_onomyd.send::<()>(&()).await?;
+    nm_onomyd.recv::<()>().await?;
+
+    // restart, packets from before the restart should still be relayed
+    let ibc_pair = IbcPair::load(&ibc_pair_path).await?;
+    // the persisted ids must still name the same channels
+    ibc_pair.assert_channels_match().await?;
+    // prove that nothing was relayed while we were stopped
+    for seq in ibc_pair.unreceived_transfer_packets().await? {
+        ibc_pair
+            .assert_not_relayed(seq, Duration::from_secs(2))
+            .await?;
+    }
+    let mut hermes_runner = ibc_pair
+        .assert_packets_survive_restart(|| hermes_start("/logs/hermes_runner.log"), TIMEOUT)
+        .await?;
+    nm_onomyd.send::<()>(&()).await?;
 
-    // restart
-    let mut hermes_runner = hermes_start("/logs/hermes_runner.log").await?;
+    // make sure everything from the round trip has been relayed
+    nm_onomyd.recv::<()>().await?;
+    phase!(timer, "drain", { ibc_pair.assert_drained(TIMEOUT).await })?;
     nm_onomyd.send::<()>(&()).await?;
 
     // termination signal
     nm_onomyd.recv::<()>().await?;
     hermes_runner.terminate(TIMEOUT).await?;
+
+    // across both Hermes instances, nothing may have been relayed before the
+    // first one reported that it started
+    let mut logs = LogAssertions::new();
+    logs.add_file("hermes_bootstrap", "/logs/hermes_bootstrap_runner.log")
+        .await?;
+    logs.add_file("hermes", "/logs/hermes_runner.log").await?;
+    logs.assert_before("Hermes has started", "packet_cmd")?;
+
+    info!("{timer}");
+    timer.write_json("/logs/hermes_timing.json").await?;
     Ok(())
 }
 
+/// The provider side of the ICS flow, see [IcsFlow]
+struct IbcRoundTrip<'a> {
+    daemon_home: &'a str,
+    consumer_id: &'a str,
+    nm_hermes: PeerMessenger,
+    nm_consumer: PeerMessenger,
+    /// The address of the "validator" key
+    addr: String,
+    cosmovisor_runner: Option<CosmovisorRunner>,
+    ibc_pair: Option<IbcPair>,
+}
+
+impl<'a> IcsFlow for IbcRoundTrip<'a> {
+    fn reach(&mut self, stage: IcsStage) -> StageFuture<'_> {
+        Box::pin(async move {
+            match stage {
+                IcsStage::ProviderUp => self.provider_up().await,
+                IcsStage::ConsumerProposed => self.consumer_proposed().await,
+                IcsStage::ConsumerLaunched => self.consumer_launched().await,
+                IcsStage::ChannelsOpen => self.channels_open().await,
+                IcsStage::TransferredOut => self.transferred_out().await,
+                IcsStage::RelayedBack => self.relayed_back().await,
+            }
+        })
+    }
+}
+
+impl<'a> IbcRoundTrip<'a> {
+    fn ibc_pair(&self) -> Result<&IbcPair> {
+        self.ibc_pair
+            .as_ref()
+            .map_add_err(|| "IbcRoundTrip the channels are not open yet")
+    }
+
+    fn cosmovisor_runner(&mut self) -> Result<&mut CosmovisorRunner> {
+        self.cosmovisor_runner
+            .as_mut()
+            .map_add_err(|| "IbcRoundTrip the provider has not been started")
+    }
+
+    async fn provider_up(&mut self) -> Result<()> {
+        self.cosmovisor_runner = Some(cosmovisor_start("onomyd_runner.log", None).await?);
+        Ok(())
+    }
+
+    async fn consumer_proposed(&mut self) -> Result<()> {
+        let daemon_home = self.daemon_home;
+        let ccvconsumer_state = cosmovisor_add_consumer(daemon_home, self.consumer_id).await?;
+
+        // send to consumer
+        self.nm_consumer
+            .send::<String>(&ccvconsumer_state.to_json_string())
+            .await?;
+
+        // send keys
+        self.nm_consumer
+            .send::<String>(
+                &FileOptions::read_to_string(&format!("{daemon_home}/config/node_key.json"))
+                    .await?,
+            )
+            .await?;
+        self.nm_consumer
+            .send::<String>(
+                &FileOptions::read_to_string(&format!(
+                    "{daemon_home}/config/priv_validator_key.json"
+                ))
+                .await?,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn consumer_launched(&mut self) -> Result<()> {
+        // wait for consumer to be online
+        self.nm_consumer.recv::<()>().await
+    }
+
+    async fn channels_open(&mut self) -> Result<()> {
+        // notify hermes to connect the chains
+        self.nm_hermes.send::<()>(&()).await?;
+        // when hermes is done
+        let ibc_pair = self.nm_hermes.recv::<IbcPair>().await?;
+        info!(
+            "received IbcPair from {}: {ibc_pair:?}",
+            self.nm_hermes.peer_id()
+        );
+        self.ibc_pair = Some(ibc_pair);
+        Ok(())
+    }
+
+    async fn transferred_out(&mut self) -> Result<()> {
+        let consumer_id = self.consumer_id;
+        let addr = &self.addr.clone();
+        // change the provider validator set, the VSC should propagate to the consumer
+        let valoper_addr = reprefix_bech32(addr, "onomyvaloper")?;
+        cosmovisor_delegate(
+            "validator",
+            &valoper_addr,
+            &token18(1000.0, "anom"),
+            &GasConfig::auto("anom"),
+        )
+        .await?;
+        wait_for_consumer_valset(&format!("tcp://{consumer_id}d:{RPC_PORT}"), 0, 10).await?;
+        // further validator set changes have to arrive on the consumer in order
+        let ibc_pair = self.ibc_pair()?;
+        ibc_pair
+            .assert_ordered_delivery(
+                &ibc_pair.b.chain_id,
+                "validator",
+                &valoper_addr,
+                &token18(1.0, "anom"),
+                3,
+                TIMEOUT,
+            )
+            .await?;
+
+        // nothing is in flight yet, so this is a consistent starting point
+        let provider_node = format!("tcp://localhost:{RPC_PORT}");
+        let consumer_node = format!("tcp://{consumer_id}d:{RPC_PORT}");
+        let consumer_validator_addr = &reprefix_bech32(addr, CONSUMER_ACCOUNT_PREFIX)?;
+        let snapshot = || {
+            dual_chain_balance_snapshot(
+                &provider_node,
+                &consumer_node,
+                addr,
+                consumer_validator_addr,
+                &["anom", ONOMY_IBC_NOM],
+            )
+        };
+        let before = snapshot().await?;
+
+        // send anom to consumer
+        let amount = token18(100.0e3, "");
+        self.ibc_pair()?
+            .b
+            .cosmovisor_ibc_transfer("validator", consumer_validator_addr, &amount, "anom")
+            .await?;
+        // it takes time for the relayer to complete relaying
+        wait_for_num_blocks(4).await?;
+        // what left the provider account should have been minted on the consumer
+        let amount = amount
+            .parse::<i128>()
+            .map_add_err(|| format!("bad transfer amount {amount}"))?;
+        let after = poll_until(
+            snapshot,
+            |after| before.diff(after).consumer.get(ONOMY_IBC_NOM) == Some(&amount),
+            TIMEOUT,
+            STD_DELAY,
+        )
+        .await?;
+        let diff = before.diff(&after);
+        // the provider side also pays the fees
+        assert!(diff.provider["anom"] <= -amount, "{diff:?}");
+        Ok(())
+    }
+
+    async fn relayed_back(&mut self) -> Result<()> {
+        let addr = &self.addr.clone();
+        let ibc_pair = self.ibc_pair()?.clone();
+        let latency = ibc_pair
+            .measure_relay_latency(
+                "onomy",
+                "validator",
+                &reprefix_bech32(addr, CONSUMER_ACCOUNT_PREFIX)?,
+                "1000anom",
+                TIMEOUT,
+            )
+            .await?;
+        if latency >= RELAY_LATENCY_BUDGET {
+            return Err(Error::from(format!(
+                "relaying took {latency:?}, more than the budget of {RELAY_LATENCY_BUDGET:?}"
+            )))
+        }
+        // Hermes should reconnect to the restarted provider on its own
+        let latency = ibc_pair
+            .assert_relays_after_restart(
+                "onomy",
+                self.cosmovisor_runner()?,
+                "validator",
+                &reprefix_bech32(addr, CONSUMER_ACCOUNT_PREFIX)?,
+                "1000anom",
+                TIMEOUT,
+            )
+            .await?;
+        info!("relay latency after the provider restart: {latency:?}");
+        // multi-hop A->B->C with the consumer as the packet-forward-middleware
+        // intermediary, C is the provider again so that the forwarded IBC NOM
+        // unwinds back into regular NOM
+        cosmovisor_keys_add("pfm_receiver", &KeyringBackend::Test).await?;
+        let pfm_receiver = &cosmovisor_get_addr("pfm_receiver").await?;
+        ibc_pair
+            .b
+            .cosmovisor_ibc_transfer_with_memo(
+                "validator",
+                // the middleware overrides the receiver on the intermediary
+                &reprefix_bech32(pfm_receiver, CONSUMER_ACCOUNT_PREFIX)?,
+                "1000anom",
+                &pfm_memo(pfm_receiver, "transfer", &ibc_pair.a.transfer_channel, None),
+                &GasConfig::auto("anom"),
+            )
+            .await?;
+        wait_for_balance(pfm_receiver, "anom", |amount| amount == 1000, TIMEOUT)
+            .await
+            .map_add_err(|| "multi-hop transfer did not arrive")?;
+        // notify consumer that we have sent NOM
+        self.nm_consumer.send::<IbcPair>(&ibc_pair).await?;
+
+        // tell hermes to restart with updated gas denom on its side
+        let ibc_nom = self.nm_consumer.recv::<String>().await?;
+        self.nm_hermes.send::<String>(&ibc_nom).await?;
+        // send a packet while hermes is stopped
+        self.nm_hermes.recv::<()>().await?;
+        ibc_pair
+            .b
+            .cosmovisor_ibc_transfer(
+                "validator",
+                &reprefix_bech32(addr, CONSUMER_ACCOUNT_PREFIX)?,
+                "1337",
+                "anom",
+            )
+            .await?;
+        // and one that will time out before hermes is back. Hermes pays its fees
+        // from the validator key, so a dedicated sender is needed for the refund
+        // to be exact.
+        cosmovisor_keys_add("timeout_sender", &KeyringBackend::Test).await?;
+        let timeout_sender = &cosmovisor_get_addr("timeout_sender").await?;
+        cosmovisor_bank_send(addr, timeout_sender, &token18(1.0, ""), "anom").await?;
+        let refunded_balance = ibc_pair
+            .b
+            .ibc_transfer_expecting_timeout(
+                timeout_sender,
+                &reprefix_bech32(addr, CONSUMER_ACCOUNT_PREFIX)?,
+                7331,
+                "anom",
+                Duration::from_secs(5),
+                &GasConfig::auto("anom"),
+            )
+            .await?;
+        self.nm_hermes.send::<()>(&()).await?;
+        self.nm_hermes.recv::<()>().await?;
+        assert_refunded(timeout_sender, refunded_balance, "anom", TIMEOUT).await?;
+        self.nm_consumer.send::<()>(&()).await?;
+
+        // recieve round trip signal
+        self.nm_consumer.recv::<()>().await?;
+        // wait for hermes to relay everything
+        self.nm_hermes.send::<()>(&()).await?;
+        self.nm_hermes.recv::<()>().await?;
+        Ok(())
+    }
+}
+
+/// Drives `round_trip` through all the [IcsStage]s, with the extra assertions
+/// of `stages` at each stage
+async fn ibc_round_trip(round_trip: &mut IbcRoundTrip<'_>, stages: &mut IcsStages) -> Result<()> {
+    stages.drive(round_trip).await
+}
+
 async fn onomyd_runner(args: &Args) -> Result<()> {
     let consumer_id = CONSUMER_ID;
     let daemon_home = args.daemon_home.as_ref().map_add_err(|| ())?;
-    let mut nm_hermes = NetMessenger::connect(STD_TRIES, STD_DELAY, "hermes:26000")
+    let hermes_addr = ORCHESTRATOR_PORTS.hermes_addr();
+    let mut nm_hermes = PeerMessenger::connect(STD_TRIES, STD_DELAY, &hermes_addr, Some("onomyd"))
         .await
-        .map_add_err(|| ())?;
-    let mut nm_consumer =
-        NetMessenger::connect(STD_TRIES, STD_DELAY, &format!("{consumer_id}d:26001"))
-            .await
-            .map_add_err(|| ())?;
+        .map_add_err(|| format!("while connecting to {hermes_addr}"))?;
+    let consumer_addr = ORCHESTRATOR_PORTS.node_addr(&format!("{consumer_id}d"));
+    let nm_consumer = PeerMessenger::connect(STD_TRIES, STD_DELAY, &consumer_addr, Some("onomyd"))
+        .await
+        .map_add_err(|| format!("while connecting to {consumer_addr}"))?;
 
     let mnemonic = onomyd_setup(daemon_home).await?;
+    // the consumer imports these after receiving the consumer genesis
+    cosmovisor_export_keyring(daemon_home, SHARED_KEYRING_DIR).await?;
     // send mnemonic to hermes
     nm_hermes.send::<String>(&mnemonic).await?;
 
     // keep these here for local testing purposes
-    let addr = &cosmovisor_get_addr("validator").await?;
+    let addr = cosmovisor_get_addr("validator").await?;
     sleep(Duration::ZERO).await;
+    nm_hermes.send::<String>(&addr).await?;
+
+    // extra assertions can be added for any stage here
+    let mut stages = IcsStages::new();
+    stages.on_stage(IcsStage::RelayedBack, || async {
+        // check that the IBC NOM converted back to regular NOM
+        assert_eq!(
+            cosmovisor_get_balances(&known_test_addr(PROVIDER_ACCOUNT_PREFIX)?).await?["anom"],
+            "5000"
+        );
+        Ok(())
+    });
+
+    let mut round_trip = IbcRoundTrip {
+        daemon_home,
+        consumer_id,
+        nm_hermes,
+        nm_consumer,
+        addr,
+        cosmovisor_runner: None,
+        ibc_pair: None,
+    };
+    ibc_round_trip(&mut round_trip, &mut stages).await?;
+    let IbcRoundTrip {
+        mut nm_hermes,
+        mut nm_consumer,
+        addr,
+        cosmovisor_runner,
+        ibc_pair,
+        ..
+    } = round_trip;
+    let addr = &addr;
+    let mut cosmovisor_runner = cosmovisor_runner.locate()?;
+    let ibc_pair = ibc_pair.locate()?;
+
+    relays_after_partition(&ibc_pair, addr, consumer_id).await?;
+
+    // every IBC NOM on the consumer should be backed by escrowed NOM, which also
+    // covers the forwarded and refunded transfers
+    let escrowed = wait_for_escrow_matching_supply(
+        "transfer",
+        &ibc_pair.b.transfer_channel,
+        "anom",
+        &format!("tcp://{consumer_id}d:{RPC_PORT}"),
+        ONOMY_IBC_NOM,
+        TIMEOUT,
+    )
+    .await?;
+    info!("{escrowed} anom is escrowed for the consumer");
+    // make sure rejections are distinguished from orchestration errors
+    assert_bank_send_fails(addr, addr, &nom(1.0e12), 5).await?;
 
-    let mut cosmovisor_runner = cosmovisor_start("onomyd_runner.log", None).await?;
-
-    let ccvconsumer_state = cosmovisor_add_consumer(daemon_home, consumer_id).await?;
-
-    // send to consumer
-    nm_consumer.send::<String>(&ccvconsumer_state).await?;
-
-    // send keys
-    nm_consumer
-        .send::<String>(
-            &FileOptions::read_to_string(&format!("{daemon_home}/config/node_key.json")).await?,
-        )
-        .await?;
-    nm_consumer
-        .send::<String>(
-            &FileOptions::read_to_string(&format!("{daemon_home}/config/priv_validator_key.json"))
-                .await?,
-        )
-        .await?;
+    hold_for_keep_running(args).await;
 
-    // wait for consumer to be online
+    // the consumer does its final checks while the ICS channel is still open
+    nm_consumer.send::<()>(&()).await?;
     nm_consumer.recv::<()>().await?;
-    // notify hermes to connect the chains
-    nm_hermes.send::<()>(&()).await?;
-    // when hermes is done
-    let ibc_pair = nm_hermes.recv::<IbcPair>().await?;
-    info!("IbcPair: {ibc_pair:?}");
-
-    // send anom to consumer
-    ibc_pair
-        .b
-        .cosmovisor_ibc_transfer(
-            "validator",
-            &reprefix_bech32(addr, CONSUMER_ACCOUNT_PREFIX)?,
-            &token18(100.0e3, ""),
-            "anom",
-        )
-        .await?;
-    // it takes time for the relayer to complete relaying
-    wait_for_num_blocks(4).await?;
-    // notify consumer that we have sent NOM
-    nm_consumer.send::<IbcPair>(&ibc_pair).await?;
 
-    // tell hermes to restart with updated gas denom on its side
-    let ibc_nom = nm_consumer.recv::<String>().await?;
-    nm_hermes.send::<String>(&ibc_nom).await?;
-    nm_hermes.recv::<()>().await?;
+    // tear down the consumer, a stop time in the past removes it right away
+    cosmovisor_remove_consumer(daemon_home, consumer_id, "2023-01-01T00:00:00Z").await?;
+    wait_for_channel_state("provider", &ibc_pair.b.ics_channel, "STATE_CLOSED", TIMEOUT).await?;
+    // the consumer confirms the removal from its side
     nm_consumer.send::<()>(&()).await?;
-
-    // recieve round trip signal
     nm_consumer.recv::<()>().await?;
-    // check that the IBC NOM converted back to regular NOM
-    assert_eq!(
-        cosmovisor_get_balances("onomy1gk7lg5kd73mcr8xuyw727ys22t7mtz9gh07ul3").await?["anom"],
-        "5000"
-    );
 
     // signal to collectively terminate
     nm_hermes.send::<()>(&()).await?;
@@ -269,14 +738,71 @@ async fn onomyd_runner(args: &Args) -> Result<()> {
     Ok(())
 }
 
+/// Has the host partition Hermes from the consumer (see [partition_relayer]),
+/// sends a transfer to the consumer that cannot be relayed meanwhile, and then
+/// checks that it is relayed after the partition heals
+async fn relays_after_partition(ibc_pair: &IbcPair, addr: &str, consumer_id: &str) -> Result<()> {
+    let state_file = &format!("/logs/{PARTITION_STATE_FILE}");
+    let pattern = |s: &str| {
+        Regex::new(s)
+            .map_err(|e| Error::boxed(Box::new(e)))
+            .map_add_err(|| format!("invalid regex {s}"))
+    };
+    info!("{PARTITION_REQUEST}");
+    wait_for_log_file(state_file, &pattern("^partitioned$")?, TIMEOUT).await?;
+
+    let consumer_node = &format!("tcp://{consumer_id}d:{RPC_PORT}");
+    let channel = &ibc_pair.a.transfer_channel;
+    let sequence = ibc_pair
+        .b
+        .cosmovisor_ibc_transfer_for_sequence(
+            "validator",
+            &reprefix_bech32(addr, CONSUMER_ACCOUNT_PREFIX)?,
+            &token18(1.0, "anom"),
+            &GasConfig::auto("anom"),
+        )
+        .await?;
+    wait_for_num_blocks(5).await?;
+    if cosmovisor_packet_received_at(consumer_node, "transfer", channel, sequence).await? {
+        return Err(Error::from(format!(
+            "packet {sequence} was relayed while Hermes was partitioned from the consumer"
+        )))
+    }
+
+    info!("{HEAL_REQUEST}");
+    wait_for_log_file(state_file, &pattern("^healed$")?, TIMEOUT).await?;
+    poll_until(
+        || cosmovisor_packet_received_at(consumer_node, "transfer", channel, sequence),
+        |received| *received,
+        TIMEOUT,
+        STD_DELAY,
+    )
+    .await
+    .map_add_err(|| format!("packet {sequence} was not relayed after the partition healed"))?;
+    Ok(())
+}
+
 async fn consumer(args: &Args) -> Result<()> {
     let daemon_home = args.daemon_home.as_ref().map_add_err(|| ())?;
     let chain_id = CONSUMER_ID;
-    let mut nm_onomyd = NetMessenger::listen_single_connect("0.0.0.0:26001", TIMEOUT).await?;
+    let mut nm_onomyd = PeerMessenger::listen_single_connect(
+        &ORCHESTRATOR_PORTS.node_listen_addr(),
+        TIMEOUT,
+        Some(chain_id),
+    )
+    .await?;
     // we need the initial consumer state
-    let ccvconsumer_state_s: String = nm_onomyd.recv().await?;
-
-    marketd_setup(daemon_home, chain_id, &ccvconsumer_state_s).await?;
+    let ccvconsumer_genesis =
+        CcvConsumerGenesis::from_json_str(&nm_onomyd.recv::<String>().await?)?;
+    // the validator and orchestrator keys of onomyd
+    cosmovisor_import_keyring(SHARED_KEYRING_DIR, daemon_home).await?;
+
+    // the IBC NOM has no metadata by default
+    let ibc_nom_metadata = DenomMetadata::new(ONOMY_IBC_NOM, "ibcnom", 18);
+    marketd_setup_with_patches(daemon_home, chain_id, &ccvconsumer_genesis, &[
+        denom_metadata_patch(ibc_nom_metadata.clone()),
+    ])
+    .await?;
     // make sure switching is possible
     set_minimum_gas_price(daemon_home, "1anative").await?;
 
@@ -297,18 +823,27 @@ async fn consumer(args: &Args) -> Result<()> {
         cosmovisor_start(&format!("{chain_id}d_bootstrap_runner.log"), None).await?;
 
     let addr = &cosmovisor_get_addr("validator").await?;
+    let voting_power = cosmovisor_total_voting_power().await?;
 
     // signal that we have started
     nm_onomyd.send::<()>(&()).await?;
 
     // wait for producer to send us stuff
     let ibc_pair = nm_onomyd.recv::<IbcPair>().await?;
+    let vsc_sequence =
+        cosmovisor_next_sequence_receive("consumer", &ibc_pair.a.ics_channel).await?;
     // get the name of the IBC NOM. Note that we can't do this on the onomyd side,
     // it has to be with respect to the consumer side
     let ibc_nom = &ibc_pair.a.get_ibc_denom("anom").await?;
     assert_eq!(ibc_nom, ONOMY_IBC_NOM);
-    let balances = cosmovisor_get_balances(addr).await?;
+    let (metadata, balances) = join_queries!(
+        "IBC NOM metadata" => cosmovisor_get_denom_metadata(ibc_nom),
+        "balances" => cosmovisor_get_balances(addr),
+    )?;
+    assert_eq!(metadata, ibc_nom_metadata);
     assert!(balances.contains_key(ibc_nom));
+    // make sure the denom was not received over some other channel
+    ibc_pair.a.assert_denom_trace(ibc_nom, "anom").await?;
 
     // we have IBC NOM, shut down, change gas in app.toml, restart
     cosmovisor_runner.terminate(TIMEOUT).await?;
@@ -320,29 +855,70 @@ async fn consumer(args: &Args) -> Result<()> {
     info!("restarted with new gas denom");
 
     // test normal transfer
-    let dst_addr = &reprefix_bech32(
-        "onomy1gk7lg5kd73mcr8xuyw727ys22t7mtz9gh07ul3",
-        CONSUMER_ACCOUNT_PREFIX,
-    )?;
+    let dst_addr = &known_test_addr(CONSUMER_ACCOUNT_PREFIX)?;
     cosmovisor_bank_send(addr, dst_addr, "5000", ibc_nom).await?;
     assert_eq!(cosmovisor_get_balances(dst_addr).await?[ibc_nom], "5000");
 
-    let test_addr = &reprefix_bech32(
-        "onomy1gk7lg5kd73mcr8xuyw727ys22t7mtz9gh07ul3",
-        PROVIDER_ACCOUNT_PREFIX,
-    )?;
+    let test_addr = &known_test_addr(PROVIDER_ACCOUNT_PREFIX)?;
     info!("sending back to {}", test_addr);
 
-    // send some IBC NOM back to origin chain using it as gas
+    // send some IBC NOM back to origin chain using it as gas, with the fee denom
+    // being determined from the `minimum-gas-prices`
     ibc_pair
         .a
-        .cosmovisor_ibc_transfer("validator", test_addr, "5000", ibc_nom)
+        .cosmovisor_ibc_transfer_with_gas(
+            "validator",
+            test_addr,
+            &format!("5000{ibc_nom}"),
+            &GasConfig::MinGasPrice {
+                daemon_home: daemon_home.to_owned(),
+                gas: 300_000,
+            },
+        )
         .await?;
-    wait_for_num_blocks(4).await?;
+
+    // the delegation on the provider should have changed our validator set, and
+    // nothing should be stuck on the ICS channel
+    poll_until(
+        cosmovisor_total_voting_power,
+        |power| *power > voting_power,
+        TIMEOUT,
+        STD_DELAY,
+    )
+    .await?;
+    // the new validator set must have arrived as a VSC packet on the ICS channel
+    let next_vsc_sequence =
+        cosmovisor_next_sequence_receive("consumer", &ibc_pair.a.ics_channel).await?;
+    if next_vsc_sequence <= vsc_sequence {
+        return Err(Error::from(format!(
+            "the validator set changed but no VSC packet was received on {}, the next sequence is \
+             still {next_vsc_sequence}",
+            ibc_pair.a.ics_channel
+        )))
+    }
+    wait_for_vsc_maturity(TIMEOUT).await?;
 
     // round trip signal
     nm_onomyd.send::<()>(&()).await?;
 
+    // final checks signal
+    nm_onomyd.recv::<()>().await?;
+
+    // the ICS channel should still be the expected one and healthy
+    let ccv_state = cosmovisor_query_ccvconsumer().await?;
+    ccv_state.assert_consumer_channel(&ibc_pair.a.ics_channel)?;
+    ccv_state.assert_no_pending_packets()?;
+    // all the transfers should have reused the trace of the first
+    ibc_pair.a.assert_single_denom_trace("anom").await?;
+    nm_onomyd.send::<()>(&()).await?;
+
+    // removal signal, Hermes does not relay the close handshake so our side of
+    // the channel stays open and we keep running
+    nm_onomyd.recv::<()>().await?;
+    let ccv_state = assert_consumer_removed(&format!("tcp://onomyd:{RPC_PORT}")).await?;
+    ccv_state.assert_consumer_channel(&ibc_pair.a.ics_channel)?;
+    nm_onomyd.send::<()>(&()).await?;
+
     // termination signal
     nm_onomyd.recv::<()>().await?;
 
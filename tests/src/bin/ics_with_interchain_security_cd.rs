@@ -1,27 +1,35 @@
 use std::time::Duration;
 
-use common::dockerfile_onomyd;
+use common::{
+    build_runner, clean_dockerfile_artifacts, clean_keyring_dirs, container_network,
+    dockerfile_onomyd, host_ports, make_and_copy, preflight, prepare_keyring_dirs,
+    publish_host_ports, wait_for_network, NetworkHooks, CONTAINER_TARGET,
+};
 use log::info;
 use onomy_test_lib::{
+    containers::ComposeService,
     cosmovisor::{
-        cosmovisor_bank_send, cosmovisor_get_addr, cosmovisor_get_balances, cosmovisor_start,
-        set_minimum_gas_price, sh_cosmovisor_no_dbg, wait_for_num_blocks,
+        cosmovisor_bank_send, cosmovisor_export_keyring, cosmovisor_get_addr,
+        cosmovisor_get_balances, cosmovisor_import_keyring, cosmovisor_start,
+        set_minimum_gas_price, sh_cosmovisor_no_dbg, wait_for_num_blocks, SHARED_KEYRING_DIR,
     },
+    dispatch_entry,
     dockerfiles::{dockerfile_hermes, onomy_std_cosmos_daemon_with_arbitrary},
+    genesis::CcvConsumerGenesis,
     hermes::{
         hermes_set_gas_price_denom, hermes_start, sh_hermes, write_hermes_config,
         HermesChainConfig, IbcPair,
     },
-    onomy_std_init, reprefix_bech32,
+    hold_for_keep_running, known_test_addr, onomy_std_init,
+    ports::ORCHESTRATOR_PORTS,
+    reprefix_bech32,
     setups::{cosmovisor_add_consumer, marketd_setup, onomyd_setup},
     super_orchestrator::{
-        docker::{Container, ContainerNetwork, Dockerfile},
         net_message::NetMessenger,
-        remove_files_in_dir, sh,
         stacked_errors::{MapAddError, Result},
         FileOptions, STD_DELAY, STD_TRIES,
     },
-    token18, Args, ONOMY_IBC_NOM, TIMEOUT,
+    token18, Args, EntryArgs, ONOMY_IBC_NOM, TIMEOUT,
 };
 use tokio::time::sleep;
 
@@ -42,21 +50,16 @@ RUN mv /root/interchain-security-$ICS_VERSION/interchain-security-cdd $DAEMON_HO
 async fn main() -> Result<()> {
     let args = onomy_std_init()?;
 
-    if let Some(ref s) = args.entry_name {
-        match s.as_str() {
-            "onomyd" => onomyd_runner(&args).await,
-            "consumer" => consumer(&args).await,
-            "hermes" => hermes_runner(&args).await,
-            _ => format!("entry_name \"{s}\" is not recognized").map_add_err(|| ()),
-        }
+    if args.entry_name.is_some() {
+        dispatch_entry(&args, &[
+            ("onomyd", |args| Box::pin(onomyd_runner(args))),
+            ("consumer", |args| Box::pin(consumer(args))),
+            ("hermes", |args| Box::pin(hermes_runner(args))),
+        ])
+        .await
     } else {
-        sh("make --directory ./../onomy/ build", &[]).await?;
-        // copy to dockerfile resources (docker cannot use files from outside cwd)
-        sh(
-            "cp ./../onomy/onomyd ./tests/dockerfiles/dockerfile_resources/onomyd",
-            &[],
-        )
-        .await?;
+        preflight(&args, &host_ports(&args)).await?;
+        make_and_copy(&args, "./../onomy", "build", "onomyd", "onomyd").await?;
         container_runner(&args).await
     }
 }
@@ -65,18 +68,13 @@ async fn container_runner(args: &Args) -> Result<()> {
     let logs_dir = "./tests/logs";
     let dockerfiles_dir = "./tests/dockerfiles";
     let bin_entrypoint = &args.bin_name;
-    let container_target = "x86_64-unknown-linux-gnu";
+    let container_target = CONTAINER_TARGET;
 
     // build internal runner with `--release`
-    sh("cargo build --release --bin", &[
-        bin_entrypoint,
-        "--target",
-        container_target,
-    ])
-    .await?;
+    build_runner(args, container_target, &[]).await?;
 
     // prepare volumed resources
-    remove_files_in_dir("./tests/resources/keyring-test/", &[".address", ".info"]).await?;
+    prepare_keyring_dirs(&["onomyd", "interchain-security-cd"]).await?;
 
     // prepare hermes config
     write_hermes_config(
@@ -93,54 +91,76 @@ async fn container_runner(args: &Args) -> Result<()> {
     ));
     let entrypoint = entrypoint.as_deref();
 
-    let mut cn = ContainerNetwork::new(
-        "test",
-        vec![
-            Container::new(
-                "hermes",
-                Dockerfile::Contents(dockerfile_hermes("__tmp_hermes_config.toml")),
-                entrypoint,
-                &["--entry-name", "hermes"],
-            ),
-            Container::new(
-                "onomyd",
-                Dockerfile::Contents(dockerfile_onomyd()),
-                entrypoint,
-                &["--entry-name", "onomyd"],
-            )
-            .volumes(&[(
-                "./tests/resources/keyring-test",
+    let services = vec![
+        ComposeService::new(
+            "hermes",
+            &dockerfile_hermes("__tmp_hermes_config.toml"),
+            entrypoint,
+            &EntryArgs::new("hermes")
+                .keep_running(args.keep_running)
+                .as_args(),
+        ),
+        ComposeService::new(
+            "onomyd",
+            &dockerfile_onomyd(),
+            entrypoint,
+            &EntryArgs::new("onomyd")
+                .keep_running(args.keep_running)
+                .as_args(),
+        )
+        .volumes(&[
+            (
+                "./tests/resources/keyring-test/onomyd",
                 "/root/.onomy/keyring-test",
-            )]),
-            Container::new(
+            ),
+            ("./tests/resources/keyring-test/shared", SHARED_KEYRING_DIR),
+        ]),
+        ComposeService::new(
+            "interchain-security-cdd",
+            &onomy_std_cosmos_daemon_with_arbitrary(
                 "interchain-security-cdd",
-                Dockerfile::Contents(onomy_std_cosmos_daemon_with_arbitrary(
-                    "interchain-security-cdd",
-                    ".interchain-security-cd",
-                    "v07-Theta",
-                    INTERCHAIN_SECURTY_CDD,
-                )),
-                entrypoint,
-                &["--entry-name", "consumer"],
-            )
-            .volumes(&[(
-                "./tests/resources/keyring-test",
+                ".interchain-security-cd",
+                "v07-Theta",
+                INTERCHAIN_SECURTY_CDD,
+            ),
+            entrypoint,
+            &EntryArgs::new("consumer")
+                .keep_running(args.keep_running)
+                .as_args(),
+        )
+        .volumes(&[
+            (
+                "./tests/resources/keyring-test/interchain-security-cd",
                 "/root/.interchain-security-cd/keyring-test",
-            )]),
-        ],
-        Some(dockerfiles_dir),
-        true,
-        logs_dir,
-    )?
-    .add_common_volumes(&[(logs_dir, "/logs")]);
-    cn.run_all(true).await?;
-    cn.wait_with_timeout_all(true, TIMEOUT).await?;
-    Ok(())
+            ),
+            ("./tests/resources/keyring-test/shared", SHARED_KEYRING_DIR),
+        ]),
+    ];
+    let services = publish_host_ports(args, services, "onomyd");
+    let Some(mut cn) = container_network(args, services, dockerfiles_dir, logs_dir).await? else {
+        return Ok(())
+    };
+    // the keys and the temporary artifacts are only needed while the network runs
+    NetworkHooks::new()
+        .after(clean_keyring_dirs())
+        .after(clean_dockerfile_artifacts(dockerfiles_dir))
+        .run(async {
+            cn.run_all(true).await?;
+            wait_for_network(args, &mut cn, &[
+                "hermes",
+                "onomyd",
+                "interchain-security-cdd",
+            ])
+            .await
+        })
+        .await
 }
 
 async fn hermes_runner(args: &Args) -> Result<()> {
     let hermes_home = args.hermes_home.as_ref().map_add_err(|| ())?;
-    let mut nm_onomyd = NetMessenger::listen_single_connect("0.0.0.0:26000", TIMEOUT).await?;
+    let mut nm_onomyd =
+        NetMessenger::listen_single_connect(&ORCHESTRATOR_PORTS.hermes_listen_addr(), TIMEOUT)
+            .await?;
 
     // get mnemonic from onomyd
     let mnemonic: String = nm_onomyd.recv().await?;
@@ -176,6 +196,11 @@ async fn hermes_runner(args: &Args) -> Result<()> {
     let mut hermes_runner = hermes_start("/logs/hermes_runner.log").await?;
     nm_onomyd.send::<()>(&()).await?;
 
+    // make sure everything from the round trip has been relayed
+    nm_onomyd.recv::<()>().await?;
+    ibc_pair.assert_drained(TIMEOUT).await?;
+    nm_onomyd.send::<()>(&()).await?;
+
     // termination signal
     nm_onomyd.recv::<()>().await?;
     hermes_runner.terminate(TIMEOUT).await?;
@@ -185,15 +210,18 @@ async fn hermes_runner(args: &Args) -> Result<()> {
 async fn onomyd_runner(args: &Args) -> Result<()> {
     let consumer_id = CONSUMER_ID;
     let daemon_home = args.daemon_home.as_ref().map_add_err(|| ())?;
-    let mut nm_hermes = NetMessenger::connect(STD_TRIES, STD_DELAY, "hermes:26000")
+    let hermes_addr = ORCHESTRATOR_PORTS.hermes_addr();
+    let mut nm_hermes = NetMessenger::connect(STD_TRIES, STD_DELAY, &hermes_addr)
         .await
-        .map_add_err(|| ())?;
-    let mut nm_consumer =
-        NetMessenger::connect(STD_TRIES, STD_DELAY, &format!("{consumer_id}d:26001"))
-            .await
-            .map_add_err(|| ())?;
+        .map_add_err(|| format!("while connecting to {hermes_addr}"))?;
+    let consumer_addr = ORCHESTRATOR_PORTS.node_addr(&format!("{consumer_id}d"));
+    let mut nm_consumer = NetMessenger::connect(STD_TRIES, STD_DELAY, &consumer_addr)
+        .await
+        .map_add_err(|| format!("while connecting to {consumer_addr}"))?;
 
     let mnemonic = onomyd_setup(daemon_home).await?;
+    // the consumer imports these after receiving the consumer genesis
+    cosmovisor_export_keyring(daemon_home, SHARED_KEYRING_DIR).await?;
     // send mnemonic to hermes
     nm_hermes.send::<String>(&mnemonic).await?;
 
@@ -206,7 +234,9 @@ async fn onomyd_runner(args: &Args) -> Result<()> {
     let ccvconsumer_state = cosmovisor_add_consumer(daemon_home, consumer_id).await?;
 
     // send to consumer
-    nm_consumer.send::<String>(&ccvconsumer_state).await?;
+    nm_consumer
+        .send::<String>(&ccvconsumer_state.to_json_string())
+        .await?;
 
     // send keys
     nm_consumer
@@ -252,12 +282,16 @@ async fn onomyd_runner(args: &Args) -> Result<()> {
 
     // recieve round trip signal
     nm_consumer.recv::<()>().await?;
+    // wait for hermes to relay everything
+    nm_hermes.send::<()>(&()).await?;
+    nm_hermes.recv::<()>().await?;
     // check that the IBC NOM converted back to regular NOM
     assert_eq!(
-        cosmovisor_get_balances("onomy1gk7lg5kd73mcr8xuyw727ys22t7mtz9gh07ul3").await?["anom"],
+        cosmovisor_get_balances(&known_test_addr(PROVIDER_ACCOUNT_PREFIX)?).await?["anom"],
         "5000"
     );
 
+    hold_for_keep_running(args).await;
     // signal to collectively terminate
     nm_hermes.send::<()>(&()).await?;
     nm_consumer.send::<()>(&()).await?;
@@ -275,11 +309,16 @@ async fn onomyd_runner(args: &Args) -> Result<()> {
 async fn consumer(args: &Args) -> Result<()> {
     let daemon_home = args.daemon_home.as_ref().map_add_err(|| ())?;
     let chain_id = CONSUMER_ID;
-    let mut nm_onomyd = NetMessenger::listen_single_connect("0.0.0.0:26001", TIMEOUT).await?;
+    let mut nm_onomyd =
+        NetMessenger::listen_single_connect(&ORCHESTRATOR_PORTS.node_listen_addr(), TIMEOUT)
+            .await?;
     // we need the initial consumer state
-    let ccvconsumer_state_s: String = nm_onomyd.recv().await?;
+    let ccvconsumer_genesis =
+        CcvConsumerGenesis::from_json_str(&nm_onomyd.recv::<String>().await?)?;
+    // the validator and orchestrator keys of onomyd
+    cosmovisor_import_keyring(SHARED_KEYRING_DIR, daemon_home).await?;
 
-    marketd_setup(daemon_home, chain_id, &ccvconsumer_state_s).await?;
+    marketd_setup(daemon_home, chain_id, &ccvconsumer_genesis).await?;
     // make sure switching is possible
     set_minimum_gas_price(daemon_home, "1anative").await?;
 
@@ -323,17 +362,11 @@ async fn consumer(args: &Args) -> Result<()> {
     info!("restarted with new gas denom");
 
     // test normal transfer
-    let dst_addr = &reprefix_bech32(
-        "onomy1gk7lg5kd73mcr8xuyw727ys22t7mtz9gh07ul3",
-        CONSUMER_ACCOUNT_PREFIX,
-    )?;
+    let dst_addr = &known_test_addr(CONSUMER_ACCOUNT_PREFIX)?;
     cosmovisor_bank_send(addr, dst_addr, "5000", ibc_nom).await?;
     assert_eq!(cosmovisor_get_balances(dst_addr).await?[ibc_nom], "5000");
 
-    let test_addr = &reprefix_bech32(
-        "onomy1gk7lg5kd73mcr8xuyw727ys22t7mtz9gh07ul3",
-        PROVIDER_ACCOUNT_PREFIX,
-    )?;
+    let test_addr = &known_test_addr(PROVIDER_ACCOUNT_PREFIX)?;
     info!("sending back to {}", test_addr);
 
     // send some IBC NOM back to origin chain using it as gas
@@ -341,7 +374,6 @@ async fn consumer(args: &Args) -> Result<()> {
         .a
         .cosmovisor_ibc_transfer("validator", test_addr, "5000", ibc_nom)
         .await?;
-    wait_for_num_blocks(4).await?;
 
     // round trip signal
     nm_onomyd.send::<()>(&()).await?;
@@ -1,13 +1,11 @@
-use common::container_runner;
+use common::{container_runner, host_ports, make_and_copy, preflight};
 use onomy_test_lib::{
     cosmovisor::{cosmovisor_get_addr, cosmovisor_start, sh_cosmovisor, sh_cosmovisor_tx},
+    dispatch_entry,
     dockerfiles::onomy_std_cosmos_daemon,
-    onomy_std_init,
+    hold_for_keep_running, onomy_std_init,
     setups::market_standaloned_setup,
-    super_orchestrator::{
-        sh,
-        stacked_errors::{MapAddError, Result},
-    },
+    super_orchestrator::stacked_errors::{MapAddError, Result},
     Args, TIMEOUT,
 };
 
@@ -15,18 +13,19 @@ use onomy_test_lib::{
 async fn main() -> Result<()> {
     let args = onomy_std_init()?;
 
-    if let Some(ref s) = args.entry_name {
-        match s.as_str() {
-            "market_standaloned" => market_standaloned_runner(&args).await,
-            _ => format!("entry_name \"{s}\" is not recognized").map_add_err(|| ()),
-        }
+    if args.entry_name.is_some() {
+        dispatch_entry(&args, &[("market_standaloned", |args| {
+            Box::pin(market_standaloned_runner(args))
+        })])
+        .await
     } else {
-        sh("make --directory ./../market/ build-standalone", &[]).await?;
-        // copy to dockerfile resources (docker cannot use files from outside cwd)
-        sh(
-            "cp ./../market/market-standaloned \
-             ./tests/dockerfiles/dockerfile_resources/market-standaloned",
-            &[],
+        preflight(&args, &host_ports(&args)).await?;
+        make_and_copy(
+            &args,
+            "./../market",
+            "build-standalone",
+            "market-standaloned",
+            "market-standaloned",
         )
         .await?;
         container_runner(&args, &[(
@@ -112,6 +111,7 @@ async fn market_standaloned_runner(args: &Args) -> Result<()> {
     // [amount] [rate] [prev] [next]").await?; cosmovisor("tx market
     // cancel-order [uid]").await?;
 
+    hold_for_keep_running(args).await;
     cosmovisor_runner.terminate(TIMEOUT).await?;
     Ok(())
 }
@@ -1,15 +1,21 @@
-use std::time::Duration;
+use std::{collections::BTreeMap, time::Duration};
 
-use common::{container_runner, dockerfile_onomyd};
+use common::{container_runner, dockerfile_onomyd, host_ports, make_and_copy, preflight};
 use log::info;
 use onomy_test_lib::{
     cosmovisor::{
-        cosmovisor_get_addr, cosmovisor_gov_file_proposal, cosmovisor_start, get_apr_annual,
-        get_delegations_to, get_staking_pool, get_treasury, get_treasury_inflation_annual,
-        sh_cosmovisor, sh_cosmovisor_no_dbg, sh_cosmovisor_tx, wait_for_num_blocks,
+        assert_exact_spend, cosmovisor_bank_send_with_gas, cosmovisor_community_pool,
+        cosmovisor_delegate, cosmovisor_feegrant, cosmovisor_get_addr, cosmovisor_get_balances,
+        cosmovisor_gov_file_proposal, cosmovisor_simulate_tx, cosmovisor_start,
+        cosmovisor_validator_rewards, get_apr_annual, get_delegations_to, get_staking_pool,
+        get_treasury, get_treasury_inflation_annual, parse_coins, sh_cosmovisor,
+        sh_cosmovisor_no_dbg, wait_for_bonded_validators, wait_for_cosmos_grpc,
+        wait_for_num_blocks, CosmovisorOptions, GasConfig,
     },
-    onomy_std_init, reprefix_bech32,
-    setups::onomyd_setup,
+    dispatch_entry, hold_for_keep_running, nom, onomy_std_init,
+    ports::GRPC_PORT,
+    reprefix_bech32,
+    setups::onomyd_setup_with_accounts,
     super_orchestrator::{
         sh,
         stacked_errors::{MapAddError, Result},
@@ -23,44 +29,84 @@ use tokio::time::sleep;
 async fn main() -> Result<()> {
     let args = onomy_std_init()?;
 
-    if let Some(ref s) = args.entry_name {
-        match s.as_str() {
-            "onomyd" => onomyd_runner(&args).await,
-            _ => format!("entry_name \"{s}\" is not recognized").map_add_err(|| ()),
-        }
+    if args.entry_name.is_some() {
+        dispatch_entry(&args, &[("onomyd", |args| Box::pin(onomyd_runner(args)))]).await
     } else {
-        sh("make --directory ./../onomy/ build", &[]).await?;
-        // copy to dockerfile resources (docker cannot use files from outside cwd)
-        sh(
-            "cp ./../onomy/onomyd ./tests/dockerfiles/dockerfile_resources/onomyd",
-            &[],
-        )
-        .await?;
+        preflight(&args, &host_ports(&args)).await?;
+        make_and_copy(&args, "./../onomy", "build", "onomyd", "onomyd").await?;
         container_runner(&args, &[("onomyd", &dockerfile_onomyd())]).await
     }
 }
 
 async fn onomyd_runner(args: &Args) -> Result<()> {
     let daemon_home = args.daemon_home.as_ref().map_add_err(|| ())?;
-    onomyd_setup(daemon_home).await?;
-    let mut cosmovisor_runner = cosmovisor_start("onomyd_runner.log", None).await?;
+    let whale_coins = nom(50.0e6);
+    let (_, accounts) =
+        onomyd_setup_with_accounts(daemon_home, &[("whale", &whale_coins), ("dust", "1anom")])
+            .await?;
+    let mut cosmovisor_runner = cosmovisor_start(
+        "onomyd_runner.log",
+        Some(CosmovisorOptions {
+            enable_grpc: true,
+            ..Default::default()
+        }),
+    )
+    .await?;
+    assert_eq!(wait_for_bonded_validators(1, TIMEOUT).await?, 1);
+    wait_for_cosmos_grpc(&format!("localhost:{GRPC_PORT}"), TIMEOUT).await?;
+
+    // each account should have exactly its genesis allocation
+    for (key, coins) in accounts.iter().zip([whale_coins.as_str(), "1anom"]) {
+        let balances: BTreeMap<String, String> = parse_coins(coins)?
+            .into_iter()
+            .map(|(denom, amount)| (denom, amount.to_string()))
+            .collect();
+        assert_eq!(
+            cosmovisor_get_balances(&key.address).await?,
+            balances,
+            "genesis balance of {}",
+            key.name
+        );
+    }
 
     let addr = &cosmovisor_get_addr("validator").await?;
+    // the gRPC path is only worth it if it is materially faster than the CLI
+    #[cfg(feature = "grpc")]
+    {
+        let (cli, grpc) = onomy_test_lib::grpc::bench_balance_queries(addr, 50).await?;
+        info!("50 balance queries took {cli:?} through the CLI and {grpc:?} through gRPC");
+        if grpc >= cli {
+            return Err(
+                onomy_test_lib::super_orchestrator::stacked_errors::Error::from(format!(
+                    "balance queries through gRPC ({grpc:?}) were not faster than the CLI \
+                     ({cli:?})"
+                )),
+            )
+        }
+    }
     let valoper_addr = &reprefix_bech32(addr, "onomyvaloper").unwrap();
     info!("valoper address: {valoper_addr}");
 
-    sh_cosmovisor_tx(
-        &format!(
-            "staking delegate {valoper_addr} 1000000000000000000000anom --gas auto \
-             --gas-adjustment 1.3 -y -b block --from validator"
-        ),
-        &[],
+    cosmovisor_delegate(
+        "validator",
+        valoper_addr,
+        "1000000000000000000000anom",
+        &GasConfig::auto("anom"),
     )
     .await?;
     sh_cosmovisor("query staking validators", &[]).await?;
 
     info!("{}", get_apr_annual(valoper_addr).await?);
 
+    // rewards accrue every block
+    let rewards = cosmovisor_validator_rewards(valoper_addr).await?;
+    info!(
+        "validator rewards: {rewards:?}, community pool: {:?}",
+        cosmovisor_community_pool().await?
+    );
+    assert!(rewards
+        .iter()
+        .any(|coin| (coin.denom == "anom") && (coin.to_f64() > 0.0)));
     info!("{}", get_delegations_to(valoper_addr).await?);
     info!("{:?}", get_staking_pool().await?);
     info!("{}", get_treasury().await?);
@@ -79,6 +125,58 @@ async fn onomyd_runner(args: &Args) -> Result<()> {
     )
     .await?;
 
+    // simulating should not change any state
+    let test_addr = "onomy1a69w3hfjqere4crkgyee79x2mxq0w2pfj9tu2m";
+    let balances = cosmovisor_get_balances(test_addr).await?;
+    let sim =
+        cosmovisor_simulate_tx(&format!("bank send {addr} {test_addr} 1337anom"), &[]).await?;
+    info!("{sim:?}");
+    assert!(sim.ok);
+    let sim = cosmovisor_simulate_tx(
+        &format!("bank send {addr} {test_addr} {}", token18(1.0e12, "anom")),
+        &[],
+    )
+    .await?;
+    assert!(!sim.ok);
+    assert!(sim.error.unwrap_or_default().contains("insufficient funds"));
+    wait_for_num_blocks(1).await?;
+    assert_eq!(cosmovisor_get_balances(test_addr).await?, balances);
+
+    // the validator pays the fees of an account that only has the coins it sends
+    sh_cosmovisor("keys add grantee", &[]).await?;
+    let grantee_addr = &cosmovisor_get_addr("grantee").await?;
+    let fixed_gas = GasConfig::Fixed {
+        gas: 200_000,
+        fees: "200000anom".to_owned(),
+    };
+    let sender_before = cosmovisor_get_balances(addr).await?;
+    cosmovisor_bank_send_with_gas(addr, grantee_addr, "1337anom", &fixed_gas).await?;
+    // fees should be charged exactly
+    assert_exact_spend(
+        &sender_before,
+        &cosmovisor_get_balances(addr).await?,
+        "1337anom",
+        "200000anom",
+    )?;
+    cosmovisor_feegrant(addr, grantee_addr, "1000000anom", &fixed_gas).await?;
+    let anom_balance = |balances: &BTreeMap<String, String>| -> u128 {
+        balances
+            .get("anom")
+            .map(|s| s.parse().unwrap())
+            .unwrap_or(0)
+    };
+    let granter_before = anom_balance(&cosmovisor_get_balances(addr).await?);
+    cosmovisor_bank_send_with_gas(grantee_addr, addr, "1337anom", &fixed_gas.fee_granter(addr))
+        .await?;
+    assert_eq!(
+        anom_balance(&cosmovisor_get_balances(grantee_addr).await?),
+        0
+    );
+    assert_eq!(
+        anom_balance(&cosmovisor_get_balances(addr).await?),
+        granter_before + 1337 - 200000
+    );
+
     //cosmovisor run tx staking delegate onomyvaloper
     // 10000000000000000000000ibc/
     // 0EEDE4D6082034D6CD465BD65761C305AACC6FCA1246F87D6A3C1F5488D18A7B --gas auto
@@ -105,7 +203,7 @@ async fn onomyd_runner(args: &Args) -> Result<()> {
     }}
     "#
         ),
-        "1anom",
+        &GasConfig::auto("anom"),
     )
     .await?;
     wait_for_num_blocks(1).await?;
@@ -113,6 +211,7 @@ async fn onomyd_runner(args: &Args) -> Result<()> {
     // inside of yaml, so we will instead test the exported genesis
     sh_cosmovisor("query params subspace crisis ConstantFee", &[]).await?;
 
+    hold_for_keep_running(args).await;
     sleep(Duration::ZERO).await;
     cosmovisor_runner.terminate(TIMEOUT).await?;
     // test that exporting works
@@ -0,0 +1,254 @@
+use common::{container_runner, dockerfile_onomyd, host_ports, make_and_copy, preflight};
+use log::info;
+use onomy_test_lib::{
+    cosmovisor::{
+        cosmovisor_bank_send, cosmovisor_downtime_threshold, cosmovisor_get_addr,
+        cosmovisor_get_cons_addr, cosmovisor_keys_add, cosmovisor_peer_ids,
+        cosmovisor_signing_info, cosmovisor_start, cosmovisor_valset, fast_block_times,
+        get_block_height, get_node_id, get_persistent_peer_info, set_minimum_gas_price,
+        sh_cosmovisor, sh_cosmovisor_no_dbg, sh_cosmovisor_tx, wait_for_bonded_validators,
+        wait_for_height, wait_for_missed_blocks, wait_for_peer, CosmovisorOptions, KeyringBackend,
+    },
+    dispatch_entry, hold_for_keep_running, onomy_std_init, poll_until,
+    ports::ORCHESTRATOR_PORTS,
+    setups::onomyd_setup,
+    super_orchestrator::{
+        net_message::NetMessenger,
+        stacked_errors::{Error, MapAddError, Result},
+        FileOptions, STD_DELAY, STD_TRIES,
+    },
+    token18, Args, MapAddContext, TIMEOUT,
+};
+
+/// The self delegation of the second validator in NOM, above the
+/// `min_global_self_delegation` of `onomyd_setup`
+const SELF_DELEGATION: f64 = 250.0e3;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = onomy_std_init()?;
+
+    if args.entry_name.is_some() {
+        dispatch_entry(&args, &[
+            ("onomyd", |args| Box::pin(onomyd_runner(args))),
+            ("onomyd_seed", |args| Box::pin(onomyd_seed_runner(args))),
+            ("onomyd_validator", |args| {
+                Box::pin(onomyd_validator_runner(args))
+            }),
+        ])
+        .await
+    } else {
+        preflight(&args, &host_ports(&args)).await?;
+        make_and_copy(&args, "./../onomy", "build", "onomyd", "onomyd").await?;
+        container_runner(&args, &[
+            ("onomyd", &dockerfile_onomyd()),
+            ("onomyd_seed", &dockerfile_onomyd()),
+            ("onomyd_validator", &dockerfile_onomyd()),
+        ])
+        .await
+    }
+}
+
+/// Initializes a node that is not a validator with the genesis of `onomyd`
+async fn init_from_genesis(daemon_home: &str, genesis_s: &str) -> Result<()> {
+    sh_cosmovisor("config chain-id onomy", &[]).await?;
+    sh_cosmovisor("config keyring-backend test", &[]).await?;
+    sh_cosmovisor_no_dbg("init --overwrite onomy", &[]).await?;
+    FileOptions::write_str(&format!("{daemon_home}/config/genesis.json"), genesis_s).await?;
+    fast_block_times(daemon_home).await?;
+    set_minimum_gas_price(daemon_home, "1anom").await
+}
+
+async fn onomyd_runner(args: &Args) -> Result<()> {
+    let daemon_home = args.daemon_home.as_ref().locate()?;
+    let nm_seed_addr = ORCHESTRATOR_PORTS.node_addr("onomyd_seed");
+    let mut nm_seed = NetMessenger::connect(STD_TRIES, STD_DELAY, &nm_seed_addr)
+        .await
+        .map_add_err(|| format!("while connecting to {nm_seed_addr}"))?;
+    let nm_validator_addr = ORCHESTRATOR_PORTS.node_addr("onomyd_validator");
+    let mut nm_validator = NetMessenger::connect(STD_TRIES, STD_DELAY, &nm_validator_addr)
+        .await
+        .map_add_err(|| format!("while connecting to {nm_validator_addr}"))?;
+
+    onomyd_setup(daemon_home).await?;
+    let genesis_s =
+        FileOptions::read_to_string(&format!("{daemon_home}/config/genesis.json")).await?;
+    nm_seed.send::<String>(&genesis_s).await?;
+    nm_validator.send::<String>(&genesis_s).await?;
+
+    // no persistent peers, everything is discovered through the seed
+    let seed = nm_seed.recv::<String>().await?;
+    nm_validator.send::<String>(&seed).await?;
+    nm_validator.send::<String>(&get_node_id().await?).await?;
+    let mut cosmovisor_runner = cosmovisor_start(
+        "onomyd_runner.log",
+        Some(CosmovisorOptions {
+            seeds: vec![seed],
+            ..Default::default()
+        }),
+    )
+    .await?;
+
+    // wait for the second validator to find us, and then find it in return
+    let validator_id = nm_validator.recv::<String>().await?;
+    wait_for_peer(&validator_id, TIMEOUT).await?;
+    info!("peers of onomyd: {:?}", cosmovisor_peer_ids().await?);
+
+    // fund the self delegation of the second validator
+    let validator_addr = nm_validator.recv::<String>().await?;
+    cosmovisor_bank_send(
+        &cosmovisor_get_addr("validator").await?,
+        &validator_addr,
+        &token18(SELF_DELEGATION + 1.0e3, ""),
+        "anom",
+    )
+    .await?;
+    nm_validator.send::<()>(&()).await?;
+
+    // the second validator has to be producing blocks with us
+    nm_validator.recv::<()>().await?;
+    let valset = cosmovisor_valset().await?;
+    if valset.len() != 2 {
+        return Err(Error::from(format!(
+            "expected both validators in the validator set, got {valset:?}"
+        )))
+    }
+    let height = get_block_height().await?;
+    wait_for_height(STD_TRIES, STD_DELAY, height + 2).await?;
+
+    // stop the second validator, its downtime should show in its signing info
+    let validator_cons_addr = nm_validator.recv::<String>().await?;
+    nm_validator.send::<()>(&()).await?;
+    nm_validator.recv::<()>().await?;
+    let threshold = cosmovisor_downtime_threshold().await?;
+    let missed = wait_for_missed_blocks(&validator_cons_addr, 1, TIMEOUT).await?;
+    // the counter keeps climbing until it crosses the threshold and the validator
+    // is jailed, which resets the counter
+    let info = poll_until(
+        || cosmovisor_signing_info(&validator_cons_addr),
+        |info| (info.missed_blocks_counter > missed.missed_blocks_counter) || info.was_jailed(),
+        TIMEOUT,
+        STD_DELAY,
+    )
+    .await?;
+    info!(
+        "signing info of the stopped validator with a downtime threshold of {threshold}: {info:?}"
+    );
+
+    hold_for_keep_running(args).await;
+    nm_validator.send::<()>(&()).await?;
+    nm_seed.send::<()>(&()).await?;
+    cosmovisor_runner.terminate(TIMEOUT).await?;
+    Ok(())
+}
+
+async fn onomyd_seed_runner(args: &Args) -> Result<()> {
+    let daemon_home = args.daemon_home.as_ref().locate()?;
+    let mut nm_onomyd =
+        NetMessenger::listen_single_connect(&ORCHESTRATOR_PORTS.node_listen_addr(), TIMEOUT)
+            .await?;
+
+    let genesis_s = nm_onomyd.recv::<String>().await?;
+    init_from_genesis(daemon_home, &genesis_s).await?;
+
+    let mut cosmovisor_runner = cosmovisor_start(
+        "onomyd_seed_runner.log",
+        Some(CosmovisorOptions {
+            seed_mode: true,
+            ..Default::default()
+        }),
+    )
+    .await?;
+    nm_onomyd
+        .send::<String>(&get_persistent_peer_info("onomyd_seed").await?)
+        .await?;
+
+    // termination signal
+    nm_onomyd.recv::<()>().await?;
+    cosmovisor_runner.terminate(TIMEOUT).await?;
+    Ok(())
+}
+
+/// Starts as a full node that only knows the seed, and then becomes the second
+/// validator
+async fn onomyd_validator_runner(args: &Args) -> Result<()> {
+    let daemon_home = args.daemon_home.as_ref().locate()?;
+    let mut nm_onomyd =
+        NetMessenger::listen_single_connect(&ORCHESTRATOR_PORTS.node_listen_addr(), TIMEOUT)
+            .await?;
+
+    let genesis_s = nm_onomyd.recv::<String>().await?;
+    init_from_genesis(daemon_home, &genesis_s).await?;
+    let seed = nm_onomyd.recv::<String>().await?;
+    let onomyd_id = nm_onomyd.recv::<String>().await?;
+
+    // the validator may not have been discovered yet, so we can't wait for heights
+    let mut cosmovisor_runner = cosmovisor_start(
+        "onomyd_validator_runner.log",
+        Some(CosmovisorOptions {
+            no_wait: true,
+            seeds: vec![seed],
+            ..Default::default()
+        }),
+    )
+    .await?;
+
+    wait_for_peer(&onomyd_id, TIMEOUT).await?;
+    info!("discovered onomyd through the seed");
+    nm_onomyd.send::<String>(&get_node_id().await?).await?;
+    // make sure we are actually following the chain
+    wait_for_height(STD_TRIES, STD_DELAY, 3).await?;
+    let height = get_block_height().await?;
+    wait_for_height(STD_TRIES, STD_DELAY, height + 2).await?;
+
+    cosmovisor_keys_add("validator", &KeyringBackend::Test).await?;
+    nm_onomyd
+        .send::<String>(&cosmovisor_get_addr("validator").await?)
+        .await?;
+    // wait for funding
+    nm_onomyd.recv::<()>().await?;
+    let pubkey = sh_cosmovisor_no_dbg("tendermint show-validator", &[]).await?;
+    sh_cosmovisor_tx("staking create-validator", &[
+        "--amount",
+        &token18(SELF_DELEGATION, "anom"),
+        "--pubkey",
+        pubkey.trim(),
+        "--moniker",
+        "onomyd_validator",
+        "--commission-rate",
+        "0.05",
+        "--commission-max-rate",
+        "0.2",
+        "--commission-max-change-rate",
+        "0.01",
+        "--min-self-delegation",
+        &token18(225.0e3, ""),
+        "--from",
+        "validator",
+        "-y",
+        "-b",
+        "block",
+        "--gas",
+        "auto",
+        "--gas-adjustment",
+        "1.3",
+        "--gas-prices",
+        "1anom",
+    ])
+    .await?;
+    wait_for_bonded_validators(2, TIMEOUT).await?;
+    nm_onomyd.send::<()>(&()).await?;
+
+    // go offline when told to
+    nm_onomyd
+        .send::<String>(&cosmovisor_get_cons_addr().await?)
+        .await?;
+    nm_onomyd.recv::<()>().await?;
+    cosmovisor_runner.terminate(TIMEOUT).await?;
+    nm_onomyd.send::<()>(&()).await?;
+
+    hold_for_keep_running(args).await;
+    // termination signal
+    nm_onomyd.recv::<()>().await?;
+    Ok(())
+}
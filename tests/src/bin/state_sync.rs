@@ -0,0 +1,134 @@
+use common::{container_runner, dockerfile_onomyd, host_ports, make_and_copy, preflight};
+use log::info;
+use onomy_test_lib::{
+    cosmovisor::{
+        cosmovisor_start, enable_state_sync, enable_state_sync_snapshots, fast_block_times,
+        get_block_hash, get_block_height, get_persistent_peer_info, set_minimum_gas_price,
+        set_persistent_peers, sh_cosmovisor, sh_cosmovisor_no_dbg, wait_for_height,
+        wait_for_state_sync_complete, CosmovisorOptions,
+    },
+    dispatch_entry, hold_for_keep_running, onomy_std_init,
+    ports::{ORCHESTRATOR_PORTS, RPC_PORT},
+    setups::onomyd_setup,
+    super_orchestrator::{
+        net_message::NetMessenger,
+        stacked_errors::{Error, MapAddError, Result},
+        FileOptions, STD_DELAY, STD_TRIES,
+    },
+    Args, MapAddContext, TIMEOUT,
+};
+
+const SNAPSHOT_INTERVAL: u64 = 10;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = onomy_std_init()?;
+
+    if args.entry_name.is_some() {
+        dispatch_entry(&args, &[
+            ("onomyd", |args| Box::pin(onomyd_runner(args))),
+            ("onomyd_sync", |args| Box::pin(onomyd_sync_runner(args))),
+        ])
+        .await
+    } else {
+        preflight(&args, &host_ports(&args)).await?;
+        make_and_copy(&args, "./../onomy", "build", "onomyd", "onomyd").await?;
+        container_runner(&args, &[
+            ("onomyd", &dockerfile_onomyd()),
+            ("onomyd_sync", &dockerfile_onomyd()),
+        ])
+        .await
+    }
+}
+
+async fn onomyd_runner(args: &Args) -> Result<()> {
+    let daemon_home = args.daemon_home.as_ref().locate()?;
+    let nm_sync_addr = ORCHESTRATOR_PORTS.node_addr("onomyd_sync");
+    let mut nm_sync = NetMessenger::connect(STD_TRIES, STD_DELAY, &nm_sync_addr)
+        .await
+        .map_add_err(|| format!("while connecting to {nm_sync_addr}"))?;
+
+    onomyd_setup(daemon_home).await?;
+    enable_state_sync_snapshots(daemon_home, SNAPSHOT_INTERVAL, 2).await?;
+    let mut cosmovisor_runner = cosmovisor_start("onomyd_runner.log", None).await?;
+
+    // make sure at least a couple of snapshots have been taken
+    wait_for_height(STD_TRIES, STD_DELAY, 3 * SNAPSHOT_INTERVAL).await?;
+    let trust_height = get_block_height().await?;
+    let trust_hash = get_block_hash(trust_height).await?;
+
+    nm_sync
+        .send::<String>(
+            &FileOptions::read_to_string(&format!("{daemon_home}/config/genesis.json")).await?,
+        )
+        .await?;
+    nm_sync
+        .send::<String>(&get_persistent_peer_info("onomyd").await?)
+        .await?;
+    nm_sync.send::<u64>(&trust_height).await?;
+    nm_sync.send::<String>(&trust_hash).await?;
+
+    // wait for the syncing node to finish
+    nm_sync.recv::<()>().await?;
+
+    hold_for_keep_running(args).await;
+    cosmovisor_runner.terminate(TIMEOUT).await?;
+    Ok(())
+}
+
+async fn onomyd_sync_runner(args: &Args) -> Result<()> {
+    let daemon_home = args.daemon_home.as_ref().locate()?;
+    let mut nm_onomyd =
+        NetMessenger::listen_single_connect(&ORCHESTRATOR_PORTS.node_listen_addr(), TIMEOUT)
+            .await?;
+
+    sh_cosmovisor("config chain-id onomy", &[]).await?;
+    sh_cosmovisor("config keyring-backend test", &[]).await?;
+    sh_cosmovisor_no_dbg("init --overwrite onomy", &[]).await?;
+
+    let genesis_s = nm_onomyd.recv::<String>().await?;
+    FileOptions::write_str(&format!("{daemon_home}/config/genesis.json"), &genesis_s).await?;
+    let peer = nm_onomyd.recv::<String>().await?;
+    let trust_height = nm_onomyd.recv::<u64>().await?;
+    let trust_hash = nm_onomyd.recv::<String>().await?;
+
+    fast_block_times(daemon_home).await?;
+    set_minimum_gas_price(daemon_home, "1anom").await?;
+    set_persistent_peers(daemon_home, &[&peer]).await?;
+    // tendermint requires two rpc servers, but they can be the same
+    let rpc_server = format!("http://onomyd:{RPC_PORT}");
+    enable_state_sync(
+        daemon_home,
+        &[&rpc_server, &rpc_server],
+        trust_height,
+        &trust_hash,
+    )
+    .await?;
+
+    let mut cosmovisor_runner = cosmovisor_start(
+        "onomyd_sync_runner.log",
+        Some(CosmovisorOptions {
+            no_wait: true,
+            ..Default::default()
+        }),
+    )
+    .await?;
+
+    let earliest_height = wait_for_state_sync_complete(TIMEOUT).await?;
+    info!("state synced with earliest height {earliest_height}");
+    if earliest_height <= 1 {
+        return Err(Error::from(format!(
+            "node replayed from genesis instead of state syncing, earliest height is \
+             {earliest_height}"
+        )))
+    }
+    // make sure we keep following the chain
+    let height = get_block_height().await?;
+    wait_for_height(STD_TRIES, STD_DELAY, height + 2).await?;
+
+    hold_for_keep_running(args).await;
+    nm_onomyd.send::<()>(&()).await?;
+
+    cosmovisor_runner.terminate(TIMEOUT).await?;
+    Ok(())
+}
@@ -1,54 +1,596 @@
+use std::{
+    fmt::Write,
+    future::Future,
+    panic::{resume_unwind, AssertUnwindSafe},
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use log::{info, warn};
 use onomy_test_lib::{
+    command::PipedCommand,
+    containers::{
+        apply_network_create_options, clean_tmp_artifacts, measure_startup, preflight_check,
+        remove_labeled_containers, run_labels, validate_daemon_binary, write_compose_file,
+        ComposeService, DockerNetworkOptions, PreflightOptions, RUN_ID_LABEL,
+    },
+    cosmovisor::DAEMON_OVERRIDE_DIR,
     dockerfiles::onomy_std_cosmos_daemon,
+    ports::RPC_PORT,
+    source_fingerprint,
     super_orchestrator::{
-        docker::{Container, ContainerNetwork, Dockerfile},
-        sh,
-        stacked_errors::Result,
+        docker::ContainerNetwork,
+        remove_files_in_dir, sh, sh_no_dbg,
+        stacked_errors::{Error, MapAddError, Result},
+        FileOptions,
     },
-    Args, TIMEOUT,
+    Args, EntryArgs, MapAddContext, TIMEOUT,
 };
+use serde_json::{json, Value};
+
+/// Generous timeout for builds done by the container runners
+pub const BUILD_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// The host directory of the keyring volumes. Each daemon container gets its
+/// own subdirectory, and the `shared` subdirectory is mounted at
+/// `SHARED_KEYRING_DIR` in all of them.
+pub const KEYRING_DIR: &str = "./tests/resources/keyring-test";
+
+/// Removes the containers of all runs (see `run_labels`) that were left behind,
+/// e.x. by a runner that was killed
+pub async fn clean_stale_containers() -> Result<()> {
+    let removed = remove_labeled_containers(RUN_ID_LABEL, None).await?;
+    if !removed.is_empty() {
+        info!("removed stale containers {removed:?}");
+    }
+    Ok(())
+}
+
+/// Creates the keyring subdirectories for the containers `names` and the
+/// `shared` subdirectory, and cleans up keys from previous runs
+pub async fn prepare_keyring_dirs(names: &[&str]) -> Result<()> {
+    for name in names.iter().chain(["shared"].iter()) {
+        let dir = format!("{KEYRING_DIR}/{name}");
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_add_err(|| format!("prepare_keyring_dirs could not create {dir}"))?;
+    }
+    clean_keyring_dirs().await
+}
+
+/// Removes the keys in all the keyring subdirectories
+pub async fn clean_keyring_dirs() -> Result<()> {
+    // keys from before the keyring was split into subdirectories
+    remove_files_in_dir(KEYRING_DIR, &[".address", ".info"]).await?;
+    let mut entries = tokio::fs::read_dir(KEYRING_DIR)
+        .await
+        .map_add_err(|| format!("clean_keyring_dirs could not read {KEYRING_DIR}"))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_add_err(|| format!("clean_keyring_dirs could not read an entry of {KEYRING_DIR}"))?
+    {
+        if entry.path().is_dir() {
+            let dir = entry.path();
+            let dir = dir.to_str().locate()?;
+            remove_files_in_dir(dir, &[".address", ".info"]).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the temporary dockerfiles and dockerfile resources (e.x. the hermes
+/// config) of a run, see [clean_tmp_artifacts]
+pub async fn clean_dockerfile_artifacts(dockerfiles_dir: &str) -> Result<()> {
+    let resources_dir = format!("{dockerfiles_dir}/dockerfile_resources");
+    let removed = clean_tmp_artifacts(&[dockerfiles_dir, &resources_dir]).await?;
+    if !removed.is_empty() {
+        info!("removed temporary artifacts {removed:?}");
+    }
+    Ok(())
+}
+
+/// Records the [source_fingerprint] of the last successful build of each
+/// dockerfile resource built by [make_and_copy]
+pub const BUILD_CACHE_PATH: &str = "./tests/dockerfiles/dockerfile_resources/.build_cache";
+
+/// Runs `make --directory {src_dir} {target}` and copies `{src_dir}/{artifact}`
+/// to the dockerfile resource `resource` (docker cannot use files from outside
+/// cwd). This is skipped if the sources of `src_dir` are unchanged since the
+/// last successful build of `resource`, unless `--force-build` was passed or
+/// `src_dir` is not a git repository.
+pub async fn make_and_copy(
+    args: &Args,
+    src_dir: &str,
+    target: &str,
+    artifact: &str,
+    resource: &str,
+) -> Result<()> {
+    let resource_path = format!("./tests/dockerfiles/dockerfile_resources/{resource}");
+    let fingerprint = source_fingerprint(src_dir).await?;
+    let mut cache: Value = match FileOptions::read_to_string(BUILD_CACHE_PATH).await {
+        Ok(s) => serde_json::from_str(&s).unwrap_or_default(),
+        Err(_) => Value::Null,
+    };
+    if !cache.is_object() {
+        cache = json!({});
+    }
+    let resource_exists = tokio::fs::metadata(&resource_path).await.is_ok();
+    if let Some(ref fingerprint) = fingerprint {
+        if !args.force_build && resource_exists && (cache[resource] == fingerprint.as_str()) {
+            info!("skipping build of {resource} because {src_dir} is unchanged");
+            return Ok(())
+        }
+    } else {
+        info!("{src_dir} is not a git repository, always rebuilding {resource}");
+    }
+    sh("make --directory", &[src_dir, target]).await?;
+    sh("cp", &[&format!("{src_dir}/{artifact}"), &resource_path]).await?;
+    cache[resource] = fingerprint.map_or(Value::Null, Value::from);
+    FileOptions::write_str(BUILD_CACHE_PATH, &cache.to_string()).await?;
+    Ok(())
+}
 
 pub fn dockerfile_onomyd() -> String {
     onomy_std_cosmos_daemon("onomyd", ".onomy", "v1.1.1", "onomyd")
 }
 
+/// `onomyd` at a specific `version`, the binary is expected to be at
+/// `./tests/dockerfiles/dockerfile_resources/onomyd_{version}`
+pub fn dockerfile_onomyd_version(version: &str) -> String {
+    onomy_std_cosmos_daemon("onomyd", ".onomy", version, &format!("onomyd_{version}"))
+}
+
+/// The target that the runners are built for to run in the containers
+pub const CONTAINER_TARGET: &str = "x86_64-unknown-linux-gnu";
+
+/// The host ports that the first daemon of a runner publishes, see
+/// [publish_host_ports]. With `--keep-running` its RPC is published so that the
+/// held network can be inspected from the host.
+pub fn host_ports(args: &Args) -> Vec<u16> {
+    if args.keep_running {
+        vec![RPC_PORT]
+    } else {
+        vec![]
+    }
+}
+
+/// Publishes the [host_ports] of the service `name` on the same ports of the
+/// host
+pub fn publish_host_ports(
+    args: &Args,
+    services: Vec<ComposeService>,
+    name: &str,
+) -> Vec<ComposeService> {
+    let ports = host_ports(args);
+    services
+        .into_iter()
+        .map(|service| {
+            if service.name == name {
+                ports
+                    .iter()
+                    .fold(service, |service, port| service.publish(*port, *port))
+            } else {
+                service
+            }
+        })
+        .collect()
+}
+
+/// Runs `preflight_check` for a runner that builds for [CONTAINER_TARGET] and
+/// publishes `free_ports` on the host, unless `--skip-preflight` was passed.
+/// Docker is not needed if only a compose file is written. Runners should call
+/// this first in their `main` so that problems are reported before the long
+/// builds.
+pub async fn preflight(args: &Args, free_ports: &[u16]) -> Result<()> {
+    if args.skip_preflight {
+        return Ok(())
+    }
+    preflight_check(&PreflightOptions {
+        docker: args.compose.is_none(),
+        rust_targets: vec![CONTAINER_TARGET.to_owned()],
+        free_ports: free_ports.to_vec(),
+    })
+    .await
+}
+
+/// Builds the internal runner (the binary of `args.bin_name`) with `--release`
+/// for `container_target` and the extra cargo `features`. The `grpc` feature
+/// is forwarded if this binary was built with it. This times out after
+/// [BUILD_TIMEOUT] in case the build gets stuck.
+pub async fn build_runner(args: &Args, container_target: &str, features: &[&str]) -> Result<()> {
+    let mut features = features.to_vec();
+    if cfg!(feature = "grpc") {
+        features.push("grpc");
+    }
+    let features = features.join(",");
+    let mut build_args = vec![args.bin_name.as_str(), "--target", container_target];
+    if !features.is_empty() {
+        build_args.extend(["--features", &features]);
+    }
+    PipedCommand::new("cargo build --release --bin", &build_args)
+        .timeout(BUILD_TIMEOUT)
+        .run_to_completion()
+        .await?
+        .assert_success()
+        .map_add_err(|| format!("build_runner(bin_name: {})", args.bin_name))
+}
+
+/// If `--daemon-binary` was passed, validates the binary and mounts it into
+/// `DAEMON_OVERRIDE_DIR` of all the `services`. Only the daemon containers with
+/// a matching `DAEMON_NAME` use it, see `apply_daemon_override`.
+async fn mount_daemon_binary(
+    args: &Args,
+    services: Vec<ComposeService>,
+) -> Result<Vec<ComposeService>> {
+    let Some(ref path) = args.daemon_binary else {
+        return Ok(services)
+    };
+    let daemon_name = validate_daemon_binary(path).await?;
+    info!("mounting {path} as the {daemon_name} binary");
+    let mount = format!("{DAEMON_OVERRIDE_DIR}/{daemon_name}");
+    Ok(services
+        .into_iter()
+        .map(|s| s.volumes(&[(path.as_str(), mount.as_str())]))
+        .collect())
+}
+
 /// Useful for running simple container networks that have a standard format and
-/// don't need extra build or volume arguments.
+/// don't need extra build or volume arguments. The first service publishes the
+/// [host_ports], `main` should have run [preflight] with them.
 pub async fn container_runner(args: &Args, name_and_contents: &[(&str, &str)]) -> Result<()> {
     let logs_dir = "./tests/logs";
     let dockerfiles_dir = "./tests/dockerfiles";
     let bin_entrypoint = &args.bin_name;
-    let container_target = "x86_64-unknown-linux-gnu";
-
-    // build internal runner
-    sh("cargo build --release --bin", &[
-        bin_entrypoint,
-        "--target",
-        container_target,
-    ])
-    .await?;
+    let container_target = CONTAINER_TARGET;
+    build_runner(args, container_target, &[]).await?;
+
+    let services: Vec<ComposeService> = name_and_contents
+        .iter()
+        .map(|(name, contents)| {
+            ComposeService::new(
+                name,
+                contents,
+                Some(&format!(
+                    "./target/{container_target}/release/{bin_entrypoint}"
+                )),
+                &EntryArgs::new(name)
+                    .keep_running(args.keep_running)
+                    .as_args(),
+            )
+        })
+        .collect();
+    let services = match name_and_contents.first() {
+        Some((name, _)) => publish_host_ports(args, services, name),
+        None => services,
+    };
+    let Some(mut cn) = container_network(args, services, dockerfiles_dir, logs_dir).await? else {
+        return Ok(())
+    };
+    cn.run_all(true).await?;
+    let names: Vec<&str> = name_and_contents.iter().map(|(name, _)| *name).collect();
+    wait_for_network(args, &mut cn, &names).await
+}
+
+/// Applies the [DockerNetworkOptions] of `--dns` to `services`. The subnet
+/// and gateway apply to the network itself, see [container_network].
+fn apply_network_options(
+    options: &DockerNetworkOptions,
+    services: Vec<ComposeService>,
+) -> Vec<ComposeService> {
+    services
+        .into_iter()
+        .map(|service| service.network_options(options))
+        .collect()
+}
 
+/// Every runner should turn its `services` into the network through this, so
+/// that `--compose` and the options applying to the containers are supported
+/// the same way everywhere. This applies `--daemon-binary`, `--dns`, and the
+/// other network options (the "test" network is created with `--subnet` and
+/// `--gateway` if they were passed), labels the services with the `run_labels`
+/// of this run so that `clean` can find them, and returns the
+/// `ContainerNetwork` "test" with `logs_dir` mounted at "/logs". If `--compose`
+/// was passed, the equivalent docker-compose file is written instead and `None`
+/// is returned, in which case the runner should not run anything.
+pub async fn container_network(
+    args: &Args,
+    services: Vec<ComposeService>,
+    dockerfiles_dir: &str,
+    logs_dir: &str,
+) -> Result<Option<ContainerNetwork>> {
+    let services = mount_daemon_binary(args, services).await?;
+    let options = DockerNetworkOptions::from_args(args)?;
+    let services = apply_network_options(&options, services);
+    let labels = run_labels(&args.bin_name).await;
+    let services: Vec<ComposeService> = services.into_iter().map(|s| s.labels(&labels)).collect();
+    if write_compose(args, &services, dockerfiles_dir, logs_dir).await? {
+        return Ok(None)
+    }
     let mut cn = ContainerNetwork::new(
         "test",
-        name_and_contents
-            .iter()
-            .map(|(name, contents)| {
-                Container::new(
-                    name,
-                    Dockerfile::Contents(contents.to_string()),
-                    Some(&format!(
-                        "./target/{container_target}/release/{bin_entrypoint}"
-                    )),
-                    &["--entry-name", name],
-                )
-            })
-            .collect(),
+        services.iter().map(|s| s.to_container()).collect(),
         Some(dockerfiles_dir),
         true,
         logs_dir,
     )?
     .add_common_volumes(&[(logs_dir, "/logs")]);
-    cn.run_all(true).await?;
-    cn.wait_with_timeout_all(true, TIMEOUT).await.unwrap();
+    apply_network_create_options(&mut cn, "test", &options).await?;
+    Ok(Some(cn))
+}
+
+/// Returns an error if `--compose` was passed to a runner that cannot express
+/// its test as a single docker-compose file, e.x. because it runs several
+/// networks
+pub fn reject_compose(args: &Args, reason: &str) -> Result<()> {
+    if args.compose.is_some() {
+        return Err(Error::from(format!(
+            "--compose is not supported by {}: {reason}",
+            args.bin_name
+        )))
+    }
+    Ok(())
+}
+
+/// If `--compose` was passed, writes the docker-compose file for `services`
+/// (with the logs directory mounted at "/logs" like the runners do) and
+/// returns true, in which case the runner should not run the network
+async fn write_compose(
+    args: &Args,
+    services: &[ComposeService],
+    dockerfiles_dir: &str,
+    logs_dir: &str,
+) -> Result<bool> {
+    let Some(ref path) = args.compose else {
+        return Ok(false)
+    };
+    write_compose_file(
+        path,
+        "test",
+        &DockerNetworkOptions::from_args(args)?,
+        services,
+        dockerfiles_dir,
+        &[(logs_dir, "/logs")],
+    )
+    .await?;
+    info!("wrote docker-compose file {path}, run it with `docker compose -f {path} up`");
+    Ok(true)
+}
+
+type Hook<'a> = Pin<Box<dyn Future<Output = Result<()>> + 'a>>;
+
+/// Steps to run after the lifetime of a container network, e.x. cleaning up
+/// volumed resources. The hooks run even if the network failed or panicked, so
+/// that cleanup is reliable.
+#[derive(Default)]
+pub struct NetworkHooks<'a> {
+    after: Vec<Hook<'a>>,
+}
+
+impl<'a> NetworkHooks<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a hook to run after the network, in order
+    pub fn after<F: Future<Output = Result<()>> + 'a>(mut self, hook: F) -> Self {
+        self.after.push(Box::pin(hook));
+        self
+    }
+
+    /// Runs `network` (which should e.x. call `run_all` and
+    /// [wait_for_network]), then all the `after` hooks. The first error is
+    /// returned, errors from later `after` hooks are only logged. If `network`
+    /// panics, the panic is resumed after the hooks have run.
+    pub async fn run<F: Future<Output = Result<()>>>(self, network: F) -> Result<()> {
+        let (mut res, panic) = match AssertUnwindSafe(network).catch_unwind().await {
+            Ok(res) => (res, None),
+            Err(panic) => {
+                warn!("the network panicked, running the after hooks before resuming the panic");
+                (Ok(()), Some(panic))
+            }
+        };
+        for hook in self.after {
+            if let Err(e) = hook.await.map_add_err(|| "NetworkHooks after hook failed") {
+                if res.is_ok() && panic.is_none() {
+                    res = Err(e);
+                } else {
+                    warn!("{e:?}");
+                }
+            }
+        }
+        if let Some(panic) = panic {
+            resume_unwind(panic)
+        }
+        res
+    }
+}
+
+/// Waits for the containers to finish. If `--keep-running` was passed, this
+/// instead prints how to inspect each container and waits for Ctrl-C before
+/// cleaning up.
+pub async fn wait_for_network(
+    args: &Args,
+    cn: &mut ContainerNetwork,
+    names: &[&str],
+) -> Result<()> {
+    if !args.keep_running {
+        cn.wait_with_timeout_all(true, TIMEOUT).await?;
+        return Ok(())
+    }
+    for name in names {
+        let ps = sh_no_dbg("docker ps", &[
+            "--format",
+            "{{.Names}}\t{{.Ports}}",
+            "--filter",
+            &format!("name={name}"),
+        ])
+        .await?;
+        info!("{}", ps.trim());
+        info!("exec into it with `cargo r --bin auto_exec_i -- --container-name {name}`");
+    }
+    info!("--keep-running was passed, press Ctrl-C to clean up");
+    tokio::signal::ctrl_c()
+        .await
+        .map_add_err(|| "wait_for_network")?;
+    cn.terminate_all().await;
     Ok(())
 }
+
+/// [wait_for_network] while measuring the startup of the `ready_ports`
+/// containers (see `measure_startup`). The `StartupReport` is written to
+/// `{logs_dir}/startup_report.log`, and after the network finishes this
+/// asserts that each container became ready within `budget`. The report is
+/// best effort, if the host could not reach any of the containers (e.x. with
+/// Docker Desktop) only a warning is logged.
+pub async fn wait_for_network_with_startup_report(
+    args: &Args,
+    cn: &mut ContainerNetwork,
+    names: &[&str],
+    logs_dir: &str,
+    ready_ports: &[(&str, u16)],
+    budget: Duration,
+) -> Result<()> {
+    let wait = wait_for_network(args, cn, names);
+    tokio::pin!(wait);
+    let report = tokio::select! {
+        report = measure_startup(ready_ports, TIMEOUT) => report,
+        res = &mut wait => {
+            warn!("the network finished before the startup report was complete");
+            return res
+        }
+    };
+    let summary = report.summary();
+    info!("startup report:\n{summary}");
+    FileOptions::write_str(&format!("{logs_dir}/startup_report.log"), &summary).await?;
+    wait.await?;
+    if report.none_ready() {
+        warn!(
+            "no container became reachable from the host, the container IPs may not be routable \
+             (e.x. with Docker Desktop), skipping the startup budget"
+        );
+        return Ok(())
+    }
+    report.assert_ready_within(budget)
+}
+
+/// The outcome of one combination run by a [VersionMatrix]
+#[derive(Debug, Clone)]
+pub struct VersionMatrixResult {
+    pub provider_version: String,
+    pub consumer_version: String,
+    pub elapsed: Duration,
+    /// The debug formatted error if the scenario failed
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VersionMatrixReport {
+    pub results: Vec<VersionMatrixResult>,
+}
+
+impl VersionMatrixReport {
+    /// Returns a table with a row for each combination
+    pub fn summary(&self) -> String {
+        let mut s = format!(
+            "{:<20} {:<20} {:<8} {:>10}\n",
+            "provider", "consumer", "result", "time"
+        );
+        for res in &self.results {
+            let outcome = if res.error.is_none() { "pass" } else { "FAIL" };
+            writeln!(
+                s,
+                "{:<20} {:<20} {:<8} {:>9}s",
+                res.provider_version,
+                res.consumer_version,
+                outcome,
+                res.elapsed.as_secs()
+            )
+            .unwrap();
+        }
+        s
+    }
+
+    /// Returns an error listing the failed combinations, if any
+    pub fn assert_all_passed(&self) -> Result<()> {
+        let failed: Vec<String> = self
+            .results
+            .iter()
+            .filter_map(|res| {
+                res.error
+                    .as_ref()
+                    .map(|e| format!("({}, {}): {e}", res.provider_version, res.consumer_version))
+            })
+            .collect();
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::from(format!(
+                "version matrix failures:\n{}",
+                failed.join("\n")
+            )))
+        }
+    }
+}
+
+/// Runs the same scenario against multiple `(provider_version,
+/// consumer_version)` combinations. The scenario should build its dockerfiles
+/// from the versions (e.x. with [dockerfile_onomyd_version]) and run a
+/// container network to completion. Combinations are run one at a time since
+/// the container networks would conflict.
+#[derive(Debug, Clone)]
+pub struct VersionMatrix {
+    pub pairs: Vec<(String, String)>,
+}
+
+impl VersionMatrix {
+    pub fn new(pairs: &[(&str, &str)]) -> Self {
+        Self {
+            pairs: pairs
+                .iter()
+                .map(|(provider, consumer)| ((*provider).to_owned(), (*consumer).to_owned()))
+                .collect(),
+        }
+    }
+
+    /// Parses the `provider_version:consumer_version` pairs of
+    /// `--version-matrix`
+    pub fn from_args(args: &Args) -> Result<Self> {
+        let mut pairs = vec![];
+        for pair in &args.version_matrix {
+            let (provider, consumer) = pair.split_once(':').map_add_err(|| {
+                format!(
+                    "--version-matrix entry \"{pair}\" is not in the format \
+                     provider_version:consumer_version"
+                )
+            })?;
+            pairs.push((provider, consumer));
+        }
+        Ok(Self::new(&pairs))
+    }
+
+    /// Runs `scenario(provider_version, consumer_version)` for every
+    /// combination, continuing after failures. The summary table is logged at
+    /// the end.
+    pub async fn run<F, Fut>(&self, mut scenario: F) -> VersionMatrixReport
+    where
+        F: FnMut(String, String) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let mut report = VersionMatrixReport::default();
+        for (provider_version, consumer_version) in &self.pairs {
+            info!("running version combination ({provider_version}, {consumer_version})");
+            let start = Instant::now();
+            let res = scenario(provider_version.clone(), consumer_version.clone()).await;
+            report.results.push(VersionMatrixResult {
+                provider_version: provider_version.clone(),
+                consumer_version: consumer_version.clone(),
+                elapsed: start.elapsed(),
+                error: res.err().map(|e| format!("{e:?}")),
+            });
+        }
+        info!("version matrix summary:\n{}", report.summary());
+        report
+    }
+}